@@ -20,7 +20,8 @@ export function computeSlippage(
 }
 
 /**
- * Compute slippage for Uniswap V3 concentrated liquidity pools
+ * Compute slippage for Uniswap V3 concentrated liquidity pools, token0 in
+ * @deprecated Use computeUniswapV3SlippageWithDirection
  */
 export function computeUniswapV3Slippage(
   liquidity: number,
@@ -30,8 +31,22 @@ export function computeUniswapV3Slippage(
   return native.computeUniswapV3Slippage(liquidity, sqrtPrice, amountIn);
 }
 
+/**
+ * Compute Uniswap V3 slippage inside the active range from L and sqrtPrice
+ * zeroForOne = true swaps token0 in (sqrtPrice falls), false swaps token1 in
+ */
+export function computeUniswapV3SlippageWithDirection(
+  liquidity: number,
+  sqrtPrice: number,
+  amountIn: number,
+  zeroForOne: boolean
+): number {
+  return native.computeUniswapV3SlippageWithDirection(liquidity, sqrtPrice, amountIn, zeroForOne);
+}
+
 /**
  * Compute slippage for Curve stableswap pools with amplification
+ * Throws if amplification is not positive
  */
 export function computeCurveSlippage(
   balanceIn: number,
@@ -42,8 +57,37 @@ export function computeCurveSlippage(
   return native.computeCurveSlippage(balanceIn, balanceOut, amountIn, amplification);
 }
 
+/**
+ * Output of a Solidly stable pool (Velodrome, Aerodrome) on the x³y + xy³ = k invariant
+ * Reserves and amountIn must already be normalized to a common precision (the pair
+ * scales each token to 18 decimals); feeBps comes off the input
+ * Throws on a non-positive reserve, a negative amount or feeBps above 10000
+ */
+export function solidlyGetAmountOut(
+  reserveIn: number,
+  reserveOut: number,
+  amountIn: number,
+  feeBps: number
+): number {
+  return native.solidlyGetAmountOut(reserveIn, reserveOut, amountIn, feeBps);
+}
+
+/**
+ * Slippage (percent) of a Solidly stable-pool trade against the pool's marginal price
+ * Near balance this is far below what constant product predicts
+ */
+export function computeSolidlyStableSlippage(
+  reserveIn: number,
+  reserveOut: number,
+  amountIn: number,
+  feeBps: number
+): number {
+  return native.computeSolidlyStableSlippage(reserveIn, reserveOut, amountIn, feeBps);
+}
+
 /**
  * Compute slippage for Balancer weighted pools
+ * Throws unless both weights are in (0, 1] and sum to at most 1
  */
 export function computeBalancerSlippage(
   balanceIn: number,
@@ -113,38 +157,51 @@ export function calculateMarketImpact(
  * Calculate total slippage for multi-hop arbitrage path
  * @param reserves - Array of [reserveIn, reserveOut] pairs for each hop
  * @param flashloanAmount - Amount borrowed via flashloan
+ * @param hopTokens - Optional [tokenIn, tokenOut] per hop; throws if the hops do not connect
+ *   (Infinity in safe mode)
  */
 export function calculateMultihopSlippage(
   reserves: number[][],
-  flashloanAmount: number
+  flashloanAmount: number,
+  hopTokens?: string[][]
 ): number {
-  return native.calculateMultihopSlippage(reserves, flashloanAmount);
+  return native.calculateMultihopSlippage(reserves, flashloanAmount, hopTokens);
 }
 
 /**
  * Simulate flashloan arbitrage execution across multiple paths in parallel
  * Returns array of [profit, slippage, pathIndex] for each path
+ * Throws if flashloanAmounts or gasCosts does not have one entry per path
  * @param paths - Array of paths, each path is array of [reserveIn, reserveOut] pairs
  * @param flashloanAmounts - Flashloan amount for each path
  * @param flashloanFee - Fee percentage for flashloan (e.g., 0.0009 for 0.09%)
  * @param gasCosts - Gas cost for each path
+ * @param pathTokens - Optional [tokenIn, tokenOut] per hop of each path; throws if any path
+ *   does not connect (in safe mode that path's row is [-Infinity, Infinity, pathIndex])
+ * @param feeBps - Optional swap fee charged by every hop in basis points (default 30)
  */
 export function simulateParallelFlashloanPaths(
   paths: number[][][],
   flashloanAmounts: number[],
   flashloanFee: number,
-  gasCosts: number[]
+  gasCosts: number[],
+  pathTokens?: string[][][],
+  feeBps?: number
 ): number[][] {
   return native.simulateParallelFlashloanPaths(
     paths,
     flashloanAmounts,
     flashloanFee,
-    gasCosts
+    gasCosts,
+    pathTokens,
+    feeBps
   );
 }
 
 /**
  * Calculate optimal flashloan amount for Uniswap V3 concentrated liquidity
+ * Models each pool as a single full-range position of `liquidity` at the 0.3% tier;
+ * use calculateFlashloanAmountV3Ticks when liquidity changes across ticks
  */
 export function calculateFlashloanAmountV3(
   liquidity: number,
@@ -162,6 +219,44 @@ export function calculateFlashloanAmountV3(
   );
 }
 
+/**
+ * Uniswap V3 pool for flashloan sizing
+ * ranges are [tickLower, tickUpper, liquidity] rows around the current price
+ */
+export interface V3Pool {
+  sqrtPrice: number;
+  ranges: number[][];
+  feeBps: number;
+}
+
+/**
+ * Optimal token1 flashloan for buying token0 on `buy` and selling it on `sell`
+ * Both legs are walked tick by tick, consuming each range's liquidity in turn, and the
+ * size is found by binary search on the marginal profit; 0 when gas is not covered
+ */
+export function calculateFlashloanAmountV3Ticks(
+  buy: V3Pool,
+  sell: V3Pool,
+  flashloanFee: number,
+  gasCost: number
+): number {
+  return native.calculateFlashloanAmountV3Ticks(buy, sell, flashloanFee, gasCost);
+}
+
+/**
+ * Exact V3 swap across initialized ranges: token0 in when zeroForOne, token1 in otherwise
+ * Returns [amountOut, amountInUsed, sqrtPriceAfter, ticksCrossed]
+ */
+export function v3SwapAcrossTicks(
+  sqrtPrice: number,
+  ranges: number[][],
+  amountIn: number,
+  feeBps: number,
+  zeroForOne: boolean
+): number[] {
+  return native.v3SwapAcrossTicks(sqrtPrice, ranges, amountIn, feeBps, zeroForOne);
+}
+
 // New arbitrage flow functions
 
 /**
@@ -208,6 +303,19 @@ export function calculateAmountIn(
   return native.calculateAmountIn(reserveIn, reserveOut, amountOut);
 }
 
+/**
+ * calculateAmountIn for a pool charging feeBps (e.g. 25 for PancakeSwap, 100 for 1%)
+ * Formula: amountIn = (ReserveIn × AmountOut × 10000) / ((ReserveOut - AmountOut) × (10000 - feeBps)) + 1
+ */
+export function calculateAmountInWithFee(
+  reserveIn: number,
+  reserveOut: number,
+  amountOut: number,
+  feeBps: number
+): number {
+  return native.calculateAmountInWithFee(reserveIn, reserveOut, amountOut, feeBps);
+}
+
 /**
  * Step 3: Calculate output amount for given input
  * Formula: amountOut = (ReserveOut × AmountIn × 997) / (ReserveIn × 1000 + AmountIn × 997)
@@ -220,6 +328,19 @@ export function calculateAmountOut(
   return native.calculateAmountOut(reserveIn, reserveOut, amountIn);
 }
 
+/**
+ * calculateAmountOut for a pool charging feeBps (e.g. 25 for PancakeSwap, 100 for 1%)
+ * Formula: amountOut = (ReserveOut × AmountIn × (10000 - feeBps)) / (ReserveIn × 10000 + AmountIn × (10000 - feeBps))
+ */
+export function calculateAmountOutWithFee(
+  reserveIn: number,
+  reserveOut: number,
+  amountIn: number,
+  feeBps: number
+): number {
+  return native.calculateAmountOutWithFee(reserveIn, reserveOut, amountIn, feeBps);
+}
+
 /**
  * Step 4: Estimate profitability of arbitrage
  * Formula: profit = AmountOut_sell - AmountIn_buy - gas_fees - flashloan_fees
@@ -244,6 +365,65 @@ export function estimateArbitrageProfit(
   );
 }
 
+/**
+ * calculatePoolPrice that throws on a non-positive reserve instead of returning 0
+ */
+export function tryCalculatePoolPrice(
+  reserveIn: number,
+  reserveOut: number
+): number {
+  return native.tryCalculatePoolPrice(reserveIn, reserveOut);
+}
+
+/**
+ * calculateAmountIn that throws on bad reserves, a negative amount or an amountOut
+ * the pool cannot pay, instead of returning 0
+ */
+export function tryCalculateAmountIn(
+  reserveIn: number,
+  reserveOut: number,
+  amountOut: number
+): number {
+  return native.tryCalculateAmountIn(reserveIn, reserveOut, amountOut);
+}
+
+/**
+ * calculateAmountOut that throws on bad reserves or a negative amount instead of
+ * returning 0
+ */
+export function tryCalculateAmountOut(
+  reserveIn: number,
+  reserveOut: number,
+  amountIn: number
+): number {
+  return native.tryCalculateAmountOut(reserveIn, reserveOut, amountIn);
+}
+
+/**
+ * estimateArbitrageProfit that throws on an unusable pool, a negative amount or
+ * flashloan fee, or a non-finite gas cost instead of quoting it; a negative gas
+ * cost is a net rebate
+ */
+export function tryEstimateArbitrageProfit(
+  buyReserveIn: number,
+  buyReserveOut: number,
+  sellReserveIn: number,
+  sellReserveOut: number,
+  amountIn: number,
+  gasCost: number,
+  flashloanFeePct: number
+): number {
+  return native.tryEstimateArbitrageProfit(
+    buyReserveIn,
+    buyReserveOut,
+    sellReserveIn,
+    sellReserveOut,
+    amountIn,
+    gasCost,
+    flashloanFeePct
+  );
+}
+
 /**
  * Step 5: Solve quadratic equation ax² + bx + c = 0
  * Returns [root1, root2]
@@ -304,11 +484,45 @@ export interface ArbitrageConfig {
   minPriceDiffPct: number;
   maxTwapDeviationPct: number;
   minProfitThreshold: number;
+  maxSlippagePct?: number;
+  competition?: CompetitionModel;
+  capMode?: CapMode;
+  slippagePenaltyWeight?: number;
+  minTradeSize?: number;
+  gasPricing?: GasPricing;
+}
+
+/**
+ * Gas paid in the native token, priced in the profit token
+ * When set on ArbitrageConfig it replaces gasCost:
+ * gasUnits × gasPriceGwei × 1e-9 × nativePriceQuoteToken
+ */
+export interface GasPricing {
+  gasUnits: number;
+  gasPriceGwei: number;
+  nativePriceQuoteToken: number;
+}
+
+/**
+ * How sizing treats its reserve-fraction cap
+ * Soft returns the true optimum beyond the cap and flags it with exceedsSoftCap
+ */
+export enum CapMode {
+  Hard = 0,
+  Soft = 1,
+}
+
+/**
+ * Expected competition for an opportunity, used to derive landing probability
+ */
+export interface CompetitionModel {
+  competitorCount: number;
+  ourEdgePct: number;
 }
 
 /**
  * Step 7: Complete arbitrage execution flow
- * Returns [shouldExecute (0/1), optimalAmount, expectedProfit]
+ * Returns [shouldExecute (0/1), optimalAmount, expectedProfit, exceedsSoftCap (0/1)]
  * 
  * This function implements the complete logical flow:
  * 1. Identify Arbitrage Opportunities
@@ -339,5 +553,72 @@ export function executeArbitrageFlow(
   );
 }
 
+/**
+ * Intermediate value from a traced arbitrage flow
+ */
+export interface TraceStep {
+  name: string;
+  value: number;
+}
+
+/**
+ * Same decision as executeArbitrageFlow, plus each step's output in flow order:
+ * price_diff_pct, direction, current_price, twap, optimal_amount, exceeds_soft_cap,
+ * expected_profit, should_execute. A flow that stops early (no opportunity, TWAP rejection) ends its trace there
+ */
+export function executeArbitrageFlowTraced(
+  pool1ReserveIn: number,
+  pool1ReserveOut: number,
+  pool2ReserveIn: number,
+  pool2ReserveOut: number,
+  priceSamplesPool1: number[][],
+  priceSamplesPool2: number[][],
+  config: ArbitrageConfig
+): { result: number[]; steps: TraceStep[] } {
+  return native.executeArbitrageFlowTraced(
+    pool1ReserveIn,
+    pool1ReserveOut,
+    pool2ReserveIn,
+    pool2ReserveOut,
+    priceSamplesPool1,
+    priceSamplesPool2,
+    config
+  );
+}
+
+/**
+ * Constant-product pool between two token ids, tradeable in both directions
+ */
+export interface PoolEdge {
+  token0: number;
+  token1: number;
+  reserve0: number;
+  reserve1: number;
+  feeBps: number;
+}
+
+export interface ArbitrageCycle {
+  /** Indices into the pools passed in, in trading order */
+  pools: number[];
+  /** Tokens visited, starting and ending with the start token */
+  tokens: number[];
+  /** Marginal return of one trip after LP fees, before price impact and gas */
+  grossReturn: number;
+}
+
+/**
+ * Profitable cycles of up to maxHops pools out of and back into startToken, best first
+ * Found by Bellman-Ford over -ln(fee-adjusted price) edge weights, so A -> B -> C -> A
+ * loops are found as well as two-pool arbs
+ * Throws on a pool with a non-positive reserve or the same token on both sides
+ */
+export function findArbitrageCycles(
+  pools: PoolEdge[],
+  startToken: number,
+  maxHops: number
+): ArbitrageCycle[] {
+  return native.findArbitrageCycles(pools, startToken, maxHops);
+}
+
 // Export native module for advanced usage
 export { native };
@@ -0,0 +1,48 @@
+// Sequential vs parallel batch evaluation on a block's worth of candidate pairs
+
+// The crate is a Node addon whose napi symbols only resolve inside Node, so the bench
+// builds the pure math module on its own
+#[allow(dead_code, unused_imports)]
+#[path = "../src/math.rs"]
+mod math;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use math::{batch_evaluate_opportunities, batch_evaluate_opportunities_parallel, ArbitrageConfig};
+
+const CANDIDATES: usize = 50_000;
+
+/// Pairs with price gaps from 0% to 10%, so the batch mixes rejected and sized opportunities
+fn candidates() -> Vec<(f64, f64, f64, f64)> {
+    (0..CANDIDATES)
+        .map(|i| {
+            let gap = 1.0 + (i % 100) as f64 / 1000.0;
+            let depth = 1e6 * (1.0 + (i % 7) as f64);
+            (depth, 2.0 * depth, depth, 2.0 * depth * gap)
+        })
+        .collect()
+}
+
+fn bench_batch_evaluate(c: &mut Criterion) {
+    let opportunities = candidates();
+    let config = ArbitrageConfig {
+        gas_cost: 100.0,
+        flashloan_fee_pct: 0.0009,
+        min_price_diff_pct: 0.5,
+        max_twap_deviation_pct: 10.0,
+        ..ArbitrageConfig::default()
+    };
+    let mut group = c.benchmark_group("batch_evaluate_50k");
+    group.sample_size(10);
+
+    group.bench_function("sequential", |b| {
+        b.iter(|| batch_evaluate_opportunities(black_box(&opportunities), &config))
+    });
+    group.bench_function("parallel", |b| {
+        b.iter(|| batch_evaluate_opportunities_parallel(black_box(&opportunities), &config))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_batch_evaluate);
+criterion_main!(benches);
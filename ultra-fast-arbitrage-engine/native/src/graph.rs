@@ -0,0 +1,263 @@
+// Token graph search for multi-pool arbitrage cycles
+
+use std::collections::HashMap;
+
+use crate::math::{fee_multiplier, MathError};
+
+/// Gap below zero a cycle's log weight must clear, so float noise on a fee-free loop
+/// is not reported as profit
+const CYCLE_EPSILON: f64 = 1e-12;
+
+/// A constant-product pool between two tokens, tradeable in both directions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolEdge {
+    pub token0: u32,
+    pub token1: u32,
+    pub reserve0: f64,
+    pub reserve1: f64,
+    pub fee_bps: u32,
+}
+
+/// A profitable loop out of and back into the start token
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArbitrageCycle {
+    /// Indices into the pools passed in, in trading order
+    pub pools: Vec<usize>,
+    /// Tokens visited, starting and ending with the start token
+    pub tokens: Vec<u32>,
+    /// Marginal return of one trip after LP fees, before price impact and gas
+    /// Formula: Π(price_i × (10000 - fee_bps_i) / 10000) - 1
+    pub gross_return: f64,
+}
+
+/// One direction of a pool, weighted by -ln(fee-adjusted price)
+struct Edge {
+    pool: usize,
+    from: usize,
+    to: usize,
+    weight: f64,
+}
+
+/// Profitable cycles of up to max_hops pools that start and end at start_token, best first
+/// Bellman-Ford over -ln(γ·reserve_out/reserve_in) edges keeps only simple walks, so a short loop
+/// cannot shadow a longer cycle. A bad pool is ZeroReserve or SameToken
+pub fn find_arbitrage_cycles(
+    pools: &[PoolEdge],
+    start_token: u32,
+    max_hops: usize,
+) -> Result<Vec<ArbitrageCycle>, MathError> {
+    let mut index: HashMap<u32, usize> = HashMap::new();
+    let mut tokens = Vec::new();
+    for pool in pools {
+        for (name, value) in [("reserve0", pool.reserve0), ("reserve1", pool.reserve1)] {
+            if !(value > 0.0 && value.is_finite()) {
+                return Err(MathError::ZeroReserve { name, value });
+            }
+        }
+        if pool.token0 == pool.token1 {
+            return Err(MathError::SameToken {
+                token: pool.token0.to_string(),
+            });
+        }
+        for token in [pool.token0, pool.token1] {
+            index.entry(token).or_insert_with(|| {
+                tokens.push(token);
+                tokens.len() - 1
+            });
+        }
+    }
+    let Some(&start) = index.get(&start_token) else {
+        return Ok(vec![]);
+    };
+
+    let edges: Vec<Edge> = pools
+        .iter()
+        .enumerate()
+        .flat_map(|(pool, p)| {
+            let gamma = fee_multiplier(p.fee_bps);
+            let (t0, t1) = (index[&p.token0], index[&p.token1]);
+            [
+                Edge {
+                    pool,
+                    from: t0,
+                    to: t1,
+                    weight: -(gamma * p.reserve1 / p.reserve0).ln(),
+                },
+                Edge {
+                    pool,
+                    from: t1,
+                    to: t0,
+                    weight: -(gamma * p.reserve0 / p.reserve1).ln(),
+                },
+            ]
+        })
+        .collect();
+
+    // walks[v]: cheapest simple walk of the current hop count from start to v; walks that
+    // return to start close a cycle and are never extended
+    let mut walks: Vec<Option<Walk>> = (0..tokens.len()).map(|_| None).collect();
+    walks[start] = Some(Walk {
+        weight: 0.0,
+        pools: vec![],
+        tokens: vec![start],
+    });
+    let mut cycles = Vec::new();
+
+    for _ in 0..max_hops {
+        let mut next: Vec<Option<Walk>> = (0..tokens.len()).map(|_| None).collect();
+        let mut closing: Option<Walk> = None;
+        for edge in &edges {
+            let Some(walk) = &walks[edge.from] else {
+                continue;
+            };
+            let weight = walk.weight + edge.weight;
+            let best = if edge.to == start {
+                &mut closing
+            } else if walk.tokens.contains(&edge.to) {
+                continue;
+            } else {
+                &mut next[edge.to]
+            };
+            if best.as_ref().is_none_or(|current| weight < current.weight) {
+                *best = Some(walk.extend(edge, weight));
+            }
+        }
+        walks = next;
+
+        if let Some(cycle) = closing.filter(|cycle| cycle.weight < -CYCLE_EPSILON) {
+            cycles.push(ArbitrageCycle {
+                pools: cycle.pools,
+                tokens: cycle.tokens.iter().map(|&t| tokens[t]).collect(),
+                gross_return: (-cycle.weight).exp() - 1.0,
+            });
+        }
+    }
+
+    cycles.sort_by(|a, b| b.gross_return.total_cmp(&a.gross_return));
+    Ok(cycles)
+}
+
+/// A walk out of the start token, with the pools traded and tokens visited in order
+struct Walk {
+    weight: f64,
+    pools: Vec<usize>,
+    tokens: Vec<usize>,
+}
+
+impl Walk {
+    /// This walk followed by one more edge, at the already summed weight
+    fn extend(&self, edge: &Edge, weight: f64) -> Walk {
+        let mut pools = self.pools.clone();
+        pools.push(edge.pool);
+        let mut tokens = self.tokens.clone();
+        tokens.push(edge.to);
+        Walk {
+            weight,
+            pools,
+            tokens,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(token0: u32, token1: u32, reserve0: f64, reserve1: f64) -> PoolEdge {
+        PoolEdge {
+            token0,
+            token1,
+            reserve0,
+            reserve1,
+            fee_bps: 30,
+        }
+    }
+
+    #[test]
+    fn test_find_arbitrage_cycles() {
+        // 0 -> 1 -> 2 -> 0 nets 2 × 1.6 × 0.33 = 1.056 before fees
+        let pools = [
+            pool(0, 1, 1e6, 2e6),
+            pool(1, 2, 1e6, 1.6e6),
+            pool(2, 0, 3e6, 0.99e6),
+        ];
+        let cycles = find_arbitrage_cycles(&pools, 0, 3).unwrap();
+        assert_eq!(cycles.len(), 1);
+        assert_eq!(cycles[0].pools, vec![0, 1, 2]);
+        assert_eq!(cycles[0].tokens, vec![0, 1, 2, 0]);
+        let expected = 2.0 * 1.6 * 0.33 * 0.997f64.powi(3) - 1.0;
+        assert!((cycles[0].gross_return - expected).abs() < 1e-12);
+
+        // The same loop from another token rotates, and is out of reach in two hops
+        let from_two = find_arbitrage_cycles(&pools, 2, 3).unwrap();
+        assert_eq!(from_two[0].tokens, vec![2, 0, 1, 2]);
+        assert!(find_arbitrage_cycles(&pools, 0, 2).unwrap().is_empty());
+
+        // Fees are part of the weight: a 2% fee per hop eats the 5.6% edge
+        let expensive: Vec<PoolEdge> = pools
+            .iter()
+            .map(|p| PoolEdge { fee_bps: 200, ..*p })
+            .collect();
+        assert!(find_arbitrage_cycles(&expensive, 0, 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_find_arbitrage_cycles_keeps_longer_cycle_behind_short_loop() {
+        // Two 0/1 pools disagree by 10% (a 2-cycle); going round that loop twice is the
+        // cheapest 4-hop walk, but it revisits tokens and must not hide 0 -> 2 -> 3 -> 4 -> 0
+        let pools = [
+            pool(0, 1, 1e6, 2e6),
+            pool(1, 0, 1e6, 0.55e6),
+            pool(0, 2, 1e6, 2e6),
+            pool(2, 3, 1e6, 1.6e6),
+            pool(3, 4, 1e6, 1e6),
+            pool(4, 0, 3e6, 0.99e6),
+        ];
+        let cycles = find_arbitrage_cycles(&pools, 0, 4).unwrap();
+        assert_eq!(cycles.len(), 2);
+        assert_eq!(cycles[0].pools, vec![0, 1]);
+        assert_eq!(cycles[0].tokens, vec![0, 1, 0]);
+        assert_eq!(cycles[1].pools, vec![2, 3, 4, 5]);
+        assert_eq!(cycles[1].tokens, vec![0, 2, 3, 4, 0]);
+        let expected = 2.0 * 1.6 * 0.33 * 0.997f64.powi(4) - 1.0;
+        assert!((cycles[1].gross_return - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_find_arbitrage_cycles_ignores_consistent_markets() {
+        // Prices that agree everywhere leave only the fees, so no loop pays
+        let pools = [
+            pool(0, 1, 1e6, 2e6),
+            pool(1, 2, 1e6, 1.5e6),
+            pool(2, 0, 3e6, 1e6),
+            pool(0, 2, 1e6, 3e6),
+        ];
+        assert!(find_arbitrage_cycles(&pools, 0, 6).unwrap().is_empty());
+
+        // Even fee-free, a balanced loop is not a negative cycle
+        let fee_free: Vec<PoolEdge> = pools
+            .iter()
+            .map(|p| PoolEdge { fee_bps: 0, ..*p })
+            .collect();
+        assert!(find_arbitrage_cycles(&fee_free, 0, 6).unwrap().is_empty());
+
+        // A start token no pool trades, or no hops, finds nothing
+        assert!(find_arbitrage_cycles(&pools, 9, 4).unwrap().is_empty());
+        assert!(find_arbitrage_cycles(&pools, 0, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_find_arbitrage_cycles_rejects_bad_pools() {
+        assert_eq!(
+            find_arbitrage_cycles(&[pool(0, 1, 0.0, 1e6)], 0, 3),
+            Err(MathError::ZeroReserve {
+                name: "reserve0",
+                value: 0.0
+            })
+        );
+        assert!(matches!(
+            find_arbitrage_cycles(&[pool(4, 4, 1e6, 2e6)], 4, 3),
+            Err(MathError::SameToken { .. })
+        ));
+    }
+}
@@ -1,9 +1,61 @@
 #![deny(clippy::all)]
 
+mod graph;
 mod math;
 
+use std::collections::HashMap;
+
+use napi::bindgen_prelude::{BigInt, Error, Result, Status};
 use napi_derive::napi;
 
+/// Safe mode: engine functions that would throw on bad input return a conservative value
+/// instead (0 for prices, profits and invariants, null for optional results, no rows for
+/// batches, a zero settlement, infinite slippage for a path whose tokens do not connect)
+/// and count the error by kind in lastErrorCounts()
+#[napi]
+pub fn set_safe_mode(enabled: bool) {
+    math::set_safe_mode(enabled);
+}
+
+#[napi(object)]
+pub struct ErrorCounts {
+    pub same_token: u32,
+    pub invalid_parameter: u32,
+    pub non_convergent: u32,
+    pub length_mismatch: u32,
+    pub broken_path: u32,
+    pub zero_reserve: u32,
+    pub negative_amount: u32,
+    pub insufficient_liquidity: u32,
+}
+
+/// Errors swallowed by safe mode since the last reset_error_counts()
+#[napi]
+pub fn last_error_counts() -> ErrorCounts {
+    let counts = math::last_error_counts();
+    ErrorCounts {
+        same_token: counts.same_token,
+        invalid_parameter: counts.invalid_parameter,
+        non_convergent: counts.non_convergent,
+        length_mismatch: counts.length_mismatch,
+        broken_path: counts.broken_path,
+        zero_reserve: counts.zero_reserve,
+        negative_amount: counts.negative_amount,
+        insufficient_liquidity: counts.insufficient_liquidity,
+    }
+}
+
+#[napi]
+pub fn reset_error_counts() {
+    math::reset_error_counts();
+}
+
+impl From<math::MathError> for Error {
+    fn from(err: math::MathError) -> Self {
+        Error::new(Status::InvalidArg, err.to_string())
+    }
+}
+
 /// Configuration parameters for arbitrage execution
 #[napi(object)]
 pub struct ArbitrageConfig {
@@ -12,6 +64,84 @@ pub struct ArbitrageConfig {
     pub min_price_diff_pct: f64,
     pub max_twap_deviation_pct: f64,
     pub min_profit_threshold: f64,
+    /// Skip opportunities whose slippage on either leg exceeds this (unset = no ceiling)
+    pub max_slippage_pct: Option<f64>,
+    /// When set, batch results also carry expected_value = profit × landing probability
+    pub competition: Option<CompetitionModel>,
+    /// Soft lets sizing exceed the reserve cap and flags it (unset = hard cap)
+    pub cap_mode: Option<CapMode>,
+    /// Profit traded away per point of combined leg slippage when sizing (unset = 0)
+    pub slippage_penalty_weight: Option<f64>,
+    /// Skip opportunities whose optimal size is below this dust granularity (unset = 0)
+    pub min_trade_size: Option<f64>,
+    /// When set, gas is priced from these instead of the flat gas_cost
+    pub gas_pricing: Option<GasPricing>,
+}
+
+/// Gas paid in the native token, priced in the profit token:
+/// gas_units × gas_price_gwei × 1e-9 × native_price_quote_token
+#[napi(object)]
+pub struct GasPricing {
+    pub gas_units: f64,
+    pub gas_price_gwei: f64,
+    pub native_price_quote_token: f64,
+}
+
+/// How sizing treats its reserve-fraction cap
+#[napi]
+pub enum CapMode {
+    Hard,
+    Soft,
+}
+
+impl From<CapMode> for math::CapMode {
+    fn from(mode: CapMode) -> Self {
+        match mode {
+            CapMode::Hard => math::CapMode::Hard,
+            CapMode::Soft => math::CapMode::Soft,
+        }
+    }
+}
+
+/// Expected competition for an opportunity, used to derive landing probability
+#[napi(object)]
+pub struct CompetitionModel {
+    pub competitor_count: u32,
+    pub our_edge_pct: f64,
+}
+
+impl From<ArbitrageConfig> for math::ArbitrageConfig {
+    fn from(config: ArbitrageConfig) -> Self {
+        math::ArbitrageConfig {
+            gas_cost: config.gas_cost,
+            flashloan_fee_pct: config.flashloan_fee_pct,
+            min_price_diff_pct: config.min_price_diff_pct,
+            max_twap_deviation_pct: config.max_twap_deviation_pct,
+            min_profit_threshold: config.min_profit_threshold,
+            max_slippage_pct: config.max_slippage_pct.unwrap_or(f64::INFINITY),
+            competition: config.competition.map(|model| math::CompetitionModel {
+                competitor_count: model.competitor_count,
+                our_edge_pct: model.our_edge_pct,
+            }),
+            cap_mode: config
+                .cap_mode
+                .map(math::CapMode::from)
+                .unwrap_or(math::CapMode::Hard),
+            slippage_penalty_weight: config.slippage_penalty_weight.unwrap_or(0.0),
+            min_trade_size: config.min_trade_size.unwrap_or(0.0),
+            gas_pricing: config.gas_pricing.map(|pricing| math::GasPricing {
+                gas_units: pricing.gas_units,
+                gas_price_gwei: pricing.gas_price_gwei,
+                native_price_quote_token: pricing.native_price_quote_token,
+            }),
+        }
+    }
+}
+
+/// Float comparison with the same tolerance semantics the engine uses internally
+#[napi]
+pub fn approx_eq(a: f64, b: f64, rel_tol: f64, abs_tol: f64) -> bool {
+    math::approx_eq(a, b, rel_tol, abs_tol)
 }
 
 #[napi]
@@ -19,337 +149,2747 @@ pub fn compute_uniswap_v2_slippage(reserve_in: f64, reserve_out: f64, amount_in:
     math::compute_uniswap_v2_slippage(reserve_in, reserve_out, amount_in)
 }
 
+/// fee_bps = 0 is supported for fee-free pools
+#[napi]
+pub fn compute_uniswap_v2_slippage_with_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    fee_bps: u32,
+) -> f64 {
+    math::compute_uniswap_v2_slippage_with_fee(reserve_in, reserve_out, amount_in, fee_bps)
+}
+
+/// Token0-in slippage
+/// @deprecated Use computeUniswapV3SlippageWithDirection, which takes the swap direction
+#[napi]
+pub fn compute_uniswap_v3_slippage(liquidity: f64, sqrt_price: f64, amount_in: f64) -> f64 {
+    #[allow(deprecated)]
+    math::compute_uniswap_v3_slippage(liquidity, sqrt_price, amount_in)
+}
+
+/// Slippage of a swap inside the active range; zero_for_one is token0 in (price falls),
+/// otherwise token1 in
+#[napi]
+pub fn compute_uniswap_v3_slippage_with_direction(
+    liquidity: f64,
+    sqrt_price: f64,
+    amount_in: f64,
+    zero_for_one: bool,
+) -> f64 {
+    math::compute_uniswap_v3_slippage_with_direction(liquidity, sqrt_price, amount_in, zero_for_one)
+}
+
+#[napi]
+pub fn sqrt_price_to_tick(sqrt_price: f64) -> i32 {
+    math::sqrt_price_to_tick(sqrt_price)
+}
+
+#[napi]
+pub fn tick_to_sqrt_price(tick: i32) -> f64 {
+    math::tick_to_sqrt_price(tick)
+}
+
+#[napi]
+pub fn round_to_tick_spacing(target_tick: i32, tick_spacing: i32) -> i32 {
+    math::round_to_tick_spacing(target_tick, tick_spacing)
+}
+
+/// Tick bitmap words (as BigInt u64 limbs) for next_initialized_tick from initialized ticks
+#[napi]
+pub fn tick_bitmap_from_ticks(ticks: Vec<i32>, tick_spacing: i32) -> Vec<BigInt> {
+    math::tick_bitmap_from_ticks(&ticks, tick_spacing)
+        .into_iter()
+        .map(BigInt::from)
+        .collect()
+}
+
+/// Next initialized tick in the swap direction, traversing the bitmap as the V3 contract does;
+/// tick_bitmap holds each 256-bit on-chain word as four little-endian u64 BigInt limbs, from
+/// the lowest word the spacing can use
+#[napi]
+pub fn next_initialized_tick(
+    tick_bitmap: Vec<BigInt>,
+    current_tick: i32,
+    tick_spacing: i32,
+    zero_for_one: bool,
+) -> Result<Option<i32>> {
+    let limbs = tick_bitmap
+        .iter()
+        .map(|limb| match limb.get_u64() {
+            (false, value, true) => Ok(value),
+            _ => Err(Error::new(
+                Status::InvalidArg,
+                "tick bitmap limbs must be non-negative BigInts that fit in u64".to_string(),
+            )),
+        })
+        .collect::<Result<Vec<u64>>>()?;
+
+    Ok(math::next_initialized_tick(
+        &limbs,
+        current_tick,
+        tick_spacing,
+        zero_for_one,
+    ))
+}
+
+#[napi]
+pub fn nearest_achievable_sqrt_price(target_sqrt_price: f64, fee_bps: u32) -> f64 {
+    math::nearest_achievable_sqrt_price(target_sqrt_price, fee_bps)
+}
+
+#[napi]
+pub fn v3_position_in_range(sqrt_price: f64, tick_lower: i32, tick_upper: i32) -> bool {
+    math::v3_position_in_range(sqrt_price, tick_lower, tick_upper)
+}
+
+#[napi]
+pub fn adjust_v3_liquidity(
+    current_liquidity: f64,
+    liquidity_delta: f64,
+    tick_in_range: bool,
+) -> f64 {
+    math::adjust_v3_liquidity(current_liquidity, liquidity_delta, tick_in_range)
+}
+
+/// Token0 -> token1 swap that stays inside the current range; returns [amount_out, sqrt_price_after]
+#[napi]
+pub fn v3_swap_within_tick(
+    sqrt_price: f64,
+    liquidity: f64,
+    amount_in: f64,
+    fee_bps: u32,
+) -> Vec<f64> {
+    let (amount_out, sqrt_price_after) =
+        math::v3_swap_within_tick(sqrt_price, liquidity, amount_in, fee_bps);
+    vec![amount_out, sqrt_price_after]
+}
+
+/// V3-equivalent liquidity L = sqrt(reserve_in × reserve_out) of a V2 pool, for comparing depth
+#[napi]
+pub fn v2_virtual_liquidity(reserve_in: f64, reserve_out: f64) -> f64 {
+    math::v2_virtual_liquidity(reserve_in, reserve_out)
+}
+
+#[napi]
+pub fn v3_has_liquidity_at(sqrt_price: f64, ticks: Vec<Vec<f64>>) -> bool {
+    math::v3_has_liquidity_at(sqrt_price, &to_tick_ranges(&ticks))
+}
+
+/// Errors when the current price sits in a range gap with no liquidity (0 in safe mode)
+#[napi]
+pub fn compute_uniswap_v3_slippage_in_range(
+    sqrt_price: f64,
+    ticks: Vec<Vec<f64>>,
+    amount_in: f64,
+    zero_for_one: bool,
+) -> Result<f64> {
+    let result = math::compute_uniswap_v3_slippage_in_range(
+        sqrt_price,
+        &to_tick_ranges(&ticks),
+        amount_in,
+        zero_for_one,
+    );
+    Ok(math::or_safe(result, 0.0)?)
+}
+
+/// Convert [tick_lower, tick_upper, liquidity] rows into tick ranges
+fn to_tick_ranges(ticks: &[Vec<f64>]) -> Vec<math::TickRange> {
+    ticks
+        .iter()
+        .filter_map(|t| {
+            if t.len() >= 3 {
+                Some(math::TickRange {
+                    tick_lower: t[0] as i32,
+                    tick_upper: t[1] as i32,
+                    liquidity: t[2],
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[napi]
+pub fn compute_curve_slippage(
+    balance_in: f64,
+    balance_out: f64,
+    amount_in: f64,
+    amplification: f64,
+) -> Result<f64> {
+    Ok(math::or_safe(
+        math::compute_curve_slippage(balance_in, balance_out, amount_in, amplification),
+        0.0,
+    )?)
+}
+
+#[napi]
+pub fn curve_slippage_vs_amp(
+    balance_in: f64,
+    balance_out: f64,
+    amount_in: f64,
+    amps: Vec<f64>,
+) -> Result<Vec<f64>> {
+    Ok(math::or_safe(
+        math::curve_slippage_vs_amp(balance_in, balance_out, amount_in, &amps),
+        vec![],
+    )?)
+}
+
+/// Solidly stable-pool (Velodrome, Aerodrome) output on the x³y + xy³ = k invariant
+/// Reserves and amount must already be normalized to a common precision; fee_bps comes off
+/// the input. Throws on a non-positive reserve, a negative amount or fee_bps above 10000
+#[napi]
+pub fn solidly_get_amount_out(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    fee_bps: u32,
+) -> Result<f64> {
+    Ok(math::or_safe(
+        math::solidly_get_amount_out(reserve_in, reserve_out, amount_in, fee_bps),
+        0.0,
+    )?)
+}
+
+/// Slippage (percent) of a Solidly stable-pool trade against the pool's marginal price
+#[napi]
+pub fn compute_solidly_stable_slippage(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    fee_bps: u32,
+) -> Result<f64> {
+    Ok(math::or_safe(
+        math::compute_solidly_stable_slippage(reserve_in, reserve_out, amount_in, fee_bps),
+        0.0,
+    )?)
+}
+
+/// Curve's get_dy on the exact StableSwap invariant: coin j out for dx of coin i, with
+/// fee_pct (a fraction, 0.0004 for the 3pool) taken from the output
+#[napi]
+pub fn curve_get_dy(
+    balances: Vec<f64>,
+    i: u32,
+    j: u32,
+    dx: f64,
+    amplification: f64,
+    fee_pct: f64,
+) -> Result<f64> {
+    Ok(math::or_safe(
+        math::curve_get_dy(
+            &balances,
+            i as usize,
+            j as usize,
+            dx,
+            amplification,
+            fee_pct,
+        ),
+        0.0,
+    )?)
+}
+
+/// Peg-restoration trade in a StableSwap pool
+#[napi(object)]
+pub struct StablePegArb {
+    pub coin_in: u32,
+    pub coin_out: u32,
+    pub deviation_pct: f64,
+    pub amount_in: f64,
+    pub amount_out: f64,
+    pub expected_profit: f64,
+}
+
+/// reserves are the pool's coin balances; returns null when no coin is far enough off peg
+#[napi]
+pub fn stable_peg_arb(
+    reserves: Vec<f64>,
+    amp: f64,
+    peg_price: f64,
+    min_deviation_pct: f64,
+    fee_bps: u32,
+    gas_cost: f64,
+) -> Result<Option<StablePegArb>> {
+    let arb = math::or_safe(
+        math::stable_peg_arb(
+            &reserves,
+            amp,
+            peg_price,
+            min_deviation_pct,
+            fee_bps,
+            gas_cost,
+        ),
+        None,
+    )?;
+
+    Ok(arb.map(|arb| StablePegArb {
+        coin_in: arb.coin_in as u32,
+        coin_out: arb.coin_out as u32,
+        deviation_pct: arb.deviation_pct,
+        amount_in: arb.amount_in,
+        amount_out: arb.amount_out,
+        expected_profit: arb.expected_profit,
+    }))
+}
+
+#[napi]
+pub fn compute_balancer_slippage(
+    balance_in: f64,
+    balance_out: f64,
+    weight_in: f64,
+    weight_out: f64,
+    amount_in: f64,
+) -> Result<f64> {
+    Ok(math::or_safe(
+        math::compute_balancer_slippage(balance_in, balance_out, weight_in, weight_out, amount_in),
+        0.0,
+    )?)
+}
+
+/// Balancer weighted pool with any number of tokens and normalized weights
+#[napi(object)]
+pub struct BalancerPool {
+    pub balances: Vec<f64>,
+    pub weights: Vec<f64>,
+    pub swap_fee_bps: u32,
+}
+
+#[napi]
+pub fn balancer_pool_quote(
+    pool: BalancerPool,
+    token_in: u32,
+    token_out: u32,
+    amount_in: f64,
+) -> f64 {
+    let math_pool = math::BalancerPool {
+        balances: pool.balances,
+        weights: pool.weights,
+        swap_fee_bps: pool.swap_fee_bps,
+    };
+
+    math_pool.quote(token_in as usize, token_out as usize, amount_in)
+}
+
+#[napi]
+pub fn balancer_invariant(balances: Vec<f64>, weights: Vec<f64>) -> Result<f64> {
+    Ok(math::or_safe(
+        math::balancer_invariant(&balances, &weights),
+        0.0,
+    )?)
+}
+
+/// Warm cache of StableSwap and Balancer invariants keyed by pool id, reused while a pool's
+/// balances are unchanged; call clear() between blocks
+#[napi]
+#[derive(Default)]
+pub struct PoolState {
+    inner: math::PoolState,
+}
+
+#[napi]
+impl PoolState {
+    #[napi(constructor)]
+    pub fn new() -> Self {
+        PoolState {
+            inner: math::PoolState::new(),
+        }
+    }
+
+    /// Number of pools with a cached invariant
+    #[napi(getter)]
+    pub fn size(&self) -> u32 {
+        self.inner.len() as u32
+    }
+
+    #[napi]
+    pub fn stableswap_invariant(
+        &mut self,
+        pool_id: String,
+        balances: Vec<f64>,
+        amp: f64,
+    ) -> Result<f64> {
+        Ok(math::or_safe(
+            self.inner.stableswap_invariant(&pool_id, &balances, amp),
+            0.0,
+        )?)
+    }
+
+    #[napi]
+    pub fn balancer_invariant(
+        &mut self,
+        pool_id: String,
+        balances: Vec<f64>,
+        weights: Vec<f64>,
+    ) -> Result<f64> {
+        Ok(math::or_safe(
+            self.inner.balancer_invariant(&pool_id, &balances, &weights),
+            0.0,
+        )?)
+    }
+
+    /// Coin j received for amount_in of coin i, given coins as [i, j], with fee_pct (a
+    /// fraction, 0.0004 for the 3pool) taken from the output as in curve_get_dy
+    #[napi]
+    pub fn stableswap_quote(
+        &mut self,
+        pool_id: String,
+        balances: Vec<f64>,
+        amp: f64,
+        coins: Vec<u32>,
+        amount_in: f64,
+        fee_pct: f64,
+    ) -> Result<f64> {
+        let (i, j) = match coins[..] {
+            [i, j] => (i as usize, j as usize),
+            _ => (usize::MAX, usize::MAX),
+        };
+        Ok(math::or_safe(
+            self.inner
+                .stableswap_quote(&pool_id, &balances, amp, (i, j), amount_in, fee_pct),
+            0.0,
+        )?)
+    }
+
+    #[napi]
+    pub fn invalidate(&mut self, pool_id: String) {
+        self.inner.invalidate(&pool_id);
+    }
+
+    #[napi]
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+}
+
+#[napi(object)]
+pub struct V3QuoteCacheStats {
+    pub hits: i64,
+    pub recomputes: i64,
+    pub entries: u32,
+}
+
+/// Cache of V3 active liquidity per pool and fee tier; quotes reuse it until sqrt_price
+/// crosses an initialized tick. Invalidate a pool when its positions change
+#[napi]
+#[derive(Default)]
+pub struct V3QuoteCache {
+    inner: math::V3QuoteCache,
+}
+
+#[napi]
+impl V3QuoteCache {
+    #[napi(constructor)]
+    pub fn new() -> Self {
+        V3QuoteCache {
+            inner: math::V3QuoteCache::new(),
+        }
+    }
+
+    #[napi(getter)]
+    pub fn stats(&self) -> V3QuoteCacheStats {
+        let stats = self.inner.stats();
+        V3QuoteCacheStats {
+            hits: stats.hits as i64,
+            recomputes: stats.recomputes as i64,
+            entries: stats.entries as u32,
+        }
+    }
+
+    /// Token1 out for amount_in of token0; ticks are [tickLower, tickUpper, liquidity] and
+    /// are only read when the price has left the cached range. Errors for an unknown fee
+    /// tier or a price with no active liquidity (0 in safe mode)
+    #[napi]
+    pub fn quote(
+        &mut self,
+        pool_id: String,
+        fee_bps: u32,
+        sqrt_price: f64,
+        ticks: Vec<Vec<f64>>,
+        amount_in: f64,
+    ) -> Result<f64> {
+        let ranges = to_tick_ranges(&ticks);
+        let result = self
+            .inner
+            .quote(&pool_id, fee_bps, sqrt_price, &ranges, amount_in);
+        Ok(math::or_safe(result, 0.0)?)
+    }
+
+    #[napi]
+    pub fn invalidate(&mut self, pool_id: String) {
+        self.inner.invalidate(&pool_id);
+    }
+
+    #[napi]
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+}
+
+#[napi]
+pub fn compute_aggregator_slippage(slippages: Vec<f64>) -> f64 {
+    math::compute_aggregator_slippage(&slippages)
+}
+
+/// Protocol of a pool for model-aware aggregation
+#[napi]
+pub enum PoolKind {
+    V2,
+    V3,
+    Curve,
+    Balancer,
+}
+
+/// Pool of any supported protocol; V2 uses reserve_in/reserve_out, V3 liquidity/sqrt_price
+/// (zero_for_one defaults to token0 in), Curve balance_in/balance_out/amplification, Balancer balance_in/balance_out/weight_in/weight_out
+#[napi(object)]
+pub struct PoolSpec {
+    pub kind: PoolKind,
+    pub reserve_in: Option<f64>,
+    pub reserve_out: Option<f64>,
+    pub liquidity: Option<f64>,
+    pub sqrt_price: Option<f64>,
+    pub zero_for_one: Option<bool>,
+    pub balance_in: Option<f64>,
+    pub balance_out: Option<f64>,
+    pub amplification: Option<f64>,
+    pub weight_in: Option<f64>,
+    pub weight_out: Option<f64>,
+}
+
+impl From<PoolSpec> for math::PoolSpec {
+    fn from(pool: PoolSpec) -> Self {
+        match pool.kind {
+            PoolKind::V2 => math::PoolSpec::V2 {
+                reserve_in: pool.reserve_in.unwrap_or(0.0),
+                reserve_out: pool.reserve_out.unwrap_or(0.0),
+            },
+            PoolKind::V3 => math::PoolSpec::V3 {
+                liquidity: pool.liquidity.unwrap_or(0.0),
+                sqrt_price: pool.sqrt_price.unwrap_or(0.0),
+                zero_for_one: pool.zero_for_one.unwrap_or(true),
+            },
+            PoolKind::Curve => math::PoolSpec::Curve {
+                balance_in: pool.balance_in.unwrap_or(0.0),
+                balance_out: pool.balance_out.unwrap_or(0.0),
+                amplification: pool.amplification.unwrap_or(0.0),
+            },
+            PoolKind::Balancer => math::PoolSpec::Balancer {
+                balance_in: pool.balance_in.unwrap_or(0.0),
+                balance_out: pool.balance_out.unwrap_or(0.0),
+                weight_in: pool.weight_in.unwrap_or(0.0),
+                weight_out: pool.weight_out.unwrap_or(0.0),
+            },
+        }
+    }
+}
+
+/// Fee-free marginal price after amount_already_in has been routed; spot price at zero
+#[napi]
+pub fn marginal_price(pool: PoolSpec, amount_already_in: f64) -> f64 {
+    math::PoolSpec::from(pool).marginal_price(amount_already_in)
+}
+
+/// Returns [pool_index, slippage] of the lowest-slippage usable pool
+#[napi]
+pub fn aggregator_best(pools: Vec<PoolSpec>, amount_in: f64) -> Vec<f64> {
+    let math_pools: Vec<math::PoolSpec> = pools.into_iter().map(math::PoolSpec::from).collect();
+    let (idx, slippage) = math::aggregator_best(&math_pools, amount_in);
+    vec![idx as f64, slippage]
+}
+
+/// Improvement of the chosen route over the direct pool, in bps
+#[napi]
+pub fn routing_improvement(direct_slippage: f64, best_route_slippage: f64) -> f64 {
+    math::routing_improvement(direct_slippage, best_route_slippage)
+}
+
+/// Route chosen across heterogeneous pools
+#[napi(object)]
+pub struct AggregatorRoute {
+    pub pool_index: u32,
+    pub slippage_pct: f64,
+    pub improvement_bps: Option<f64>,
+}
+
+/// direct_index is the pool the trade would otherwise hit; improvement_bps is set when given
+/// router_fee_bps is the router's cut of the output (unset = none), charged on the route only
+#[napi]
+pub fn best_aggregator_route(
+    pools: Vec<PoolSpec>,
+    amount_in: f64,
+    direct_index: Option<u32>,
+    router_fee_bps: Option<u32>,
+) -> AggregatorRoute {
+    let math_pools: Vec<math::PoolSpec> = pools.into_iter().map(math::PoolSpec::from).collect();
+    let route = math::best_aggregator_route(
+        &math_pools,
+        amount_in,
+        direct_index.map(|idx| idx as usize),
+        router_fee_bps.unwrap_or(0),
+    );
+
+    AggregatorRoute {
+        pool_index: route.pool_index as u32,
+        slippage_pct: route.slippage_pct,
+        improvement_bps: route.improvement_bps,
+    }
+}
+
+#[napi]
+pub fn optimal_trade_size(
+    reserve_in: f64,
+    reserve_out: f64,
+    gas_cost: f64,
+    min_profit: f64,
+) -> f64 {
+    math::optimal_trade_size(reserve_in, reserve_out, gas_cost, min_profit)
+}
+
+#[napi]
+pub fn optimal_trade_size_with_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    gas_cost: f64,
+    min_profit: f64,
+    fee_bps: u32,
+) -> f64 {
+    math::optimal_trade_size_with_fee(reserve_in, reserve_out, gas_cost, min_profit, fee_bps)
+}
+
+/// Returns [size, utilization_pct]
+#[napi]
+pub fn optimal_trade_size_with_utilization(
+    reserve_in: f64,
+    reserve_out: f64,
+    gas_cost: f64,
+    min_profit: f64,
+) -> Vec<f64> {
+    let (size, utilization_pct) =
+        math::optimal_trade_size_with_utilization(reserve_in, reserve_out, gas_cost, min_profit);
+    vec![size, utilization_pct]
+}
+
+#[napi]
+pub fn calculate_flashloan_amount(
+    reserve_in_buy: f64,
+    reserve_out_buy: f64,
+    reserve_in_sell: f64,
+    reserve_out_sell: f64,
+    flashloan_fee: f64,
+    gas_cost: f64,
+) -> f64 {
+    math::calculate_flashloan_amount(
+        reserve_in_buy,
+        reserve_out_buy,
+        reserve_in_sell,
+        reserve_out_sell,
+        flashloan_fee,
+        gas_cost,
+    )
+}
+
+/// `calculate_flashloan_amount` with separate leg fees; pools are [reserve_in, reserve_out]
+#[napi]
+pub fn calculate_flashloan_amount_with_fees(
+    buy_pool: Vec<f64>,
+    sell_pool: Vec<f64>,
+    flashloan_fee: f64,
+    gas_cost: f64,
+    buy_fee_bps: u32,
+    sell_fee_bps: u32,
+) -> f64 {
+    let (reserve_in_buy, reserve_out_buy) = to_reserve_pair(&buy_pool);
+    let (reserve_in_sell, reserve_out_sell) = to_reserve_pair(&sell_pool);
+    math::calculate_flashloan_amount_with_fees(
+        reserve_in_buy,
+        reserve_out_buy,
+        reserve_in_sell,
+        reserve_out_sell,
+        flashloan_fee,
+        gas_cost,
+        (buy_fee_bps, sell_fee_bps),
+    )
+}
+
+/// Returns [flashloan_amount, utilization_pct]
+#[napi]
+pub fn calculate_flashloan_amount_with_utilization(
+    reserve_in_buy: f64,
+    reserve_out_buy: f64,
+    reserve_in_sell: f64,
+    reserve_out_sell: f64,
+    flashloan_fee: f64,
+    gas_cost: f64,
+) -> Vec<f64> {
+    let (amount, utilization_pct) = math::calculate_flashloan_amount_with_utilization(
+        reserve_in_buy,
+        reserve_out_buy,
+        reserve_in_sell,
+        reserve_out_sell,
+        flashloan_fee,
+        gas_cost,
+    );
+    vec![amount, utilization_pct]
+}
+
+/// Returns [new_reserve_in, new_reserve_out, amount_out]
+#[napi]
+pub fn apply_swap(reserve_in: f64, reserve_out: f64, amount_in: f64, fee_bps: u32) -> Vec<f64> {
+    let (new_reserve_in, new_reserve_out, amount_out) =
+        math::apply_swap(reserve_in, reserve_out, amount_in, fee_bps);
+    vec![new_reserve_in, new_reserve_out, amount_out]
+}
+
+/// One swap of a bundle; zero_for_one pays the pool's first reserve token for its second
+#[napi(object)]
+pub struct BundleTx {
+    pub pool_index: u32,
+    pub zero_for_one: bool,
+    /// Omit to spend the previous transaction's whole output
+    pub amount_in: Option<f64>,
+    /// Revert below this output (unset = no check)
+    pub min_amount_out: Option<f64>,
+    pub fee_bps: u32,
+    pub gas_cost: f64,
+}
+
+#[napi(object)]
+pub struct BundleTxEffect {
+    pub amount_in: f64,
+    pub amount_out: f64,
+    /// [reserve0, reserve1] after the swap
+    pub reserves_after: Vec<f64>,
+}
+
+#[napi(object)]
+pub struct BundleResult {
+    pub net_profit: f64,
+    pub per_tx_effects: Vec<BundleTxEffect>,
+    pub reverted_index: Option<u32>,
+    pub final_pool_states: Vec<Vec<f64>>,
+}
+
+/// Simulate an atomic bundle of swaps over [reserve0, reserve1] pool states; any revert
+/// drops the whole bundle
+#[napi]
+pub fn simulate_bundle(txs: Vec<BundleTx>, initial_pool_states: Vec<Vec<f64>>) -> BundleResult {
+    let txs: Vec<math::BundleTx> = txs
+        .into_iter()
+        .map(|tx| math::BundleTx {
+            pool_index: tx.pool_index as usize,
+            zero_for_one: tx.zero_for_one,
+            amount_in: tx.amount_in,
+            min_amount_out: tx.min_amount_out.unwrap_or(0.0),
+            fee_bps: tx.fee_bps,
+            gas_cost: tx.gas_cost,
+        })
+        .collect();
+    let pool_states: Vec<(f64, f64)> = initial_pool_states
+        .iter()
+        .map(|state| to_reserve_pair(state))
+        .collect();
+
+    let result = math::simulate_bundle(&txs, &pool_states);
+    BundleResult {
+        net_profit: result.net_profit,
+        per_tx_effects: result
+            .per_tx_effects
+            .iter()
+            .map(|effect| BundleTxEffect {
+                amount_in: effect.amount_in,
+                amount_out: effect.amount_out,
+                reserves_after: vec![effect.reserves_after.0, effect.reserves_after.1],
+            })
+            .collect(),
+        reverted_index: result.reverted_index.map(|index| index as u32),
+        final_pool_states: result
+            .final_pool_states
+            .iter()
+            .map(|(reserve0, reserve1)| vec![*reserve0, *reserve1])
+            .collect(),
+    }
+}
+
+/// Realized profit from logged fills, defined exactly as estimateArbitrageProfit
+#[napi]
+pub fn profit_from_fills(
+    amount_in: f64,
+    amount_out: f64,
+    gas_paid: f64,
+    flashloan_fee_paid: f64,
+) -> f64 {
+    math::profit_from_fills(amount_in, amount_out, gas_paid, flashloan_fee_paid)
+}
+
+/// Our profit after a competitor trades first on our buy or sell pool; pools are
+/// [reserve_in, reserve_out] in our direction of travel
+#[napi]
+pub fn profit_after_competitor(
+    buy_pool: Vec<f64>,
+    sell_pool: Vec<f64>,
+    our_amount: f64,
+    competitor_amount: f64,
+    competitor_on_buy: bool,
+    fee_bps: u32,
+) -> f64 {
+    math::profit_after_competitor(
+        to_reserve_pair(&buy_pool),
+        to_reserve_pair(&sell_pool),
+        our_amount,
+        competitor_amount,
+        competitor_on_buy,
+        fee_bps,
+    )
+}
+
+/// Whether both legs of an arb trade the same token pair
+#[napi]
+pub enum LegTokens {
+    Independent,
+    Shared,
+}
+
+impl From<LegTokens> for math::LegTokens {
+    fn from(legs: LegTokens) -> Self {
+        match legs {
+            LegTokens::Independent => math::LegTokens::Independent,
+            LegTokens::Shared => math::LegTokens::Shared,
+        }
+    }
+}
+
+/// Combined percent impact of a two-leg arb; Shared nets the opposite moves of both legs on
+/// the pair's one market. Pools are [reserveIn, reserveOut]
+#[napi]
+pub fn combined_leg_impact(
+    buy_pool: Vec<f64>,
+    sell_pool: Vec<f64>,
+    amount_in: f64,
+    legs: LegTokens,
+) -> f64 {
+    math::combined_leg_impact(
+        to_reserve_pair(&buy_pool),
+        to_reserve_pair(&sell_pool),
+        amount_in,
+        legs.into(),
+    )
+}
+
+#[napi]
+pub fn calculate_market_impact(reserve_in: f64, reserve_out: f64, flashloan_amount: f64) -> f64 {
+    math::calculate_market_impact(reserve_in, reserve_out, flashloan_amount)
+}
+
+#[napi]
+pub fn calculate_market_impact_with_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    flashloan_amount: f64,
+    fee_bps: u32,
+) -> f64 {
+    math::calculate_market_impact_with_fee(reserve_in, reserve_out, flashloan_amount, fee_bps)
+}
+
+/// Convert [tokenIn, tokenOut] hops into tuples; a hop with any other number of tokens is
+/// rejected rather than dropped, which would shift every later hop onto the wrong pool
+fn to_hop_tokens(
+    hop_tokens: &[Vec<String>],
+) -> std::result::Result<Vec<(String, String)>, math::MathError> {
+    hop_tokens
+        .iter()
+        .map(|t| match &t[..] {
+            [token_in, token_out] => Ok((token_in.clone(), token_out.clone())),
+            _ => Err(math::MathError::LengthMismatch {
+                name: "tokens in a hop",
+                expected: 2,
+                actual: t.len(),
+            }),
+        })
+        .collect()
+}
+
+/// hop_tokens optionally gives [tokenIn, tokenOut] per hop; when present the path must
+/// connect (each hop takes what the previous one outputs) or this throws, and in safe
+/// mode the slippage is infinite
+#[napi]
+pub fn calculate_multihop_slippage(
+    reserves: Vec<Vec<f64>>,
+    flashloan_amount: f64,
+    hop_tokens: Option<Vec<Vec<String>>>,
+) -> Result<f64> {
+    let reserve_pairs: Vec<(f64, f64)> = reserves
+        .iter()
+        .filter_map(|r| {
+            if r.len() >= 2 {
+                Some((r[0], r[1]))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if let Some(hop_tokens) = hop_tokens {
+        let validated = to_hop_tokens(&hop_tokens)
+            .and_then(|hop_tokens| math::validate_path_tokens(&hop_tokens, reserve_pairs.len()));
+        if !math::or_safe(validated.map(|()| true), false)? {
+            return Ok(f64::INFINITY);
+        }
+    }
+    Ok(math::calculate_multihop_slippage(
+        &reserve_pairs,
+        flashloan_amount,
+    ))
+}
+
+#[napi]
+pub fn calculate_multihop_slippage_with_fee(
+    reserves: Vec<Vec<f64>>,
+    flashloan_amount: f64,
+    fee_bps: u32,
+) -> f64 {
+    let reserve_pairs: Vec<(f64, f64)> = reserves
+        .iter()
+        .filter_map(|r| {
+            if r.len() >= 2 {
+                Some((r[0], r[1]))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    math::calculate_multihop_slippage_with_fee(&reserve_pairs, flashloan_amount, fee_bps)
+}
+
+/// fee_bps is the swap fee charged by every hop, defaulting to the 0.3% V2 fee
+/// In safe mode a path whose tokens do not connect gets a [-Infinity, Infinity, index]
+/// row, so the other paths' results still come back
+#[napi]
+pub fn simulate_parallel_flashloan_paths(
+    paths: Vec<Vec<Vec<f64>>>,
+    flashloan_amounts: Vec<f64>,
+    flashloan_fee: f64,
+    gas_costs: Vec<f64>,
+    path_tokens: Option<Vec<Vec<Vec<String>>>>,
+    fee_bps: Option<u32>,
+) -> Result<Vec<Vec<f64>>> {
+    let path_tuples: Vec<Vec<(f64, f64)>> = paths
+        .iter()
+        .map(|path| {
+            path.iter()
+                .filter_map(|r| {
+                    if r.len() >= 2 {
+                        Some((r[0], r[1]))
+                    } else {
+                        None
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut broken = vec![false; path_tuples.len()];
+    if let Some(path_tokens) = path_tokens {
+        let validated = path_tokens
+            .iter()
+            .map(|path| to_hop_tokens(path))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .and_then(|hop_tokens| math::validate_paths_tokens(&hop_tokens, &path_tuples));
+        if !math::or_safe(validated.map(|()| true), false)? {
+            // Safe mode swallowed the error: find which paths are at fault, or all of them
+            // when the annotations do not line up with the paths
+            let aligned = path_tokens.len() == path_tuples.len();
+            broken = path_tokens
+                .iter()
+                .zip(&path_tuples)
+                .map(|(tokens, path)| {
+                    !aligned
+                        || to_hop_tokens(tokens)
+                            .and_then(|hop_tokens| {
+                                math::validate_path_tokens(&hop_tokens, path.len())
+                            })
+                            .is_err()
+                })
+                .chain(std::iter::repeat(true))
+                .take(path_tuples.len())
+                .collect();
+        }
+    }
+
+    let simulated = match fee_bps {
+        Some(fee_bps) => math::simulate_parallel_flashloan_paths_with_fee(
+            &path_tuples,
+            &flashloan_amounts,
+            flashloan_fee,
+            &gas_costs,
+            fee_bps,
+        ),
+        None => math::simulate_parallel_flashloan_paths(
+            &path_tuples,
+            &flashloan_amounts,
+            flashloan_fee,
+            &gas_costs,
+        ),
+    };
+    let results = math::or_safe(simulated, vec![])?;
+
+    Ok(results
+        .iter()
+        .map(|&(profit, slippage, idx)| {
+            if broken[idx] {
+                vec![f64::NEG_INFINITY, f64::INFINITY, idx as f64]
+            } else {
+                vec![profit, slippage, idx as f64]
+            }
+        })
+        .collect())
+}
+
+/// Constant-product pool between two token ids, tradeable in both directions
+#[napi(object)]
+pub struct PoolEdge {
+    pub token0: u32,
+    pub token1: u32,
+    pub reserve0: f64,
+    pub reserve1: f64,
+    pub fee_bps: u32,
+}
+
+#[napi(object)]
+pub struct ArbitrageCycle {
+    /// Indices into the pools passed in, in trading order
+    pub pools: Vec<u32>,
+    /// Tokens visited, starting and ending with the start token
+    pub tokens: Vec<u32>,
+    /// Marginal return of one trip after LP fees, before price impact and gas
+    pub gross_return: f64,
+}
+
+/// Profitable cycles of up to max_hops pools out of and back into start_token, best first,
+/// found by Bellman-Ford over -ln(fee-adjusted price) edge weights
+/// Throws on a pool with a non-positive reserve or the same token on both sides
+#[napi]
+pub fn find_arbitrage_cycles(
+    pools: Vec<PoolEdge>,
+    start_token: u32,
+    max_hops: u32,
+) -> Result<Vec<ArbitrageCycle>> {
+    let edges: Vec<graph::PoolEdge> = pools
+        .iter()
+        .map(|p| graph::PoolEdge {
+            token0: p.token0,
+            token1: p.token1,
+            reserve0: p.reserve0,
+            reserve1: p.reserve1,
+            fee_bps: p.fee_bps,
+        })
+        .collect();
+    let cycles = math::or_safe(
+        graph::find_arbitrage_cycles(&edges, start_token, max_hops as usize),
+        vec![],
+    )?;
+
+    Ok(cycles
+        .into_iter()
+        .map(|cycle| ArbitrageCycle {
+            pools: cycle.pools.iter().map(|&i| i as u32).collect(),
+            tokens: cycle.tokens,
+            gross_return: cycle.gross_return,
+        })
+        .collect())
+}
+
+/// Protocol of a path hop
+#[napi]
+pub enum HopKind {
+    V2,
+    V3,
+}
+
+/// One hop of a mixed-protocol path; V2 hops use reserve_in/reserve_out,
+/// V3 hops use liquidity/sqrt_price/zero_for_one. fee_bps defaults to the 0.3% V2 fee
+#[napi(object)]
+pub struct HopSpec {
+    pub kind: HopKind,
+    pub reserve_in: Option<f64>,
+    pub reserve_out: Option<f64>,
+    pub liquidity: Option<f64>,
+    pub sqrt_price: Option<f64>,
+    pub zero_for_one: Option<bool>,
+    pub fee_bps: Option<u32>,
+}
+
+impl From<HopSpec> for math::HopSpec {
+    fn from(hop: HopSpec) -> Self {
+        let fee_bps = hop.fee_bps.unwrap_or(math::DEFAULT_FEE_BPS);
+        match hop.kind {
+            HopKind::V2 => math::HopSpec::V2 {
+                reserve_in: hop.reserve_in.unwrap_or(0.0),
+                reserve_out: hop.reserve_out.unwrap_or(0.0),
+                fee_bps,
+            },
+            HopKind::V3 => math::HopSpec::V3 {
+                liquidity: hop.liquidity.unwrap_or(0.0),
+                sqrt_price: hop.sqrt_price.unwrap_or(0.0),
+                zero_for_one: hop.zero_for_one.unwrap_or(true),
+                fee_bps,
+            },
+        }
+    }
+}
+
+/// Outcome of simulating a mixed-protocol path
+#[napi(object)]
+pub struct MixedPathResult {
+    pub amount_out: f64,
+    pub infeasible_hop: Option<u32>,
+}
+
+#[napi]
+pub fn simulate_mixed_path(
+    hops: Vec<HopSpec>,
+    amount_in: f64,
+    max_reserve_fraction: f64,
+) -> MixedPathResult {
+    let math_hops: Vec<math::HopSpec> = hops.into_iter().map(math::HopSpec::from).collect();
+    let result = math::simulate_mixed_path(&math_hops, amount_in, max_reserve_fraction);
+
+    MixedPathResult {
+        amount_out: result.amount_out,
+        infeasible_hop: result.infeasible_hop.map(|idx| idx as u32),
+    }
+}
+
+/// Every cost component of a cyclic arbitrage, all in the borrowed token
+#[napi(object)]
+pub struct ProfitBreakdown {
+    pub amount_out: f64,
+    pub lp_fee_cost: f64,
+    pub flashloan_fee: f64,
+    pub gas_cost: f64,
+    pub mev_tip: f64,
+    pub bridge_cost: f64,
+    pub net_profit: f64,
+}
+
+#[napi]
+pub fn net_profit_full(
+    legs: Vec<HopSpec>,
+    amount_in: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+    mev_tip: f64,
+    bridge_cost: f64,
+) -> ProfitBreakdown {
+    let math_legs: Vec<math::HopSpec> = legs.into_iter().map(math::HopSpec::from).collect();
+    let breakdown = math::net_profit_full(
+        &math_legs,
+        amount_in,
+        gas_cost,
+        flashloan_fee_pct,
+        mev_tip,
+        bridge_cost,
+    );
+
+    ProfitBreakdown {
+        amount_out: breakdown.amount_out,
+        lp_fee_cost: breakdown.lp_fee_cost,
+        flashloan_fee: breakdown.flashloan_fee,
+        gas_cost: breakdown.gas_cost,
+        mev_tip: breakdown.mev_tip,
+        bridge_cost: breakdown.bridge_cost,
+        net_profit: breakdown.net_profit,
+    }
+}
+
+#[napi(object)]
+pub struct PathSizing {
+    pub amount_in: f64,
+    pub expected_profit: f64,
+    /// False when amount_in is only the best sampled size of an unresolvable profit curve
+    pub converged: bool,
+}
+
+#[napi]
+pub fn optimize_path_size(path: Vec<HopSpec>, flashloan_fee: f64, gas_cost: f64) -> PathSizing {
+    let math_path: Vec<math::HopSpec> = path.into_iter().map(math::HopSpec::from).collect();
+    let sizing = math::optimize_path_size(&math_path, flashloan_fee, gas_cost);
+
+    PathSizing {
+        amount_in: sizing.amount_in,
+        expected_profit: sizing.expected_profit,
+        converged: sizing.converged,
+    }
+}
+
+#[napi]
+pub fn optimal_split(pools: Vec<HopSpec>, amount_in: f64) -> Vec<f64> {
+    let math_pools: Vec<math::HopSpec> = pools.into_iter().map(math::HopSpec::from).collect();
+    math::optimal_split(&math_pools, amount_in)
+}
+
+/// Single full-range position of `liquidity` in each pool at the 0.3% tier; use
+/// calculate_flashloan_amount_v3_ticks when liquidity changes across ticks
+#[napi]
+pub fn calculate_flashloan_amount_v3(
+    liquidity: f64,
+    sqrt_price_buy: f64,
+    sqrt_price_sell: f64,
+    flashloan_fee: f64,
+    gas_cost: f64,
+) -> f64 {
+    math::calculate_flashloan_amount_v3(
+        liquidity,
+        sqrt_price_buy,
+        sqrt_price_sell,
+        flashloan_fee,
+        gas_cost,
+    )
+}
+
+/// Uniswap V3 pool for flashloan sizing; ranges are [tick_lower, tick_upper, liquidity] rows
+#[napi(object)]
+pub struct V3Pool {
+    pub sqrt_price: f64,
+    pub ranges: Vec<Vec<f64>>,
+    pub fee_bps: u32,
+}
+
+impl From<V3Pool> for math::V3Pool {
+    fn from(pool: V3Pool) -> Self {
+        math::V3Pool {
+            sqrt_price: pool.sqrt_price,
+            ranges: to_tick_ranges(&pool.ranges),
+            fee_bps: pool.fee_bps,
+        }
+    }
+}
+
+/// Optimal token1 flashloan for buying token0 on `buy` and selling it on `sell`, with both
+/// legs walked across every crossed range; 0 when the best size does not cover gas
+#[napi]
+pub fn calculate_flashloan_amount_v3_ticks(
+    buy: V3Pool,
+    sell: V3Pool,
+    flashloan_fee: f64,
+    gas_cost: f64,
+) -> f64 {
+    math::calculate_flashloan_amount_v3_ticks(
+        &math::V3Pool::from(buy),
+        &math::V3Pool::from(sell),
+        flashloan_fee,
+        gas_cost,
+    )
+}
+
+/// Returns [amount_out, amount_in_used, sqrt_price_after, ticks_crossed]; amount_in_used falls
+/// short of amount_in when the ranges run out of liquidity
+#[napi]
+pub fn v3_swap_across_ticks(
+    sqrt_price: f64,
+    ranges: Vec<Vec<f64>>,
+    amount_in: f64,
+    fee_bps: u32,
+    zero_for_one: bool,
+) -> Vec<f64> {
+    let swap = math::v3_swap_across_ticks(
+        sqrt_price,
+        &to_tick_ranges(&ranges),
+        amount_in,
+        fee_bps,
+        zero_for_one,
+    );
+    vec![
+        swap.amount_out,
+        swap.amount_in_used,
+        swap.sqrt_price_after,
+        swap.ticks_crossed as f64,
+    ]
+}
+
+// New arbitrage flow functions
+
+#[napi]
+pub fn calculate_pool_price(reserve_in: f64, reserve_out: f64) -> f64 {
+    math::calculate_pool_price(reserve_in, reserve_out)
+}
+
+/// calculate_pool_price that throws on a non-positive reserve instead of returning 0
+#[napi]
+pub fn try_calculate_pool_price(reserve_in: f64, reserve_out: f64) -> Result<f64> {
+    Ok(math::or_safe(
+        math::try_calculate_pool_price(reserve_in, reserve_out),
+        0.0,
+    )?)
+}
+
+/// reserves are [reserve_in, reserve_out] rows; a malformed row yields NaN at its index
+#[napi]
+pub fn batch_pool_prices(reserves: Vec<Vec<f64>>) -> Vec<f64> {
+    let reserve_pairs: Vec<(f64, f64)> = reserves
+        .iter()
+        .map(|r| {
+            if r.len() >= 2 {
+                (r[0], r[1])
+            } else {
+                (f64::NAN, f64::NAN)
+            }
+        })
+        .collect();
+
+    math::batch_pool_prices(&reserve_pairs)
+}
+
+/// Pool with the token on each side identified, so mis-mapped reserves can be rejected
+#[napi(object)]
+pub struct Pool {
+    pub token_in: String,
+    pub token_out: String,
+    pub reserve_in: f64,
+    pub reserve_out: f64,
+}
+
+/// Same as calculatePoolPrice, but throws when both sides of the pool are the same token
+#[napi]
+pub fn calculate_pool_price_checked(pool: Pool) -> Result<f64> {
+    let price = math::Pool::new(
+        pool.token_in,
+        pool.token_out,
+        pool.reserve_in,
+        pool.reserve_out,
+    )
+    .map(|pool| pool.price());
+    Ok(math::or_safe(price, 0.0)?)
+}
+
+#[napi]
+pub fn balancer_spot_price(
+    balance_in: f64,
+    balance_out: f64,
+    weight_in: f64,
+    weight_out: f64,
+) -> f64 {
+    math::balancer_spot_price(balance_in, balance_out, weight_in, weight_out)
+}
+
+#[napi]
+pub fn identify_arbitrage_opportunity(
+    pool1_reserve_in: f64,
+    pool1_reserve_out: f64,
+    pool2_reserve_in: f64,
+    pool2_reserve_out: f64,
+    min_price_diff_pct: f64,
+) -> Vec<f64> {
+    let (has_opportunity, price_diff, direction) = math::identify_arbitrage_opportunity(
+        pool1_reserve_in,
+        pool1_reserve_out,
+        pool2_reserve_in,
+        pool2_reserve_out,
+        min_price_diff_pct,
+    );
+
+    vec![
+        if has_opportunity { 1.0 } else { 0.0 },
+        price_diff,
+        direction as f64,
+    ]
+}
+
+/// Stable-pair variant comparing deviations from peg_price in basis points
+/// Returns [hasOpportunity (0/1), gapBps, direction (0/1/2)]
+#[napi]
+pub fn identify_stable_opportunity(
+    pool1_reserve_in: f64,
+    pool1_reserve_out: f64,
+    pool2_reserve_in: f64,
+    pool2_reserve_out: f64,
+    peg_price: f64,
+    min_diff_bps: f64,
+) -> Vec<f64> {
+    let (has_opportunity, gap_bps, direction) = math::identify_stable_opportunity(
+        pool1_reserve_in,
+        pool1_reserve_out,
+        pool2_reserve_in,
+        pool2_reserve_out,
+        peg_price,
+        min_diff_bps,
+    );
+
+    vec![
+        if has_opportunity { 1.0 } else { 0.0 },
+        gap_bps,
+        direction as f64,
+    ]
+}
+
+/// Same as identifyArbitrageOpportunity, with each pool priced by its own protocol's formula
+#[napi]
+pub fn identify_cross_protocol_opportunity(
+    pool1: PoolSpec,
+    pool2: PoolSpec,
+    min_price_diff_pct: f64,
+) -> Vec<f64> {
+    let (has_opportunity, price_diff, direction) = math::identify_cross_protocol_opportunity(
+        &math::PoolSpec::from(pool1),
+        &math::PoolSpec::from(pool2),
+        min_price_diff_pct,
+    );
+
+    vec![
+        if has_opportunity { 1.0 } else { 0.0 },
+        price_diff,
+        direction as f64,
+    ]
+}
+
+/// pool reserves are [reserve_in, reserve_out]; returns (price1 - price2) / price2
+#[napi]
+pub fn signed_price_divergence(pool1_reserves: Vec<f64>, pool2_reserves: Vec<f64>) -> f64 {
+    math::signed_price_divergence(
+        to_reserve_pair(&pool1_reserves),
+        to_reserve_pair(&pool2_reserves),
+    )
+}
+
+/// pools are [reserve_in, reserve_out]; round-trip spread in bps after both fees, before gas
+#[napi]
+pub fn net_spread_bps(
+    pool1_reserves: Vec<f64>,
+    pool2_reserves: Vec<f64>,
+    fee1_bps: u32,
+    fee2_bps: u32,
+) -> f64 {
+    math::net_spread_bps(
+        to_reserve_pair(&pool1_reserves),
+        to_reserve_pair(&pool2_reserves),
+        fee1_bps,
+        fee2_bps,
+    )
+}
+
+#[napi]
+pub fn calculate_amount_in(reserve_in: f64, reserve_out: f64, amount_out: f64) -> f64 {
+    math::calculate_amount_in(reserve_in, reserve_out, amount_out)
+}
+
+/// fee_bps = 0 is supported for fee-free pools
+#[napi]
+pub fn calculate_amount_in_with_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_out: f64,
+    fee_bps: u32,
+) -> f64 {
+    math::calculate_amount_in_with_fee(reserve_in, reserve_out, amount_out, fee_bps)
+}
+
+/// calculate_amount_in that throws on bad reserves, a negative amount or an amount_out the
+/// pool cannot pay, instead of returning 0
+#[napi]
+pub fn try_calculate_amount_in(reserve_in: f64, reserve_out: f64, amount_out: f64) -> Result<f64> {
+    Ok(math::or_safe(
+        math::try_calculate_amount_in(reserve_in, reserve_out, amount_out),
+        0.0,
+    )?)
+}
+
+#[napi]
+pub fn size_sell_leg_for_repayment(
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    required_output: f64,
+    fee_bps: u32,
+) -> f64 {
+    math::size_sell_leg_for_repayment(sell_reserve_in, sell_reserve_out, required_output, fee_bps)
+}
+
+#[napi]
+pub fn calculate_amount_out(reserve_in: f64, reserve_out: f64, amount_in: f64) -> f64 {
+    math::calculate_amount_out(reserve_in, reserve_out, amount_in)
+}
+
+/// fee_bps = 0 is supported for fee-free pools
+#[napi]
+pub fn calculate_amount_out_with_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    fee_bps: u32,
+) -> f64 {
+    math::calculate_amount_out_with_fee(reserve_in, reserve_out, amount_in, fee_bps)
+}
+
+/// calculate_amount_out that throws on bad reserves or a negative amount instead of
+/// returning 0
+#[napi]
+pub fn try_calculate_amount_out(reserve_in: f64, reserve_out: f64, amount_in: f64) -> Result<f64> {
+    Ok(math::or_safe(
+        math::try_calculate_amount_out(reserve_in, reserve_out, amount_in),
+        0.0,
+    )?)
+}
+
+#[napi]
+pub fn infer_fee_bps(reserve_in: f64, reserve_out: f64, amount_in: f64, amount_out: f64) -> u32 {
+    math::infer_fee_bps(reserve_in, reserve_out, amount_in, amount_out)
+}
+
+#[napi]
+pub fn calculate_amount_out_with_protocol_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    fee_bps: u32,
+    protocol_fee_bps: u32,
+) -> f64 {
+    math::calculate_amount_out_with_protocol_fee(
+        reserve_in,
+        reserve_out,
+        amount_in,
+        fee_bps,
+        protocol_fee_bps,
+    )
+}
+
+/// Convert a JS BigInt into u128, rejecting negative or oversized values
+fn bigint_to_u128(value: &BigInt, name: &str) -> Result<u128> {
+    let (signed, amount, lossless) = value.get_u128();
+    if signed || !lossless {
+        return Err(Error::new(
+            Status::InvalidArg,
+            format!("{} must be a non-negative BigInt that fits in u128", name),
+        ));
+    }
+    Ok(amount)
+}
+
+/// Exact integer amount out, floored like the Uniswap V2 router; BigInt in and out
+/// Throws on a fee above 10000 bps or an intermediate that overflows u128
+#[napi]
+pub fn calculate_amount_out_u128(
+    reserve_in: BigInt,
+    reserve_out: BigInt,
+    amount_in: BigInt,
+    fee_bps: u32,
+) -> Result<BigInt> {
+    let amount_out = math::calculate_amount_out_u128(
+        bigint_to_u128(&reserve_in, "reserve_in")?,
+        bigint_to_u128(&reserve_out, "reserve_out")?,
+        bigint_to_u128(&amount_in, "amount_in")?,
+        fee_bps,
+    );
+    Ok(BigInt::from(math::or_safe(amount_out, 0)?))
+}
+
+#[napi]
+pub fn calculate_multihop_amount_out_u128(
+    reserves: Vec<Vec<BigInt>>,
+    amount_in: BigInt,
+    fee_bps: u32,
+) -> Result<BigInt> {
+    let mut reserve_pairs = Vec::with_capacity(reserves.len());
+    for r in reserves.iter().filter(|r| r.len() >= 2) {
+        reserve_pairs.push((
+            bigint_to_u128(&r[0], "reserve_in")?,
+            bigint_to_u128(&r[1], "reserve_out")?,
+        ));
+    }
+    let amount_in = bigint_to_u128(&amount_in, "amount_in")?;
+
+    let amount_out = math::calculate_multihop_amount_out_u128(&reserve_pairs, amount_in, fee_bps);
+    Ok(BigInt::from(math::or_safe(amount_out, 0)?))
+}
+
+#[napi]
+pub fn estimate_arbitrage_profit(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    amount_in: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+) -> f64 {
+    math::estimate_arbitrage_profit(
+        buy_reserve_in,
+        buy_reserve_out,
+        sell_reserve_in,
+        sell_reserve_out,
+        amount_in,
+        gas_cost,
+        flashloan_fee_pct,
+    )
+}
+
+/// estimate_arbitrage_profit for legs that may trade the same pair; Shared charges only the
+/// netted impact of the two legs on the pair's one market. Pools are [reserveIn, reserveOut]
+#[napi]
+pub fn estimate_arbitrage_profit_with_legs(
+    buy_pool: Vec<f64>,
+    sell_pool: Vec<f64>,
+    amount_in: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+    legs: LegTokens,
+) -> f64 {
+    math::estimate_arbitrage_profit_with_legs(
+        to_reserve_pair(&buy_pool),
+        to_reserve_pair(&sell_pool),
+        amount_in,
+        gas_cost,
+        flashloan_fee_pct,
+        legs.into(),
+    )
+}
+
+/// estimate_arbitrage_profit that throws on an unusable pool, a negative amount or
+/// flashloan fee, or a non-finite gas cost instead of quoting it; negative gas is a rebate
+#[napi]
+pub fn try_estimate_arbitrage_profit(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    amount_in: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+) -> Result<f64> {
+    Ok(math::or_safe(
+        math::try_estimate_arbitrage_profit(
+            buy_reserve_in,
+            buy_reserve_out,
+            sell_reserve_in,
+            sell_reserve_out,
+            amount_in,
+            gas_cost,
+            flashloan_fee_pct,
+        ),
+        0.0,
+    )?)
+}
+
+/// Net profit of a two-leg trade for each gas cost scenario; pools are [reserveIn, reserveOut]
+#[napi]
+pub fn profit_vs_gas(
+    buy_pool: Vec<f64>,
+    sell_pool: Vec<f64>,
+    amount_in: f64,
+    flashloan_fee_pct: f64,
+    gas_costs: Vec<f64>,
+) -> Vec<f64> {
+    math::profit_vs_gas(
+        to_reserve_pair(&buy_pool),
+        to_reserve_pair(&sell_pool),
+        amount_in,
+        flashloan_fee_pct,
+        &gas_costs,
+    )
+}
+
+/// Profit of a two-leg trade sent with amountOutMin on its final output; pools are
+/// [reserveIn, reserveOut]. Returns -gasCost when the output misses the minimum and the tx reverts
+#[napi]
+pub fn expected_profit_with_revert_protection(
+    buy_pool: Vec<f64>,
+    sell_pool: Vec<f64>,
+    amount_in: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+    amount_out_min: f64,
+) -> f64 {
+    math::expected_profit_with_revert_protection(
+        to_reserve_pair(&buy_pool),
+        to_reserve_pair(&sell_pool),
+        amount_in,
+        gas_cost,
+        flashloan_fee_pct,
+        amount_out_min,
+    )
+}
+
+#[napi]
+pub fn optimal_entry(expected_profit: f64, competitor_count: u32, our_edge_pct: f64) -> f64 {
+    math::optimal_entry(expected_profit, competitor_count, our_edge_pct)
+}
+
+#[napi]
+pub fn session_size_cap(
+    proposed_size: f64,
+    realized_pnl: f64,
+    drawdown_limit: f64,
+    current_drawdown: f64,
+) -> f64 {
+    math::session_size_cap(
+        proposed_size,
+        realized_pnl,
+        drawdown_limit,
+        current_drawdown,
+    )
+}
+
+#[napi]
+pub fn optimal_two_leg_input(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    fee_bps: u32,
+) -> f64 {
+    math::optimal_two_leg_input(
+        buy_reserve_in,
+        buy_reserve_out,
+        sell_reserve_in,
+        sell_reserve_out,
+        fee_bps,
+    )
+}
+
+/// pool reserves are [reserve_x, reserve_y]; returns [low, high]
+#[napi]
+pub fn equilibrium_price(
+    pool1_reserves: Vec<f64>,
+    pool2_reserves: Vec<f64>,
+    fee_bps: u32,
+) -> Vec<f64> {
+    let (low, high) = math::equilibrium_price(
+        to_reserve_pair(&pool1_reserves),
+        to_reserve_pair(&pool2_reserves),
+        fee_bps,
+    );
+    vec![low, high]
+}
+
+/// pools are [reserve_x, reserve_y] and gas is in X; returns [low, high] bounds on price2 / price1
+#[napi]
+pub fn no_arb_band(
+    pool1_reserves: Vec<f64>,
+    pool2_reserves: Vec<f64>,
+    gas_cost: f64,
+    fee_bps: u32,
+) -> Vec<f64> {
+    let (low, high) = math::no_arb_band(
+        to_reserve_pair(&pool1_reserves),
+        to_reserve_pair(&pool2_reserves),
+        gas_cost,
+        fee_bps,
+    );
+    vec![low, high]
+}
+
+/// Read a [reserve_in, reserve_out] row; malformed rows become an empty pool
+fn to_reserve_pair(reserves: &[f64]) -> (f64, f64) {
+    if reserves.len() >= 2 {
+        (reserves[0], reserves[1])
+    } else {
+        (0.0, 0.0)
+    }
+}
+
+/// What a multi-venue arbitrage does in one pool
+#[napi]
+pub enum VenueSide {
+    Skip,
+    Sell,
+    Buy,
+}
+
+/// Per-pool action; amount_in is in X for sells and in Y for buys
+#[napi(object)]
+pub struct VenueAction {
+    pub side: VenueSide,
+    pub amount_in: f64,
+    pub amount_out: f64,
+}
+
+#[napi(object)]
+pub struct MultiVenueArb {
+    pub actions: Vec<VenueAction>,
+    pub net_profit: f64,
+}
+
+/// pools are [reserve_x, reserve_y] quoting the same pair; profit and gas are in X
+#[napi]
+pub fn multi_venue_arb(
+    pools: Vec<Vec<f64>>,
+    fee_bps: u32,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+) -> MultiVenueArb {
+    let reserve_pairs: Vec<(f64, f64)> = pools.iter().map(|r| to_reserve_pair(r)).collect();
+    let result = math::multi_venue_arb(&reserve_pairs, fee_bps, gas_cost, flashloan_fee_pct);
+
+    MultiVenueArb {
+        actions: result
+            .actions
+            .iter()
+            .map(|action| VenueAction {
+                side: match action.side {
+                    math::VenueSide::Skip => VenueSide::Skip,
+                    math::VenueSide::Sell => VenueSide::Sell,
+                    math::VenueSide::Buy => VenueSide::Buy,
+                },
+                amount_in: action.amount_in,
+                amount_out: action.amount_out,
+            })
+            .collect(),
+        net_profit: result.net_profit,
+    }
+}
+
+/// Indices of [reserve_x, reserve_y] pools by spot price, then liquidity, as multi_venue_arb
+/// processes them; identical pool sets give the same order however they are passed in
+#[napi]
+pub fn canonical_pool_order(pools: Vec<Vec<f64>>) -> Vec<u32> {
+    let reserve_pairs: Vec<(f64, f64)> = pools.iter().map(|r| to_reserve_pair(r)).collect();
+    math::canonical_pool_order(&reserve_pairs)
+        .into_iter()
+        .map(|idx| idx as u32)
+        .collect()
+}
+
+#[napi]
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    let (root1, root2) = math::solve_quadratic(a, b, c);
+    vec![root1, root2]
+}
+
+#[napi]
+pub fn optimize_trade_size_quadratic(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+) -> f64 {
+    math::optimize_trade_size_quadratic(
+        buy_reserve_in,
+        buy_reserve_out,
+        sell_reserve_in,
+        sell_reserve_out,
+        gas_cost,
+        flashloan_fee_pct,
+    )
+}
+
+/// Returns [size, utilization_pct]
+/// Objective is profit - slippage_penalty_weight × combined leg slippage (percent)
+#[napi]
+pub fn optimize_trade_size_quadratic_with_penalty(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+    slippage_penalty_weight: f64,
+) -> f64 {
+    math::optimize_trade_size_quadratic_with_penalty(
+        buy_reserve_in,
+        buy_reserve_out,
+        sell_reserve_in,
+        sell_reserve_out,
+        gas_cost,
+        flashloan_fee_pct,
+        slippage_penalty_weight,
+    )
+}
+
+#[napi]
+pub fn optimize_trade_size_quadratic_with_utilization(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+) -> Vec<f64> {
+    let (size, utilization_pct) = math::optimize_trade_size_quadratic_with_utilization(
+        buy_reserve_in,
+        buy_reserve_out,
+        sell_reserve_in,
+        sell_reserve_out,
+        gas_cost,
+        flashloan_fee_pct,
+    );
+    vec![size, utilization_pct]
+}
+
+/// Returns [size, exceeds_soft_cap (0/1)]
+#[napi]
+pub fn optimize_trade_size_with_cap_mode(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+    cap_mode: CapMode,
+) -> Vec<f64> {
+    let (size, exceeds_soft_cap) = math::optimize_trade_size_with_cap_mode(
+        buy_reserve_in,
+        buy_reserve_out,
+        sell_reserve_in,
+        sell_reserve_out,
+        gas_cost,
+        flashloan_fee_pct,
+        cap_mode.into(),
+    );
+    vec![size, if exceeds_soft_cap { 1.0 } else { 0.0 }]
+}
+
+#[napi]
+pub fn calculate_twap(price_samples: Vec<Vec<f64>>) -> f64 {
+    let samples: Vec<(f64, f64)> = price_samples
+        .iter()
+        .filter_map(|s| {
+            if s.len() >= 2 {
+                Some((s[0], s[1]))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    math::calculate_twap(&samples)
+}
+
+/// price_samples are [timestamp, price, sourceWeight]; errors on a negative weight
+#[napi]
+pub fn calculate_weighted_twap(price_samples: Vec<Vec<f64>>) -> Result<f64> {
+    let samples: Vec<(f64, f64, f64)> = price_samples
+        .iter()
+        .filter_map(|s| {
+            if s.len() >= 3 {
+                Some((s[0], s[1], s[2]))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    Ok(math::or_safe(math::calculate_weighted_twap(&samples), 0.0)?)
+}
+
+#[napi]
+pub fn calculate_ema(price_samples: Vec<Vec<f64>>, half_life_secs: f64) -> f64 {
+    let samples: Vec<(f64, f64)> = price_samples
+        .iter()
+        .filter_map(|s| {
+            if s.len() >= 2 {
+                Some((s[0], s[1]))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    math::calculate_ema(&samples, half_life_secs)
+}
+
+/// Per-window realized volatility (root sum of squared log returns) of [timestamp, price]
+/// samples over the trailing window_secs
+#[napi]
+pub fn realized_volatility(samples: Vec<Vec<f64>>, window_secs: f64) -> f64 {
+    let samples: Vec<(f64, f64)> = samples
+        .iter()
+        .filter_map(|s| {
+            if s.len() >= 2 {
+                Some((s[0], s[1]))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    math::realized_volatility(&samples, window_secs)
+}
+
+#[napi]
+pub fn validate_with_twap(current_price: f64, twap: f64, max_deviation_pct: f64) -> bool {
+    math::validate_with_twap(current_price, twap, max_deviation_pct)
+}
+
+/// 0-1 score that the current price is manipulated, from TWAP deviation, depth and volume
+#[napi]
+pub fn manipulation_risk(current_price: f64, twap: f64, liquidity: f64, recent_volume: f64) -> f64 {
+    math::manipulation_risk(current_price, twap, liquidity, recent_volume)
+}
+
+#[napi(object)]
+pub struct OracleReversionArb {
+    pub sells_token_in: bool,
+    pub amount_in: f64,
+    pub amount_out: f64,
+    pub price_after: f64,
+    pub expected_profit: f64,
+}
+
+/// Trade moving a pool back toward its TWAP, with profit assuming the price reverts there;
+/// returns null when fees and gas leave no edge
+#[napi]
+pub fn oracle_reversion_arb(
+    reserve_in: f64,
+    reserve_out: f64,
+    twap: f64,
+    fee_bps: u32,
+    gas_cost: f64,
+) -> Option<OracleReversionArb> {
+    math::oracle_reversion_arb(reserve_in, reserve_out, twap, fee_bps, gas_cost).map(|arb| {
+        OracleReversionArb {
+            sells_token_in: arb.sells_token_in,
+            amount_in: arb.amount_in,
+            amount_out: arb.amount_out,
+            price_after: arb.price_after,
+            expected_profit: arb.expected_profit,
+        }
+    })
+}
+
+/// Each trade is [amount_in, is_buy] with is_buy as 1 (buy) or 0 (sell)
+#[napi]
+pub fn order_flow_imbalance(trades: Vec<Vec<f64>>) -> f64 {
+    let trade_tuples: Vec<(f64, bool)> = trades
+        .iter()
+        .filter_map(|t| {
+            if t.len() >= 2 {
+                Some((t[0], t[1] > 0.5))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    math::order_flow_imbalance(&trade_tuples)
+}
+
+#[napi]
+pub fn execute_arbitrage_flow(
+    pool1_reserve_in: f64,
+    pool1_reserve_out: f64,
+    pool2_reserve_in: f64,
+    pool2_reserve_out: f64,
+    price_samples_pool1: Vec<Vec<f64>>,
+    price_samples_pool2: Vec<Vec<f64>>,
+    config: ArbitrageConfig,
+) -> Vec<f64> {
+    let samples1: Vec<(f64, f64)> = price_samples_pool1
+        .iter()
+        .filter_map(|s| {
+            if s.len() >= 2 {
+                Some((s[0], s[1]))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let samples2: Vec<(f64, f64)> = price_samples_pool2
+        .iter()
+        .filter_map(|s| {
+            if s.len() >= 2 {
+                Some((s[0], s[1]))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let math_config = math::ArbitrageConfig::from(config);
+
+    flow_decision_row(math::execute_arbitrage_flow(
+        pool1_reserve_in,
+        pool1_reserve_out,
+        pool2_reserve_in,
+        pool2_reserve_out,
+        &samples1,
+        &samples2,
+        &math_config,
+    ))
+}
+
+/// [shouldExecute (0/1), optimalAmount, expectedProfit, exceedsSoftCap (0/1)]
+fn flow_decision_row(decision: math::FlowDecision) -> Vec<f64> {
+    vec![
+        if decision.should_execute { 1.0 } else { 0.0 },
+        decision.optimal_amount,
+        decision.expected_profit,
+        if decision.exceeds_soft_cap { 1.0 } else { 0.0 },
+    ]
+}
+
+#[napi(object)]
+pub struct TraceStep {
+    pub name: String,
+    pub value: f64,
+}
+
+#[napi(object)]
+pub struct TracedFlow {
+    /// [shouldExecute (0/1), optimalAmount, expectedProfit, exceedsSoftCap (0/1)], as
+    /// execute_arbitrage_flow
+    pub result: Vec<f64>,
+    pub steps: Vec<TraceStep>,
+}
+
+/// execute_arbitrage_flow plus each step's output (price_diff_pct, direction, current_price,
+/// twap, optimal_amount, exceeds_soft_cap, expected_profit, should_execute) up to where the
+/// flow stopped
+#[napi]
+pub fn execute_arbitrage_flow_traced(
+    pool1_reserve_in: f64,
+    pool1_reserve_out: f64,
+    pool2_reserve_in: f64,
+    pool2_reserve_out: f64,
+    price_samples_pool1: Vec<Vec<f64>>,
+    price_samples_pool2: Vec<Vec<f64>>,
+    config: ArbitrageConfig,
+) -> TracedFlow {
+    let samples1: Vec<(f64, f64)> = price_samples_pool1
+        .iter()
+        .filter_map(|s| {
+            if s.len() >= 2 {
+                Some((s[0], s[1]))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let samples2: Vec<(f64, f64)> = price_samples_pool2
+        .iter()
+        .filter_map(|s| {
+            if s.len() >= 2 {
+                Some((s[0], s[1]))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let (decision, steps) = math::execute_arbitrage_flow_traced(
+        (pool1_reserve_in, pool1_reserve_out),
+        (pool2_reserve_in, pool2_reserve_out),
+        &samples1,
+        &samples2,
+        &math::ArbitrageConfig::from(config),
+    );
+
+    TracedFlow {
+        result: flow_decision_row(decision),
+        steps: steps
+            .into_iter()
+            .map(|step| TraceStep {
+                name: step.name.to_string(),
+                value: step.value,
+            })
+            .collect(),
+    }
+}
+
+/// Convert [pool1_res_in, pool1_res_out, pool2_res_in, pool2_res_out] rows into tuples
+fn to_opportunity_tuples(opportunities: &[Vec<f64>]) -> Vec<(f64, f64, f64, f64)> {
+    opportunities
+        .iter()
+        .filter_map(|opp| {
+            if opp.len() >= 4 {
+                Some((opp[0], opp[1], opp[2], opp[3]))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Like to_opportunity_tuples but keeps positions: malformed rows become empty pools
+/// that never qualify, so returned indices still refer to the caller's rows
+fn to_positional_opportunity_tuples(opportunities: &[Vec<f64>]) -> Vec<(f64, f64, f64, f64)> {
+    opportunities
+        .iter()
+        .map(|opp| {
+            if opp.len() >= 4 {
+                (opp[0], opp[1], opp[2], opp[3])
+            } else {
+                (0.0, 0.0, 0.0, 0.0)
+            }
+        })
+        .collect()
+}
+
+#[napi]
+pub fn batch_evaluate_opportunities(
+    opportunities: Vec<Vec<f64>>, // Each inner vec: [pool1_res_in, pool1_res_out, pool2_res_in, pool2_res_out]
+    config: ArbitrageConfig,
+) -> Vec<Vec<f64>> {
+    let opp_tuples = to_opportunity_tuples(&opportunities);
+
+    let math_config = math::ArbitrageConfig::from(config);
+
+    let results = math::batch_evaluate_opportunities(&opp_tuples, &math_config);
+
+    to_evaluation_rows(&results)
+}
+
+/// Run the parallel batch functions on a dedicated pool of `threads` workers instead of
+/// rayon's global pool (threads = 0 goes back to the global pool); applies process-wide
+#[napi]
+pub fn configure_threads(threads: u32) -> Result<()> {
+    Ok(math::or_safe(
+        math::configure_threads(threads as usize),
+        (),
+    )?)
+}
+
+/// Worker threads the parallel batch functions currently run on
+#[napi]
+pub fn parallel_threads() -> u32 {
+    math::parallel_threads() as u32
+}
+
+/// batch_evaluate_opportunities evaluated on the configured thread pool; same rows in the
+/// same order
+#[napi]
+pub fn batch_evaluate_opportunities_parallel(
+    opportunities: Vec<Vec<f64>>,
+    config: ArbitrageConfig,
+) -> Vec<Vec<f64>> {
+    let opp_tuples = to_opportunity_tuples(&opportunities);
+    let results = math::batch_evaluate_opportunities_parallel(
+        &opp_tuples,
+        &math::ArbitrageConfig::from(config),
+    );
+
+    to_evaluation_rows(&results)
+}
+
+/// How many opportunities could clear gas and the profit threshold, without full sizing;
+/// 0 means batch_evaluate_opportunities would execute nothing
+#[napi]
+pub fn viable_count(opportunities: Vec<Vec<f64>>, config: ArbitrageConfig) -> u32 {
+    let opp_tuples = to_opportunity_tuples(&opportunities);
+    math::viable_count(&opp_tuples, &math::ArbitrageConfig::from(config)) as u32
+}
+
+/// [should_execute, optimal_amount, expected_profit, reject_reason, expected_value?] rows
+fn to_evaluation_rows(results: &[math::OpportunityEvaluation]) -> Vec<Vec<f64>> {
+    results
+        .iter()
+        .map(|result| {
+            let mut row = vec![
+                if result.should_execute { 1.0 } else { 0.0 },
+                result.optimal_amount,
+                result.expected_profit,
+                result.reject_reason as u8 as f64,
+            ];
+            if let Some(expected_value) = result.expected_value {
+                row.push(expected_value);
+            }
+            row
+        })
+        .collect()
+}
+
+#[napi(object)]
+pub struct BudgetedBatch {
+    /// Rows as returned by batch_evaluate_opportunities
+    pub evaluations: Vec<Vec<f64>>,
+    pub avg_iterations: f64,
+}
+
+/// batch_evaluate_opportunities within time_budget_ms, sizing later opportunities on coarser
+/// grids as the budget runs down rather than skipping them
+#[napi]
+pub fn batch_evaluate_with_budget(
+    opportunities: Vec<Vec<f64>>,
+    config: ArbitrageConfig,
+    time_budget_ms: f64,
+) -> BudgetedBatch {
+    let opp_tuples = to_opportunity_tuples(&opportunities);
+    let batch = math::batch_evaluate_with_budget(
+        &opp_tuples,
+        &math::ArbitrageConfig::from(config),
+        time_budget_ms / 1000.0,
+    );
+
+    BudgetedBatch {
+        evaluations: to_evaluation_rows(&batch.evaluations),
+        avg_iterations: batch.avg_iterations,
+    }
+}
+
+fn to_price_table(usd_prices: HashMap<String, f64>) -> math::PriceTable {
+    let mut table = math::PriceTable::new();
+    for (token_id, usd_price) in &usd_prices {
+        table.set_price(token_id, *usd_price);
+    }
+    table
+}
+
+/// Profit in token_id converted with a { tokenId: usdPrice } table; null when unpriced
+#[napi]
+pub fn to_usd(profit: f64, token_id: String, usd_prices: HashMap<String, f64>) -> Option<f64> {
+    math::to_usd(profit, &token_id, &to_price_table(usd_prices))
+}
+
+/// batch_evaluate_opportunities profits in USD; profit_tokens names each opportunity's
+/// profit token, and unpriced tokens give null
+#[napi]
+pub fn batch_evaluate_opportunities_usd(
+    opportunities: Vec<Vec<f64>>,
+    config: ArbitrageConfig,
+    profit_tokens: Vec<String>,
+    usd_prices: HashMap<String, f64>,
+) -> Result<Vec<Option<f64>>> {
+    let opp_tuples = to_opportunity_tuples(&opportunities);
+    let results =
+        math::batch_evaluate_opportunities(&opp_tuples, &math::ArbitrageConfig::from(config));
+
+    Ok(math::or_safe(
+        math::batch_to_usd(&results, &profit_tokens, &to_price_table(usd_prices)),
+        vec![],
+    )?)
+}
+
+/// Opportunity with the price history each pool has (omit a history to skip its TWAP check)
+#[napi(object)]
+pub struct Candidate {
+    /// [pool1_res_in, pool1_res_out, pool2_res_in, pool2_res_out]
+    pub reserves: Vec<f64>,
+    /// [timestamp, price] rows
+    pub price_samples_pool1: Option<Vec<Vec<f64>>>,
+    pub price_samples_pool2: Option<Vec<Vec<f64>>>,
+}
+
+/// reject_reason codes: 0 accepted, 1 no opportunity, 2 below min profit, 3 slippage exceeded,
+/// 4 TWAP rejected, 5 negative after gas, 6 liquidity constrained, 7 below min size
+#[napi(object)]
+pub struct ReasonedEvaluation {
+    pub should_execute: bool,
+    pub optimal_amount: f64,
+    pub profit: f64,
+    pub reject_reason: u32,
+}
+
+#[napi]
+pub fn evaluate_with_reasons(
+    candidates: Vec<Candidate>,
+    config: ArbitrageConfig,
+) -> Vec<ReasonedEvaluation> {
+    let to_samples = |rows: Option<Vec<Vec<f64>>>| -> Vec<(f64, f64)> {
+        rows.unwrap_or_default()
+            .iter()
+            .filter_map(|s| {
+                if s.len() >= 2 {
+                    Some((s[0], s[1]))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    };
+    let math_candidates: Vec<math::Candidate> = candidates
+        .into_iter()
+        .map(|candidate| {
+            let r = &candidate.reserves;
+            math::Candidate {
+                reserves: if r.len() >= 4 {
+                    (r[0], r[1], r[2], r[3])
+                } else {
+                    (0.0, 0.0, 0.0, 0.0)
+                },
+                price_samples_pool1: to_samples(candidate.price_samples_pool1),
+                price_samples_pool2: to_samples(candidate.price_samples_pool2),
+            }
+        })
+        .collect();
+
+    math::evaluate_with_reasons(&math_candidates, &math::ArbitrageConfig::from(config))
+        .iter()
+        .map(|result| ReasonedEvaluation {
+            should_execute: result.should_execute,
+            optimal_amount: result.optimal_amount,
+            profit: result.profit,
+            reject_reason: result.reject_reason as u32,
+        })
+        .collect()
+}
+
+/// Sizing against a forecasted next-block state, checked against the current one
+#[napi(object)]
+pub struct ForecastEvaluation {
+    pub should_execute: bool,
+    pub amount: f64,
+    pub forecasted_profit: f64,
+    pub current_profit: f64,
+}
+
+/// Both states are [pool1_res_in, pool1_res_out, pool2_res_in, pool2_res_out]; size is capped
+/// by current liquidity
 #[napi]
-pub fn compute_uniswap_v3_slippage(liquidity: f64, sqrt_price: f64, amount_in: f64) -> f64 {
-    math::compute_uniswap_v3_slippage(liquidity, sqrt_price, amount_in)
+pub fn evaluate_forecasted(
+    current_reserves: Vec<f64>,
+    forecasted_reserves: Vec<f64>,
+    config: ArbitrageConfig,
+) -> ForecastEvaluation {
+    let to_state = |reserves: &[f64]| {
+        if reserves.len() >= 4 {
+            (reserves[0], reserves[1], reserves[2], reserves[3])
+        } else {
+            (0.0, 0.0, 0.0, 0.0)
+        }
+    };
+    let result = math::evaluate_forecasted(
+        to_state(&current_reserves),
+        to_state(&forecasted_reserves),
+        &math::ArbitrageConfig::from(config),
+    );
+
+    ForecastEvaluation {
+        should_execute: result.should_execute,
+        amount: result.amount,
+        forecasted_profit: result.forecasted_profit,
+        current_profit: result.current_profit,
+    }
 }
 
+/// Deterministic 16-hex-char ID for the ordered pools, direction (1 or 2) and block; null for
+/// any other direction. Flow and batch results carry no ID, so tag rows with this
 #[napi]
-pub fn compute_curve_slippage(
-    balance_in: f64,
-    balance_out: f64,
-    amount_in: f64,
-    amplification: f64,
-) -> f64 {
-    math::compute_curve_slippage(balance_in, balance_out, amount_in, amplification)
+pub fn opportunity_id(pool_ids: Vec<String>, direction: u32, block_number: i64) -> Option<String> {
+    math::opportunity_id(&pool_ids, direction, block_number.max(0) as u64)
 }
 
+/// buy/sell reserves are [reserve_in, reserve_out]; prices share a common numeraire
 #[napi]
-pub fn compute_balancer_slippage(
-    balance_in: f64,
-    balance_out: f64,
-    weight_in: f64,
-    weight_out: f64,
+pub fn cross_token_profit(
+    buy_reserves: Vec<f64>,
+    sell_reserves: Vec<f64>,
     amount_in: f64,
+    output_token_price: f64,
+    input_token_price: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
 ) -> f64 {
-    math::compute_balancer_slippage(balance_in, balance_out, weight_in, weight_out, amount_in)
-}
-
-#[napi]
-pub fn compute_aggregator_slippage(slippages: Vec<f64>) -> f64 {
-    math::compute_aggregator_slippage(&slippages)
+    math::cross_token_profit(
+        to_reserve_pair(&buy_reserves),
+        to_reserve_pair(&sell_reserves),
+        amount_in,
+        output_token_price,
+        input_token_price,
+        gas_cost,
+        flashloan_fee_pct,
+    )
 }
 
+/// amm_reserves are [reserve_in, reserve_out] selling the order's fill back to the input token
 #[napi]
-pub fn optimal_trade_size(
-    reserve_in: f64,
-    reserve_out: f64,
+pub fn limit_order_plus_amm_profit(
+    limit_price: f64,
+    limit_max_size: f64,
+    amm_reserves: Vec<f64>,
+    amount: f64,
+    fee_bps: u32,
     gas_cost: f64,
-    min_profit: f64,
 ) -> f64 {
-    math::optimal_trade_size(reserve_in, reserve_out, gas_cost, min_profit)
+    math::limit_order_plus_amm_profit(
+        limit_price,
+        limit_max_size,
+        to_reserve_pair(&amm_reserves),
+        amount,
+        fee_bps,
+        gas_cost,
+    )
 }
 
+/// Every pool is [reserve_in, reserve_out] in the direction of travel: X -> Y, Y -> Z, Z -> Y, Y -> X
 #[napi]
-pub fn calculate_flashloan_amount(
-    reserve_in_buy: f64,
-    reserve_out_buy: f64,
-    reserve_in_sell: f64,
-    reserve_out_sell: f64,
-    flashloan_fee: f64,
+pub fn cross_asset_flashloan_profit(
+    borrow_reserves: Vec<f64>,
+    leg1_reserves: Vec<f64>,
+    leg2_reserves: Vec<f64>,
+    repay_reserves: Vec<f64>,
+    amount: f64,
+    flashloan_fee_pct: f64,
     gas_cost: f64,
 ) -> f64 {
-    math::calculate_flashloan_amount(
-        reserve_in_buy,
-        reserve_out_buy,
-        reserve_in_sell,
-        reserve_out_sell,
-        flashloan_fee,
+    math::cross_asset_flashloan_profit(
+        to_reserve_pair(&borrow_reserves),
+        to_reserve_pair(&leg1_reserves),
+        to_reserve_pair(&leg2_reserves),
+        to_reserve_pair(&repay_reserves),
+        amount,
+        flashloan_fee_pct,
         gas_cost,
     )
 }
 
-#[napi]
-pub fn calculate_market_impact(reserve_in: f64, reserve_out: f64, flashloan_amount: f64) -> f64 {
-    math::calculate_market_impact(reserve_in, reserve_out, flashloan_amount)
+/// A flashloan source; fee_pct is a fraction like flashloan_fee_pct
+#[napi(object)]
+pub struct ProviderSpec {
+    pub fee_pct: f64,
+    pub max_borrow: f64,
 }
 
-#[napi]
-pub fn calculate_multihop_slippage(reserves: Vec<Vec<f64>>, flashloan_amount: f64) -> f64 {
-    let reserve_pairs: Vec<(f64, f64)> = reserves
-        .iter()
-        .filter_map(|r| {
-            if r.len() >= 2 {
-                Some((r[0], r[1]))
-            } else {
-                None
-            }
-        })
-        .collect();
-
-    math::calculate_multihop_slippage(&reserve_pairs, flashloan_amount)
+#[napi(object)]
+pub struct FlashloanProviderChoice {
+    pub provider_index: u32,
+    pub fee_paid: f64,
 }
 
+/// Returns null when no provider can lend amount_needed
 #[napi]
-pub fn simulate_parallel_flashloan_paths(
-    paths: Vec<Vec<Vec<f64>>>,
-    flashloan_amounts: Vec<f64>,
-    flashloan_fee: f64,
-    gas_costs: Vec<f64>,
-) -> Vec<Vec<f64>> {
-    let path_tuples: Vec<Vec<(f64, f64)>> = paths
+pub fn best_flashloan_provider(
+    amount_needed: f64,
+    providers: Vec<ProviderSpec>,
+) -> Option<FlashloanProviderChoice> {
+    let specs: Vec<math::ProviderSpec> = providers
         .iter()
-        .map(|path| {
-            path.iter()
-                .filter_map(|r| {
-                    if r.len() >= 2 {
-                        Some((r[0], r[1]))
-                    } else {
-                        None
-                    }
-                })
-                .collect()
+        .map(|provider| math::ProviderSpec {
+            fee_pct: provider.fee_pct,
+            max_borrow: provider.max_borrow,
         })
         .collect();
 
-    let results = math::simulate_parallel_flashloan_paths(
-        &path_tuples,
-        &flashloan_amounts,
-        flashloan_fee,
-        &gas_costs,
-    );
-
-    results
-        .iter()
-        .map(|(profit, slippage, idx)| vec![*profit, *slippage, *idx as f64])
-        .collect()
+    math::best_flashloan_provider(amount_needed, &specs).map(|(idx, fee_paid)| {
+        FlashloanProviderChoice {
+            provider_index: idx as u32,
+            fee_paid,
+        }
+    })
 }
 
+/// Breakeven flashloan fee fraction for borrowing amount_in; pools are [reserveIn, reserveOut]
+/// Negative when the trade loses money even with a free loan
 #[napi]
-pub fn calculate_flashloan_amount_v3(
-    liquidity: f64,
-    sqrt_price_buy: f64,
-    sqrt_price_sell: f64,
-    flashloan_fee: f64,
+pub fn max_flashloan_fee_for_profit(
+    buy_pool: Vec<f64>,
+    sell_pool: Vec<f64>,
+    amount_in: f64,
     gas_cost: f64,
 ) -> f64 {
-    math::calculate_flashloan_amount_v3(
-        liquidity,
-        sqrt_price_buy,
-        sqrt_price_sell,
-        flashloan_fee,
+    math::max_flashloan_fee_for_profit(
+        to_reserve_pair(&buy_pool),
+        to_reserve_pair(&sell_pool),
+        amount_in,
         gas_cost,
     )
 }
 
-// New arbitrage flow functions
-
+/// Which token a flashloan surplus is held in
 #[napi]
-pub fn calculate_pool_price(reserve_in: f64, reserve_out: f64) -> f64 {
-    math::calculate_pool_price(reserve_in, reserve_out)
+pub enum SurplusToken {
+    Borrowed,
+    Output,
 }
 
-#[napi]
-pub fn identify_arbitrage_opportunity(
-    pool1_reserve_in: f64,
-    pool1_reserve_out: f64,
-    pool2_reserve_in: f64,
-    pool2_reserve_out: f64,
-    min_price_diff_pct: f64,
-) -> Vec<f64> {
-    let (has_opportunity, price_diff, direction) = math::identify_arbitrage_opportunity(
-        pool1_reserve_in,
-        pool1_reserve_out,
-        pool2_reserve_in,
-        pool2_reserve_out,
-        min_price_diff_pct,
-    );
-
-    vec![
-        if has_opportunity { 1.0 } else { 0.0 },
-        price_diff,
-        direction as f64,
-    ]
+#[napi(object)]
+pub struct Settlement {
+    pub repayment_due: f64,
+    pub surplus: f64,
+    pub surplus_token: SurplusToken,
 }
 
+/// conversion_price is borrowed tokens per output token; ignored when the output is the borrowed token
 #[napi]
-pub fn calculate_amount_in(reserve_in: f64, reserve_out: f64, amount_out: f64) -> f64 {
-    math::calculate_amount_in(reserve_in, reserve_out, amount_out)
-}
+pub fn repayment_and_surplus(
+    amount_borrowed: f64,
+    flashloan_fee_pct: f64,
+    final_output: f64,
+    output_is_borrowed_token: bool,
+    conversion_price: f64,
+) -> Result<Settlement> {
+    let settlement = math::or_safe(
+        math::repayment_and_surplus(
+            amount_borrowed,
+            flashloan_fee_pct,
+            final_output,
+            output_is_borrowed_token,
+            conversion_price,
+        ),
+        math::Settlement {
+            repayment_due: 0.0,
+            surplus: 0.0,
+            surplus_token: math::SurplusToken::Borrowed,
+        },
+    )?;
 
-#[napi]
-pub fn calculate_amount_out(reserve_in: f64, reserve_out: f64, amount_in: f64) -> f64 {
-    math::calculate_amount_out(reserve_in, reserve_out, amount_in)
+    Ok(Settlement {
+        repayment_due: settlement.repayment_due,
+        surplus: settlement.surplus,
+        surplus_token: match settlement.surplus_token {
+            math::SurplusToken::Borrowed => SurplusToken::Borrowed,
+            math::SurplusToken::Output => SurplusToken::Output,
+        },
+    })
 }
 
 #[napi]
-pub fn estimate_arbitrage_profit(
-    buy_reserve_in: f64,
-    buy_reserve_out: f64,
-    sell_reserve_in: f64,
-    sell_reserve_out: f64,
+pub fn profit_vs_reference(
+    reserve_in: f64,
+    reserve_out: f64,
     amount_in: f64,
+    reference_price: f64,
+    fee_bps: u32,
     gas_cost: f64,
-    flashloan_fee_pct: f64,
 ) -> f64 {
-    math::estimate_arbitrage_profit(
-        buy_reserve_in,
-        buy_reserve_out,
-        sell_reserve_in,
-        sell_reserve_out,
+    math::profit_vs_reference(
+        reserve_in,
+        reserve_out,
         amount_in,
+        reference_price,
+        fee_bps,
         gas_cost,
-        flashloan_fee_pct,
     )
 }
 
 #[napi]
-pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
-    let (root1, root2) = math::solve_quadratic(a, b, c);
-    vec![root1, root2]
+pub fn effective_gas_cost(raw_gas_cost: f64, rebate_fraction: f64) -> f64 {
+    math::effective_gas_cost(raw_gas_cost, rebate_fraction)
 }
 
 #[napi]
-pub fn optimize_trade_size_quadratic(
-    buy_reserve_in: f64,
-    buy_reserve_out: f64,
-    sell_reserve_in: f64,
-    sell_reserve_out: f64,
-    gas_cost: f64,
-    flashloan_fee_pct: f64,
-) -> f64 {
-    math::optimize_trade_size_quadratic(
-        buy_reserve_in,
-        buy_reserve_out,
-        sell_reserve_in,
-        sell_reserve_out,
-        gas_cost,
-        flashloan_fee_pct,
-    )
+pub fn diff_reserves(old: Vec<Vec<f64>>, new: Vec<Vec<f64>>, epsilon: f64) -> Vec<u32> {
+    math::diff_reserves(&old, &new, epsilon)
+        .into_iter()
+        .map(|idx| idx as u32)
+        .collect()
 }
 
 #[napi]
-pub fn calculate_twap(price_samples: Vec<Vec<f64>>) -> f64 {
-    let samples: Vec<(f64, f64)> = price_samples
-        .iter()
-        .filter_map(|s| {
-            if s.len() >= 2 {
-                Some((s[0], s[1]))
-            } else {
-                None
-            }
-        })
-        .collect();
+pub fn quick_gap_exists(p1_in: f64, p1_out: f64, p2_in: f64, p2_out: f64, min_ratio: f64) -> bool {
+    math::quick_gap_exists(p1_in, p1_out, p2_in, p2_out, min_ratio)
+}
 
-    math::calculate_twap(&samples)
+#[napi]
+pub fn prefilter_opportunities(opportunities: Vec<Vec<f64>>, min_price_diff_pct: f64) -> Vec<u32> {
+    let opp_tuples = to_positional_opportunity_tuples(&opportunities);
+
+    math::prefilter_opportunities(&opp_tuples, min_price_diff_pct)
+        .into_iter()
+        .map(|idx| idx as u32)
+        .collect()
 }
 
+/// Ordering applied by batch_evaluate_and_rank
 #[napi]
-pub fn validate_with_twap(current_price: f64, twap: f64, max_deviation_pct: f64) -> bool {
-    math::validate_with_twap(current_price, twap, max_deviation_pct)
+pub enum SortKey {
+    Profit,
+    /// Same order as Profit while every opportunity shares the config's gas cost
+    ProfitPerGas,
+    /// Executable opportunities by ascending slippage, then the rejected ones
+    Slippage,
+    ExpectedValue,
+}
+
+/// Batch evaluation result tagged with its position in the input
+#[napi(object)]
+pub struct RankedResult {
+    pub index: u32,
+    pub should_execute: bool,
+    pub optimal_amount: f64,
+    pub expected_profit: f64,
+    pub max_leg_slippage_pct: f64,
+    pub reject_reason: u32,
+    pub expected_value: Option<f64>,
+    pub exceeds_soft_cap: bool,
 }
 
 #[napi]
-pub fn execute_arbitrage_flow(
-    pool1_reserve_in: f64,
-    pool1_reserve_out: f64,
-    pool2_reserve_in: f64,
-    pool2_reserve_out: f64,
-    price_samples_pool1: Vec<Vec<f64>>,
-    price_samples_pool2: Vec<Vec<f64>>,
+pub fn batch_evaluate_and_rank(
+    opportunities: Vec<Vec<f64>>,
     config: ArbitrageConfig,
-) -> Vec<f64> {
-    let samples1: Vec<(f64, f64)> = price_samples_pool1
-        .iter()
-        .filter_map(|s| {
-            if s.len() >= 2 {
-                Some((s[0], s[1]))
-            } else {
-                None
-            }
-        })
-        .collect();
+    sort_key: SortKey,
+) -> Vec<RankedResult> {
+    let opp_tuples = to_positional_opportunity_tuples(&opportunities);
+    let math_config = math::ArbitrageConfig::from(config);
+    let math_sort_key = match sort_key {
+        SortKey::Profit => math::SortKey::Profit,
+        SortKey::ProfitPerGas => math::SortKey::ProfitPerGas,
+        SortKey::Slippage => math::SortKey::Slippage,
+        SortKey::ExpectedValue => math::SortKey::ExpectedValue,
+    };
 
-    let samples2: Vec<(f64, f64)> = price_samples_pool2
+    math::batch_evaluate_and_rank(&opp_tuples, &math_config, math_sort_key)
         .iter()
-        .filter_map(|s| {
-            if s.len() >= 2 {
-                Some((s[0], s[1]))
-            } else {
-                None
-            }
+        .map(|ranked| RankedResult {
+            index: ranked.index as u32,
+            should_execute: ranked.evaluation.should_execute,
+            optimal_amount: ranked.evaluation.optimal_amount,
+            expected_profit: ranked.evaluation.expected_profit,
+            max_leg_slippage_pct: ranked.evaluation.max_leg_slippage_pct,
+            reject_reason: ranked.evaluation.reject_reason as u32,
+            expected_value: ranked.evaluation.expected_value,
+            exceeds_soft_cap: ranked.evaluation.exceeds_soft_cap,
         })
-        .collect();
+        .collect()
+}
 
-    let math_config = math::ArbitrageConfig {
-        gas_cost: config.gas_cost,
-        flashloan_fee_pct: config.flashloan_fee_pct,
-        min_price_diff_pct: config.min_price_diff_pct,
-        max_twap_deviation_pct: config.max_twap_deviation_pct,
-        min_profit_threshold: config.min_profit_threshold,
-    };
+/// Summary of predicted vs realized slippage for model calibration
+#[napi(object)]
+pub struct CalibrationReport {
+    pub bias: f64,
+    pub mae: f64,
+    pub rmse: f64,
+    pub correlation: f64,
+}
 
-    let (should_execute, optimal_amount, expected_profit) = math::execute_arbitrage_flow(
-        pool1_reserve_in,
-        pool1_reserve_out,
-        pool2_reserve_in,
-        pool2_reserve_out,
-        &samples1,
-        &samples2,
-        &math_config,
-    );
+/// Reproducible sum of aggregate results (e.g. profits across a batch), independent of any
+/// parallel split used to produce them
+#[napi]
+pub fn deterministic_sum(values: Vec<f64>) -> f64 {
+    math::deterministic_sum(&values)
+}
 
-    vec![
-        if should_execute { 1.0 } else { 0.0 },
-        optimal_amount,
-        expected_profit,
-    ]
+#[napi]
+pub fn calibration_report(predicted: Vec<f64>, realized: Vec<f64>) -> CalibrationReport {
+    let report = math::calibration_report(&predicted, &realized);
+    CalibrationReport {
+        bias: report.bias,
+        mae: report.mae,
+        rmse: report.rmse,
+        correlation: report.correlation,
+    }
 }
 
+/// Learn a min_profit_threshold from logged [predictedProfit, realizedProfit, gasPaid] rows
+/// Returns Infinity when no threshold would have been profitable; malformed rows are skipped
 #[napi]
-pub fn batch_evaluate_opportunities(
-    opportunities: Vec<Vec<f64>>,  // Each inner vec: [pool1_res_in, pool1_res_out, pool2_res_in, pool2_res_out]
-    config: ArbitrageConfig,
-) -> Vec<Vec<f64>> {
-    let opp_tuples: Vec<(f64, f64, f64, f64)> = opportunities
+pub fn suggest_min_profit(historical: Vec<Vec<f64>>) -> f64 {
+    let outcomes: Vec<(f64, f64, f64)> = historical
         .iter()
-        .filter_map(|opp| {
-            if opp.len() >= 4 {
-                Some((opp[0], opp[1], opp[2], opp[3]))
+        .filter_map(|row| {
+            if row.len() >= 3 {
+                Some((row[0], row[1], row[2]))
             } else {
                 None
             }
         })
         .collect();
 
-    let math_config = math::ArbitrageConfig {
-        gas_cost: config.gas_cost,
-        flashloan_fee_pct: config.flashloan_fee_pct,
-        min_price_diff_pct: config.min_price_diff_pct,
-        max_twap_deviation_pct: config.max_twap_deviation_pct,
-        min_profit_threshold: config.min_profit_threshold,
-    };
+    math::suggest_min_profit(&outcomes)
+}
 
-    let results = math::batch_evaluate_opportunities(&opp_tuples, &math_config);
+/// Known-answer checks the orchestrator can run at startup to catch a mis-built addon
+#[napi]
+pub fn self_test() -> bool {
+    math::self_test()
+}
 
-    results
-        .iter()
-        .map(|(should_execute, optimal_amount, profit)| {
-            vec![
-                if *should_execute { 1.0 } else { 0.0 },
-                *optimal_amount,
-                *profit,
-            ]
-        })
-        .collect()
+/// Throughput of the canned arbitrage-flow workload
+#[napi(object)]
+pub struct BenchmarkResult {
+    pub ops_per_sec: f64,
+    pub avg_latency_ns: f64,
+}
+
+/// Time the canned execute_arbitrage_flow workload so CI can catch math-layer regressions
+#[napi]
+pub fn benchmark(iterations: u32) -> BenchmarkResult {
+    let result = math::benchmark(iterations);
+    BenchmarkResult {
+        ops_per_sec: result.ops_per_sec,
+        avg_latency_ns: result.avg_latency_ns,
+    }
 }
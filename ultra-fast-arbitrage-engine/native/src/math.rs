@@ -1,13 +1,257 @@
 // Core math engine for DEX slippage calculations
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use rayon::prelude::*;
+
+/// Input errors the math engine reports explicitly instead of returning a plausible number
+#[derive(Debug, Clone, PartialEq)]
+pub enum MathError {
+    /// Both sides of a pool map to the same token, so its "price" is meaningless
+    SameToken { token: String },
+    /// A parameter is outside the range the model is defined on
+    InvalidParameter { name: &'static str, value: f64 },
+    /// An iterative solver hit its iteration cap; `residual` is how far the last iterate
+    /// is from satisfying the equation being solved (|f(x)|, not the last step size)
+    NonConvergent { residual: f64 },
+    /// Parallel inputs that must line up one-to-one have different lengths
+    LengthMismatch {
+        name: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    /// Hop `hop` of a path takes a different token than the previous hop produces
+    BrokenPath {
+        hop: usize,
+        expected: String,
+        actual: String,
+    },
+    /// A pool reserve is zero, negative or not finite
+    ZeroReserve { name: &'static str, value: f64 },
+    /// A trade amount is negative or not finite
+    NegativeAmount { name: &'static str, value: f64 },
+    /// The pool cannot pay out `requested` of a side that only holds `available`
+    InsufficientLiquidity { requested: f64, available: f64 },
+}
+
+impl fmt::Display for MathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MathError::SameToken { token } => {
+                write!(f, "pool has the same token {} on both sides", token)
+            }
+            MathError::InvalidParameter { name, value } => {
+                write!(f, "invalid {}: {}", name, value)
+            }
+            MathError::NonConvergent { residual } => {
+                write!(f, "solver did not converge (residual {:e})", residual)
+            }
+            MathError::LengthMismatch {
+                name,
+                expected,
+                actual,
+            } => {
+                write!(f, "expected {} {}, got {}", expected, name, actual)
+            }
+            MathError::BrokenPath {
+                hop,
+                expected,
+                actual,
+            } => {
+                write!(
+                    f,
+                    "hop {} takes {} but the previous hop outputs {}",
+                    hop, actual, expected
+                )
+            }
+            MathError::ZeroReserve { name, value } => {
+                write!(f, "{} must be a positive reserve, got {}", name, value)
+            }
+            MathError::NegativeAmount { name, value } => {
+                write!(f, "{} must be a non-negative amount, got {}", name, value)
+            }
+            MathError::InsufficientLiquidity {
+                requested,
+                available,
+            } => {
+                write!(
+                    f,
+                    "requested {} out of a reserve of only {}",
+                    requested, available
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for MathError {}
+
+impl MathError {
+    /// Slot of this variant in the safe-mode error counters
+    fn counter_index(&self) -> usize {
+        match self {
+            MathError::SameToken { .. } => 0,
+            MathError::InvalidParameter { .. } => 1,
+            MathError::NonConvergent { .. } => 2,
+            MathError::LengthMismatch { .. } => 3,
+            MathError::BrokenPath { .. } => 4,
+            MathError::ZeroReserve { .. } => 5,
+            MathError::NegativeAmount { .. } => 6,
+            MathError::InsufficientLiquidity { .. } => 7,
+        }
+    }
+}
+
+/// Whether `or_safe` replaces errors with safe values (off by default)
+static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Errors `or_safe` has replaced, indexed by `MathError::counter_index`
+static SAFE_MODE_ERROR_COUNTS: [AtomicU32; 8] = [const { AtomicU32::new(0) }; 8];
+
+/// Turn safe mode on or off for every caller in the process
+pub fn set_safe_mode(enabled: bool) {
+    SAFE_MODE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn safe_mode() -> bool {
+    SAFE_MODE.load(Ordering::Relaxed)
+}
+
+/// How many errors of each kind safe mode has swallowed since the last reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ErrorCounts {
+    pub same_token: u32,
+    pub invalid_parameter: u32,
+    pub non_convergent: u32,
+    pub length_mismatch: u32,
+    pub broken_path: u32,
+    pub zero_reserve: u32,
+    pub negative_amount: u32,
+    pub insufficient_liquidity: u32,
+}
+
+pub fn last_error_counts() -> ErrorCounts {
+    let count = |index: usize| SAFE_MODE_ERROR_COUNTS[index].load(Ordering::Relaxed);
+    ErrorCounts {
+        same_token: count(0),
+        invalid_parameter: count(1),
+        non_convergent: count(2),
+        length_mismatch: count(3),
+        broken_path: count(4),
+        zero_reserve: count(5),
+        negative_amount: count(6),
+        insufficient_liquidity: count(7),
+    }
+}
+
+pub fn reset_error_counts() {
+    for counter in &SAFE_MODE_ERROR_COUNTS {
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Pass a result through, or in safe mode swap an error for `safe_value` and count it
+/// Callers pick a conservative safe value: zero profit or price, no opportunity, no rows
+pub fn or_safe<T>(result: Result<T, MathError>, safe_value: T) -> Result<T, MathError> {
+    match result {
+        Err(err) if safe_mode() => {
+            SAFE_MODE_ERROR_COUNTS[err.counter_index()].fetch_add(1, Ordering::Relaxed);
+            Ok(safe_value)
+        }
+        result => result,
+    }
+}
+
+/// Dedicated pool the parallel batch functions run on; None uses rayon's global pool
+static THREAD_POOL: RwLock<Option<Arc<rayon::ThreadPool>>> = RwLock::new(None);
+
+/// Size the pool every parallel entry point runs on, for every caller in the process
+/// A dedicated pool keeps several engine instances per host from contending on rayon's
+/// global one; threads = 0 drops it and goes back to the global pool
+pub fn configure_threads(threads: usize) -> Result<(), MathError> {
+    let pool = if threads == 0 {
+        None
+    } else {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(|_| MathError::InvalidParameter {
+                name: "threads",
+                value: threads as f64,
+            })?;
+        Some(Arc::new(pool))
+    };
+    *THREAD_POOL
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = pool;
+    Ok(())
+}
+
+/// Run `op` on the configured pool, so its rayon iterators use that pool's workers
+fn run_parallel<R: Send>(op: impl FnOnce() -> R + Send) -> R {
+    let pool = THREAD_POOL
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone();
+    match pool {
+        Some(pool) => pool.install(op),
+        None => op(),
+    }
+}
+
+/// Worker threads the parallel entry points currently run on
+pub fn parallel_threads() -> usize {
+    run_parallel(rayon::current_num_threads)
+}
+
+/// Default V2-style swap fee (0.3%), shared by every function that does not take a fee
+pub const DEFAULT_FEE_BPS: u32 = 30;
+
+/// Fraction of the input that reaches the curve after the swap fee (30 bps -> 0.997)
+pub fn fee_multiplier(fee_bps: u32) -> f64 {
+    (10000 - fee_bps.min(10000)) as f64 / 10000.0
+}
+
+/// Tolerance-aware float equality shared by convergence checks, tie-breaks and reconciliation
+/// True when |a - b| <= max(rel_tol × max(|a|, |b|), abs_tol); exactly equal values (including
+/// equal infinities) always match and NaN never does
+pub fn approx_eq(a: f64, b: f64, rel_tol: f64, abs_tol: f64) -> bool {
+    if a == b {
+        return true;
+    }
+    if !a.is_finite() || !b.is_finite() {
+        return false;
+    }
+
+    (a - b).abs() <= (rel_tol * a.abs().max(b.abs())).max(abs_tol)
+}
+
 /// Compute Uniswap V2 slippage using constant product formula (x * y = k)
 pub fn compute_uniswap_v2_slippage(reserve_in: f64, reserve_out: f64, amount_in: f64) -> f64 {
+    compute_uniswap_v2_slippage_with_fee(reserve_in, reserve_out, amount_in, DEFAULT_FEE_BPS)
+}
+
+/// Compute Uniswap V2 slippage with an explicit pool fee
+/// fee_bps = 0 (fee-free pools) leaves only the constant-product price impact
+pub fn compute_uniswap_v2_slippage_with_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    fee_bps: u32,
+) -> f64 {
     if amount_in == 0.0 {
         return 0.0;
     }
 
-    // Apply 0.3% fee
-    let amount_in_with_fee = amount_in * 0.997;
+    // Apply the pool fee
+    let amount_in_with_fee = amount_in * fee_multiplier(fee_bps);
 
     // Constant product formula
     let numerator = amount_in_with_fee * reserve_out;
@@ -22,30 +266,605 @@ pub fn compute_uniswap_v2_slippage(reserve_in: f64, reserve_out: f64, amount_in:
     slippage.max(0.0)
 }
 
-/// Compute Uniswap V3 slippage with concentrated liquidity
+/// Compute Uniswap V3 slippage for token0 in
+#[deprecated(
+    note = "use compute_uniswap_v3_slippage_with_direction, which takes the swap direction"
+)]
 pub fn compute_uniswap_v3_slippage(liquidity: f64, sqrt_price: f64, amount_in: f64) -> f64 {
-    if amount_in == 0.0 || liquidity == 0.0 {
+    compute_uniswap_v3_slippage_with_direction(liquidity, sqrt_price, amount_in, true)
+}
+
+/// Uniswap V3 slippage against spot for a fee-free swap inside the active range, token0 in when
+/// zero_for_one; sqrt_price_next = L / (L / √P + amount_in) for token0, √P + amount_in / L for
+/// token1
+pub fn compute_uniswap_v3_slippage_with_direction(
+    liquidity: f64,
+    sqrt_price: f64,
+    amount_in: f64,
+    zero_for_one: bool,
+) -> f64 {
+    if amount_in <= 0.0 || liquidity <= 0.0 || sqrt_price <= 0.0 {
         return 0.0;
     }
 
-    // Simplified concentrated liquidity calculation
-    // In real V3, this would involve tick math and price ranges
-    let amount_out = (amount_in * sqrt_price * liquidity) / (liquidity + amount_in);
-    let expected_amount_out = amount_in * sqrt_price;
+    let (amount_out, expected_amount_out) = if zero_for_one {
+        let sqrt_price_next = liquidity / (liquidity / sqrt_price + amount_in);
+        (
+            liquidity * (sqrt_price - sqrt_price_next),
+            amount_in * sqrt_price * sqrt_price,
+        )
+    } else {
+        let sqrt_price_next = sqrt_price + amount_in / liquidity;
+        (
+            liquidity * (1.0 / sqrt_price - 1.0 / sqrt_price_next),
+            amount_in / (sqrt_price * sqrt_price),
+        )
+    };
 
     let slippage = ((expected_amount_out - amount_out) / expected_amount_out) * 100.0;
     slippage.max(0.0)
 }
 
+/// Initialized Uniswap V3 liquidity range: active while tick_lower <= tick < tick_upper
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TickRange {
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity: f64,
+}
+
+impl TickRange {
+    pub fn contains_tick(&self, tick: i32) -> bool {
+        self.tick_lower <= tick && tick < self.tick_upper
+    }
+}
+
+/// Convert a sqrt price to the V3 tick containing it
+/// Formula: tick = floor(log_1.0001(sqrt_price²))
+/// The log estimate can land a tick off at a boundary, so it is corrected against
+/// `tick_to_sqrt_price`: a price exactly on a tick maps to that tick
+pub fn sqrt_price_to_tick(sqrt_price: f64) -> i32 {
+    let tick = (2.0 * sqrt_price.ln() / 1.0001_f64.ln()).floor() as i32;
+    if tick_to_sqrt_price(tick.saturating_add(1)) <= sqrt_price {
+        tick.saturating_add(1)
+    } else if tick_to_sqrt_price(tick) > sqrt_price {
+        tick.saturating_sub(1)
+    } else {
+        tick
+    }
+}
+
+/// Convert a V3 tick to its sqrt price
+/// Formula: sqrt_price = 1.0001^(tick / 2)
+pub fn tick_to_sqrt_price(tick: i32) -> f64 {
+    1.0001_f64.powf(tick as f64 / 2.0)
+}
+
+/// Lowest and highest ticks Uniswap V3 supports
+pub const MIN_TICK: i32 = -887272;
+pub const MAX_TICK: i32 = 887272;
+
+/// Tick spacing Uniswap V3 uses for each standard fee tier
+pub fn tick_spacing_for_fee(fee_bps: u32) -> Option<i32> {
+    match fee_bps {
+        1 => Some(1),
+        5 => Some(10),
+        30 => Some(60),
+        100 => Some(200),
+        _ => None,
+    }
+}
+
+/// Round a target tick to the nearest tick usable with the given spacing
+/// Ties round down; results stay within [MIN_TICK, MAX_TICK]
+pub fn round_to_tick_spacing(target_tick: i32, tick_spacing: i32) -> i32 {
+    if tick_spacing <= 0 {
+        return target_tick;
+    }
+
+    let lower = target_tick.div_euclid(tick_spacing) * tick_spacing;
+    let upper = lower + tick_spacing;
+    let rounded = if upper - target_tick < target_tick - lower {
+        upper
+    } else {
+        lower
+    };
+
+    // Snap inward so the tick stays representable
+    let min_usable = (MIN_TICK / tick_spacing) * tick_spacing;
+    let max_usable = (MAX_TICK / tick_spacing) * tick_spacing;
+    rounded.clamp(min_usable, max_usable)
+}
+
+/// Nearest sqrt price a pool in the given fee tier can actually be pushed to
+/// Back-run sizing should target this rather than a price between usable ticks
+/// Returns the target unchanged for unknown fee tiers
+pub fn nearest_achievable_sqrt_price(target_sqrt_price: f64, fee_bps: u32) -> f64 {
+    let Some(tick_spacing) = tick_spacing_for_fee(fee_bps) else {
+        return target_sqrt_price;
+    };
+    if target_sqrt_price <= 0.0 {
+        return 0.0;
+    }
+
+    let tick = sqrt_price_to_tick(target_sqrt_price);
+    let lower = round_to_tick_spacing(tick.div_euclid(tick_spacing) * tick_spacing, tick_spacing);
+    let upper = round_to_tick_spacing(lower + tick_spacing, tick_spacing);
+
+    let lower_price = tick_to_sqrt_price(lower);
+    let upper_price = tick_to_sqrt_price(upper);
+    if (upper_price - target_sqrt_price).abs() < (target_sqrt_price - lower_price).abs() {
+        upper_price
+    } else {
+        lower_price
+    }
+}
+
+/// First on-chain bitmap word (int16 wordPos of 256 compressed ticks) a spacing can use
+/// A tick bitmap here is those 256-bit words from this one upward, each split into four
+/// little-endian u64 limbs, so word w's limb l sits at index (w - first word)·4 + l;
+/// missing trailing words count as empty
+fn tick_bitmap_first_word(tick_spacing: i32) -> i32 {
+    MIN_TICK.div_euclid(tick_spacing) >> 8
+}
+
+/// Build a tick bitmap in the layout `next_initialized_tick` reads from initialized ticks
+/// Ticks off the spacing grid or outside [MIN_TICK, MAX_TICK] are ignored
+pub fn tick_bitmap_from_ticks(ticks: &[i32], tick_spacing: i32) -> Vec<u64> {
+    let mut bitmap = Vec::new();
+    if tick_spacing <= 0 {
+        return bitmap;
+    }
+
+    let first_word = tick_bitmap_first_word(tick_spacing);
+    for &tick in ticks {
+        if tick % tick_spacing != 0 || !(MIN_TICK..=MAX_TICK).contains(&tick) {
+            continue;
+        }
+        let compressed = tick / tick_spacing;
+        let bit = (compressed & 255) as usize;
+        let limb = ((compressed >> 8) - first_word) as usize * 4 + bit / 64;
+        if bitmap.len() <= limb {
+            bitmap.resize(limb / 4 * 4 + 4, 0);
+        }
+        bitmap[limb] |= 1 << (bit % 64);
+    }
+    bitmap
+}
+
+/// Uniswap V3's TickBitmap.nextInitializedTickWithinOneWord over a bitmap laid out as in
+/// `tick_bitmap_from_ticks`. lte searches at or below `tick`, otherwise strictly above it,
+/// never leaving the 256-tick word; returns (next_tick, initialized), where an uninitialized
+/// result is the word's boundary tick, exactly where the contract would stop a swap step
+pub fn next_initialized_tick_within_one_word(
+    tick_bitmap: &[u64],
+    tick: i32,
+    tick_spacing: i32,
+    lte: bool,
+) -> (i32, bool) {
+    let compressed = tick.div_euclid(tick_spacing);
+    let position = if lte { compressed } else { compressed + 1 };
+    let bit = (position & 255) as u32;
+    let word_index = (position >> 8) - tick_bitmap_first_word(tick_spacing);
+
+    let limbs: [u64; 4] = std::array::from_fn(|l| {
+        let limb = if word_index >= 0 {
+            tick_bitmap.get(word_index as usize * 4 + l).copied()
+        } else {
+            None
+        }
+        .unwrap_or(0);
+        let low = l as u32 * 64;
+        let mask = if lte {
+            // Bits at or below `bit`
+            match bit.checked_sub(low) {
+                Some(offset) if offset >= 63 => u64::MAX,
+                Some(offset) => (1u64 << (offset + 1)) - 1,
+                None => 0,
+            }
+        } else {
+            // Bits at or above `bit`
+            match bit.checked_sub(low) {
+                None | Some(0) => u64::MAX,
+                Some(offset) if offset > 63 => 0,
+                Some(offset) => !((1u64 << offset) - 1),
+            }
+        };
+        limb & mask
+    });
+
+    if lte {
+        match (0..4).rev().find(|l| limbs[*l] != 0) {
+            Some(l) => {
+                let most_significant = l as u32 * 64 + 63 - limbs[l].leading_zeros();
+                (
+                    (position - (bit - most_significant) as i32) * tick_spacing,
+                    true,
+                )
+            }
+            None => ((position - bit as i32) * tick_spacing, false),
+        }
+    } else {
+        match (0..4).find(|l| limbs[*l] != 0) {
+            Some(l) => {
+                let least_significant = l as u32 * 64 + limbs[l].trailing_zeros();
+                (
+                    (position + (least_significant - bit) as i32) * tick_spacing,
+                    true,
+                )
+            }
+            None => ((position + (255 - bit) as i32) * tick_spacing, false),
+        }
+    }
+}
+
+/// Next initialized tick a swap would reach from `current_tick`, walking word by word as
+/// the V3 swap loop does: at or below it for zero_for_one, strictly above it otherwise
+/// Returns None when no initialized tick lies in that direction or the spacing is invalid
+pub fn next_initialized_tick(
+    tick_bitmap: &[u64],
+    current_tick: i32,
+    tick_spacing: i32,
+    zero_for_one: bool,
+) -> Option<i32> {
+    if tick_spacing <= 0 {
+        return None;
+    }
+
+    let mut tick = current_tick;
+    loop {
+        let (next, initialized) =
+            next_initialized_tick_within_one_word(tick_bitmap, tick, tick_spacing, zero_for_one);
+        if !(MIN_TICK..=MAX_TICK).contains(&next) {
+            return None;
+        }
+        if initialized {
+            return Some(next);
+        }
+        tick = if zero_for_one { next - 1 } else { next };
+    }
+}
+
+/// Sum the liquidity of every range active at the given sqrt price
+pub fn v3_active_liquidity(sqrt_price: f64, ticks: &[TickRange]) -> f64 {
+    if sqrt_price <= 0.0 {
+        return 0.0;
+    }
+
+    let current_tick = sqrt_price_to_tick(sqrt_price);
+    ticks
+        .iter()
+        .filter(|range| range.contains_tick(current_tick))
+        .map(|range| range.liquidity)
+        .sum()
+}
+
+/// Check whether a position's range covers the current price, i.e. whether adding or
+/// removing it changes the active liquidity (same convention as `v3_active_liquidity`)
+pub fn v3_position_in_range(sqrt_price: f64, tick_lower: i32, tick_upper: i32) -> bool {
+    if sqrt_price <= 0.0 {
+        return false;
+    }
+
+    TickRange {
+        tick_lower,
+        tick_upper,
+        liquidity: 0.0,
+    }
+    .contains_tick(sqrt_price_to_tick(sqrt_price))
+}
+
+/// Active liquidity after an LP position is added (positive delta) or removed (negative)
+/// Only positions covering the current tick change it; the result never goes below zero
+/// Feed the result to the V3 quote functions to reprice without refetching the pool
+pub fn adjust_v3_liquidity(
+    current_liquidity: f64,
+    liquidity_delta: f64,
+    tick_in_range: bool,
+) -> f64 {
+    if !tick_in_range {
+        return current_liquidity;
+    }
+
+    (current_liquidity + liquidity_delta).max(0.0)
+}
+
+/// Exact V3 swap of token0 for token1 that stays inside the current tick range
+/// Formula: sqrt_price_after = L·√P / (L + amount_in_less_fee·√P), out = L·(√P - sqrt_price_after)
+/// Returns (amount_out, sqrt_price_after); the caller checks the price against the lower tick
+pub fn v3_swap_within_tick(
+    sqrt_price: f64,
+    liquidity: f64,
+    amount_in: f64,
+    fee_bps: u32,
+) -> (f64, f64) {
+    if sqrt_price <= 0.0 || liquidity <= 0.0 || amount_in <= 0.0 {
+        return (0.0, sqrt_price);
+    }
+
+    let amount_in_less_fee = amount_in * fee_multiplier(fee_bps);
+    let sqrt_price_after = liquidity * sqrt_price / (liquidity + amount_in_less_fee * sqrt_price);
+    (
+        liquidity * (sqrt_price - sqrt_price_after),
+        sqrt_price_after,
+    )
+}
+
+/// Outcome of a V3 swap walked across initialized ranges by `v3_swap_across_ticks`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct V3TickSwap {
+    pub amount_out: f64,
+    /// Input actually swapped; short of amount_in once the ranges run out of liquidity
+    pub amount_in_used: f64,
+    pub sqrt_price_after: f64,
+    /// Range boundaries the price reached on the way
+    pub ticks_crossed: u32,
+}
+
+/// Exact V3 swap across initialized ranges: token0 in (price falls) when zero_for_one, token1 in
+/// (price rises) otherwise. Each step between boundaries is a within-tick swap at that step's L;
+/// gaps are crossed for free, and an infinite amount_in measures the pool's capacity
+pub fn v3_swap_across_ticks(
+    sqrt_price: f64,
+    ranges: &[TickRange],
+    amount_in: f64,
+    fee_bps: u32,
+    zero_for_one: bool,
+) -> V3TickSwap {
+    let mut swap = V3TickSwap {
+        amount_out: 0.0,
+        amount_in_used: 0.0,
+        sqrt_price_after: sqrt_price,
+        ticks_crossed: 0,
+    };
+    if !(sqrt_price > 0.0 && sqrt_price.is_finite())
+        || amount_in.is_nan()
+        || amount_in <= 0.0
+        || fee_bps >= 10000
+    {
+        return swap;
+    }
+
+    let gamma = fee_multiplier(fee_bps);
+    let spans: Vec<(f64, f64, f64)> = ranges
+        .iter()
+        .filter(|range| range.liquidity > 0.0 && range.tick_lower < range.tick_upper)
+        .map(|range| {
+            (
+                tick_to_sqrt_price(range.tick_lower),
+                tick_to_sqrt_price(range.tick_upper),
+                range.liquidity,
+            )
+        })
+        .collect();
+    let mut boundaries: Vec<f64> = spans.iter().flat_map(|&(lo, hi, _)| [lo, hi]).collect();
+    boundaries.sort_by(f64::total_cmp);
+    boundaries.dedup();
+
+    let mut price = sqrt_price;
+    while swap.amount_in_used < amount_in {
+        let next = if zero_for_one {
+            boundaries.iter().rev().find(|&&b| b < price)
+        } else {
+            boundaries.iter().find(|&&b| b > price)
+        };
+        let Some(&boundary) = next else {
+            break;
+        };
+        let (step_low, step_high) = if zero_for_one {
+            (boundary, price)
+        } else {
+            (price, boundary)
+        };
+        let liquidity: f64 = spans
+            .iter()
+            .filter(|&&(lo, hi, _)| lo <= step_low && hi >= step_high)
+            .map(|&(_, _, l)| l)
+            .sum();
+
+        let remaining = amount_in - swap.amount_in_used;
+        let net_to_boundary = if zero_for_one {
+            liquidity * (1.0 / boundary - 1.0 / price)
+        } else {
+            liquidity * (boundary - price)
+        };
+        if remaining * gamma < net_to_boundary {
+            let (amount_out, price_after) = if zero_for_one {
+                v3_swap_within_tick(price, liquidity, remaining, fee_bps)
+            } else {
+                let price_after = price + remaining * gamma / liquidity;
+                (liquidity * (1.0 / price - 1.0 / price_after), price_after)
+            };
+            swap.amount_out += amount_out;
+            swap.amount_in_used = amount_in;
+            price = price_after;
+            break;
+        }
+
+        swap.amount_out += if zero_for_one {
+            liquidity * (price - boundary)
+        } else {
+            liquidity * (1.0 / price - 1.0 / boundary)
+        };
+        swap.amount_in_used += net_to_boundary / gamma;
+        swap.ticks_crossed += 1;
+        price = boundary;
+    }
+
+    swap.sqrt_price_after = price;
+    swap
+}
+
+/// V3-equivalent liquidity of a V2 pool, a position over every price
+/// Formula: L = sqrt(reserve_in × reserve_out); returns 0 for non-positive reserves
+pub fn v2_virtual_liquidity(reserve_in: f64, reserve_out: f64) -> f64 {
+    if reserve_in <= 0.0 || reserve_out <= 0.0 {
+        return 0.0;
+    }
+
+    (reserve_in * reserve_out).sqrt()
+}
+
+/// Check whether the current V3 price sits inside an initialized range with liquidity
+pub fn v3_has_liquidity_at(sqrt_price: f64, ticks: &[TickRange]) -> bool {
+    v3_active_liquidity(sqrt_price, ticks) > 0.0
+}
+
+/// Compute Uniswap V3 slippage in the `zero_for_one` direction using the liquidity active at
+/// the current price
+/// Errors with InsufficientLiquidity when the price sits in a gap with no liquidity, where
+/// any quote is meaningless
+pub fn compute_uniswap_v3_slippage_in_range(
+    sqrt_price: f64,
+    ticks: &[TickRange],
+    amount_in: f64,
+    zero_for_one: bool,
+) -> Result<f64, MathError> {
+    let liquidity = v3_active_liquidity(sqrt_price, ticks);
+    if liquidity <= 0.0 {
+        return Err(MathError::InsufficientLiquidity {
+            requested: amount_in,
+            available: 0.0,
+        });
+    }
+
+    Ok(compute_uniswap_v3_slippage_with_direction(
+        liquidity,
+        sqrt_price,
+        amount_in,
+        zero_for_one,
+    ))
+}
+
+/// Active liquidity and the initialized ticks bracketing the price it was read at
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CachedV3Range {
+    liquidity: f64,
+    tick_lower: i32,
+    tick_upper: i32,
+}
+
+/// Hit/recompute counters for `V3QuoteCache`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct V3QuoteCacheStats {
+    /// Quotes served from a cached range
+    pub hits: u64,
+    /// Quotes that had to rescan the ticks
+    pub recomputes: u64,
+    /// Pools (per fee tier) with a cached range
+    pub entries: usize,
+}
+
+/// Cache of V3 active liquidity keyed by pool id and fee tier, reused until sqrt_price crosses
+/// an initialized tick. Invalidate a pool when its positions change (mints or burns)
+#[derive(Debug, Default)]
+pub struct V3QuoteCache {
+    ranges: HashMap<(String, u32), CachedV3Range>,
+    hits: u64,
+    recomputes: u64,
+}
+
+impl V3QuoteCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> V3QuoteCacheStats {
+        V3QuoteCacheStats {
+            hits: self.hits,
+            recomputes: self.recomputes,
+            entries: self.ranges.len(),
+        }
+    }
+
+    /// Drop one pool's entries in every fee tier
+    pub fn invalidate(&mut self, pool_id: &str) {
+        self.ranges.retain(|(id, _), _| id != pool_id);
+    }
+
+    /// Drop every entry; the counters are kept
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+    }
+
+    /// Token1 out for `amount_in` of token0, as `v3_swap_within_tick` at the liquidity active
+    /// at sqrt_price. Tick boundaries come from fee_bps's tick spacing, so ticks off that grid
+    /// are not treated as boundaries
+    /// Errors for an unknown fee tier or price, and with InsufficientLiquidity like
+    /// `compute_uniswap_v3_slippage_in_range` when no liquidity is active at the price
+    pub fn quote(
+        &mut self,
+        pool_id: &str,
+        fee_bps: u32,
+        sqrt_price: f64,
+        ticks: &[TickRange],
+        amount_in: f64,
+    ) -> Result<f64, MathError> {
+        let tick_spacing = tick_spacing_for_fee(fee_bps).ok_or(MathError::InvalidParameter {
+            name: "fee_bps",
+            value: fee_bps as f64,
+        })?;
+        if !(sqrt_price > 0.0 && sqrt_price.is_finite()) {
+            return Err(MathError::InvalidParameter {
+                name: "sqrt_price",
+                value: sqrt_price,
+            });
+        }
+
+        let tick = sqrt_price_to_tick(sqrt_price);
+        let key = (pool_id.to_string(), fee_bps);
+        let range = match self.ranges.get(&key) {
+            Some(range) if range.tick_lower <= tick && tick < range.tick_upper => {
+                self.hits += 1;
+                *range
+            }
+            _ => {
+                self.recomputes += 1;
+                let boundaries: Vec<i32> = ticks
+                    .iter()
+                    .flat_map(|range| [range.tick_lower, range.tick_upper])
+                    .collect();
+                let bitmap = tick_bitmap_from_ticks(&boundaries, tick_spacing);
+                let range = CachedV3Range {
+                    liquidity: v3_active_liquidity(sqrt_price, ticks),
+                    tick_lower: next_initialized_tick(&bitmap, tick, tick_spacing, true)
+                        .unwrap_or(MIN_TICK),
+                    tick_upper: next_initialized_tick(&bitmap, tick, tick_spacing, false)
+                        .unwrap_or(MAX_TICK),
+                };
+                self.ranges.insert(key, range);
+                range
+            }
+        };
+
+        if range.liquidity <= 0.0 {
+            return Err(MathError::InsufficientLiquidity {
+                requested: amount_in,
+                available: 0.0,
+            });
+        }
+        Ok(v3_swap_within_tick(sqrt_price, range.liquidity, amount_in, fee_bps).0)
+    }
+}
+
 /// Compute Curve stableswap slippage with amplification coefficient
+/// This blends constant-sum and constant-product curves and drifts from the real invariant
+/// in imbalanced pools; `curve_get_dy` quotes what the contract pays
+/// Errors when amplification is not positive, since the blend weight is undefined there
 pub fn compute_curve_slippage(
     balance_in: f64,
     balance_out: f64,
     amount_in: f64,
     amplification: f64,
-) -> f64 {
+) -> Result<f64, MathError> {
+    if !(amplification > 0.0 && amplification.is_finite()) {
+        return Err(MathError::InvalidParameter {
+            name: "amplification",
+            value: amplification,
+        });
+    }
     if amount_in == 0.0 {
-        return 0.0;
+        return Ok(0.0);
     }
 
     // Simplified Curve StableSwap formula
@@ -71,39 +890,924 @@ pub fn compute_curve_slippage(
 
     let expected_amount_out = amount_in * (balance_out / balance_in);
     let slippage = ((expected_amount_out - amount_out) / expected_amount_out) * 100.0;
-    slippage.max(0.0)
+    Ok(slippage.max(0.0))
 }
 
-/// Compute Balancer weighted pool slippage
-pub fn compute_balancer_slippage(
+/// Sweep Curve slippage across amplification values for pool family analysis
+/// Returns one slippage value per entry in `amps`, in the same order; the first invalid
+/// amplification fails the whole sweep
+pub fn curve_slippage_vs_amp(
     balance_in: f64,
     balance_out: f64,
-    weight_in: f64,
-    weight_out: f64,
     amount_in: f64,
-) -> f64 {
-    if amount_in == 0.0 {
-        return 0.0;
-    }
+    amps: &[f64],
+) -> Result<Vec<f64>, MathError> {
+    amps.iter()
+        .map(|amp| compute_curve_slippage(balance_in, balance_out, amount_in, *amp))
+        .collect()
+}
 
-    // Balancer weighted pool formula: amount_out = balance_out * (1 - (balance_in / (balance_in + amount_in))^(weight_in/weight_out))
-    let base = balance_in / (balance_in + amount_in);
-    let exponent = weight_in / weight_out;
-    let amount_out = balance_out * (1.0 - base.powf(exponent));
+/// Iteration cap for the StableSwap Newton solvers, as in Curve's contracts
+const STABLESWAP_MAX_ITERATIONS: usize = 255;
 
-    let expected_amount_out = amount_in * (balance_out / balance_in);
-    let slippage = ((expected_amount_out - amount_out) / expected_amount_out) * 100.0;
-    slippage.max(0.0)
+/// Relative step size at which a StableSwap Newton iterate counts as converged
+const STABLESWAP_TOLERANCE: f64 = 1e-13;
+
+/// Amplification scaled as in the whitepaper invariant, Ann = A·n^n
+fn stableswap_ann(balances: &[f64], amp: f64) -> Result<f64, MathError> {
+    if balances.len() < 2 {
+        return Err(MathError::InvalidParameter {
+            name: "coin count",
+            value: balances.len() as f64,
+        });
+    }
+    if !(amp > 0.0 && amp.is_finite()) {
+        return Err(MathError::InvalidParameter {
+            name: "amplification",
+            value: amp,
+        });
+    }
+    if let Some(balance) = balances.iter().find(|b| !(**b > 0.0 && b.is_finite())) {
+        return Err(MathError::InvalidParameter {
+            name: "balance",
+            value: *balance,
+        });
+    }
+
+    let n = balances.len() as f64;
+    Ok(amp * n.powi(balances.len() as i32))
 }
 
-/// Compute aggregator slippage by selecting minimum slippage route
-pub fn compute_aggregator_slippage(slippages: &[f64]) -> f64 {
-    if slippages.is_empty() {
-        return 0.0;
+/// StableSwap invariant D for the given balances, solved by Newton's method
+/// Invariant: A·n^n·Σx + D = A·D·n^n + D^(n+1) / (n^n·Πx)
+/// Returns NonConvergent with the invariant's residual at the last D if the iteration cap
+/// is reached
+pub fn stableswap_invariant(balances: &[f64], amp: f64) -> Result<f64, MathError> {
+    let ann = stableswap_ann(balances, amp)?;
+    let n = balances.len() as f64;
+    let sum: f64 = balances.iter().sum();
+
+    let mut d = sum;
+    for _ in 0..STABLESWAP_MAX_ITERATIONS {
+        let d_p = balances.iter().fold(d, |d_p, x| d_p * d / (x * n));
+        let previous = d;
+        d = (ann * sum + d_p * n) * d / ((ann - 1.0) * d + (n + 1.0) * d_p);
+        if approx_eq(d, previous, STABLESWAP_TOLERANCE, 0.0) {
+            return Ok(d);
+        }
     }
 
-    // Return minimum slippage (best route)
-    slippages.iter().cloned().fold(f64::INFINITY, f64::min)
+    // d_p is D^(n+1) / (n^n·Πx), so this is the invariant's left side minus its right
+    let d_p = balances.iter().fold(d, |d_p, x| d_p * d / (x * n));
+    Err(MathError::NonConvergent {
+        residual: (ann * sum + d - ann * d - d_p).abs(),
+    })
+}
+
+/// Balance of coin `j` that keeps invariant `d` once coin `i` holds `new_balance_i`
+/// Solves y² + (b - D)·y = c by Newton's method, as Curve's get_y
+pub fn stableswap_get_y(
+    balances: &[f64],
+    amp: f64,
+    i: usize,
+    j: usize,
+    new_balance_i: f64,
+    d: f64,
+) -> Result<f64, MathError> {
+    let ann = stableswap_ann(balances, amp)?;
+    if i == j || i >= balances.len() || j >= balances.len() {
+        return Err(MathError::InvalidParameter {
+            name: "coin index",
+            value: if i == j || i >= balances.len() { i } else { j } as f64,
+        });
+    }
+    if !(new_balance_i > 0.0 && new_balance_i.is_finite()) {
+        return Err(MathError::InvalidParameter {
+            name: "new balance",
+            value: new_balance_i,
+        });
+    }
+
+    let n = balances.len() as f64;
+    let mut c = d;
+    let mut sum = 0.0;
+    for (k, balance) in balances.iter().enumerate() {
+        if k == j {
+            continue;
+        }
+        let x = if k == i { new_balance_i } else { *balance };
+        sum += x;
+        c = c * d / (x * n);
+    }
+    c = c * d / (ann * n);
+    let b = sum + d / ann;
+
+    let mut y = d;
+    for _ in 0..STABLESWAP_MAX_ITERATIONS {
+        let previous = y;
+        y = (y * y + c) / (2.0 * y + b - d);
+        if approx_eq(y, previous, STABLESWAP_TOLERANCE, 0.0) {
+            return Ok(y);
+        }
+    }
+
+    Err(MathError::NonConvergent {
+        residual: (y * y + (b - d) * y - c).abs(),
+    })
+}
+
+/// Fee-free marginal rate of a StableSwap pool: coin `j` received per unit of coin `i`
+/// Differentiating the invariant at fixed D gives (Ann·x_i + Q)·x_j / ((Ann·x_j + Q)·x_i)
+/// with Q = D^(n+1) / (n^n·Πx); exact near peg, where finite differences lose precision
+pub fn stableswap_spot_price(
+    balances: &[f64],
+    amp: f64,
+    i: usize,
+    j: usize,
+) -> Result<f64, MathError> {
+    let ann = stableswap_ann(balances, amp)?;
+    if i == j || i >= balances.len() || j >= balances.len() {
+        return Err(MathError::InvalidParameter {
+            name: "coin index",
+            value: if i == j || i >= balances.len() { i } else { j } as f64,
+        });
+    }
+
+    let d = stableswap_invariant(balances, amp)?;
+    let n = balances.len() as f64;
+    let q = balances.iter().fold(d, |q, x| q * d / (n * x));
+    let (x_i, x_j) = (balances[i], balances[j]);
+
+    Ok((ann * x_i + q) * x_j / ((ann * x_j + q) * x_i))
+}
+
+/// Curve's get_dy: coin `j` received for `dx` of coin `i` on a StableSwap pool of 2 or more coins
+/// fee_pct is the pool fee as a fraction (0.0004 for the 3pool), taken from the output
+/// Errors on bad balances, amplification, coin indices or fee, or if either solve stalls
+pub fn curve_get_dy(
+    balances: &[f64],
+    i: usize,
+    j: usize,
+    dx: f64,
+    amplification: f64,
+    fee_pct: f64,
+) -> Result<f64, MathError> {
+    if !(0.0..1.0).contains(&fee_pct) {
+        return Err(MathError::InvalidParameter {
+            name: "fee",
+            value: fee_pct,
+        });
+    }
+    if !(dx >= 0.0 && dx.is_finite()) {
+        return Err(MathError::InvalidParameter {
+            name: "dx",
+            value: dx,
+        });
+    }
+
+    let d = stableswap_invariant(balances, amplification)?;
+    let new_balance_i = balances.get(i).copied().unwrap_or(0.0) + dx;
+    let y = stableswap_get_y(balances, amplification, i, j, new_balance_i, d)?;
+    let dy = (balances[j] - y).max(0.0);
+
+    Ok(dy * (1.0 - fee_pct))
+}
+
+/// Solidly stable-pool invariant (Velodrome, Aerodrome): k = x³y + xy³
+fn solidly_k(x: f64, y: f64) -> f64 {
+    x * y * (x * x + y * y)
+}
+
+/// Reserve out that keeps invariant k once the input side holds x0, as the pair's _get_y
+/// Newton's method on f(y) = x0·y³ + x0³·y with f'(y) = 3·x0·y² + x0³. f is increasing and
+/// convex for y > 0, so starting from the old reserve (above the root when x0 grew) every
+/// step moves down onto it without overshooting
+fn solidly_get_y(x0: f64, k: f64, reserve_out: f64) -> Result<f64, MathError> {
+    let mut y = reserve_out;
+    for _ in 0..STABLESWAP_MAX_ITERATIONS {
+        let f = x0 * y * y * y + x0 * x0 * x0 * y;
+        let derivative = 3.0 * x0 * y * y + x0 * x0 * x0;
+        let previous = y;
+        y -= (f - k) / derivative;
+        if approx_eq(y, previous, STABLESWAP_TOLERANCE, 0.0) {
+            return Ok(y);
+        }
+    }
+
+    Err(MathError::NonConvergent {
+        residual: (solidly_k(x0, y) - k).abs(),
+    })
+}
+
+/// Output of a Solidly stable pool (Velodrome, Aerodrome) on the x³y + xy³ = k invariant
+/// Reserves and amounts share one precision (18 decimals); fee_bps comes off the input
+pub fn solidly_get_amount_out(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    fee_bps: u32,
+) -> Result<f64, MathError> {
+    check_reserves(reserve_in, reserve_out)?;
+    check_amount("amount_in", amount_in)?;
+    if fee_bps > 10000 {
+        return Err(MathError::InvalidParameter {
+            name: "fee_bps",
+            value: fee_bps as f64,
+        });
+    }
+    if amount_in == 0.0 {
+        return Ok(0.0);
+    }
+
+    let k = solidly_k(reserve_in, reserve_out);
+    let y = solidly_get_y(
+        reserve_in + amount_in * fee_multiplier(fee_bps),
+        k,
+        reserve_out,
+    )?;
+    Ok((reserve_out - y).max(0.0))
+}
+
+/// Slippage of a Solidly stable-pool trade against its marginal price, fee included like
+/// `compute_uniswap_v2_slippage_with_fee`
+/// Marginal price of the invariant: dy/dx = (3x²y + y³) / (x³ + 3xy²), 1 at balance, where
+/// the curve stays far flatter than constant product
+pub fn compute_solidly_stable_slippage(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    fee_bps: u32,
+) -> Result<f64, MathError> {
+    let amount_out = solidly_get_amount_out(reserve_in, reserve_out, amount_in, fee_bps)?;
+    if amount_in == 0.0 {
+        return Ok(0.0);
+    }
+
+    let (x, y) = (reserve_in, reserve_out);
+    let spot_price = (3.0 * x * x * y + y * y * y) / (x * x * x + 3.0 * x * y * y);
+    let expected_amount_out = amount_in * spot_price;
+    let slippage = ((expected_amount_out - amount_out) / expected_amount_out) * 100.0;
+    Ok(slippage.max(0.0))
+}
+
+/// Peg-restoration trade found by `stable_peg_arb`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StablePegArb {
+    /// Scarce coin paid into the pool
+    pub coin_in: usize,
+    /// Cheap coin taken out of the pool, worth peg_price once the peg restores
+    pub coin_out: usize,
+    /// Pool's marginal rate of coin_out per coin_in above 1:1, in percent
+    pub deviation_pct: f64,
+    pub amount_in: f64,
+    pub amount_out: f64,
+    /// (amount_out - amount_in) × peg_price - gas_cost
+    pub expected_profit: f64,
+}
+
+/// Size the trade that profits from a StableSwap coin trading off peg, valuing both coins at
+/// peg_price; uses the pair with the largest marginal deviation
+/// Returns None below min_deviation_pct or when gas eats the edge
+pub fn stable_peg_arb(
+    balances: &[f64],
+    amp: f64,
+    peg_price: f64,
+    min_deviation_pct: f64,
+    fee_bps: u32,
+    gas_cost: f64,
+) -> Result<Option<StablePegArb>, MathError> {
+    if !(peg_price > 0.0 && peg_price.is_finite()) {
+        return Err(MathError::InvalidParameter {
+            name: "peg price",
+            value: peg_price,
+        });
+    }
+    let d = stableswap_invariant(balances, amp)?;
+    let fee_factor = fee_multiplier(fee_bps);
+
+    // Pair whose fee-free marginal rate sits furthest above 1:1
+    let mut best: Option<(usize, usize, f64)> = None;
+    for i in 0..balances.len() {
+        for j in 0..balances.len() {
+            if i == j {
+                continue;
+            }
+            let rate = stableswap_spot_price(balances, amp, i, j)?;
+            if best.is_none_or(|(_, _, best_rate)| rate > best_rate) {
+                best = Some((i, j, rate));
+            }
+        }
+    }
+    let (coin_in, coin_out, rate) = match best {
+        Some(pair) => pair,
+        None => return Ok(None),
+    };
+    let deviation_pct = (rate - 1.0) * 100.0;
+    if deviation_pct < min_deviation_pct {
+        return Ok(None);
+    }
+
+    let amount_out_for = |amount_in: f64| -> Result<f64, MathError> {
+        if amount_in <= 0.0 {
+            return Ok(0.0);
+        }
+        let new_y = stableswap_get_y(
+            balances,
+            amp,
+            coin_in,
+            coin_out,
+            balances[coin_in] + amount_in,
+            d,
+        )?;
+        Ok(((balances[coin_out] - new_y) * fee_factor).max(0.0))
+    };
+    let edge_for =
+        |amount_in: f64| -> Result<f64, MathError> { Ok(amount_out_for(amount_in)? - amount_in) };
+
+    // Edge is concave in the input, so golden-section search finds its peak
+    let inv_phi = (5.0_f64.sqrt() - 1.0) / 2.0;
+    let mut low = 0.0;
+    let mut high = balances[coin_out];
+    let mut left = high - inv_phi * (high - low);
+    let mut right = low + inv_phi * (high - low);
+    let mut left_edge = edge_for(left)?;
+    let mut right_edge = edge_for(right)?;
+    for _ in 0..200 {
+        if approx_eq(low, high, 1e-12, 0.0) {
+            break;
+        }
+        if left_edge < right_edge {
+            low = left;
+            left = right;
+            left_edge = right_edge;
+            right = low + inv_phi * (high - low);
+            right_edge = edge_for(right)?;
+        } else {
+            high = right;
+            right = left;
+            right_edge = left_edge;
+            left = high - inv_phi * (high - low);
+            left_edge = edge_for(left)?;
+        }
+    }
+
+    let amount_in = (low + high) / 2.0;
+    let amount_out = amount_out_for(amount_in)?;
+    let expected_profit = (amount_out - amount_in) * peg_price - gas_cost;
+    if expected_profit <= 0.0 {
+        return Ok(None);
+    }
+
+    Ok(Some(StablePegArb {
+        coin_in,
+        coin_out,
+        deviation_pct,
+        amount_in,
+        amount_out,
+        expected_profit,
+    }))
+}
+
+/// Compute Balancer weighted pool slippage
+/// Weights must be the pool's normalized weights, each in (0, 1], with the remaining tokens
+/// holding the rest; anything else is an error naming the offending weight
+pub fn compute_balancer_slippage(
+    balance_in: f64,
+    balance_out: f64,
+    weight_in: f64,
+    weight_out: f64,
+    amount_in: f64,
+) -> Result<f64, MathError> {
+    validate_balancer_weights(weight_in, weight_out)?;
+    if amount_in == 0.0 {
+        return Ok(0.0);
+    }
+
+    // Balancer weighted pool formula: amount_out = balance_out * (1 - (balance_in / (balance_in + amount_in))^(weight_in/weight_out))
+    let base = balance_in / (balance_in + amount_in);
+    let exponent = weight_in / weight_out;
+    let amount_out = balance_out * (1.0 - base.powf(exponent));
+
+    let expected_amount_out = amount_in * (balance_out / balance_in);
+    let slippage = ((expected_amount_out - amount_out) / expected_amount_out) * 100.0;
+    Ok(slippage.max(0.0))
+}
+
+/// Tolerance when checking that Balancer weights are normalized
+const WEIGHT_EPSILON: f64 = 1e-9;
+
+/// Check a pair of Balancer weights is part of a normalized weight set
+fn validate_balancer_weights(weight_in: f64, weight_out: f64) -> Result<(), MathError> {
+    for (name, weight) in [("weight_in", weight_in), ("weight_out", weight_out)] {
+        if !(weight > 0.0 && weight <= 1.0) {
+            return Err(MathError::InvalidParameter {
+                name,
+                value: weight,
+            });
+        }
+    }
+    if weight_in + weight_out > 1.0 + WEIGHT_EPSILON {
+        return Err(MathError::InvalidParameter {
+            name: "weight_in + weight_out",
+            value: weight_in + weight_out,
+        });
+    }
+    Ok(())
+}
+
+/// Balancer weighted pool holding any number of tokens (typically 2-8)
+/// Weights are the pool's normalized weights and must sum to 1
+pub struct BalancerPool {
+    pub balances: Vec<f64>,
+    pub weights: Vec<f64>,
+    pub swap_fee_bps: u32,
+}
+
+impl BalancerPool {
+    /// Check that balances and weights line up and the weights are normalized
+    pub fn is_valid(&self) -> bool {
+        if self.balances.len() < 2 || self.balances.len() != self.weights.len() {
+            return false;
+        }
+        if self.swap_fee_bps >= 10000 {
+            return false;
+        }
+        if self.weights.iter().any(|w| *w <= 0.0) || self.balances.iter().any(|b| *b <= 0.0) {
+            return false;
+        }
+
+        let total_weight: f64 = self.weights.iter().sum();
+        approx_eq(total_weight, 1.0, 0.0, WEIGHT_EPSILON)
+    }
+
+    /// Quote the amount of token `j` received for `amount_in` of token `i`
+    /// Formula: amount_out = balance_j * (1 - (balance_i / (balance_i + amount_in_after_fee))^(weight_i/weight_j))
+    pub fn quote(&self, i: usize, j: usize, amount_in: f64) -> f64 {
+        if !self.is_valid() || i == j || i >= self.balances.len() || j >= self.balances.len() {
+            return 0.0;
+        }
+        if amount_in <= 0.0 {
+            return 0.0;
+        }
+
+        let amount_in_with_fee = amount_in * fee_multiplier(self.swap_fee_bps);
+        let base = self.balances[i] / (self.balances[i] + amount_in_with_fee);
+        let exponent = self.weights[i] / self.weights[j];
+
+        self.balances[j] * (1.0 - base.powf(exponent))
+    }
+}
+
+/// Spot price of a Balancer weighted pool in the protocol's convention
+/// Formula: (balance_in / weight_in) / (balance_out / weight_out), i.e. token_in paid per token_out,
+/// the inverse orientation of `calculate_pool_price`; fees are not included
+pub fn balancer_spot_price(
+    balance_in: f64,
+    balance_out: f64,
+    weight_in: f64,
+    weight_out: f64,
+) -> f64 {
+    if balance_in <= 0.0 || balance_out <= 0.0 || weight_in <= 0.0 || weight_out <= 0.0 {
+        return 0.0;
+    }
+
+    (balance_in / weight_in) / (balance_out / weight_out)
+}
+
+/// Balancer weighted-pool invariant V = Π balance_k^weight_k
+/// Weights are the pool's normalized weights, as in `BalancerPool`
+pub fn balancer_invariant(balances: &[f64], weights: &[f64]) -> Result<f64, MathError> {
+    if balances.len() < 2 || balances.len() != weights.len() {
+        return Err(MathError::InvalidParameter {
+            name: "token count",
+            value: balances.len() as f64,
+        });
+    }
+    if let Some(balance) = balances.iter().find(|b| !(**b > 0.0 && b.is_finite())) {
+        return Err(MathError::InvalidParameter {
+            name: "balance",
+            value: *balance,
+        });
+    }
+    if let Some(weight) = weights.iter().find(|w| !(**w > 0.0 && w.is_finite())) {
+        return Err(MathError::InvalidParameter {
+            name: "weight",
+            value: *weight,
+        });
+    }
+
+    Ok(balances
+        .iter()
+        .zip(weights)
+        .map(|(balance, weight)| balance.powf(*weight))
+        .product())
+}
+
+/// Invariant last computed for a pool, with the exact inputs it was computed from and their
+/// hash as a fast pre-check
+#[derive(Debug, Clone)]
+struct CachedInvariant {
+    inputs_hash: u64,
+    inputs: Vec<u64>,
+    invariant: f64,
+}
+
+/// Warm cache of StableSwap and Balancer invariants keyed by pool id
+/// An entry is reused while the pool's balances (and amp or weights) are bit-identical and is
+/// recomputed as soon as they change, so a pool quoted many times in a block runs Newton once.
+/// Use one id per pool; clear between blocks to bound memory
+#[derive(Debug, Default)]
+pub struct PoolState {
+    invariants: HashMap<String, CachedInvariant>,
+}
+
+impl PoolState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of pools with a cached invariant
+    pub fn len(&self) -> usize {
+        self.invariants.len()
+    }
+
+    /// Drop one pool's entry, e.g. after a swap event for it
+    pub fn invalidate(&mut self, pool_id: &str) {
+        self.invariants.remove(pool_id);
+    }
+
+    /// Drop every entry
+    pub fn clear(&mut self) {
+        self.invariants.clear();
+    }
+
+    /// `stableswap_invariant`, reusing the cached D while balances and amp are unchanged
+    pub fn stableswap_invariant(
+        &mut self,
+        pool_id: &str,
+        balances: &[f64],
+        amp: f64,
+    ) -> Result<f64, MathError> {
+        let inputs = pool_inputs(balances, &[amp]);
+        self.cached(pool_id, inputs, || stableswap_invariant(balances, amp))
+    }
+
+    /// `balancer_invariant`, reusing the cached value while balances and weights are unchanged
+    pub fn balancer_invariant(
+        &mut self,
+        pool_id: &str,
+        balances: &[f64],
+        weights: &[f64],
+    ) -> Result<f64, MathError> {
+        let inputs = pool_inputs(balances, weights);
+        self.cached(pool_id, inputs, || balancer_invariant(balances, weights))
+    }
+
+    /// Amount of coin `j` out for `amount_in` of coin `i` on a StableSwap pool, with fee_pct
+    /// (a fraction, as in `curve_get_dy`) taken from the output as Curve does; only the
+    /// get_y solve runs on a warm cache
+    pub fn stableswap_quote(
+        &mut self,
+        pool_id: &str,
+        balances: &[f64],
+        amp: f64,
+        (i, j): (usize, usize),
+        amount_in: f64,
+        fee_pct: f64,
+    ) -> Result<f64, MathError> {
+        if !(0.0..1.0).contains(&fee_pct) {
+            return Err(MathError::InvalidParameter {
+                name: "fee",
+                value: fee_pct,
+            });
+        }
+        let d = self.stableswap_invariant(pool_id, balances, amp)?;
+        if amount_in <= 0.0 {
+            return Ok(0.0);
+        }
+        let balance_j = balances.get(j).copied().unwrap_or(0.0);
+        let new_balance_i = balances.get(i).copied().unwrap_or(0.0) + amount_in;
+        let new_y = stableswap_get_y(balances, amp, i, j, new_balance_i, d)?;
+
+        Ok(((balance_j - new_y) * (1.0 - fee_pct)).max(0.0))
+    }
+
+    fn cached(
+        &mut self,
+        pool_id: &str,
+        inputs: Vec<u64>,
+        compute: impl FnOnce() -> Result<f64, MathError>,
+    ) -> Result<f64, MathError> {
+        let inputs_hash = hash_pool_inputs(&inputs);
+        if let Some(entry) = self.invariants.get(pool_id) {
+            if entry.inputs_hash == inputs_hash && entry.inputs == inputs {
+                return Ok(entry.invariant);
+            }
+        }
+
+        let invariant = compute()?;
+        self.invariants.insert(
+            pool_id.to_string(),
+            CachedInvariant {
+                inputs_hash,
+                inputs,
+                invariant,
+            },
+        );
+        Ok(invariant)
+    }
+}
+
+/// A pool's balance count, balances and parameters as bit patterns, for cache invalidation
+fn pool_inputs(balances: &[f64], params: &[f64]) -> Vec<u64> {
+    std::iter::once(balances.len() as u64)
+        .chain(balances.iter().chain(params).map(|value| value.to_bits()))
+        .collect()
+}
+
+fn hash_pool_inputs(inputs: &[u64]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    inputs.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Compute aggregator slippage by selecting minimum slippage route
+pub fn compute_aggregator_slippage(slippages: &[f64]) -> f64 {
+    if slippages.is_empty() {
+        return 0.0;
+    }
+
+    // Return minimum slippage (best route)
+    slippages.iter().cloned().fold(f64::INFINITY, f64::min)
+}
+
+/// A pool of any supported protocol, so slippage can be computed with the matching model
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PoolSpec {
+    V2 {
+        reserve_in: f64,
+        reserve_out: f64,
+    },
+    V3 {
+        liquidity: f64,
+        sqrt_price: f64,
+        zero_for_one: bool,
+    },
+    Curve {
+        balance_in: f64,
+        balance_out: f64,
+        amplification: f64,
+    },
+    Balancer {
+        balance_in: f64,
+        balance_out: f64,
+        weight_in: f64,
+        weight_out: f64,
+    },
+}
+
+impl PoolSpec {
+    /// Slippage for `amount_in` using this pool's protocol model
+    /// Returns None for an unusable pool, since the models report 0 slippage on invalid
+    /// input and that would otherwise win every best-route comparison
+    pub fn slippage(&self, amount_in: f64) -> Option<f64> {
+        let slippage = match *self {
+            PoolSpec::V2 {
+                reserve_in,
+                reserve_out,
+            } => {
+                if reserve_in <= 0.0 || reserve_out <= 0.0 {
+                    return None;
+                }
+                compute_uniswap_v2_slippage(reserve_in, reserve_out, amount_in)
+            }
+            PoolSpec::V3 {
+                liquidity,
+                sqrt_price,
+                zero_for_one,
+            } => {
+                if liquidity <= 0.0 || sqrt_price <= 0.0 {
+                    return None;
+                }
+                compute_uniswap_v3_slippage_with_direction(
+                    liquidity,
+                    sqrt_price,
+                    amount_in,
+                    zero_for_one,
+                )
+            }
+            PoolSpec::Curve {
+                balance_in,
+                balance_out,
+                amplification,
+            } => {
+                if balance_in <= 0.0 || balance_out <= 0.0 || amplification <= 0.0 {
+                    return None;
+                }
+                compute_curve_slippage(balance_in, balance_out, amount_in, amplification).ok()?
+            }
+            PoolSpec::Balancer {
+                balance_in,
+                balance_out,
+                weight_in,
+                weight_out,
+            } => {
+                if balance_in <= 0.0
+                    || balance_out <= 0.0
+                    || weight_in <= 0.0
+                    || weight_out <= 0.0
+                    || weight_in + weight_out > 1.0 + WEIGHT_EPSILON
+                {
+                    return None;
+                }
+                compute_balancer_slippage(balance_in, balance_out, weight_in, weight_out, amount_in)
+                    .ok()?
+            }
+        };
+
+        if slippage.is_finite() {
+            Some(slippage)
+        } else {
+            None
+        }
+    }
+
+    /// Fee-free spot price in output token per input token, as `calculate_pool_price` for V2
+    /// V3 quotes sqrt_price² for token0 in and its reciprocal for token1 in; Curve is `stableswap_spot_price` of the
+    /// two balances; Balancer inverts `balancer_spot_price`
+    /// Returns 0 for an unusable pool
+    pub fn spot_price(&self) -> f64 {
+        match *self {
+            PoolSpec::V2 {
+                reserve_in,
+                reserve_out,
+            } => calculate_pool_price(reserve_in, reserve_out),
+            PoolSpec::V3 {
+                sqrt_price,
+                zero_for_one,
+                ..
+            } => {
+                if sqrt_price <= 0.0 {
+                    return 0.0;
+                }
+                let price = sqrt_price * sqrt_price;
+                if zero_for_one {
+                    price
+                } else {
+                    1.0 / price
+                }
+            }
+            PoolSpec::Curve {
+                balance_in,
+                balance_out,
+                amplification,
+            } => stableswap_spot_price(&[balance_in, balance_out], amplification, 0, 1)
+                .unwrap_or(0.0),
+            PoolSpec::Balancer {
+                balance_in,
+                balance_out,
+                weight_in,
+                weight_out,
+            } => {
+                let price = balancer_spot_price(balance_in, balance_out, weight_in, weight_out);
+                if price <= 0.0 {
+                    return 0.0;
+                }
+                1.0 / price
+            }
+        }
+    }
+
+    /// Fee-free marginal price (output per unit of extra input) after `amount_already_in`
+    /// has been routed through the pool; equals `spot_price` at zero and falls as size grows
+    /// V2 differentiates x·y = k; V3 stays within one range and prices at the moved sqrt
+    /// price in the swap direction; Curve moves along the StableSwap invariant with
+    /// `stableswap_get_y` and takes `stableswap_spot_price` there; Balancer differentiates
+    /// the weighted-math output
+    /// Returns 0 for an unusable pool
+    pub fn marginal_price(&self, amount_already_in: f64) -> f64 {
+        let spot = self.spot_price();
+        if spot <= 0.0 {
+            return 0.0;
+        }
+        let amount = amount_already_in.max(0.0);
+
+        match *self {
+            PoolSpec::V2 { reserve_in, .. } => {
+                let depth = reserve_in / (reserve_in + amount);
+                spot * depth * depth
+            }
+            PoolSpec::V3 {
+                liquidity,
+                sqrt_price,
+                zero_for_one,
+            } => {
+                if liquidity <= 0.0 {
+                    return 0.0;
+                }
+                if zero_for_one {
+                    let moved_sqrt_price =
+                        liquidity * sqrt_price / (liquidity + amount * sqrt_price);
+                    moved_sqrt_price * moved_sqrt_price
+                } else {
+                    let moved_sqrt_price = sqrt_price + amount / liquidity;
+                    1.0 / (moved_sqrt_price * moved_sqrt_price)
+                }
+            }
+            PoolSpec::Curve {
+                balance_in,
+                balance_out,
+                amplification,
+            } => {
+                let balances = [balance_in, balance_out];
+                let moved_balance_out =
+                    stableswap_invariant(&balances, amplification).and_then(|d| {
+                        stableswap_get_y(&balances, amplification, 0, 1, balance_in + amount, d)
+                    });
+                match moved_balance_out {
+                    Ok(balance_out) => stableswap_spot_price(
+                        &[balance_in + amount, balance_out],
+                        amplification,
+                        0,
+                        1,
+                    )
+                    .unwrap_or(0.0),
+                    Err(_) => 0.0,
+                }
+            }
+            PoolSpec::Balancer {
+                balance_in,
+                weight_in,
+                weight_out,
+                ..
+            } => {
+                let depth = balance_in / (balance_in + amount);
+                spot * depth.powf(weight_in / weight_out + 1.0)
+            }
+        }
+    }
+}
+
+/// Compute slippage for every pool with its own protocol model and pick the best route
+/// Returns (pool_index, slippage) of the lowest-slippage usable pool; ties (within float noise)
+/// keep the first pool
+/// Returns (0, 0.0) when no pool is usable, matching `compute_aggregator_slippage` on empty input
+pub fn aggregator_best(pools: &[PoolSpec], amount_in: f64) -> (usize, f64) {
+    pools
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, pool)| pool.slippage(amount_in).map(|slippage| (idx, slippage)))
+        .fold(None, |best: Option<(usize, f64)>, candidate| match best {
+            Some(current)
+                if current.1 <= candidate.1 || approx_eq(current.1, candidate.1, 1e-12, 0.0) =>
+            {
+                Some(current)
+            }
+            _ => Some(candidate),
+        })
+        .unwrap_or((0, 0.0))
+}
+
+/// Improvement of the chosen route over trading the direct pool, in basis points
+/// Slippages are percentages, so one percentage point is 100 bps; negative when the route is worse
+pub fn routing_improvement(direct_slippage: f64, best_route_slippage: f64) -> f64 {
+    (direct_slippage - best_route_slippage) * 100.0
+}
+
+/// Route chosen by `best_aggregator_route`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AggregatorRoute {
+    pub pool_index: usize,
+    /// Slippage net of the router's fee, i.e. what the route actually delivers
+    pub slippage_pct: f64,
+    /// Improvement over the direct pool in bps, when a usable direct pool was given
+    pub improvement_bps: Option<f64>,
+}
+
+/// `aggregator_best`, optionally measuring its improvement over the pool at `direct_index`
+/// router_fee_bps is charged on the route only, so a negative improvement favors going direct
+pub fn best_aggregator_route(
+    pools: &[PoolSpec],
+    amount_in: f64,
+    direct_index: Option<usize>,
+    router_fee_bps: u32,
+) -> AggregatorRoute {
+    let (pool_index, gross_slippage) = aggregator_best(pools, amount_in);
+    let slippage_pct = if router_fee_bps > 0 {
+        100.0 - (100.0 - gross_slippage) * fee_multiplier(router_fee_bps)
+    } else {
+        gross_slippage
+    };
+    let improvement_bps = direct_index
+        .and_then(|idx| pools.get(idx))
+        .and_then(|direct| direct.slippage(amount_in))
+        .map(|direct_slippage| routing_improvement(direct_slippage, slippage_pct));
+
+    AggregatorRoute {
+        pool_index,
+        slippage_pct,
+        improvement_bps,
+    }
 }
 
 /// Find optimal trade size for arbitrage given profit function
@@ -112,6 +1816,23 @@ pub fn optimal_trade_size(
     reserve_out: f64,
     gas_cost: f64,
     min_profit: f64,
+) -> f64 {
+    optimal_trade_size_with_fee(
+        reserve_in,
+        reserve_out,
+        gas_cost,
+        min_profit,
+        DEFAULT_FEE_BPS,
+    )
+}
+
+/// `optimal_trade_size` for a pool charging fee_bps instead of the 0.3% default
+pub fn optimal_trade_size_with_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    gas_cost: f64,
+    min_profit: f64,
+    fee_bps: u32,
 ) -> f64 {
     if reserve_in <= 0.0 || reserve_out <= 0.0 {
         return 0.0;
@@ -127,7 +1848,7 @@ pub fn optimal_trade_size(
         let mid = (low + high) / 2.0;
 
         // Calculate profit at this trade size
-        let amount_in_with_fee = mid * 0.997;
+        let amount_in_with_fee = mid * fee_multiplier(fee_bps);
         let numerator = amount_in_with_fee * reserve_out;
         let denominator = reserve_in + amount_in_with_fee;
         let amount_out = numerator / denominator;
@@ -150,6 +1871,27 @@ pub fn optimal_trade_size(
     best_size
 }
 
+/// Share of the pool's input reserve a trade consumes, as a percentage (amount_in / reserve_in × 100)
+/// High utilization flags fragile opportunities likely to be front-run or to revert
+pub fn utilization_pct(amount_in: f64, reserve_in: f64) -> f64 {
+    if reserve_in <= 0.0 {
+        return 0.0;
+    }
+    amount_in / reserve_in * 100.0
+}
+
+/// `optimal_trade_size` alongside its utilization of `reserve_in`
+/// Returns (size, utilization_pct)
+pub fn optimal_trade_size_with_utilization(
+    reserve_in: f64,
+    reserve_out: f64,
+    gas_cost: f64,
+    min_profit: f64,
+) -> (f64, f64) {
+    let size = optimal_trade_size(reserve_in, reserve_out, gas_cost, min_profit);
+    (size, utilization_pct(size, reserve_in))
+}
+
 /// Calculate flashloan amount needed for arbitrage opportunity
 /// Returns the optimal flashloan amount based on available liquidity and expected profit
 pub fn calculate_flashloan_amount(
@@ -159,6 +1901,29 @@ pub fn calculate_flashloan_amount(
     reserve_out_sell: f64,
     flashloan_fee: f64,
     gas_cost: f64,
+) -> f64 {
+    calculate_flashloan_amount_with_fees(
+        reserve_in_buy,
+        reserve_out_buy,
+        reserve_in_sell,
+        reserve_out_sell,
+        flashloan_fee,
+        gas_cost,
+        (DEFAULT_FEE_BPS, DEFAULT_FEE_BPS),
+    )
+}
+
+/// `calculate_flashloan_amount` with each leg's pool fee given separately, for loans routed
+/// across venues with different fee tiers; the flashloan fee is still charged on the
+/// borrowed amount at repayment
+pub fn calculate_flashloan_amount_with_fees(
+    reserve_in_buy: f64,
+    reserve_out_buy: f64,
+    reserve_in_sell: f64,
+    reserve_out_sell: f64,
+    flashloan_fee: f64,
+    gas_cost: f64,
+    (buy_fee_bps, sell_fee_bps): (u32, u32),
 ) -> f64 {
     if reserve_in_buy <= 0.0
         || reserve_out_buy <= 0.0
@@ -179,12 +1944,12 @@ pub fn calculate_flashloan_amount(
         let mid = (low + high) / 2.0;
 
         // Calculate buy side (with flashloan)
-        let amount_in_with_fee = mid * 0.997;
+        let amount_in_with_fee = mid * fee_multiplier(buy_fee_bps);
         let amount_out_buy =
             (amount_in_with_fee * reserve_out_buy) / (reserve_in_buy + amount_in_with_fee);
 
         // Calculate sell side
-        let amount_in_sell = amount_out_buy * 0.997;
+        let amount_in_sell = amount_out_buy * fee_multiplier(sell_fee_bps);
         let amount_out_sell =
             (amount_in_sell * reserve_out_sell) / (reserve_in_sell + amount_in_sell);
 
@@ -203,7 +1968,7 @@ pub fn calculate_flashloan_amount(
             high = mid;
         }
 
-        if (high - low) < 0.01 {
+        if approx_eq(low, high, 0.0, 0.01) {
             break;
         }
     }
@@ -215,23 +1980,161 @@ pub fn calculate_flashloan_amount(
     }
 }
 
-/// Calculate market impact (price slippage) caused by a flashloan-sized trade
-/// Returns the percentage price impact on the pool
-pub fn calculate_market_impact(reserve_in: f64, reserve_out: f64, flashloan_amount: f64) -> f64 {
-    if reserve_in <= 0.0 || reserve_out <= 0.0 || flashloan_amount <= 0.0 {
-        return 0.0;
-    }
+/// `calculate_flashloan_amount` alongside its utilization of the buy pool's input reserve
+/// Returns (flashloan_amount, utilization_pct)
+pub fn calculate_flashloan_amount_with_utilization(
+    reserve_in_buy: f64,
+    reserve_out_buy: f64,
+    reserve_in_sell: f64,
+    reserve_out_sell: f64,
+    flashloan_fee: f64,
+    gas_cost: f64,
+) -> (f64, f64) {
+    let amount = calculate_flashloan_amount(
+        reserve_in_buy,
+        reserve_out_buy,
+        reserve_in_sell,
+        reserve_out_sell,
+        flashloan_fee,
+        gas_cost,
+    );
+    (amount, utilization_pct(amount, reserve_in_buy))
+}
 
-    // Price before trade
-    let price_before = reserve_out / reserve_in;
+/// Reserves after a swap, for chaining simulations or predicting the next block's state
+/// The LP fee stays in the pool, so the input reserve grows by the full `amount_in`
+/// Returns (new_reserve_in, new_reserve_out, amount_out); reserves are unchanged for no input
+pub fn apply_swap(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    fee_bps: u32,
+) -> (f64, f64, f64) {
+    let amount_out = calculate_amount_out_with_fee(reserve_in, reserve_out, amount_in, fee_bps);
+    if amount_out <= 0.0 {
+        return (reserve_in, reserve_out, 0.0);
+    }
+
+    (reserve_in + amount_in, reserve_out - amount_out, amount_out)
+}
+
+/// One swap of a bundle, against a pool of `simulate_bundle`'s pool states
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BundleTx {
+    pub pool_index: usize,
+    /// true pays token0 (the pool's first reserve) for token1, false the reverse
+    pub zero_for_one: bool,
+    /// Input amount; None spends the previous transaction's whole output
+    pub amount_in: Option<f64>,
+    /// The transaction reverts if it would receive less than this
+    pub min_amount_out: f64,
+    pub fee_bps: u32,
+    pub gas_cost: f64,
+}
+
+/// What one landed bundle transaction did
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BundleTxEffect {
+    pub amount_in: f64,
+    pub amount_out: f64,
+    /// (reserve0, reserve1) of the pool it traded against, after the swap
+    pub reserves_after: (f64, f64),
+}
+
+/// Outcome of `simulate_bundle`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BundleResult {
+    /// Last output - first input - gas, in the bundle's start token; 0 if it reverted
+    pub net_profit: f64,
+    /// Effects of the transactions that ran, up to (not including) any revert
+    pub per_tx_effects: Vec<BundleTxEffect>,
+    /// First transaction that reverted, which reverts the whole bundle
+    pub reverted_index: Option<usize>,
+    /// (reserve0, reserve1) per pool after the bundle, or the initial states if it reverted
+    pub final_pool_states: Vec<(f64, f64)>,
+}
+
+/// Simulate an atomic bundle of swaps through `apply_swap`, each seeing the reserves the last
+/// left; like a Flashbots bundle, one revert (unknown pool, bad input, output below
+/// min_amount_out) drops the whole bundle
+pub fn simulate_bundle(txs: &[BundleTx], initial_pool_states: &[(f64, f64)]) -> BundleResult {
+    let mut pool_states = initial_pool_states.to_vec();
+    let mut per_tx_effects = Vec::with_capacity(txs.len());
+    let mut previous_output = None;
+
+    for (index, tx) in txs.iter().enumerate() {
+        let amount_in = tx.amount_in.or(previous_output).unwrap_or(0.0);
+        let reverted = |per_tx_effects| BundleResult {
+            net_profit: 0.0,
+            per_tx_effects,
+            reverted_index: Some(index),
+            final_pool_states: initial_pool_states.to_vec(),
+        };
+        let Some((reserve0, reserve1)) = pool_states.get(tx.pool_index).copied() else {
+            return reverted(per_tx_effects);
+        };
+        if amount_in <= 0.0 {
+            return reverted(per_tx_effects);
+        }
+
+        let (amount_out, reserves_after) = if tx.zero_for_one {
+            let (new0, new1, amount_out) = apply_swap(reserve0, reserve1, amount_in, tx.fee_bps);
+            (amount_out, (new0, new1))
+        } else {
+            let (new1, new0, amount_out) = apply_swap(reserve1, reserve0, amount_in, tx.fee_bps);
+            (amount_out, (new0, new1))
+        };
+        if amount_out <= 0.0 || amount_out < tx.min_amount_out {
+            return reverted(per_tx_effects);
+        }
+
+        pool_states[tx.pool_index] = reserves_after;
+        previous_output = Some(amount_out);
+        per_tx_effects.push(BundleTxEffect {
+            amount_in,
+            amount_out,
+            reserves_after,
+        });
+    }
+
+    let net_profit = match (per_tx_effects.first(), per_tx_effects.last()) {
+        (Some(first), Some(last)) => {
+            last.amount_out - first.amount_in - txs.iter().map(|tx| tx.gas_cost).sum::<f64>()
+        }
+        _ => 0.0,
+    };
+
+    BundleResult {
+        net_profit,
+        per_tx_effects,
+        reverted_index: None,
+        final_pool_states: pool_states,
+    }
+}
+
+/// Calculate market impact (price slippage) caused by a flashloan-sized trade
+/// Returns the percentage price impact on the pool
+pub fn calculate_market_impact(reserve_in: f64, reserve_out: f64, flashloan_amount: f64) -> f64 {
+    calculate_market_impact_with_fee(reserve_in, reserve_out, flashloan_amount, DEFAULT_FEE_BPS)
+}
+
+/// `calculate_market_impact` for a pool charging fee_bps instead of the 0.3% default
+pub fn calculate_market_impact_with_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    flashloan_amount: f64,
+    fee_bps: u32,
+) -> f64 {
+    if reserve_in <= 0.0 || reserve_out <= 0.0 || flashloan_amount <= 0.0 {
+        return 0.0;
+    }
 
-    // Calculate output amount
-    let amount_in_with_fee = flashloan_amount * 0.997;
-    let amount_out = (amount_in_with_fee * reserve_out) / (reserve_in + amount_in_with_fee);
+    // Price before trade
+    let price_before = reserve_out / reserve_in;
 
     // Price after trade (new reserves)
-    let new_reserve_in = reserve_in + flashloan_amount;
-    let new_reserve_out = reserve_out - amount_out;
+    let (new_reserve_in, new_reserve_out, _) =
+        apply_swap(reserve_in, reserve_out, flashloan_amount, fee_bps);
     let price_after = new_reserve_out / new_reserve_in;
 
     // Market impact as percentage
@@ -239,11 +2142,55 @@ pub fn calculate_market_impact(reserve_in: f64, reserve_out: f64, flashloan_amou
     impact.abs()
 }
 
+/// Whether the two legs of an arb trade the same token pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegTokens {
+    /// Each leg trades its own pair, so each moves a separate market
+    Independent,
+    /// Both pools quote the same pair (e.g. WETH/USDC on two venues)
+    Shared,
+}
+
+/// Combined market impact (percent) of a two-leg arb's buy and sell; independent impacts add
+/// Formula (Shared): |L_buy·impact_buy - L_sell·impact_sell| / (L_buy + L_sell)
+pub fn combined_leg_impact(
+    (buy_reserve_in, buy_reserve_out): (f64, f64),
+    (sell_reserve_in, sell_reserve_out): (f64, f64),
+    amount_in: f64,
+    legs: LegTokens,
+) -> f64 {
+    let buy_impact = calculate_market_impact(buy_reserve_in, buy_reserve_out, amount_in);
+    let amount_bought = calculate_amount_out(buy_reserve_in, buy_reserve_out, amount_in);
+    let sell_impact = calculate_market_impact(sell_reserve_in, sell_reserve_out, amount_bought);
+
+    match legs {
+        LegTokens::Independent => buy_impact + sell_impact,
+        LegTokens::Shared => {
+            let buy_liquidity = v2_virtual_liquidity(buy_reserve_in, buy_reserve_out);
+            let sell_liquidity = v2_virtual_liquidity(sell_reserve_in, sell_reserve_out);
+            let total_liquidity = buy_liquidity + sell_liquidity;
+            if total_liquidity <= 0.0 {
+                return 0.0;
+            }
+            (buy_liquidity * buy_impact - sell_liquidity * sell_impact).abs() / total_liquidity
+        }
+    }
+}
+
 /// Calculate total slippage for a multi-hop flashloan arbitrage path
 /// Returns combined slippage across all hops in the path
 pub fn calculate_multihop_slippage(
     reserves: &[(f64, f64)], // Array of (reserve_in, reserve_out) pairs
     flashloan_amount: f64,
+) -> f64 {
+    calculate_multihop_slippage_with_fee(reserves, flashloan_amount, DEFAULT_FEE_BPS)
+}
+
+/// Calculate total slippage for a multi-hop path whose pools all charge fee_bps
+pub fn calculate_multihop_slippage_with_fee(
+    reserves: &[(f64, f64)],
+    flashloan_amount: f64,
+    fee_bps: u32,
 ) -> f64 {
     if reserves.is_empty() || flashloan_amount <= 0.0 {
         return 0.0;
@@ -258,32 +2205,115 @@ pub fn calculate_multihop_slippage(
         }
 
         // Calculate slippage for this hop
-        let hop_slippage = compute_uniswap_v2_slippage(*reserve_in, *reserve_out, current_amount);
+        let hop_slippage = compute_uniswap_v2_slippage_with_fee(
+            *reserve_in,
+            *reserve_out,
+            current_amount,
+            fee_bps,
+        );
         total_slippage += hop_slippage;
 
         // Calculate output for next hop
-        let amount_in_with_fee = current_amount * 0.997;
-        current_amount = (amount_in_with_fee * reserve_out) / (reserve_in + amount_in_with_fee);
+        current_amount = apply_swap(*reserve_in, *reserve_out, current_amount, fee_bps).2;
     }
 
     total_slippage
 }
 
+/// Check per-hop (token_in, token_out) annotations for a path of hop_count reserve pairs:
+/// one pair per hop, and each hop's token_in equal to the previous hop's token_out
+/// (case-insensitively, as `Pool::new` compares addresses). Run it before the multi-hop
+/// functions, which trust that every (reserve_in, reserve_out) follows the direction of travel
+pub fn validate_path_tokens(
+    hop_tokens: &[(String, String)],
+    hop_count: usize,
+) -> Result<(), MathError> {
+    if hop_tokens.len() != hop_count {
+        return Err(MathError::LengthMismatch {
+            name: "hop token pairs",
+            expected: hop_count,
+            actual: hop_tokens.len(),
+        });
+    }
+
+    for (idx, pair) in hop_tokens.windows(2).enumerate() {
+        let (produced, taken) = (&pair[0].1, &pair[1].0);
+        if !produced.eq_ignore_ascii_case(taken) {
+            return Err(MathError::BrokenPath {
+                hop: idx + 1,
+                expected: produced.clone(),
+                actual: taken.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// `validate_path_tokens` for every path given to `simulate_parallel_flashloan_paths`,
+/// with one set of annotations per path
+pub fn validate_paths_tokens(
+    path_tokens: &[Vec<(String, String)>],
+    paths: &[Vec<(f64, f64)>],
+) -> Result<(), MathError> {
+    if path_tokens.len() != paths.len() {
+        return Err(MathError::LengthMismatch {
+            name: "path token annotations",
+            expected: paths.len(),
+            actual: path_tokens.len(),
+        });
+    }
+
+    path_tokens
+        .iter()
+        .zip(paths)
+        .try_for_each(|(hop_tokens, path)| validate_path_tokens(hop_tokens, path.len()))
+}
+
 /// Simulate flashloan arbitrage execution across multiple paths simultaneously
 /// Returns array of (profit, slippage, path_index) for each path
+/// flashloan_amounts and gas_costs need one entry per path, else LengthMismatch is returned
 pub fn simulate_parallel_flashloan_paths(
     paths: &[Vec<(f64, f64)>], // Array of paths, each path is array of reserve pairs
     flashloan_amounts: &[f64],
     flashloan_fee: f64,
     gas_costs: &[f64],
-) -> Vec<(f64, f64, usize)> {
+) -> Result<Vec<(f64, f64, usize)>, MathError> {
+    simulate_parallel_flashloan_paths_with_fee(
+        paths,
+        flashloan_amounts,
+        flashloan_fee,
+        gas_costs,
+        DEFAULT_FEE_BPS,
+    )
+}
+
+/// `simulate_parallel_flashloan_paths` with every hop charging fee_bps instead of the 0.3%
+/// default
+pub fn simulate_parallel_flashloan_paths_with_fee(
+    paths: &[Vec<(f64, f64)>],
+    flashloan_amounts: &[f64],
+    flashloan_fee: f64,
+    gas_costs: &[f64],
+    fee_bps: u32,
+) -> Result<Vec<(f64, f64, usize)>, MathError> {
+    if flashloan_amounts.len() != paths.len() {
+        return Err(MathError::LengthMismatch {
+            name: "flashloan amounts",
+            expected: paths.len(),
+            actual: flashloan_amounts.len(),
+        });
+    }
+    if gas_costs.len() != paths.len() {
+        return Err(MathError::LengthMismatch {
+            name: "gas costs",
+            expected: paths.len(),
+            actual: gas_costs.len(),
+        });
+    }
+
     let mut results = Vec::new();
 
     for (idx, path) in paths.iter().enumerate() {
-        if idx >= flashloan_amounts.len() || idx >= gas_costs.len() {
-            break;
-        }
-
         let flashloan_amount = flashloan_amounts[idx];
         let gas_cost = gas_costs[idx];
 
@@ -295,8 +2325,7 @@ pub fn simulate_parallel_flashloan_paths(
         // Calculate output through the path
         let mut current_amount = flashloan_amount;
         for (reserve_in, reserve_out) in path {
-            let amount_in_with_fee = current_amount * 0.997;
-            current_amount = (amount_in_with_fee * reserve_out) / (reserve_in + amount_in_with_fee);
+            current_amount = apply_swap(*reserve_in, *reserve_out, current_amount, fee_bps).2;
         }
 
         // Calculate profit after flashloan repayment
@@ -304,15 +2333,380 @@ pub fn simulate_parallel_flashloan_paths(
         let profit = current_amount - flashloan_repayment - gas_cost;
 
         // Calculate total slippage
-        let slippage = calculate_multihop_slippage(path, flashloan_amount);
+        let slippage = calculate_multihop_slippage_with_fee(path, flashloan_amount, fee_bps);
 
         results.push((profit, slippage, idx));
     }
 
-    results
+    Ok(results)
+}
+
+/// One hop of a mixed-protocol path, oriented in the direction of travel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HopSpec {
+    /// Constant-product pool
+    V2 {
+        reserve_in: f64,
+        reserve_out: f64,
+        fee_bps: u32,
+    },
+    /// Concentrated-liquidity pool trading within its active range
+    V3 {
+        liquidity: f64,
+        sqrt_price: f64,
+        zero_for_one: bool,
+        fee_bps: u32,
+    },
+}
+
+impl HopSpec {
+    /// Reserves the hop behaves like, as (reserve_in, reserve_out)
+    /// Within a V3 range the pool is a constant-product curve with virtual reserves
+    /// token0 = L / sqrt_price and token1 = L × sqrt_price
+    pub fn virtual_reserves(&self) -> (f64, f64) {
+        match *self {
+            HopSpec::V2 {
+                reserve_in,
+                reserve_out,
+                ..
+            } => (reserve_in, reserve_out),
+            HopSpec::V3 {
+                liquidity,
+                sqrt_price,
+                zero_for_one,
+                ..
+            } => {
+                if sqrt_price <= 0.0 {
+                    return (0.0, 0.0);
+                }
+                let reserve0 = liquidity / sqrt_price;
+                let reserve1 = liquidity * sqrt_price;
+                if zero_for_one {
+                    (reserve0, reserve1)
+                } else {
+                    (reserve1, reserve0)
+                }
+            }
+        }
+    }
+
+    pub fn fee_bps(&self) -> u32 {
+        match *self {
+            HopSpec::V2 { fee_bps, .. } | HopSpec::V3 { fee_bps, .. } => fee_bps,
+        }
+    }
+
+    /// Same pool with a different swap fee
+    pub fn with_fee_bps(&self, fee_bps: u32) -> HopSpec {
+        match *self {
+            HopSpec::V2 {
+                reserve_in,
+                reserve_out,
+                ..
+            } => HopSpec::V2 {
+                reserve_in,
+                reserve_out,
+                fee_bps,
+            },
+            HopSpec::V3 {
+                liquidity,
+                sqrt_price,
+                zero_for_one,
+                ..
+            } => HopSpec::V3 {
+                liquidity,
+                sqrt_price,
+                zero_for_one,
+                fee_bps,
+            },
+        }
+    }
+
+    /// Output of the hop for a given input
+    pub fn amount_out(&self, amount_in: f64) -> f64 {
+        let (reserve_in, reserve_out) = self.virtual_reserves();
+        if reserve_in <= 0.0 || reserve_out <= 0.0 {
+            return 0.0;
+        }
+        calculate_amount_out_with_fee(reserve_in, reserve_out, amount_in, self.fee_bps())
+    }
+}
+
+/// Outcome of simulating a mixed-protocol path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MixedPathResult {
+    /// Final output, or 0 when a hop is infeasible
+    pub amount_out: f64,
+    /// First hop whose incoming amount exceeds `max_reserve_fraction` of its input reserve
+    pub infeasible_hop: Option<usize>,
+}
+
+/// Simulate a path of V2 and V3 hops, checking each hop has the liquidity to absorb its input
+/// A hop is infeasible when the amount arriving exceeds `max_reserve_fraction` of its
+/// (virtual) input reserve, which catches deep paths that look profitable but cannot execute
+pub fn simulate_mixed_path(
+    hops: &[HopSpec],
+    amount_in: f64,
+    max_reserve_fraction: f64,
+) -> MixedPathResult {
+    let mut current_amount = amount_in;
+
+    for (idx, hop) in hops.iter().enumerate() {
+        let (reserve_in, reserve_out) = hop.virtual_reserves();
+        if reserve_in <= 0.0
+            || reserve_out <= 0.0
+            || current_amount > reserve_in * max_reserve_fraction
+        {
+            return MixedPathResult {
+                amount_out: 0.0,
+                infeasible_hop: Some(idx),
+            };
+        }
+
+        current_amount = hop.amount_out(current_amount);
+    }
+
+    MixedPathResult {
+        amount_out: current_amount,
+        infeasible_hop: None,
+    }
+}
+
+/// Every cost component of a cyclic arbitrage, all in the borrowed token
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProfitBreakdown {
+    pub amount_out: f64,
+    /// Output lost to LP swap fees across all legs (fee-free output minus actual output)
+    pub lp_fee_cost: f64,
+    pub flashloan_fee: f64,
+    pub gas_cost: f64,
+    pub mev_tip: f64,
+    pub bridge_cost: f64,
+    /// amount_out - amount_in - flashloan_fee - gas_cost - mev_tip - bridge_cost
+    pub net_profit: f64,
+}
+
+/// Authoritative net profit for a path, subtracting every cost component explicitly
+/// `legs` must form a cycle back to the borrowed token; LP fees are already inside the
+/// leg outputs and are reported separately in `lp_fee_cost` rather than subtracted twice
+pub fn net_profit_full(
+    legs: &[HopSpec],
+    amount_in: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+    mev_tip: f64,
+    bridge_cost: f64,
+) -> ProfitBreakdown {
+    let amount_out = legs
+        .iter()
+        .fold(amount_in, |amount, leg| leg.amount_out(amount));
+    let fee_free_out = legs.iter().fold(amount_in, |amount, leg| {
+        leg.with_fee_bps(0).amount_out(amount)
+    });
+    let flashloan_fee = amount_in * flashloan_fee_pct;
+
+    ProfitBreakdown {
+        amount_out,
+        lp_fee_cost: fee_free_out - amount_out,
+        flashloan_fee,
+        gas_cost,
+        mev_tip,
+        bridge_cost,
+        net_profit: amount_out - amount_in - flashloan_fee - gas_cost - mev_tip - bridge_cost,
+    }
+}
+
+/// Profit-maximizing input for a whole multi-hop path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathSizing {
+    pub amount_in: f64,
+    /// Net of flashloan fee and gas; 0 when no size is profitable
+    pub expected_profit: f64,
+    /// False when the search could not isolate a peak and `amount_in` is only the best size
+    /// it sampled; treat the result as provisional
+    pub converged: bool,
+}
+
+/// Iteration cap for `golden_section_max`; a well-behaved bracket converges in about 60
+const GOLDEN_SECTION_MAX_ITERATIONS: usize = 200;
+
+/// Maximize a unimodal `objective` over [low, high] by golden-section search
+/// Returns (argmax, max, converged); unconverged, the argmax is the smallest best sampled point
+fn golden_section_max(objective: impl Fn(f64) -> f64, low: f64, high: f64) -> (f64, f64, bool) {
+    let inv_phi = (5.0_f64.sqrt() - 1.0) / 2.0;
+    let (mut low, mut high) = (low, high);
+    let mut left = high - inv_phi * (high - low);
+    let mut right = low + inv_phi * (high - low);
+    let mut left_value = objective(left);
+    let mut right_value = objective(right);
+
+    let mut best = if right_value > left_value {
+        (right, right_value)
+    } else {
+        (left, left_value)
+    };
+    let mut lowest_value = left_value.min(right_value);
+    let mut record = |x: f64, value: f64| {
+        if value > best.1 || (value == best.1 && x < best.0) {
+            best = (x, value);
+        }
+        lowest_value = lowest_value.min(value);
+    };
+
+    let mut bracketed = false;
+    for _ in 0..GOLDEN_SECTION_MAX_ITERATIONS {
+        if approx_eq(low, high, 1e-12, 0.0) {
+            bracketed = true;
+            break;
+        }
+        if left_value < right_value {
+            low = left;
+            left = right;
+            left_value = right_value;
+            right = low + inv_phi * (high - low);
+            right_value = objective(right);
+            record(right, right_value);
+        } else {
+            high = right;
+            right = left;
+            right_value = left_value;
+            left = high - inv_phi * (high - low);
+            left_value = objective(left);
+            record(left, left_value);
+        }
+    }
+
+    let flat = approx_eq(best.1, lowest_value, 1e-12, 0.0);
+    if bracketed && !flat {
+        let x = (low + high) / 2.0;
+        (x, objective(x), true)
+    } else {
+        (best.0, best.1, false)
+    }
+}
+
+/// Find the profit-maximizing flashloan size for a cyclic multi-hop path by golden-section search,
+/// capped at 30% of the first hop's input reserve; `converged` is false when it cannot resolve
+pub fn optimize_path_size(path: &[HopSpec], flashloan_fee: f64, gas_cost: f64) -> PathSizing {
+    const NOT_PROFITABLE: PathSizing = PathSizing {
+        amount_in: 0.0,
+        expected_profit: 0.0,
+        converged: true,
+    };
+
+    let first_reserve_in = match path.first() {
+        Some(hop) => hop.virtual_reserves().0,
+        None => return NOT_PROFITABLE,
+    };
+    if first_reserve_in <= 0.0 {
+        return NOT_PROFITABLE;
+    }
+
+    let gross_profit = |amount: f64| {
+        path.iter()
+            .fold(amount, |current, hop| hop.amount_out(current))
+            - amount * (1.0 + flashloan_fee)
+    };
+
+    let (amount_in, gross, converged) =
+        golden_section_max(gross_profit, 0.0, first_reserve_in * 0.3);
+    let expected_profit = gross - gas_cost;
+    if expected_profit > 0.0 {
+        PathSizing {
+            amount_in,
+            expected_profit,
+            converged,
+        }
+    } else {
+        PathSizing {
+            converged,
+            ..NOT_PROFITABLE
+        }
+    }
+}
+
+/// Split an order across heterogeneous V2/V3 pools to maximize total output
+/// Each pool's marginal output rate is γ·R_in·R_out / (R_in + γ·x)² on its (virtual) reserves;
+/// allocations are chosen so every pool used ends at the same marginal rate, found by bisection
+/// Returns the per-pool input allocation in pool order, summing to `amount_in`
+pub fn optimal_split(pools: &[HopSpec], amount_in: f64) -> Vec<f64> {
+    let mut allocations = vec![0.0; pools.len()];
+    if pools.is_empty() || amount_in <= 0.0 {
+        return allocations;
+    }
+
+    // (reserve_in, reserve_out, fee multiplier) for each usable pool
+    let curves: Vec<Option<(f64, f64, f64)>> = pools
+        .iter()
+        .map(|pool| {
+            let (reserve_in, reserve_out) = pool.virtual_reserves();
+            let gamma = fee_multiplier(pool.fee_bps());
+            if reserve_in > 0.0 && reserve_out > 0.0 && gamma > 0.0 {
+                Some((reserve_in, reserve_out, gamma))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    // Input a pool absorbs before its marginal rate drops to `rate`
+    let allocation_at = |curve: &Option<(f64, f64, f64)>, rate: f64| match curve {
+        Some((reserve_in, reserve_out, gamma)) => {
+            ((gamma * reserve_in * reserve_out / rate).sqrt() - reserve_in).max(0.0) / gamma
+        }
+        None => 0.0,
+    };
+
+    let mut high = curves
+        .iter()
+        .flatten()
+        .map(|(reserve_in, reserve_out, gamma)| gamma * reserve_out / reserve_in)
+        .fold(0.0, f64::max);
+    if high <= 0.0 {
+        return allocations;
+    }
+    let mut low = high * 1e-18;
+
+    // Bisect on the common marginal rate (geometrically, it spans many orders of magnitude)
+    for _ in 0..200 {
+        let mid = (low * high).sqrt();
+        let total: f64 = curves.iter().map(|curve| allocation_at(curve, mid)).sum();
+        if total > amount_in {
+            low = mid;
+        } else {
+            high = mid;
+        }
+        if approx_eq(low, high, 1e-15, 0.0) {
+            break;
+        }
+    }
+
+    for (allocation, curve) in allocations.iter_mut().zip(curves.iter()) {
+        *allocation = allocation_at(curve, high);
+    }
+
+    // Remove residual bisection error so the split sums exactly to the order size
+    let total: f64 = allocations.iter().sum();
+    if total > 0.0 {
+        for allocation in allocations.iter_mut() {
+            *allocation *= amount_in / total;
+        }
+    }
+
+    allocations
 }
 
-/// Calculate optimal flashloan amount for Uniswap V3 concentrated liquidity
+/// Uniswap V3 pool as a flashloan leg sees it: current price, initialized ranges, fee tier
+#[derive(Debug, Clone, PartialEq)]
+pub struct V3Pool {
+    pub sqrt_price: f64,
+    pub ranges: Vec<TickRange>,
+    pub fee_bps: u32,
+}
+
+/// Optimal flashloan amount for a two-pool Uniswap V3 arb with a single full-range position
+/// of `liquidity` in each pool at the 0.3% tier; see `calculate_flashloan_amount_v3_ticks`
+/// for pools whose liquidity changes across ticks
 pub fn calculate_flashloan_amount_v3(
     liquidity: f64,
     sqrt_price_buy: f64,
@@ -320,46 +2714,96 @@ pub fn calculate_flashloan_amount_v3(
     flashloan_fee: f64,
     gas_cost: f64,
 ) -> f64 {
-    if liquidity <= 0.0 || sqrt_price_buy <= 0.0 || sqrt_price_sell <= 0.0 {
+    if liquidity <= 0.0 {
         return 0.0;
     }
 
-    // Binary search for optimal amount
-    let max_amount = liquidity * 0.3;
-    let mut low = 0.0;
-    let mut high = max_amount;
-    let mut best_amount = 0.0;
-    let mut best_profit = 0.0;
-
-    for _ in 0..100 {
-        let mid = (low + high) / 2.0;
-
-        // Simplified V3 calculation
-        let amount_out_buy = (mid * sqrt_price_buy * liquidity) / (liquidity + mid);
-        let amount_out_sell =
-            (amount_out_buy * sqrt_price_sell * liquidity) / (liquidity + amount_out_buy);
+    let full_range = |sqrt_price: f64| V3Pool {
+        sqrt_price,
+        ranges: vec![TickRange {
+            tick_lower: MIN_TICK,
+            tick_upper: MAX_TICK,
+            liquidity,
+        }],
+        fee_bps: DEFAULT_FEE_BPS,
+    };
+    calculate_flashloan_amount_v3_ticks(
+        &full_range(sqrt_price_buy),
+        &full_range(sqrt_price_sell),
+        flashloan_fee,
+        gas_cost,
+    )
+}
 
-        let flashloan_repayment = mid * (1.0 + flashloan_fee);
-        let profit = amount_out_sell - flashloan_repayment - gas_cost;
+/// Bisection cap for `calculate_flashloan_amount_v3_ticks`; the 1e-12 relative width it
+/// stops at takes about 40 halvings
+const V3_SIZE_ITERATIONS: usize = 200;
 
-        if profit > best_profit {
-            best_profit = profit;
-            best_amount = mid;
+/// Optimal token1 flashloan for a two-pool Uniswap V3 arb, walking both legs with
+/// `v3_swap_across_ticks`; profit is concave in the loan, so the size is a binary search on the
+/// sign of the marginal profit. Returns 0 when the best size does not cover gas
+pub fn calculate_flashloan_amount_v3_ticks(
+    buy: &V3Pool,
+    sell: &V3Pool,
+    flashloan_fee: f64,
+    gas_cost: f64,
+) -> f64 {
+    let legs = |amount: f64| {
+        let bought = v3_swap_across_ticks(buy.sqrt_price, &buy.ranges, amount, buy.fee_bps, false);
+        let sold = v3_swap_across_ticks(
+            sell.sqrt_price,
+            &sell.ranges,
+            bought.amount_out,
+            sell.fee_bps,
+            true,
+        );
+        (bought, sold)
+    };
+    let marginal_profit = |amount: f64| {
+        let (bought, sold) = legs(amount);
+        if bought.amount_in_used < amount {
+            // The buy pool is drained: more loan comes straight back, less its fee
+            return -flashloan_fee;
         }
+        if sold.amount_in_used < bought.amount_out {
+            // The sell pool is drained: more token0 cannot be sold at all
+            return -(1.0 + flashloan_fee);
+        }
+        let buy_rate = fee_multiplier(buy.fee_bps) / bought.sqrt_price_after.powi(2);
+        let sell_rate = fee_multiplier(sell.fee_bps) * sold.sqrt_price_after.powi(2);
+        buy_rate * sell_rate - (1.0 + flashloan_fee)
+    };
 
-        if profit > 0.0 {
+    let capacity = v3_swap_across_ticks(
+        buy.sqrt_price,
+        &buy.ranges,
+        f64::INFINITY,
+        buy.fee_bps,
+        false,
+    )
+    .amount_in_used;
+    if !(capacity > 0.0 && capacity.is_finite()) || marginal_profit(0.0) <= 0.0 {
+        return 0.0;
+    }
+
+    let (mut low, mut high) = (0.0, capacity);
+    for _ in 0..V3_SIZE_ITERATIONS {
+        let mid = (low + high) / 2.0;
+        if marginal_profit(mid) > 0.0 {
             low = mid;
         } else {
             high = mid;
         }
-
-        if (high - low) < 0.01 {
+        if approx_eq(low, high, 1e-12, 0.0) {
             break;
         }
     }
 
-    if best_profit > 0.0 {
-        best_amount
+    let (bought, sold) = legs(low);
+    let profit =
+        sold.amount_out + (low - bought.amount_in_used) - low * (1.0 + flashloan_fee) - gas_cost;
+    if profit > 0.0 {
+        low
     } else {
         0.0
     }
@@ -374,6 +2818,82 @@ pub fn calculate_pool_price(reserve_in: f64, reserve_out: f64) -> f64 {
     reserve_out / reserve_in
 }
 
+/// Reject a pool unless both reserves are positive and finite
+fn check_reserves(reserve_in: f64, reserve_out: f64) -> Result<(), MathError> {
+    for (name, value) in [("reserve_in", reserve_in), ("reserve_out", reserve_out)] {
+        if !(value > 0.0 && value.is_finite()) {
+            return Err(MathError::ZeroReserve { name, value });
+        }
+    }
+    Ok(())
+}
+
+/// Reject a trade amount unless it is non-negative and finite
+fn check_amount(name: &'static str, value: f64) -> Result<(), MathError> {
+    if !(value >= 0.0 && value.is_finite()) {
+        return Err(MathError::NegativeAmount { name, value });
+    }
+    Ok(())
+}
+
+/// `calculate_pool_price` that reports an unusable pool instead of pricing it at 0
+pub fn try_calculate_pool_price(reserve_in: f64, reserve_out: f64) -> Result<f64, MathError> {
+    check_reserves(reserve_in, reserve_out)?;
+    Ok(calculate_pool_price(reserve_in, reserve_out))
+}
+
+/// Spot prices for many pools in one call, each as `calculate_pool_price`
+/// Output is positional: a pool with a non-finite reserve (how malformed rows arrive from the
+/// napi layer) yields NaN at its own index instead of being dropped
+pub fn batch_pool_prices(reserves: &[(f64, f64)]) -> Vec<f64> {
+    reserves
+        .iter()
+        .map(|&(reserve_in, reserve_out)| {
+            if reserve_in.is_finite() && reserve_out.is_finite() {
+                calculate_pool_price(reserve_in, reserve_out)
+            } else {
+                f64::NAN
+            }
+        })
+        .collect()
+}
+
+/// Constant-product pool with the token on each side identified
+/// Construction rejects a pool whose two sides are the same token (compared case-insensitively,
+/// so checksummed and lowercase addresses match), catching mis-mapped reserves up front
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pool {
+    pub token_in: String,
+    pub token_out: String,
+    pub reserve_in: f64,
+    pub reserve_out: f64,
+}
+
+impl Pool {
+    pub fn new(
+        token_in: String,
+        token_out: String,
+        reserve_in: f64,
+        reserve_out: f64,
+    ) -> Result<Pool, MathError> {
+        if token_in.eq_ignore_ascii_case(&token_out) {
+            return Err(MathError::SameToken { token: token_in });
+        }
+
+        Ok(Pool {
+            token_in,
+            token_out,
+            reserve_in,
+            reserve_out,
+        })
+    }
+
+    /// Price of token_in in units of token_out, as `calculate_pool_price`
+    pub fn price(&self) -> f64 {
+        calculate_pool_price(self.reserve_in, self.reserve_out)
+    }
+}
+
 /// Step 2: Identify arbitrage opportunity by comparing prices across pools
 /// Returns (has_opportunity, price_difference_percentage, direction)
 /// direction: 0 = no opportunity, 1 = buy pool1/sell pool2, 2 = buy pool2/sell pool1
@@ -387,6 +2907,57 @@ pub fn identify_arbitrage_opportunity(
     let price1 = calculate_pool_price(pool1_reserve_in, pool1_reserve_out);
     let price2 = calculate_pool_price(pool2_reserve_in, pool2_reserve_out);
 
+    classify_price_gap(price1, price2, min_price_diff_pct)
+}
+
+/// Step 2 for stablecoin pairs, measured in basis points against the peg
+/// Formula: deviation = (reserve_out - peg·reserve_in) / (peg·reserve_in)
+/// Returns (has_opportunity, gap_bps, direction) with direction as `identify_arbitrage_opportunity`
+pub fn identify_stable_opportunity(
+    pool1_reserve_in: f64,
+    pool1_reserve_out: f64,
+    pool2_reserve_in: f64,
+    pool2_reserve_out: f64,
+    peg_price: f64,
+    min_diff_bps: f64,
+) -> (bool, f64, u8) {
+    if pool1_reserve_in <= 0.0
+        || pool1_reserve_out <= 0.0
+        || pool2_reserve_in <= 0.0
+        || pool2_reserve_out <= 0.0
+        || peg_price <= 0.0
+    {
+        return (false, 0.0, 0);
+    }
+
+    let deviation_bps = |reserve_in: f64, reserve_out: f64| {
+        let pegged_in = peg_price * reserve_in;
+        (reserve_out - pegged_in) / pegged_in * 10000.0
+    };
+    let deviation1 = deviation_bps(pool1_reserve_in, pool1_reserve_out);
+    let deviation2 = deviation_bps(pool2_reserve_in, pool2_reserve_out);
+    let gap_bps = (deviation2 - deviation1).abs();
+
+    if gap_bps < min_diff_bps || gap_bps == 0.0 {
+        return (false, gap_bps, 0);
+    }
+    let direction = if deviation1 < deviation2 { 1 } else { 2 };
+    (true, gap_bps, direction)
+}
+
+/// Step 2 for pools of any protocol, comparing each pool's own spot-price formula
+/// Both pools must quote the same pair in the same orientation; returns as
+/// `identify_arbitrage_opportunity`
+pub fn identify_cross_protocol_opportunity(
+    pool1: &PoolSpec,
+    pool2: &PoolSpec,
+    min_price_diff_pct: f64,
+) -> (bool, f64, u8) {
+    classify_price_gap(pool1.spot_price(), pool2.spot_price(), min_price_diff_pct)
+}
+
+/// Direction and size of the gap between two out-per-in spot prices
+fn classify_price_gap(price1: f64, price2: f64, min_price_diff_pct: f64) -> (bool, f64, u8) {
     if price1 <= 0.0 || price2 <= 0.0 {
         return (false, 0.0, 0);
     }
@@ -407,15 +2978,61 @@ pub fn identify_arbitrage_opportunity(
     (false, price_diff, 0)
 }
 
-/// Step 3: Calculate input amount needed for desired output
-/// Formula: amountIn = (ReserveIn × AmountOut × 1000) / ((ReserveOut - AmountOut) × 997) + 1
+/// Signed spot-price divergence of pool1 relative to pool2, with pools as (reserve_in, reserve_out)
+/// Formula: (price1 - price2) / price2; positive means pool1 quotes the higher price
+/// Unlike identify_arbitrage_opportunity this is not threshold-gated, so it suits monitoring
+pub fn signed_price_divergence(pool1_reserves: (f64, f64), pool2_reserves: (f64, f64)) -> f64 {
+    let price1 = calculate_pool_price(pool1_reserves.0, pool1_reserves.1);
+    let price2 = calculate_pool_price(pool2_reserves.0, pool2_reserves.1);
+
+    if price1 <= 0.0 || price2 <= 0.0 {
+        return 0.0;
+    }
+
+    (price1 - price2) / price2
+}
+
+/// Round-trip spread between two pools in bps after both swap fees, i.e. the marginal edge
+/// of selling into the richer pool and buying back from the cheaper one, before gas
+/// Formula: (γ1·γ2·max(p1, p2) / min(p1, p2) - 1) × 10000, with γ = (10000 - fee_bps) / 10000
+/// Negative when the fees are wider than the price gap; 0 for unusable pools
+pub fn net_spread_bps(
+    pool1_reserves: (f64, f64),
+    pool2_reserves: (f64, f64),
+    fee1_bps: u32,
+    fee2_bps: u32,
+) -> f64 {
+    let price1 = calculate_pool_price(pool1_reserves.0, pool1_reserves.1);
+    let price2 = calculate_pool_price(pool2_reserves.0, pool2_reserves.1);
+
+    if price1 <= 0.0 || price2 <= 0.0 {
+        return 0.0;
+    }
+
+    let gross_ratio = price1.max(price2) / price1.min(price2);
+    (fee_multiplier(fee1_bps) * fee_multiplier(fee2_bps) * gross_ratio - 1.0) * 10000.0
+}
+
+/// Step 3: Calculate input amount needed for desired output
+/// Formula: amountIn = (ReserveIn × AmountOut × 1000) / ((ReserveOut - AmountOut) × 997) + 1
 pub fn calculate_amount_in(reserve_in: f64, reserve_out: f64, amount_out: f64) -> f64 {
-    if reserve_out <= amount_out || amount_out <= 0.0 {
+    calculate_amount_in_with_fee(reserve_in, reserve_out, amount_out, DEFAULT_FEE_BPS)
+}
+
+/// Step 3: Calculate input amount needed for desired output with an explicit pool fee
+/// Formula: amountIn = (ReserveIn × AmountOut × 10000) / ((ReserveOut - AmountOut) × (10000 - fee_bps)) + 1
+pub fn calculate_amount_in_with_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_out: f64,
+    fee_bps: u32,
+) -> f64 {
+    if reserve_out <= amount_out || amount_out <= 0.0 || fee_bps >= 10000 {
         return 0.0;
     }
 
-    let numerator = reserve_in * amount_out * 1000.0;
-    let denominator = (reserve_out - amount_out) * 997.0;
+    let numerator = reserve_in * amount_out * 10000.0;
+    let denominator = (reserve_out - amount_out) * (10000 - fee_bps) as f64;
 
     if denominator <= 0.0 {
         return 0.0;
@@ -424,15 +3041,82 @@ pub fn calculate_amount_in(reserve_in: f64, reserve_out: f64, amount_out: f64) -
     (numerator / denominator) + 1.0
 }
 
+/// `calculate_amount_in` that reports bad input instead of returning 0
+pub fn try_calculate_amount_in(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_out: f64,
+) -> Result<f64, MathError> {
+    try_calculate_amount_in_with_fee(reserve_in, reserve_out, amount_out, DEFAULT_FEE_BPS)
+}
+
+/// `calculate_amount_in_with_fee` that reports bad input instead of returning 0
+/// An amount_out the pool cannot pay (at or above reserve_out) is InsufficientLiquidity
+pub fn try_calculate_amount_in_with_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_out: f64,
+    fee_bps: u32,
+) -> Result<f64, MathError> {
+    check_reserves(reserve_in, reserve_out)?;
+    check_amount("amount_out", amount_out)?;
+    if fee_bps >= 10000 {
+        return Err(MathError::InvalidParameter {
+            name: "fee_bps",
+            value: fee_bps as f64,
+        });
+    }
+    if amount_out >= reserve_out {
+        return Err(MathError::InsufficientLiquidity {
+            requested: amount_out,
+            available: reserve_out,
+        });
+    }
+    if amount_out == 0.0 {
+        return Ok(0.0);
+    }
+
+    Ok(calculate_amount_in_with_fee(
+        reserve_in,
+        reserve_out,
+        amount_out,
+        fee_bps,
+    ))
+}
+
+/// Size the sell leg so it yields exactly `required_output` (e.g. the flashloan repayment)
+/// Any surplus from the buy leg stays as profit in the other token
+/// Returns the sell-leg input needed, or 0 if the pool cannot produce that much
+pub fn size_sell_leg_for_repayment(
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    required_output: f64,
+    fee_bps: u32,
+) -> f64 {
+    calculate_amount_in_with_fee(sell_reserve_in, sell_reserve_out, required_output, fee_bps)
+}
+
 /// Step 3: Calculate output amount for given input
 /// Formula: amountOut = (ReserveOut × AmountIn × 997) / (ReserveIn × 1000 + AmountIn × 997)
 pub fn calculate_amount_out(reserve_in: f64, reserve_out: f64, amount_in: f64) -> f64 {
-    if amount_in <= 0.0 {
+    calculate_amount_out_with_fee(reserve_in, reserve_out, amount_in, DEFAULT_FEE_BPS)
+}
+
+/// Step 3: Calculate output amount for given input with an explicit pool fee
+/// Formula: amountOut = (ReserveOut × AmountIn × (10000 - fee_bps)) / (ReserveIn × 10000 + AmountIn × (10000 - fee_bps))
+pub fn calculate_amount_out_with_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    fee_bps: u32,
+) -> f64 {
+    if amount_in <= 0.0 || fee_bps > 10000 {
         return 0.0;
     }
 
-    let numerator = reserve_out * amount_in * 997.0;
-    let denominator = reserve_in * 1000.0 + amount_in * 997.0;
+    let fee_factor = (10000 - fee_bps) as f64;
+    let numerator = reserve_out * amount_in * fee_factor;
+    let denominator = reserve_in * 10000.0 + amount_in * fee_factor;
 
     if denominator <= 0.0 {
         return 0.0;
@@ -441,6 +3125,177 @@ pub fn calculate_amount_out(reserve_in: f64, reserve_out: f64, amount_in: f64) -
     numerator / denominator
 }
 
+/// `calculate_amount_out` that reports bad input instead of returning 0
+pub fn try_calculate_amount_out(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+) -> Result<f64, MathError> {
+    try_calculate_amount_out_with_fee(reserve_in, reserve_out, amount_in, DEFAULT_FEE_BPS)
+}
+
+/// `calculate_amount_out_with_fee` that reports bad input instead of returning 0
+/// A zero amount_in is valid and gets 0 out
+pub fn try_calculate_amount_out_with_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    fee_bps: u32,
+) -> Result<f64, MathError> {
+    check_reserves(reserve_in, reserve_out)?;
+    check_amount("amount_in", amount_in)?;
+    if fee_bps > 10000 {
+        return Err(MathError::InvalidParameter {
+            name: "fee_bps",
+            value: fee_bps as f64,
+        });
+    }
+
+    Ok(calculate_amount_out_with_fee(
+        reserve_in,
+        reserve_out,
+        amount_in,
+        fee_bps,
+    ))
+}
+
+/// Infer a constant-product pool's swap fee from one observed swap against known reserves
+/// Inverts the output formula: γ = amount_out × reserve_in / (amount_in × (reserve_out - amount_out)),
+/// fee_bps = round((1 - γ) × 10000), clamped to 0..=10000. Rounding to the nearest bps absorbs
+/// the router's floor division. Observations no fee can explain fall back to DEFAULT_FEE_BPS
+pub fn infer_fee_bps(reserve_in: f64, reserve_out: f64, amount_in: f64, amount_out: f64) -> u32 {
+    if reserve_in <= 0.0 || amount_in <= 0.0 || amount_out <= 0.0 || amount_out >= reserve_out {
+        return DEFAULT_FEE_BPS;
+    }
+
+    let gamma = amount_out * reserve_in / (amount_in * (reserve_out - amount_out));
+    if !gamma.is_finite() || gamma > 1.0 + 1e-6 {
+        return DEFAULT_FEE_BPS;
+    }
+
+    ((1.0 - gamma) * 10000.0).round().clamp(0.0, 10000.0) as u32
+}
+
+/// Calculate output for forks that split the swap fee between LPs and the protocol
+/// The protocol cut is removed from the input before the swap, then the LP fee applies
+/// to what remains, so the output is lower than charging the LP fee alone
+pub fn calculate_amount_out_with_protocol_fee(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    fee_bps: u32,
+    protocol_fee_bps: u32,
+) -> f64 {
+    if protocol_fee_bps > 10000 {
+        return 0.0;
+    }
+
+    let amount_after_protocol = amount_in * fee_multiplier(protocol_fee_bps);
+    calculate_amount_out_with_fee(reserve_in, reserve_out, amount_after_protocol, fee_bps)
+}
+
+/// Full 256-bit product of two u128 values, returned as (high, low) halves
+fn widening_mul(a: u128, b: u128) -> (u128, u128) {
+    const MASK: u128 = u64::MAX as u128;
+    let (a_hi, a_lo) = (a >> 64, a & MASK);
+    let (b_hi, b_lo) = (b >> 64, b & MASK);
+
+    let ll = a_lo * b_lo;
+    let lh = a_lo * b_hi;
+    let hl = a_hi * b_lo;
+    let hh = a_hi * b_hi;
+
+    let mid = (ll >> 64) + (lh & MASK) + (hl & MASK);
+    let lo = (ll & MASK) | (mid << 64);
+    let hi = hh + (lh >> 64) + (hl >> 64) + (mid >> 64);
+    (hi, lo)
+}
+
+/// floor(a × b / denominator) with a 256-bit intermediate, as Solidity's uint256 math does
+/// Returns None when the denominator is zero or the quotient does not fit in u128
+fn mul_div_floor(a: u128, b: u128, denominator: u128) -> Option<u128> {
+    if denominator == 0 {
+        return None;
+    }
+
+    let (hi, lo) = widening_mul(a, b);
+    if hi >= denominator {
+        return None;
+    }
+
+    // Restoring long division over the low 128 bits; the remainder stays below the denominator
+    let mut remainder = hi;
+    let mut quotient = 0u128;
+    for bit in (0..128).rev() {
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | ((lo >> bit) & 1);
+        quotient <<= 1;
+        if carry == 1 || remainder >= denominator {
+            remainder = remainder.wrapping_sub(denominator);
+            quotient |= 1;
+        }
+    }
+
+    Some(quotient)
+}
+
+/// Calculate output amount in integer token units (wei) with an exact bps fee
+/// Formula: amountOut = floor(amountIn × (10000 - fee_bps) × ReserveOut / (ReserveIn × 10000 + amountIn × (10000 - fee_bps)))
+/// A fee above 10000 bps is InvalidParameter, as is an amount_in or reserve_in whose
+/// scaled intermediate overflows u128; an empty pool with nothing traded in is ZeroReserve
+pub fn calculate_amount_out_u128(
+    reserve_in: u128,
+    reserve_out: u128,
+    amount_in: u128,
+    fee_bps: u32,
+) -> Result<u128, MathError> {
+    if fee_bps > 10000 {
+        return Err(MathError::InvalidParameter {
+            name: "fee_bps",
+            value: fee_bps as f64,
+        });
+    }
+    if amount_in == 0 {
+        return Ok(0);
+    }
+
+    let amount_in_with_fee =
+        amount_in
+            .checked_mul((10000 - fee_bps) as u128)
+            .ok_or(MathError::InvalidParameter {
+                name: "amount_in",
+                value: amount_in as f64,
+            })?;
+    let denominator = reserve_in
+        .checked_mul(10000)
+        .and_then(|scaled| scaled.checked_add(amount_in_with_fee))
+        .ok_or(MathError::InvalidParameter {
+            name: "reserve_in",
+            value: reserve_in as f64,
+        })?;
+
+    // The quotient is below reserve_out whenever the denominator is non-zero
+    mul_div_floor(amount_in_with_fee, reserve_out, denominator).ok_or(MathError::ZeroReserve {
+        name: "reserve_in",
+        value: reserve_in as f64,
+    })
+}
+
+/// Chain exact integer swaps across a multi-hop path of (reserve_in, reserve_out) pairs
+/// Each hop floors its output like the on-chain router, so the result matches to the wei
+/// The first hop that `calculate_amount_out_u128` rejects fails the whole path
+pub fn calculate_multihop_amount_out_u128(
+    reserves: &[(u128, u128)],
+    amount_in: u128,
+    fee_bps: u32,
+) -> Result<u128, MathError> {
+    reserves
+        .iter()
+        .try_fold(amount_in, |amount, &(reserve_in, reserve_out)| {
+            calculate_amount_out_u128(reserve_in, reserve_out, amount, fee_bps)
+        })
+}
+
 /// Step 4: Estimate profitability of arbitrage
 /// Formula: profit = AmountOut_sell - AmountIn_buy - gas_fees - flashloan_fees
 pub fn estimate_arbitrage_profit(
@@ -452,338 +3307,5485 @@ pub fn estimate_arbitrage_profit(
     gas_cost: f64,
     flashloan_fee_pct: f64,
 ) -> f64 {
-    // Calculate amount out from buy pool
-    let amount_out_buy = calculate_amount_out(buy_reserve_in, buy_reserve_out, amount_in);
-
-    // Calculate amount out from sell pool
-    let amount_out_sell = calculate_amount_out(sell_reserve_in, sell_reserve_out, amount_out_buy);
-
-    // Calculate flashloan repayment
-    let flashloan_repayment = amount_in * (1.0 + flashloan_fee_pct);
-
-    // Calculate net profit
-    amount_out_sell - flashloan_repayment - gas_cost
+    estimate_arbitrage_profit_with_legs(
+        (buy_reserve_in, buy_reserve_out),
+        (sell_reserve_in, sell_reserve_out),
+        amount_in,
+        gas_cost,
+        flashloan_fee_pct,
+        LegTokens::Independent,
+    )
 }
 
-/// Step 5: Solve quadratic equation for optimal trade size
-/// Formula: ax² + bx + c = 0
-/// Returns the positive root(s) or 0 if no real solutions
-pub fn solve_quadratic(a: f64, b: f64, c: f64) -> (f64, f64) {
-    if a == 0.0 {
-        // Linear equation: bx + c = 0
-        if b != 0.0 {
-            return (-c / b, 0.0);
-        }
-        return (0.0, 0.0);
-    }
-
-    let discriminant = b * b - 4.0 * a * c;
-
-    if discriminant < 0.0 {
-        // No real solutions
-        return (0.0, 0.0);
-    }
+/// `estimate_arbitrage_profit` for legs that may trade the same pair; Shared assumes the two
+/// pools are the pair's whole market and charges only the netted `combined_leg_impact`
+/// Formula (Shared): AmountOut_sell = AmountIn × γ² × P_buy × P_sell × (1 - impact / 100)
+pub fn estimate_arbitrage_profit_with_legs(
+    (buy_reserve_in, buy_reserve_out): (f64, f64),
+    (sell_reserve_in, sell_reserve_out): (f64, f64),
+    amount_in: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+    legs: LegTokens,
+) -> f64 {
+    let amount_out_sell = match legs {
+        LegTokens::Independent => {
+            // Calculate amount out from buy pool
+            let amount_out_buy = calculate_amount_out(buy_reserve_in, buy_reserve_out, amount_in);
 
-    let sqrt_discriminant = discriminant.sqrt();
-    let root1 = (-b + sqrt_discriminant) / (2.0 * a);
-    let root2 = (-b - sqrt_discriminant) / (2.0 * a);
+            // Calculate amount out from sell pool
+            calculate_amount_out(sell_reserve_in, sell_reserve_out, amount_out_buy)
+        }
+        LegTokens::Shared => {
+            let buy_price = calculate_pool_price(buy_reserve_in, buy_reserve_out);
+            let sell_price = calculate_pool_price(sell_reserve_in, sell_reserve_out);
+            let gamma = fee_multiplier(DEFAULT_FEE_BPS);
+            let impact = combined_leg_impact(
+                (buy_reserve_in, buy_reserve_out),
+                (sell_reserve_in, sell_reserve_out),
+                amount_in,
+                LegTokens::Shared,
+            );
+            (amount_in.max(0.0) * gamma * gamma * buy_price * sell_price * (1.0 - impact / 100.0))
+                .max(0.0)
+        }
+    };
 
-    (root1, root2)
+    // Calculate net profit after the flashloan fee
+    profit_from_fills(
+        amount_in,
+        amount_out_sell,
+        gas_cost,
+        amount_in * flashloan_fee_pct,
+    )
 }
 
-/// Step 5: Find optimal trade size using quadratic optimization
-/// This finds the trade size that maximizes profit considering slippage
-pub fn optimize_trade_size_quadratic(
+/// `estimate_arbitrage_profit` that reports an unusable pool, a negative amount or
+/// flashloan fee, or a non-finite gas cost instead of quoting it; a loss is still a valid
+/// (negative) profit, and a negative gas cost is a net rebate (see `effective_gas_cost`)
+pub fn try_estimate_arbitrage_profit(
     buy_reserve_in: f64,
     buy_reserve_out: f64,
     sell_reserve_in: f64,
     sell_reserve_out: f64,
+    amount_in: f64,
     gas_cost: f64,
     flashloan_fee_pct: f64,
+) -> Result<f64, MathError> {
+    if !gas_cost.is_finite() {
+        return Err(MathError::InvalidParameter {
+            name: "gas_cost",
+            value: gas_cost,
+        });
+    }
+    check_amount("flashloan_fee_pct", flashloan_fee_pct)?;
+    let amount_out_buy = try_calculate_amount_out(buy_reserve_in, buy_reserve_out, amount_in)?;
+    let amount_out_sell =
+        try_calculate_amount_out(sell_reserve_in, sell_reserve_out, amount_out_buy)?;
+
+    Ok(profit_from_fills(
+        amount_in,
+        amount_out_sell,
+        gas_cost,
+        amount_in * flashloan_fee_pct,
+    ))
+}
+
+/// Realized profit from the exact amounts in an executed trade's logs, by the same
+/// definition `estimate_arbitrage_profit` uses, so realized and expected profit compare
+/// like for like. All amounts are in the borrowed token
+/// Formula: profit = amount_out - amount_in - flashloan_fee_paid - gas_paid
+pub fn profit_from_fills(
+    amount_in: f64,
+    amount_out: f64,
+    gas_paid: f64,
+    flashloan_fee_paid: f64,
 ) -> f64 {
-    // Use binary search to find optimal size (more robust than pure quadratic)
-    // Limit trade size to 30% of reserves to avoid excessive slippage and market impact.
-    // 30% is a common DeFi heuristic, balancing profit potential with risk of moving the market,
-    // and is consistent with the approach in `calculate_flashloan_amount_v3`.
-    let max_amount = (buy_reserve_in * 0.3).min(sell_reserve_in * 0.3);
-    let mut best_size = 0.0;
-    let mut best_profit = 0.0;
+    amount_out - amount_in - flashloan_fee_paid - gas_paid
+}
 
-    for i in 0..100 {
-        let amount = (i as f64 / 100.0) * max_amount;
-        if amount <= 0.0 {
-            continue;
-        }
+/// `estimate_arbitrage_profit` for a trade sent with amountOutMin on its final output
+/// If the sell leg would return less than amount_out_min the transaction reverts, the
+/// flashloan never settles and only gas is lost, so the downside is -gas_cost rather than
+/// the unprotected loss. Use this as the loss branch of expected-value calculations
+pub fn expected_profit_with_revert_protection(
+    (buy_reserve_in, buy_reserve_out): (f64, f64),
+    (sell_reserve_in, sell_reserve_out): (f64, f64),
+    amount_in: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+    amount_out_min: f64,
+) -> f64 {
+    let amount_out_buy = calculate_amount_out(buy_reserve_in, buy_reserve_out, amount_in);
+    let amount_out_sell = calculate_amount_out(sell_reserve_in, sell_reserve_out, amount_out_buy);
+    if amount_out_sell < amount_out_min {
+        return -gas_cost;
+    }
 
-        let profit = estimate_arbitrage_profit(
-            buy_reserve_in,
-            buy_reserve_out,
+    profit_from_fills(
+        amount_in,
+        amount_out_sell,
+        gas_cost,
+        amount_in * flashloan_fee_pct,
+    )
+}
+
+/// `estimate_arbitrage_profit` under each gas cost in gas_costs, in the same order
+/// The swaps are quoted once and each scenario only subtracts its gas, so a whole gas-price
+/// range costs one call; the profit of a zero-gas scenario is the breakeven gas cost
+pub fn profit_vs_gas(
+    (buy_reserve_in, buy_reserve_out): (f64, f64),
+    (sell_reserve_in, sell_reserve_out): (f64, f64),
+    amount_in: f64,
+    flashloan_fee_pct: f64,
+    gas_costs: &[f64],
+) -> Vec<f64> {
+    let profit_before_gas = estimate_arbitrage_profit(
+        buy_reserve_in,
+        buy_reserve_out,
+        sell_reserve_in,
+        sell_reserve_out,
+        amount_in,
+        0.0,
+        flashloan_fee_pct,
+    );
+    gas_costs
+        .iter()
+        .map(|gas_cost| profit_before_gas - gas_cost)
+        .collect()
+}
+
+/// Our two-leg profit (sell output - amount in, before gas and flashloan fee) after a
+/// competitor lands `competitor_amount` on the same gap first, trading in our direction on
+/// the buy pool (competitor_on_buy) or the sell pool, and shifting it with `apply_swap`
+/// Compare with competitor_amount = 0 for the margin a rival can take before we lose money
+pub fn profit_after_competitor(
+    (buy_reserve_in, buy_reserve_out): (f64, f64),
+    (sell_reserve_in, sell_reserve_out): (f64, f64),
+    our_amount: f64,
+    competitor_amount: f64,
+    competitor_on_buy: bool,
+    fee_bps: u32,
+) -> f64 {
+    let ((buy_in, buy_out), (sell_in, sell_out)) = if competitor_on_buy {
+        let (new_in, new_out, _) =
+            apply_swap(buy_reserve_in, buy_reserve_out, competitor_amount, fee_bps);
+        ((new_in, new_out), (sell_reserve_in, sell_reserve_out))
+    } else {
+        let (new_in, new_out, _) = apply_swap(
             sell_reserve_in,
             sell_reserve_out,
-            amount,
-            gas_cost,
-            flashloan_fee_pct,
+            competitor_amount,
+            fee_bps,
         );
+        ((buy_reserve_in, buy_reserve_out), (new_in, new_out))
+    };
 
-        if profit > best_profit {
-            best_profit = profit;
-            best_size = amount;
-        }
+    let amount_out_buy = calculate_amount_out_with_fee(buy_in, buy_out, our_amount, fee_bps);
+    let amount_out_sell = calculate_amount_out_with_fee(sell_in, sell_out, amount_out_buy, fee_bps);
+    amount_out_sell - our_amount
+}
+
+/// Profit from buying on a pool and settling the output at an external reference price (CEX-DEX)
+/// reference_price uses the same orientation as `calculate_pool_price` (output token per input token)
+/// Formula: profit = amount_out / reference_price - amount_in - gas_cost
+pub fn profit_vs_reference(
+    reserve_in: f64,
+    reserve_out: f64,
+    amount_in: f64,
+    reference_price: f64,
+    fee_bps: u32,
+    gas_cost: f64,
+) -> f64 {
+    if reference_price <= 0.0 {
+        return -gas_cost;
     }
 
-    best_size
+    let amount_out = calculate_amount_out_with_fee(reserve_in, reserve_out, amount_in, fee_bps);
+    amount_out / reference_price - amount_in - gas_cost
 }
 
-/// Step 6: Calculate TWAP (Time-Weighted Average Price)
-/// Formula: TWAP = (a_t2 - a_t1) / (t2 - t1)
-/// This validates that price discrepancy is legitimate and not temporary
-pub fn calculate_twap(price_samples: &[(f64, f64)], // Array of (timestamp, price) pairs
+/// Profit of filling a resting fixed-price order (0x/RFQ-style) and unwinding on an AMM
+/// amm_reserves run intermediate -> input; any `amount` beyond limit_max_size is not deployed
+/// Formula: profit = amm_out(min(amount, limit_max_size) × limit_price) - filled - gas_cost
+pub fn limit_order_plus_amm_profit(
+    limit_price: f64,
+    limit_max_size: f64,
+    amm_reserves: (f64, f64),
+    amount: f64,
+    fee_bps: u32,
+    gas_cost: f64,
 ) -> f64 {
-    if price_samples.len() < 2 {
-        return 0.0;
+    if limit_price <= 0.0 || limit_max_size <= 0.0 || amount <= 0.0 {
+        return -gas_cost;
     }
 
-    let mut weighted_sum = 0.0;
-    let mut total_time = 0.0;
-
-    for i in 0..price_samples.len() - 1 {
-        let (t1, p1) = price_samples[i];
-        let (t2, _p2) = price_samples[i + 1];
-        let time_diff = t2 - t1;
+    let filled = amount.min(limit_max_size);
+    let amount_out = calculate_amount_out_with_fee(
+        amm_reserves.0,
+        amm_reserves.1,
+        filled * limit_price,
+        fee_bps,
+    );
+    amount_out - filled - gas_cost
+}
 
-        if time_diff > 0.0 {
-            weighted_sum += p1 * time_diff;
-            total_time += time_diff;
-        }
+/// Profit of a non-cyclic arb that ends holding a different token than it borrowed, valued
+/// through external prices in a common numeraire; only gas is reported without an input price
+/// Formula: profit = amount_out × output_token_price / input_token_price - amount_in × (1 + flashloan_fee_pct) - gas_cost
+pub fn cross_token_profit(
+    buy_reserves: (f64, f64),
+    sell_reserves: (f64, f64),
+    amount_in: f64,
+    output_token_price: f64,
+    input_token_price: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+) -> f64 {
+    if input_token_price <= 0.0 || output_token_price < 0.0 {
+        return -gas_cost;
     }
 
-    if total_time > 0.0 {
-        weighted_sum / total_time
-    } else {
-        0.0
+    let intermediate = calculate_amount_out(buy_reserves.0, buy_reserves.1, amount_in);
+    let amount_out = calculate_amount_out(sell_reserves.0, sell_reserves.1, intermediate);
+    let output_value = amount_out * output_token_price / input_token_price;
+
+    output_value - amount_in * (1.0 + flashloan_fee_pct) - gas_cost
+}
+
+/// Profit of an arb in asset Y funded by a flashloan of asset X, converted through
+/// borrow_reserves and repay_reserves; pools are (reserve_in, reserve_out) along the trade
+/// Formula: profit = X_out - amount × (1 + flashloan_fee_pct) - gas_cost
+pub fn cross_asset_flashloan_profit(
+    borrow_reserves: (f64, f64),
+    leg1_reserves: (f64, f64),
+    leg2_reserves: (f64, f64),
+    repay_reserves: (f64, f64),
+    amount: f64,
+    flashloan_fee_pct: f64,
+    gas_cost: f64,
+) -> f64 {
+    let amount_out = [
+        borrow_reserves,
+        leg1_reserves,
+        leg2_reserves,
+        repay_reserves,
+    ]
+    .iter()
+    .fold(amount, |current, (reserve_in, reserve_out)| {
+        calculate_amount_out(*reserve_in, *reserve_out, current)
+    });
+
+    amount_out - amount * (1.0 + flashloan_fee_pct) - gas_cost
+}
+
+/// A flashloan source and how much it will lend
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProviderSpec {
+    /// Fee as a fraction of the amount borrowed, like flashloan_fee_pct (0.0009 = 0.09%)
+    pub fee_pct: f64,
+    pub max_borrow: f64,
+}
+
+/// Cheapest provider able to lend amount_needed in full, as (provider index, fee paid)
+/// Ties keep the earlier provider; None when no provider has the liquidity
+pub fn best_flashloan_provider(
+    amount_needed: f64,
+    providers: &[ProviderSpec],
+) -> Option<(usize, f64)> {
+    providers
+        .iter()
+        .enumerate()
+        .filter(|(_, provider)| provider.max_borrow >= amount_needed && provider.fee_pct >= 0.0)
+        .map(|(idx, provider)| (idx, amount_needed * provider.fee_pct))
+        .fold(None, |best: Option<(usize, f64)>, candidate| match best {
+            Some(current) if current.1 <= candidate.1 => Some(current),
+            _ => Some(candidate),
+        })
+}
+
+/// Highest flashloan fee (a fraction) at which borrowing amount_in still breaks even after gas
+/// Negative when the trade loses even with a free loan; 0.0 for a non-positive amount_in
+/// Formula: fee = (amount_out_sell - amount_in - gas_cost) / amount_in
+pub fn max_flashloan_fee_for_profit(
+    (buy_reserve_in, buy_reserve_out): (f64, f64),
+    (sell_reserve_in, sell_reserve_out): (f64, f64),
+    amount_in: f64,
+    gas_cost: f64,
+) -> f64 {
+    if amount_in <= 0.0 {
+        return 0.0;
     }
+
+    let profit_before_fee = estimate_arbitrage_profit(
+        buy_reserve_in,
+        buy_reserve_out,
+        sell_reserve_in,
+        sell_reserve_out,
+        amount_in,
+        gas_cost,
+        0.0,
+    );
+    profit_before_fee / amount_in
 }
 
-/// Step 6: Validate arbitrage opportunity using TWAP
-/// Returns true if current price is close to TWAP (not manipulated)
-pub fn validate_with_twap(current_price: f64, twap: f64, max_deviation_pct: f64) -> bool {
-    if twap <= 0.0 {
-        return false;
+/// Which token a flashloan surplus is held in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SurplusToken {
+    Borrowed,
+    Output,
+}
+
+/// Flashloan settlement split between the lender and the arbitrageur
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settlement {
+    /// Owed to the lender, in the borrowed token
+    pub repayment_due: f64,
+    /// What is left after repaying, in `surplus_token`; negative is a shortfall
+    pub surplus: f64,
+    pub surplus_token: SurplusToken,
+}
+
+/// Split a flashloan arb's output into the repayment and a surplus left in the output token,
+/// converting only what repays at conversion_price (borrowed tokens per output token)
+/// Formula: repayment_due = amount_borrowed × (1 + flashloan_fee_pct)
+pub fn repayment_and_surplus(
+    amount_borrowed: f64,
+    flashloan_fee_pct: f64,
+    final_output: f64,
+    output_is_borrowed_token: bool,
+    conversion_price: f64,
+) -> Result<Settlement, MathError> {
+    let repayment_due = amount_borrowed * (1.0 + flashloan_fee_pct);
+    if output_is_borrowed_token {
+        return Ok(Settlement {
+            repayment_due,
+            surplus: final_output - repayment_due,
+            surplus_token: SurplusToken::Borrowed,
+        });
     }
 
-    let deviation = ((current_price - twap).abs() / twap) * 100.0;
-    deviation <= max_deviation_pct
+    if !(conversion_price > 0.0 && conversion_price.is_finite()) {
+        return Err(MathError::InvalidParameter {
+            name: "conversion price",
+            value: conversion_price,
+        });
+    }
+
+    Ok(Settlement {
+        repayment_due,
+        surplus: final_output - repayment_due / conversion_price,
+        surplus_token: SurplusToken::Output,
+    })
 }
 
-/// Configuration parameters for arbitrage execution
-pub struct ArbitrageConfig {
-    pub gas_cost: f64,
-    pub flashloan_fee_pct: f64,
-    pub min_price_diff_pct: f64,
-    pub max_twap_deviation_pct: f64,
-    pub min_profit_threshold: f64,
+/// Apply a gas rebate or MEV refund to the raw gas cost
+/// rebate_fraction is the share of gas refunded (0.5 = half back); values above 1.0
+/// model MEV-share payouts exceeding gas and yield a negative effective cost,
+/// which the profit functions treat as extra income
+pub fn effective_gas_cost(raw_gas_cost: f64, rebate_fraction: f64) -> f64 {
+    if !rebate_fraction.is_finite() {
+        return raw_gas_cost;
+    }
+    raw_gas_cost * (1.0 - rebate_fraction)
 }
 
-/// Step 7: Complete arbitrage execution flow
-/// Returns (should_execute, optimal_amount, expected_profit)
-pub fn execute_arbitrage_flow(
-    pool1_reserve_in: f64,
-    pool1_reserve_out: f64,
-    pool2_reserve_in: f64,
-    pool2_reserve_out: f64,
-    price_samples_pool1: &[(f64, f64)],
-    price_samples_pool2: &[(f64, f64)],
-    config: &ArbitrageConfig,
-) -> (bool, f64, f64) {
-    // Step 1 & 2: Identify arbitrage opportunity
-    let (has_opportunity, _price_diff, direction) = identify_arbitrage_opportunity(
-        pool1_reserve_in,
-        pool1_reserve_out,
-        pool2_reserve_in,
-        pool2_reserve_out,
-        config.min_price_diff_pct,
-    );
+/// Probability of winning an opportunity contested by `competitor_count` other bots
+/// Each competitor has equal weight 1; our weight is 1 + our_edge_pct / 100
+pub fn win_probability(competitor_count: u32, our_edge_pct: f64) -> f64 {
+    let our_weight = (1.0 + our_edge_pct / 100.0).max(0.0);
+    let total_weight = our_weight + competitor_count as f64;
 
-    if !has_opportunity {
-        return (false, 0.0, 0.0);
+    if total_weight <= 0.0 {
+        return 0.0;
     }
+    our_weight / total_weight
+}
 
-    // Determine buy and sell pools based on direction
-    let (buy_res_in, buy_res_out, sell_res_in, sell_res_out, price_samples) = if direction == 1 {
-        (
-            pool1_reserve_in,
-            pool1_reserve_out,
-            pool2_reserve_in,
-            pool2_reserve_out,
-            price_samples_pool1,
-        )
+/// Expected net profit of attempting an opportunity given expected competition
+/// Formula: expected_profit × win_probability(competitor_count, our_edge_pct)
+/// Losing attempts are assumed to cost nothing extra (reverting bundles are not included)
+pub fn optimal_entry(expected_profit: f64, competitor_count: u32, our_edge_pct: f64) -> f64 {
+    expected_profit * win_probability(competitor_count, our_edge_pct)
+}
+
+/// Shrink a proposed trade size as session drawdown approaches its limit
+/// Drawdown is the larger of `current_drawdown` and the session loss implied by a negative
+/// `realized_pnl` (both as positive magnitudes); the size scales linearly with the
+/// remaining headroom and is zero once the limit is reached
+pub fn session_size_cap(
+    proposed_size: f64,
+    realized_pnl: f64,
+    drawdown_limit: f64,
+    current_drawdown: f64,
+) -> f64 {
+    if drawdown_limit <= 0.0 || proposed_size <= 0.0 {
+        return 0.0;
+    }
+
+    let drawdown = current_drawdown.max(-realized_pnl).max(0.0);
+    let headroom = ((drawdown_limit - drawdown) / drawdown_limit).clamp(0.0, 1.0);
+
+    proposed_size * headroom
+}
+
+/// Closed-form profit-maximizing input for a two-leg cycle of constant-product pools
+/// Formula: x* = (√(γ·E_in·E_out) - E_in) / γ, E_in = a_in·b_in / (b_in + γ·a_out),
+/// E_out = γ·a_out·b_out / (b_in + γ·a_out); returns 0 when nothing is profitable before gas
+pub fn optimal_two_leg_input(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    fee_bps: u32,
+) -> f64 {
+    two_leg_optimum_with_cost(
+        buy_reserve_in,
+        buy_reserve_out,
+        sell_reserve_in,
+        sell_reserve_out,
+        fee_bps,
+        1.0,
+    )
+}
+
+/// `optimal_two_leg_input` when each unit of input costs `cost_factor` to source
+/// (1 + flashloan fee); the optimum moves to where the composed marginal rate equals it
+fn two_leg_optimum_with_cost(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    fee_bps: u32,
+    cost_factor: f64,
+) -> f64 {
+    if cost_factor <= 0.0 {
+        return 0.0;
+    }
+    if buy_reserve_in <= 0.0
+        || buy_reserve_out <= 0.0
+        || sell_reserve_in <= 0.0
+        || sell_reserve_out <= 0.0
+    {
+        return 0.0;
+    }
+
+    let gamma = fee_multiplier(fee_bps);
+    if gamma <= 0.0 {
+        return 0.0;
+    }
+
+    let denominator = sell_reserve_in + gamma * buy_reserve_out;
+    let effective_in = buy_reserve_in * sell_reserve_in / denominator;
+    let effective_out = gamma * buy_reserve_out * sell_reserve_out / denominator;
+
+    ((gamma * effective_in * effective_out / cost_factor).sqrt() - effective_in).max(0.0) / gamma
+}
+
+/// Price band two pools settle into once the optimal arbitrage between them is executed
+/// Both pools are given as (reserve_x, reserve_y) with price = reserve_y / reserve_x
+/// Fees leave a no-arbitrage band rather than a single price, so this returns (low, high);
+/// pools already inside the band are returned with their current prices
+pub fn equilibrium_price(
+    pool1_reserves: (f64, f64),
+    pool2_reserves: (f64, f64),
+    fee_bps: u32,
+) -> (f64, f64) {
+    let (x1, y1) = pool1_reserves;
+    let (x2, y2) = pool2_reserves;
+    let price1 = calculate_pool_price(x1, y1);
+    let price2 = calculate_pool_price(x2, y2);
+    if price1 <= 0.0 || price2 <= 0.0 {
+        return (0.0, 0.0);
+    }
+
+    // Sell X into the pool that pays more Y for it, then sell the Y back into the other pool
+    let ((rich_x, rich_y), (cheap_x, cheap_y)) = if price1 >= price2 {
+        ((x1, y1), (x2, y2))
     } else {
-        (
-            pool2_reserve_in,
-            pool2_reserve_out,
-            pool1_reserve_in,
-            pool1_reserve_out,
-            price_samples_pool2,
-        )
+        ((x2, y2), (x1, y1))
+    };
+
+    let amount_in = optimal_two_leg_input(rich_x, rich_y, cheap_y, cheap_x, fee_bps);
+    if amount_in <= 0.0 {
+        return (price1.min(price2), price1.max(price2));
+    }
+
+    let (rich_x_after, rich_y_after, y_bought) = apply_swap(rich_x, rich_y, amount_in, fee_bps);
+    let (cheap_y_after, cheap_x_after, _) = apply_swap(cheap_y, cheap_x, y_bought, fee_bps);
+
+    let rich_price = rich_y_after / rich_x_after;
+    let cheap_price = cheap_y_after / cheap_x_after;
+    (rich_price.min(cheap_price), rich_price.max(cheap_price))
+}
+
+/// Range of price ratios p2 / p1 within which no trade between the pools clears gas, with pool 2
+/// re-priced along its own curve; zero gas gives the fee band (γ², 1/γ²)
+/// Pools are (reserve_x, reserve_y) with gas in X; unusable pools return (0, ∞)
+pub fn no_arb_band(
+    pool1_reserves: (f64, f64),
+    pool2_reserves: (f64, f64),
+    gas_cost: f64,
+    fee_bps: u32,
+) -> (f64, f64) {
+    let (x1, y1) = pool1_reserves;
+    let (x2, y2) = pool2_reserves;
+    let gamma = fee_multiplier(fee_bps);
+    if x1 <= 0.0 || y1 <= 0.0 || x2 <= 0.0 || y2 <= 0.0 || gamma <= 0.0 {
+        return (0.0, f64::INFINITY);
+    }
+
+    let price1 = y1 / x1;
+    let k2 = x2 * y2;
+    // Best net profit (in X) of the cycle through pool 2 re-priced to ratio × price1
+    let best_profit = |ratio: f64| {
+        let price2 = ratio * price1;
+        let (x2, y2) = ((k2 / price2).sqrt(), (k2 * price2).sqrt());
+        // Sell X where it fetches more Y, buy it back in the other pool
+        let ((rich_x, rich_y), (cheap_x, cheap_y)) = if price2 >= price1 {
+            ((x2, y2), (x1, y1))
+        } else {
+            ((x1, y1), (x2, y2))
+        };
+        let amount_in = optimal_two_leg_input(rich_x, rich_y, cheap_y, cheap_x, fee_bps);
+        let y_bought = calculate_amount_out_with_fee(rich_x, rich_y, amount_in, fee_bps);
+        calculate_amount_out_with_fee(cheap_y, cheap_x, y_bought, fee_bps) - amount_in - gas_cost
+    };
+    // Bisect between a ratio inside the band and one outside it
+    let edge = |inside: f64, outside: f64| {
+        let (mut inside, mut outside) = (inside, outside);
+        while !approx_eq(inside, outside, 1e-12, 0.0) {
+            let mid = 0.5 * (inside + outside);
+            if best_profit(mid) > 0.0 {
+                outside = mid;
+            } else {
+                inside = mid;
+            }
+        }
+        inside
+    };
+
+    // Widen from the fee band until the gap pays for gas; give up after 2^64
+    let fee_edge = 1.0 / (gamma * gamma);
+    let mut upper = fee_edge * 2.0;
+    let mut doublings = 0;
+    while best_profit(upper) <= 0.0 {
+        if doublings == 64 {
+            return (0.0, f64::INFINITY);
+        }
+        upper *= 2.0;
+        doublings += 1;
+    }
+    let mut lower = 0.5 / fee_edge;
+    while best_profit(lower) <= 0.0 {
+        if lower < f64::MIN_POSITIVE {
+            return (0.0, edge(1.0, upper));
+        }
+        lower *= 0.5;
+    }
+
+    (edge(1.0, lower), edge(1.0, upper))
+}
+
+/// What a multi-venue arbitrage does in one pool, with pools given as (reserve_x, reserve_y)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VenueSide {
+    /// Pool is left alone
+    Skip,
+    /// Sell borrowed X into the pool for Y
+    Sell,
+    /// Spend Y to buy X back from the pool
+    Buy,
+}
+
+/// Per-pool action; amount_in is in X for sells and in Y for buys
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VenueAction {
+    pub side: VenueSide,
+    pub amount_in: f64,
+    pub amount_out: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiVenueArb {
+    /// One action per input pool, in input order
+    pub actions: Vec<VenueAction>,
+    /// X returned minus X borrowed, flashloan fee and gas
+    pub net_profit: f64,
+}
+
+/// Size an arbitrage across any number of pools quoting the same X/Y pair, moving every touched
+/// pool to one marginal rate found by bisection; gas and profit are in X
+/// Pools run in `canonical_pool_order`, so any permutation gives bit-identical results
+pub fn multi_venue_arb(
+    pools: &[(f64, f64)],
+    fee_bps: u32,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+) -> MultiVenueArb {
+    let order = canonical_pool_order(pools);
+    let ordered: Vec<(f64, f64)> = order.iter().map(|&idx| pools[idx]).collect();
+    let result = multi_venue_arb_ordered(&ordered, fee_bps, gas_cost, flashloan_fee_pct);
+
+    let mut actions = result.actions.clone();
+    for (action, &idx) in result.actions.iter().zip(order.iter()) {
+        actions[idx] = *action;
+    }
+    MultiVenueArb {
+        actions,
+        net_profit: result.net_profit,
+    }
+}
+
+/// Indices of (reserve_x, reserve_y) pools sorted by spot price y/x, then by liquidity √(x·y),
+/// then by the raw reserves, so callers that sum or break ties across pools do it in an order
+/// independent of how the pools were passed in
+/// The sort is total: unusable pools with zero or NaN reserves still get a fixed position
+pub fn canonical_pool_order(pools: &[(f64, f64)]) -> Vec<usize> {
+    let key = |&(x, y): &(f64, f64)| (y / x, (x * y).sqrt(), x, y);
+    let mut order: Vec<usize> = (0..pools.len()).collect();
+    order.sort_by(|&a, &b| {
+        let (ka, kb) = (key(&pools[a]), key(&pools[b]));
+        ka.0.total_cmp(&kb.0)
+            .then(ka.1.total_cmp(&kb.1))
+            .then(ka.2.total_cmp(&kb.2))
+            .then(ka.3.total_cmp(&kb.3))
+    });
+    order
+}
+
+/// `multi_venue_arb` on pools already in canonical order
+fn multi_venue_arb_ordered(
+    pools: &[(f64, f64)],
+    fee_bps: u32,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+) -> MultiVenueArb {
+    const SKIP: VenueAction = VenueAction {
+        side: VenueSide::Skip,
+        amount_in: 0.0,
+        amount_out: 0.0,
     };
+    let no_trade = MultiVenueArb {
+        actions: vec![SKIP; pools.len()],
+        net_profit: 0.0,
+    };
+
+    let gamma = fee_multiplier(fee_bps);
+    let cost_factor = 1.0 + flashloan_fee_pct;
+    if gamma <= 0.0 || cost_factor <= 0.0 {
+        return no_trade;
+    }
+    let usable = |x: f64, y: f64| x > 0.0 && y > 0.0;
+
+    // X sold into a pool until its marginal Y per X falls to cost_factor·P
+    let sell_at = |x: f64, y: f64, price: f64| {
+        ((gamma * x * y / (cost_factor * price)).sqrt() - x).max(0.0) / gamma
+    };
+    // Y spent in a pool until its marginal X per Y falls to 1/P
+    let buy_at = |x: f64, y: f64, price: f64| ((gamma * x * y * price).sqrt() - y).max(0.0) / gamma;
+
+    let y_surplus = |price: f64| {
+        pools
+            .iter()
+            .filter(|(x, y)| usable(*x, *y))
+            .map(|&(x, y)| {
+                let y_received = calculate_amount_out_with_fee(x, y, sell_at(x, y, price), fee_bps);
+                y_received - buy_at(x, y, price)
+            })
+            .sum::<f64>()
+    };
+
+    // At the lowest spot price nothing is bought and at the highest nothing is sold
+    let spot_prices = pools
+        .iter()
+        .filter(|(x, y)| usable(*x, *y))
+        .map(|(x, y)| y / x);
+    let mut low = spot_prices.clone().fold(f64::INFINITY, f64::min);
+    let mut high = spot_prices.fold(0.0, f64::max);
+    if !low.is_finite() || high <= low {
+        return no_trade;
+    }
+
+    for _ in 0..200 {
+        let mid = (low * high).sqrt();
+        if y_surplus(mid) > 0.0 {
+            low = mid;
+        } else {
+            high = mid;
+        }
+        if approx_eq(low, high, 1e-15, 0.0) {
+            break;
+        }
+    }
+
+    let mut actions = vec![SKIP; pools.len()];
+    let mut x_sold = 0.0;
+    let mut y_received = 0.0;
+    let mut y_to_spend = 0.0;
+    for (action, &(x, y)) in actions.iter_mut().zip(pools.iter()) {
+        if !usable(x, y) {
+            continue;
+        }
+        let sell = sell_at(x, y, low);
+        let buy = buy_at(x, y, low);
+        if sell > 0.0 {
+            let amount_out = calculate_amount_out_with_fee(x, y, sell, fee_bps);
+            *action = VenueAction {
+                side: VenueSide::Sell,
+                amount_in: sell,
+                amount_out,
+            };
+            x_sold += sell;
+            y_received += amount_out;
+        } else if buy > 0.0 {
+            *action = VenueAction {
+                side: VenueSide::Buy,
+                amount_in: buy,
+                amount_out: 0.0,
+            };
+            y_to_spend += buy;
+        }
+    }
+    if x_sold <= 0.0 || y_to_spend <= 0.0 {
+        return no_trade;
+    }
+
+    // Remove residual bisection error so the buys spend exactly the Y the sells produced
+    let mut x_bought = 0.0;
+    for (action, &(x, y)) in actions.iter_mut().zip(pools.iter()) {
+        if action.side == VenueSide::Buy {
+            action.amount_in *= y_received / y_to_spend;
+            action.amount_out = calculate_amount_out_with_fee(y, x, action.amount_in, fee_bps);
+            x_bought += action.amount_out;
+        }
+    }
+
+    let net_profit = x_bought - x_sold * cost_factor - gas_cost;
+    if net_profit <= 0.0 {
+        return no_trade;
+    }
+
+    MultiVenueArb {
+        actions,
+        net_profit,
+    }
+}
+
+/// Step 5: Solve quadratic equation for optimal trade size
+/// Formula: ax² + bx + c = 0
+/// Returns the positive root(s) or 0 if no real solutions
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> (f64, f64) {
+    if a == 0.0 {
+        // Linear equation: bx + c = 0
+        if b != 0.0 {
+            return (-c / b, 0.0);
+        }
+        return (0.0, 0.0);
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+
+    if discriminant < 0.0 {
+        // No real solutions
+        return (0.0, 0.0);
+    }
+
+    let sqrt_discriminant = discriminant.sqrt();
+    let root1 = (-b + sqrt_discriminant) / (2.0 * a);
+    let root2 = (-b - sqrt_discriminant) / (2.0 * a);
+
+    (root1, root2)
+}
+
+/// Step 5: Find optimal trade size using quadratic optimization
+/// This finds the trade size that maximizes profit considering slippage
+pub fn optimize_trade_size_quadratic(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+) -> f64 {
+    optimize_trade_size_quadratic_with_penalty(
+        buy_reserve_in,
+        buy_reserve_out,
+        sell_reserve_in,
+        sell_reserve_out,
+        gas_cost,
+        flashloan_fee_pct,
+        0.0,
+    )
+}
+
+/// Combined slippage (percent) of both legs of a two-pool trade of `amount`
+fn two_leg_slippage_pct(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    amount: f64,
+) -> f64 {
+    let amount_out_buy = calculate_amount_out(buy_reserve_in, buy_reserve_out, amount);
+    compute_uniswap_v2_slippage(buy_reserve_in, buy_reserve_out, amount)
+        + compute_uniswap_v2_slippage(sell_reserve_in, sell_reserve_out, amount_out_buy)
+}
+
+/// Step 5 with a risk preference for lower-impact trades
+/// Maximizes profit - slippage_penalty_weight × slippage, where slippage is the combined
+/// percentage slippage of both legs, so the weight is in profit units per slippage point.
+/// A weight of 0 is the raw profit-max size; only profitable sizes are ever chosen
+pub fn optimize_trade_size_quadratic_with_penalty(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+    slippage_penalty_weight: f64,
+) -> f64 {
+    grid_search_trade_size(
+        (buy_reserve_in, buy_reserve_out),
+        (sell_reserve_in, sell_reserve_out),
+        gas_cost,
+        flashloan_fee_pct,
+        slippage_penalty_weight,
+        SIZE_GRID_POINTS,
+    )
+}
+
+/// Sizes `optimize_trade_size_quadratic_with_penalty` scores across the reserve cap
+const SIZE_GRID_POINTS: usize = 100;
+
+/// Fewest sizes a deadline-budgeted batch still scores per opportunity
+const MIN_BUDGETED_GRID_POINTS: usize = 10;
+
+/// Penalized-objective grid search over `grid_points` evenly spaced sizes up to the cap
+/// Fewer points give a coarser optimum for proportionally less work
+fn grid_search_trade_size(
+    (buy_reserve_in, buy_reserve_out): (f64, f64),
+    (sell_reserve_in, sell_reserve_out): (f64, f64),
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+    slippage_penalty_weight: f64,
+    grid_points: usize,
+) -> f64 {
+    // Use binary search to find optimal size (more robust than pure quadratic)
+    // Limit trade size to 30% of reserves to avoid excessive slippage and market impact.
+    // 30% is a common DeFi heuristic, balancing profit potential with risk of moving the market.
+    let max_amount = (buy_reserve_in * 0.3).min(sell_reserve_in * 0.3);
+    let mut best_size = 0.0;
+    let mut best_objective = 0.0;
+
+    for i in 0..grid_points {
+        let amount = (i as f64 / grid_points as f64) * max_amount;
+        if amount <= 0.0 {
+            continue;
+        }
+
+        let profit = estimate_arbitrage_profit(
+            buy_reserve_in,
+            buy_reserve_out,
+            sell_reserve_in,
+            sell_reserve_out,
+            amount,
+            gas_cost,
+            flashloan_fee_pct,
+        );
+        if profit <= 0.0 {
+            continue;
+        }
+
+        let objective = if slippage_penalty_weight > 0.0 {
+            profit
+                - slippage_penalty_weight
+                    * two_leg_slippage_pct(
+                        buy_reserve_in,
+                        buy_reserve_out,
+                        sell_reserve_in,
+                        sell_reserve_out,
+                        amount,
+                    )
+        } else {
+            profit
+        };
+
+        if objective > best_objective {
+            best_objective = objective;
+            best_size = amount;
+        }
+    }
+
+    best_size
+}
+
+/// `optimize_trade_size_quadratic` alongside its utilization of the buy pool's input reserve
+/// Returns (size, utilization_pct)
+pub fn optimize_trade_size_quadratic_with_utilization(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+) -> (f64, f64) {
+    let size = optimize_trade_size_quadratic(
+        buy_reserve_in,
+        buy_reserve_out,
+        sell_reserve_in,
+        sell_reserve_out,
+        gas_cost,
+        flashloan_fee_pct,
+    );
+    (size, utilization_pct(size, buy_reserve_in))
+}
+
+/// Size a two-pool trade honoring the configured reserve cap mode; soft mode returns an
+/// optimum past the 30% cap, flagged, when it earns more. Returns (size, exceeds_soft_cap)
+pub fn optimize_trade_size_with_cap_mode(
+    buy_reserve_in: f64,
+    buy_reserve_out: f64,
+    sell_reserve_in: f64,
+    sell_reserve_out: f64,
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+    cap_mode: CapMode,
+) -> (f64, bool) {
+    size_with_cap_mode(
+        (buy_reserve_in, buy_reserve_out),
+        (sell_reserve_in, sell_reserve_out),
+        gas_cost,
+        flashloan_fee_pct,
+        cap_mode,
+        0.0,
+        SIZE_GRID_POINTS,
+    )
+}
+
+/// `optimize_trade_size_with_cap_mode` scoring sizes by the penalized objective of
+/// `optimize_trade_size_quadratic_with_penalty` on a grid of `grid_points` sizes
+fn size_with_cap_mode(
+    (buy_reserve_in, buy_reserve_out): (f64, f64),
+    (sell_reserve_in, sell_reserve_out): (f64, f64),
+    gas_cost: f64,
+    flashloan_fee_pct: f64,
+    cap_mode: CapMode,
+    slippage_penalty_weight: f64,
+    grid_points: usize,
+) -> (f64, bool) {
+    let capped = grid_search_trade_size(
+        (buy_reserve_in, buy_reserve_out),
+        (sell_reserve_in, sell_reserve_out),
+        gas_cost,
+        flashloan_fee_pct,
+        slippage_penalty_weight,
+        grid_points,
+    );
+    if cap_mode == CapMode::Hard {
+        return (capped, false);
+    }
+
+    let cap = (buy_reserve_in * 0.3).min(sell_reserve_in * 0.3);
+    let optimum = two_leg_optimum_with_cost(
+        buy_reserve_in,
+        buy_reserve_out,
+        sell_reserve_in,
+        sell_reserve_out,
+        DEFAULT_FEE_BPS,
+        1.0 + flashloan_fee_pct,
+    );
+    if optimum <= cap {
+        return (capped, false);
+    }
+
+    let profit_at = |amount: f64| {
+        estimate_arbitrage_profit(
+            buy_reserve_in,
+            buy_reserve_out,
+            sell_reserve_in,
+            sell_reserve_out,
+            amount,
+            gas_cost,
+            flashloan_fee_pct,
+        )
+    };
+    let objective_at = |amount: f64| {
+        profit_at(amount)
+            - slippage_penalty_weight.max(0.0)
+                * two_leg_slippage_pct(
+                    buy_reserve_in,
+                    buy_reserve_out,
+                    sell_reserve_in,
+                    sell_reserve_out,
+                    amount,
+                )
+    };
+    let optimum_profit = profit_at(optimum);
+    if optimum_profit > 0.0 && (capped <= 0.0 || objective_at(optimum) > objective_at(capped)) {
+        (optimum, true)
+    } else {
+        (capped, false)
+    }
+}
+
+/// Step 6: Calculate TWAP (Time-Weighted Average Price)
+/// Formula: TWAP = (a_t2 - a_t1) / (t2 - t1)
+/// This validates that price discrepancy is legitimate and not temporary
+pub fn calculate_twap(price_samples: &[(f64, f64)], // Array of (timestamp, price) pairs
+) -> f64 {
+    if price_samples.len() < 2 {
+        return 0.0;
+    }
+
+    let mut weighted_sum = 0.0;
+    let mut total_time = 0.0;
+
+    for i in 0..price_samples.len() - 1 {
+        let (t1, p1) = price_samples[i];
+        let (t2, _p2) = price_samples[i + 1];
+        let time_diff = t2 - t1;
+
+        if time_diff > 0.0 {
+            weighted_sum += p1 * time_diff;
+            total_time += time_diff;
+        }
+    }
+
+    if total_time > 0.0 {
+        weighted_sum / total_time
+    } else {
+        0.0
+    }
+}
+
+/// TWAP over (timestamp, price, source_weight) samples from sources of varying reliability
+/// Each sample's price holds until the next timestamp, as in `calculate_twap`, and its
+/// interval is additionally scaled by the weight of the source that reported it, so a
+/// flaky feed weighted near 0 barely moves the average. Weights must be non-negative
+pub fn calculate_weighted_twap(price_samples: &[(f64, f64, f64)]) -> Result<f64, MathError> {
+    if let Some((_, _, weight)) = price_samples
+        .iter()
+        .find(|(_, _, w)| !(*w >= 0.0 && w.is_finite()))
+    {
+        return Err(MathError::InvalidParameter {
+            name: "source weight",
+            value: *weight,
+        });
+    }
+
+    let mut weighted_sum = 0.0;
+    let mut total_weight = 0.0;
+
+    for pair in price_samples.windows(2) {
+        let (t1, p1, w1) = pair[0];
+        let time_diff = pair[1].0 - t1;
+
+        if time_diff > 0.0 {
+            weighted_sum += p1 * time_diff * w1;
+            total_weight += time_diff * w1;
+        }
+    }
+
+    if total_weight > 0.0 {
+        Ok(weighted_sum / total_weight)
+    } else {
+        Ok(0.0)
+    }
+}
+
+/// Time-decayed exponential moving average over (timestamp, price) samples in time order
+/// Each step blends toward the new price by alpha = 1 - 0.5^(elapsed / half_life_secs), so
+/// irregular spacing is handled by the elapsed time rather than a fixed per-sample weight
+/// Returns the latest price when half_life_secs <= 0, or 0 for no samples
+pub fn calculate_ema(price_samples: &[(f64, f64)], half_life_secs: f64) -> f64 {
+    let (first_time, first_price) = match price_samples.first() {
+        Some(sample) => *sample,
+        None => return 0.0,
+    };
+    if half_life_secs <= 0.0 {
+        return price_samples[price_samples.len() - 1].1;
+    }
+
+    let mut ema = first_price;
+    let mut last_time = first_time;
+
+    for &(timestamp, price) in &price_samples[1..] {
+        let elapsed = timestamp - last_time;
+        if elapsed <= 0.0 {
+            continue;
+        }
+
+        let alpha = 1.0 - 0.5_f64.powf(elapsed / half_life_secs);
+        ema += alpha * (price - ema);
+        last_time = timestamp;
+    }
+
+    ema
+}
+
+/// Per-window realized volatility of time-ordered (timestamp, price) samples over the trailing
+/// window_secs; scale by √(seconds_per_year / window_secs) to annualize. 0 under two samples
+/// Formula: σ = √Σ ln(p_i / p_(i-1))²
+pub fn realized_volatility(price_samples: &[(f64, f64)], window_secs: f64) -> f64 {
+    let Some(&(latest_time, _)) = price_samples.last() else {
+        return 0.0;
+    };
+    if !(window_secs > 0.0 && window_secs.is_finite()) {
+        return 0.0;
+    }
+
+    let window_start = latest_time - window_secs;
+    let mut previous: Option<(f64, f64)> = None;
+    let mut sum_squared = 0.0;
+    for &(timestamp, price) in price_samples {
+        if timestamp < window_start || !(price > 0.0 && price.is_finite()) {
+            continue;
+        }
+        if let Some((last_time, last_price)) = previous {
+            if timestamp <= last_time {
+                continue;
+            }
+            sum_squared += (price / last_price).ln().powi(2);
+        }
+        previous = Some((timestamp, price));
+    }
+
+    sum_squared.sqrt()
+}
+
+/// Step 6: Validate arbitrage opportunity using TWAP
+/// Returns true if current price is close to TWAP (not manipulated)
+pub fn validate_with_twap(current_price: f64, twap: f64, max_deviation_pct: f64) -> bool {
+    if twap <= 0.0 {
+        return false;
+    }
+
+    let deviation = ((current_price - twap).abs() / twap) * 100.0;
+    deviation <= max_deviation_pct
+}
+
+/// Deviation from TWAP at which `manipulation_risk` scores the price signal 0.5
+const MANIPULATION_HALF_DEVIATION: f64 = 0.02;
+
+/// Pool depth (in the caller's value units, e.g. USD) below which a pool counts as shallow
+const MANIPULATION_HALF_LIQUIDITY: f64 = 1_000_000.0;
+
+/// Recent volume as a fraction of depth below which a pool counts as quiet
+const MANIPULATION_HALF_TURNOVER: f64 = 0.01;
+
+/// Graded 0-1 risk that a pool's price is a reserve manipulation, unlike `validate_with_twap`
+/// Formula: risk = d/(d + 2%) × (1 + 1/(1 + liquidity / 1M) + 1/(1 + (recent_volume / liquidity) / 1%)) / 3
+pub fn manipulation_risk(current_price: f64, twap: f64, liquidity: f64, recent_volume: f64) -> f64 {
+    if twap <= 0.0 || liquidity <= 0.0 {
+        return 1.0;
+    }
+
+    let deviation = (current_price - twap).abs() / twap;
+    let price_signal = deviation / (deviation + MANIPULATION_HALF_DEVIATION);
+    let shallow = 1.0 / (1.0 + liquidity / MANIPULATION_HALF_LIQUIDITY);
+    let turnover = recent_volume.max(0.0) / liquidity;
+    let quiet = 1.0 / (1.0 + turnover / MANIPULATION_HALF_TURNOVER);
+
+    price_signal * (1.0 + shallow + quiet) / 3.0
+}
+
+/// Trade pushing a pool back toward its own TWAP, found by `oracle_reversion_arb`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OracleReversionArb {
+    /// true sells token_in into the pool (its price is above TWAP), false buys it
+    pub sells_token_in: bool,
+    /// Paid into the pool: token_in when selling it, token_out when buying it
+    pub amount_in: f64,
+    pub amount_out: f64,
+    /// Pool price (reserve_out / reserve_in) after the trade
+    pub price_after: f64,
+    /// Profit in token_out, valuing token_in at the TWAP it is assumed to revert to
+    pub expected_profit: f64,
+}
+
+/// Size the trade that arbs a pool back toward its lagging `twap` (`calculate_pool_price` order)
+/// Formula (above TWAP): x = (sqrt(γ·R_in·R_out / twap) - R_in) / γ; below, z = (sqrt(γ·R_in·R_out·twap) - R_out) / γ
+/// Returns None for an unusable pool or when gas eats the edge
+pub fn oracle_reversion_arb(
+    reserve_in: f64,
+    reserve_out: f64,
+    twap: f64,
+    fee_bps: u32,
+    gas_cost: f64,
+) -> Option<OracleReversionArb> {
+    if reserve_in <= 0.0 || reserve_out <= 0.0 || twap <= 0.0 || fee_bps >= 10000 {
+        return None;
+    }
+
+    let gamma = fee_multiplier(fee_bps);
+    let k = reserve_in * reserve_out;
+    let arb = if reserve_out / reserve_in > twap {
+        let amount_in = ((gamma * k / twap).sqrt() - reserve_in) / gamma;
+        let amount_out = calculate_amount_out_with_fee(reserve_in, reserve_out, amount_in, fee_bps);
+        OracleReversionArb {
+            sells_token_in: true,
+            amount_in,
+            amount_out,
+            price_after: (reserve_out - amount_out) / (reserve_in + amount_in),
+            expected_profit: amount_out - amount_in * twap - gas_cost,
+        }
+    } else {
+        let amount_in = ((gamma * k * twap).sqrt() - reserve_out) / gamma;
+        let amount_out = calculate_amount_out_with_fee(reserve_out, reserve_in, amount_in, fee_bps);
+        OracleReversionArb {
+            sells_token_in: false,
+            amount_in,
+            amount_out,
+            price_after: (reserve_out + amount_in) / (reserve_in - amount_out),
+            expected_profit: amount_out * twap - amount_in - gas_cost,
+        }
+    };
+
+    if arb.amount_in > 0.0 && arb.expected_profit > 0.0 {
+        Some(arb)
+    } else {
+        None
+    }
+}
+
+/// Cumulative-volume-delta style order-flow imbalance over a window of trades
+/// Each trade is (amount_in, is_buy); returns (buy_volume - sell_volume) / total_volume,
+/// from -1 (all sells) to 1 (all buys), or 0 for an empty window
+pub fn order_flow_imbalance(trades: &[(f64, bool)]) -> f64 {
+    let mut net_flow = 0.0;
+    let mut total_volume = 0.0;
+
+    for (amount_in, is_buy) in trades {
+        if *amount_in <= 0.0 {
+            continue;
+        }
+        total_volume += amount_in;
+        if *is_buy {
+            net_flow += amount_in;
+        } else {
+            net_flow -= amount_in;
+        }
+    }
+
+    if total_volume > 0.0 {
+        net_flow / total_volume
+    } else {
+        0.0
+    }
+}
+
+/// Configuration parameters for arbitrage execution
+pub struct ArbitrageConfig {
+    pub gas_cost: f64,
+    pub flashloan_fee_pct: f64,
+    pub min_price_diff_pct: f64,
+    pub max_twap_deviation_pct: f64,
+    pub min_profit_threshold: f64,
+    /// Maximum slippage allowed on either leg at the optimal size (f64::INFINITY disables the check)
+    pub max_slippage_pct: f64,
+    /// When set, evaluations also report profit weighted by the chance of winning the race
+    pub competition: Option<CompetitionModel>,
+    /// Whether the reserve-fraction sizing cap may be exceeded (and flagged) when it binds
+    pub cap_mode: CapMode,
+    /// Profit given up per point of combined leg slippage when sizing (0 = pure profit-max)
+    pub slippage_penalty_weight: f64,
+    /// Smallest size worth sending; a positive optimum below it is skipped as dust
+    pub min_trade_size: f64,
+    /// When set, gas is priced from live gas and native-token prices instead of gas_cost
+    pub gas_pricing: Option<GasPricing>,
+}
+
+impl ArbitrageConfig {
+    /// Gas cost in the profit token: from gas_pricing when set, otherwise the flat gas_cost
+    pub fn resolved_gas_cost(&self) -> f64 {
+        self.gas_pricing
+            .map_or(self.gas_cost, |pricing| pricing.gas_cost())
+    }
+}
+
+/// Gas paid in the chain's native token (ETH, MATIC, BNB), converted to the profit token
+/// Sharing one native price across configs keeps every opportunity's gas accounting
+/// consistent when that price moves
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GasPricing {
+    pub gas_units: f64,
+    pub gas_price_gwei: f64,
+    /// Price of one native token in the quote (profit) token
+    pub native_price_quote_token: f64,
+}
+
+impl GasPricing {
+    /// Formula: gas_cost = gas_units × gas_price_gwei × 1e-9 × native_price_quote_token
+    pub fn gas_cost(&self) -> f64 {
+        self.gas_units * self.gas_price_gwei * 1e-9 * self.native_price_quote_token
+    }
+}
+
+/// How sizing treats its reserve-fraction cap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapMode {
+    /// Never exceed the cap
+    Hard,
+    /// Return the true optimum beyond the cap, flagged with `exceeds_soft_cap`
+    Soft,
+}
+
+impl Default for ArbitrageConfig {
+    /// Zero costs and thresholds with the optional checks disabled
+    fn default() -> Self {
+        ArbitrageConfig {
+            gas_cost: 0.0,
+            flashloan_fee_pct: 0.0,
+            min_price_diff_pct: 0.0,
+            max_twap_deviation_pct: f64::INFINITY,
+            min_profit_threshold: 0.0,
+            max_slippage_pct: f64::INFINITY,
+            competition: None,
+            cap_mode: CapMode::Hard,
+            slippage_penalty_weight: 0.0,
+            min_trade_size: 0.0,
+            gas_pricing: None,
+        }
+    }
+}
+
+/// Expected competition for an opportunity, used to derive landing probability
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompetitionModel {
+    pub competitor_count: u32,
+    pub our_edge_pct: f64,
+}
+
+/// Reason code attached to an evaluated opportunity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    Accepted = 0,
+    NoOpportunity = 1,
+    BelowMinProfit = 2,
+    SlippageExceeded = 3,
+    /// The buy pool's price strayed too far from its TWAP (possible manipulation)
+    TwapRejected = 4,
+    /// Even the best size loses money once gas and the flashloan fee are paid
+    NegativeAfterGas = 5,
+    /// The reserve cap held the size below the optimum and the capped trade falls short
+    LiquidityConstrained = 6,
+    /// The optimal size is positive but under min_trade_size
+    BelowMinSize = 7,
+}
+
+/// Result of evaluating a single two-pool opportunity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpportunityEvaluation {
+    pub should_execute: bool,
+    pub optimal_amount: f64,
+    pub expected_profit: f64,
+    /// Worse of the buy and sell leg slippage at the optimal size
+    pub max_leg_slippage_pct: f64,
+    pub reject_reason: RejectReason,
+    /// expected_profit × landing probability, present when a `CompetitionModel` is configured
+    pub expected_value: Option<f64>,
+    /// optimal_amount lies beyond the reserve cap, which only happens in `CapMode::Soft`
+    pub exceeds_soft_cap: bool,
+}
+
+impl OpportunityEvaluation {
+    fn rejected(reject_reason: RejectReason) -> Self {
+        OpportunityEvaluation {
+            should_execute: false,
+            optimal_amount: 0.0,
+            expected_profit: 0.0,
+            max_leg_slippage_pct: 0.0,
+            reject_reason,
+            expected_value: None,
+            exceeds_soft_cap: false,
+        }
+    }
+}
+
+/// Outcome of `execute_arbitrage_flow`; a flow that stops before sizing is all zeros
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FlowDecision {
+    pub should_execute: bool,
+    pub optimal_amount: f64,
+    pub expected_profit: f64,
+    /// optimal_amount lies beyond the reserve cap, which only happens in `CapMode::Soft`
+    pub exceeds_soft_cap: bool,
+}
+
+/// Step 7: Complete arbitrage execution flow
+pub fn execute_arbitrage_flow(
+    pool1_reserve_in: f64,
+    pool1_reserve_out: f64,
+    pool2_reserve_in: f64,
+    pool2_reserve_out: f64,
+    price_samples_pool1: &[(f64, f64)],
+    price_samples_pool2: &[(f64, f64)],
+    config: &ArbitrageConfig,
+) -> FlowDecision {
+    run_arbitrage_flow(
+        (pool1_reserve_in, pool1_reserve_out),
+        (pool2_reserve_in, pool2_reserve_out),
+        price_samples_pool1,
+        price_samples_pool2,
+        config,
+        &mut |_| {},
+    )
+}
+
+/// Intermediate value recorded by `execute_arbitrage_flow_traced`
+/// Names, in flow order: price_diff_pct, direction (0/1/2), current_price, twap,
+/// optimal_amount, exceeds_soft_cap (1/0), expected_profit, should_execute (1/0); a flow
+/// that stops early records only the steps it reached
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceStep {
+    pub name: &'static str,
+    pub value: f64,
+}
+
+/// `execute_arbitrage_flow` that also returns each step's output, for diagnosing a decision
+/// The untraced flow shares the same code with a no-op recorder, so it pays nothing
+pub fn execute_arbitrage_flow_traced(
+    pool1_reserves: (f64, f64),
+    pool2_reserves: (f64, f64),
+    price_samples_pool1: &[(f64, f64)],
+    price_samples_pool2: &[(f64, f64)],
+    config: &ArbitrageConfig,
+) -> (FlowDecision, Vec<TraceStep>) {
+    let mut steps = Vec::new();
+    let result = run_arbitrage_flow(
+        pool1_reserves,
+        pool2_reserves,
+        price_samples_pool1,
+        price_samples_pool2,
+        config,
+        &mut |step| steps.push(step),
+    );
+    (result, steps)
+}
+
+fn run_arbitrage_flow(
+    (pool1_reserve_in, pool1_reserve_out): (f64, f64),
+    (pool2_reserve_in, pool2_reserve_out): (f64, f64),
+    price_samples_pool1: &[(f64, f64)],
+    price_samples_pool2: &[(f64, f64)],
+    config: &ArbitrageConfig,
+    record: &mut impl FnMut(TraceStep),
+) -> FlowDecision {
+    let mut trace = |name, value| record(TraceStep { name, value });
+
+    // Step 1 & 2: Identify arbitrage opportunity
+    let (has_opportunity, price_diff, direction) = identify_arbitrage_opportunity(
+        pool1_reserve_in,
+        pool1_reserve_out,
+        pool2_reserve_in,
+        pool2_reserve_out,
+        config.min_price_diff_pct,
+    );
+    trace("price_diff_pct", price_diff);
+    trace("direction", direction as f64);
+
+    if !has_opportunity {
+        return FlowDecision::default();
+    }
+
+    // Determine buy and sell pools based on direction
+    let (buy_res_in, buy_res_out, sell_res_in, sell_res_out, price_samples) = if direction == 1 {
+        (
+            pool1_reserve_in,
+            pool1_reserve_out,
+            pool2_reserve_in,
+            pool2_reserve_out,
+            price_samples_pool1,
+        )
+    } else {
+        (
+            pool2_reserve_in,
+            pool2_reserve_out,
+            pool1_reserve_in,
+            pool1_reserve_out,
+            price_samples_pool2,
+        )
+    };
+
+    // Step 6: Validate with TWAP
+    let current_price = calculate_pool_price(buy_res_in, buy_res_out);
+    let twap = calculate_twap(price_samples);
+    trace("current_price", current_price);
+    trace("twap", twap);
+
+    if !validate_with_twap(current_price, twap, config.max_twap_deviation_pct) {
+        return FlowDecision::default();
+    }
+
+    // Step 5: Optimize trade size
+    let (optimal_amount, exceeds_soft_cap) = size_with_cap_mode(
+        (buy_res_in, buy_res_out),
+        (sell_res_in, sell_res_out),
+        config.resolved_gas_cost(),
+        config.flashloan_fee_pct,
+        config.cap_mode,
+        config.slippage_penalty_weight,
+        SIZE_GRID_POINTS,
+    );
+    trace("optimal_amount", optimal_amount);
+    trace("exceeds_soft_cap", if exceeds_soft_cap { 1.0 } else { 0.0 });
+
+    if optimal_amount <= 0.0 {
+        return FlowDecision::default();
+    }
+
+    // Step 4: Estimate profitability
+    let expected_profit = estimate_arbitrage_profit(
+        buy_res_in,
+        buy_res_out,
+        sell_res_in,
+        sell_res_out,
+        optimal_amount,
+        config.resolved_gas_cost(),
+        config.flashloan_fee_pct,
+    );
+
+    trace("expected_profit", expected_profit);
+
+    // Step 7: Execute if profitable and not dust
+    let should_execute =
+        optimal_amount >= config.min_trade_size && expected_profit >= config.min_profit_threshold;
+    trace("should_execute", if should_execute { 1.0 } else { 0.0 });
+
+    FlowDecision {
+        should_execute,
+        optimal_amount,
+        expected_profit,
+        exceeds_soft_cap,
+    }
+}
+
+/// Batch process multiple arbitrage opportunities for optimized performance
+/// Opportunities whose optimal-size slippage on either leg exceeds `max_slippage_pct`
+/// are not executed and carry `RejectReason::SlippageExceeded`; a positive optimum under
+/// `min_trade_size` carries `RejectReason::BelowMinSize`
+pub fn batch_evaluate_opportunities(
+    opportunities: &[(f64, f64, f64, f64)], // (pool1_res_in, pool1_res_out, pool2_res_in, pool2_res_out)
+    config: &ArbitrageConfig,
+) -> Vec<OpportunityEvaluation> {
+    opportunities
+        .iter()
+        .map(|opportunity| evaluate_opportunity(opportunity, config))
+        .collect()
+}
+
+/// `batch_evaluate_opportunities` spread across the worker threads of the pool set by
+/// `configure_threads` (rayon's global pool until one is configured)
+/// Each opportunity is evaluated independently, so results match the sequential batch in
+/// the same order; worth it for large batches (tens of thousands of pairs per block)
+pub fn batch_evaluate_opportunities_parallel(
+    opportunities: &[(f64, f64, f64, f64)],
+    config: &ArbitrageConfig,
+) -> Vec<OpportunityEvaluation> {
+    run_parallel(|| {
+        opportunities
+            .par_iter()
+            .map(|opportunity| evaluate_opportunity(opportunity, config))
+            .collect()
+    })
+}
+
+/// One entry of `batch_evaluate_opportunities`
+fn evaluate_opportunity(
+    opportunity: &(f64, f64, f64, f64),
+    config: &ArbitrageConfig,
+) -> OpportunityEvaluation {
+    evaluate_opportunity_with_grid(opportunity, config, SIZE_GRID_POINTS)
+}
+
+/// `evaluate_opportunity` sizing on a grid of `grid_points` sizes
+fn evaluate_opportunity_with_grid(
+    (p1_in, p1_out, p2_in, p2_out): &(f64, f64, f64, f64),
+    config: &ArbitrageConfig,
+    grid_points: usize,
+) -> OpportunityEvaluation {
+    let (has_opp, _, direction) =
+        identify_arbitrage_opportunity(*p1_in, *p1_out, *p2_in, *p2_out, config.min_price_diff_pct);
+
+    if !has_opp {
+        let mut rejected = OpportunityEvaluation::rejected(RejectReason::NoOpportunity);
+        rejected.expected_value = config.competition.map(|_| 0.0);
+        return rejected;
+    }
+
+    let (buy_res_in, buy_res_out, sell_res_in, sell_res_out) = if direction == 1 {
+        (*p1_in, *p1_out, *p2_in, *p2_out)
+    } else {
+        (*p2_in, *p2_out, *p1_in, *p1_out)
+    };
+
+    let (optimal_amount, exceeds_soft_cap) = size_with_cap_mode(
+        (buy_res_in, buy_res_out),
+        (sell_res_in, sell_res_out),
+        config.resolved_gas_cost(),
+        config.flashloan_fee_pct,
+        config.cap_mode,
+        config.slippage_penalty_weight,
+        grid_points,
+    );
+
+    let profit = estimate_arbitrage_profit(
+        buy_res_in,
+        buy_res_out,
+        sell_res_in,
+        sell_res_out,
+        optimal_amount,
+        config.resolved_gas_cost(),
+        config.flashloan_fee_pct,
+    );
+
+    // Realized slippage on each leg at the optimal size
+    let buy_slippage = compute_uniswap_v2_slippage(buy_res_in, buy_res_out, optimal_amount);
+    let amount_out_buy = calculate_amount_out(buy_res_in, buy_res_out, optimal_amount);
+    let sell_slippage = compute_uniswap_v2_slippage(sell_res_in, sell_res_out, amount_out_buy);
+    let max_leg_slippage_pct = buy_slippage.max(sell_slippage);
+
+    let reject_reason = if optimal_amount > 0.0 && optimal_amount < config.min_trade_size {
+        RejectReason::BelowMinSize
+    } else if max_leg_slippage_pct > config.max_slippage_pct {
+        RejectReason::SlippageExceeded
+    } else if profit < config.min_profit_threshold {
+        RejectReason::BelowMinProfit
+    } else {
+        RejectReason::Accepted
+    };
+
+    OpportunityEvaluation {
+        should_execute: reject_reason == RejectReason::Accepted,
+        optimal_amount,
+        expected_profit: profit,
+        max_leg_slippage_pct,
+        reject_reason,
+        expected_value: config
+            .competition
+            .map(|model| optimal_entry(profit, model.competitor_count, model.our_edge_pct)),
+        exceeds_soft_cap,
+    }
+}
+
+/// Cheap pre-pass for `batch_evaluate_opportunities`: an upper bound on how many opportunities
+/// could clear gas, the flashloan fee and min_profit_threshold, from the uncapped optimum
+pub fn viable_count(opportunities: &[(f64, f64, f64, f64)], config: &ArbitrageConfig) -> usize {
+    opportunities
+        .iter()
+        .filter(|(p1_in, p1_out, p2_in, p2_out)| {
+            let (has_opp, _, direction) = identify_arbitrage_opportunity(
+                *p1_in,
+                *p1_out,
+                *p2_in,
+                *p2_out,
+                config.min_price_diff_pct,
+            );
+            if !has_opp {
+                return false;
+            }
+
+            let (buy_in, buy_out, sell_in, sell_out) = if direction == 1 {
+                (*p1_in, *p1_out, *p2_in, *p2_out)
+            } else {
+                (*p2_in, *p2_out, *p1_in, *p1_out)
+            };
+            let optimum = two_leg_optimum_with_cost(
+                buy_in,
+                buy_out,
+                sell_in,
+                sell_out,
+                DEFAULT_FEE_BPS,
+                1.0 + config.flashloan_fee_pct,
+            );
+            let best_profit = estimate_arbitrage_profit(
+                buy_in,
+                buy_out,
+                sell_in,
+                sell_out,
+                optimum,
+                config.resolved_gas_cost(),
+                config.flashloan_fee_pct,
+            );
+            optimum > 0.0 && best_profit > 0.0 && best_profit >= config.min_profit_threshold
+        })
+        .count()
+}
+
+/// `batch_evaluate_opportunities` result under a time budget
+#[derive(Debug, Clone, PartialEq)]
+pub struct BudgetedBatch {
+    pub evaluations: Vec<OpportunityEvaluation>,
+    /// Mean sizing grid points scored per opportunity (100 when the budget never binds)
+    pub avg_iterations: f64,
+}
+
+/// `batch_evaluate_opportunities` that shares a time budget across the batch
+/// Before each opportunity the sizing search gets the iterations the remaining time affords
+/// when split evenly over the remaining opportunities, at the per-iteration cost measured so
+/// far, between 10 and the usual 100. Late opportunities get coarser optima instead of none
+pub fn batch_evaluate_with_budget(
+    opportunities: &[(f64, f64, f64, f64)],
+    config: &ArbitrageConfig,
+    time_budget_secs: f64,
+) -> BudgetedBatch {
+    let start = Instant::now();
+    let mut evaluations = Vec::with_capacity(opportunities.len());
+    let mut total_iterations = 0;
+
+    for (index, opportunity) in opportunities.iter().enumerate() {
+        let elapsed = start.elapsed().as_secs_f64();
+        let remaining_secs = time_budget_secs - elapsed;
+        let grid_points = if remaining_secs <= 0.0 {
+            MIN_BUDGETED_GRID_POINTS
+        } else if total_iterations == 0 || elapsed <= 0.0 {
+            SIZE_GRID_POINTS
+        } else {
+            let secs_per_iteration = elapsed / total_iterations as f64;
+            let share = remaining_secs / (opportunities.len() - index) as f64;
+            ((share / secs_per_iteration) as usize)
+                .clamp(MIN_BUDGETED_GRID_POINTS, SIZE_GRID_POINTS)
+        };
+
+        evaluations.push(evaluate_opportunity_with_grid(
+            opportunity,
+            config,
+            grid_points,
+        ));
+        total_iterations += grid_points;
+    }
+
+    let avg_iterations = if evaluations.is_empty() {
+        0.0
+    } else {
+        total_iterations as f64 / evaluations.len() as f64
+    };
+    BudgetedBatch {
+        evaluations,
+        avg_iterations,
+    }
+}
+
+/// USD price per token id, for comparing profits denominated in different tokens
+/// Ids are matched case-insensitively, the way `Pool` compares tokens
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PriceTable {
+    usd_prices: HashMap<String, f64>,
+}
+
+impl PriceTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set or replace a token's USD price
+    pub fn set_price(&mut self, token_id: &str, usd_price: f64) {
+        self.usd_prices
+            .insert(token_id.to_ascii_lowercase(), usd_price);
+    }
+
+    pub fn usd_price(&self, token_id: &str) -> Option<f64> {
+        self.usd_prices.get(&token_id.to_ascii_lowercase()).copied()
+    }
+}
+
+/// Convert a profit denominated in `token_id` to USD; None when the token has no price
+/// Gas is already netted out of the engine's profits, so the result is the USD net profit
+pub fn to_usd(profit: f64, token_id: &str, table: &PriceTable) -> Option<f64> {
+    table
+        .usd_price(token_id)
+        .map(|usd_price| profit * usd_price)
+}
+
+/// `to_usd` over a `batch_evaluate_opportunities` result, where profit_tokens names the
+/// token each opportunity's expected_profit is in; the two must line up one-to-one
+pub fn batch_to_usd(
+    evaluations: &[OpportunityEvaluation],
+    profit_tokens: &[String],
+    table: &PriceTable,
+) -> Result<Vec<Option<f64>>, MathError> {
+    if profit_tokens.len() != evaluations.len() {
+        return Err(MathError::LengthMismatch {
+            name: "profit tokens",
+            expected: evaluations.len(),
+            actual: profit_tokens.len(),
+        });
+    }
+
+    Ok(evaluations
+        .iter()
+        .zip(profit_tokens)
+        .map(|(evaluation, token_id)| to_usd(evaluation.expected_profit, token_id, table))
+        .collect())
+}
+
+/// Opportunity for `evaluate_with_reasons`, with the price history each pool has
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    /// (pool1_res_in, pool1_res_out, pool2_res_in, pool2_res_out)
+    pub reserves: (f64, f64, f64, f64),
+    /// (timestamp, price) samples; an empty history skips the TWAP check for that pool
+    pub price_samples_pool1: Vec<(f64, f64)>,
+    pub price_samples_pool2: Vec<(f64, f64)>,
+}
+
+/// Verdict on a candidate with the first check it failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReasonedEvaluation {
+    pub should_execute: bool,
+    pub optimal_amount: f64,
+    pub profit: f64,
+    pub reject_reason: RejectReason,
+}
+
+/// Evaluate every candidate and tag each skipped one with the `RejectReason` of the first check
+/// it failed, in `execute_arbitrage_flow` order (price gap, TWAP, dust size, slippage, profit)
+pub fn evaluate_with_reasons(
+    candidates: &[Candidate],
+    config: &ArbitrageConfig,
+) -> Vec<ReasonedEvaluation> {
+    candidates
+        .iter()
+        .map(|candidate| {
+            let verdict = |reject_reason: RejectReason, optimal_amount: f64, profit: f64| {
+                ReasonedEvaluation {
+                    should_execute: reject_reason == RejectReason::Accepted,
+                    optimal_amount,
+                    profit,
+                    reject_reason,
+                }
+            };
+
+            let (p1_in, p1_out, p2_in, p2_out) = candidate.reserves;
+            let (has_opp, _, direction) = identify_arbitrage_opportunity(
+                p1_in,
+                p1_out,
+                p2_in,
+                p2_out,
+                config.min_price_diff_pct,
+            );
+            if !has_opp {
+                return verdict(RejectReason::NoOpportunity, 0.0, 0.0);
+            }
+            let (buy, sell, buy_samples) = if direction == 1 {
+                (
+                    (p1_in, p1_out),
+                    (p2_in, p2_out),
+                    &candidate.price_samples_pool1,
+                )
+            } else {
+                (
+                    (p2_in, p2_out),
+                    (p1_in, p1_out),
+                    &candidate.price_samples_pool2,
+                )
+            };
+
+            if !buy_samples.is_empty() {
+                let current_price = calculate_pool_price(buy.0, buy.1);
+                let twap = calculate_twap(buy_samples);
+                if !validate_with_twap(current_price, twap, config.max_twap_deviation_pct) {
+                    return verdict(RejectReason::TwapRejected, 0.0, 0.0);
+                }
+            }
+
+            let evaluation = evaluate_opportunity(&candidate.reserves, config);
+            let reject_reason = match evaluation.reject_reason {
+                RejectReason::BelowMinProfit => {
+                    let cap = (buy.0 * 0.3).min(sell.0 * 0.3);
+                    let optimum = two_leg_optimum_with_cost(
+                        buy.0,
+                        buy.1,
+                        sell.0,
+                        sell.1,
+                        DEFAULT_FEE_BPS,
+                        1.0 + config.flashloan_fee_pct,
+                    );
+                    if !evaluation.exceeds_soft_cap && optimum > cap {
+                        RejectReason::LiquidityConstrained
+                    } else if evaluation.expected_profit < 0.0 {
+                        RejectReason::NegativeAfterGas
+                    } else {
+                        RejectReason::BelowMinProfit
+                    }
+                }
+                reason => reason,
+            };
+
+            verdict(
+                reject_reason,
+                evaluation.optimal_amount,
+                evaluation.expected_profit,
+            )
+        })
+        .collect()
+}
+
+/// Sizing against a forecasted next-block state, checked against the state we can trade now
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ForecastEvaluation {
+    pub should_execute: bool,
+    /// Forecast-optimal size, capped at 30% of the current reserves of either pool
+    pub amount: f64,
+    /// Profit of `amount` if the forecasted reserves materialize
+    pub forecasted_profit: f64,
+    /// Profit of `amount` if the reserves stay where they are now (the conservative case)
+    pub current_profit: f64,
+}
+
+/// Size an opportunity on forecast reserves (no TWAP check) and price the trade on both states
+/// Executes only when the capped size still meets min_profit_threshold on the forecast
+pub fn evaluate_forecasted(
+    current_reserves: (f64, f64, f64, f64),
+    forecasted_reserves: (f64, f64, f64, f64),
+    config: &ArbitrageConfig,
+) -> ForecastEvaluation {
+    const SKIP: ForecastEvaluation = ForecastEvaluation {
+        should_execute: false,
+        amount: 0.0,
+        forecasted_profit: 0.0,
+        current_profit: 0.0,
+    };
+
+    let evaluation = evaluate_opportunity(&forecasted_reserves, config);
+    if evaluation.optimal_amount <= 0.0 {
+        return SKIP;
+    }
+
+    let (f1_in, f1_out, f2_in, f2_out) = forecasted_reserves;
+    let (_, _, direction) =
+        identify_arbitrage_opportunity(f1_in, f1_out, f2_in, f2_out, config.min_price_diff_pct);
+    // Orient a state as (buy_in, buy_out, sell_in, sell_out) for the forecast's direction
+    let orient = |(p1_in, p1_out, p2_in, p2_out): (f64, f64, f64, f64)| {
+        if direction == 1 {
+            (p1_in, p1_out, p2_in, p2_out)
+        } else {
+            (p2_in, p2_out, p1_in, p1_out)
+        }
+    };
+    let profit_on = |state: (f64, f64, f64, f64), amount: f64| {
+        let (buy_in, buy_out, sell_in, sell_out) = orient(state);
+        estimate_arbitrage_profit(
+            buy_in,
+            buy_out,
+            sell_in,
+            sell_out,
+            amount,
+            config.resolved_gas_cost(),
+            config.flashloan_fee_pct,
+        )
+    };
+
+    let (current_buy_in, _, current_sell_in, _) = orient(current_reserves);
+    let cap = (current_buy_in * 0.3).min(current_sell_in * 0.3).max(0.0);
+    let amount = evaluation.optimal_amount.min(cap);
+    if amount <= 0.0 {
+        return SKIP;
+    }
+
+    let forecasted_profit = profit_on(forecasted_reserves, amount);
+    ForecastEvaluation {
+        should_execute: evaluation.should_execute
+            && forecasted_profit >= config.min_profit_threshold,
+        amount,
+        forecasted_profit,
+        current_profit: profit_on(current_reserves, amount),
+    }
+}
+
+/// Indices of pools whose reserves moved between two snapshots
+/// A pool is dirty when any reserve changed by more than `epsilon` relative to the larger
+/// value, when its row shape changed, or when it exists in only one snapshot
+pub fn diff_reserves(old: &[Vec<f64>], new: &[Vec<f64>], epsilon: f64) -> Vec<usize> {
+    let pool_count = old.len().max(new.len());
+
+    (0..pool_count)
+        .filter(|idx| match (old.get(*idx), new.get(*idx)) {
+            (Some(before), Some(after)) => {
+                before.len() != after.len()
+                    || before.iter().zip(after.iter()).any(|(a, b)| {
+                        let scale = a.abs().max(b.abs());
+                        (a - b).abs() > epsilon * scale
+                    })
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+/// Cheap first pass over a batch: keep only opportunities whose price gap clears the threshold
+/// Skips TWAP validation and size optimization; returns candidate indices in input order
+/// so callers can run the full `execute_arbitrage_flow` on the survivors only
+pub fn prefilter_opportunities(
+    opportunities: &[(f64, f64, f64, f64)],
+    min_price_diff_pct: f64,
+) -> Vec<usize> {
+    opportunities
+        .iter()
+        .enumerate()
+        .filter(|(_, (p1_in, p1_out, p2_in, p2_out))| {
+            identify_arbitrage_opportunity(*p1_in, *p1_out, *p2_in, *p2_out, min_price_diff_pct).0
+        })
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+/// Division-free price-gap check for the outermost loop over many pools
+/// Compares the cross-products p1_out·p2_in and p1_in·p2_out (each price is out/in), scaled by
+/// `min_ratio` (e.g. 1.01 for a 1% gap), in either direction; no fee math is applied
+/// Pools with non-positive reserves never report a gap
+pub fn quick_gap_exists(p1_in: f64, p1_out: f64, p2_in: f64, p2_out: f64, min_ratio: f64) -> bool {
+    if p1_in <= 0.0 || p1_out <= 0.0 || p2_in <= 0.0 || p2_out <= 0.0 {
+        return false;
+    }
+
+    let cross1 = p1_out * p2_in;
+    let cross2 = p1_in * p2_out;
+    cross1 >= cross2 * min_ratio || cross2 >= cross1 * min_ratio
+}
+
+/// Ordering applied by `batch_evaluate_and_rank`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Highest expected profit first
+    Profit,
+    /// Highest expected profit per unit of gas first; every opportunity in a batch shares
+    /// the config's gas cost, so for now this is the `Profit` ordering
+    ProfitPerGas,
+    /// Lowest worst-leg slippage first among executable opportunities, then the rest
+    /// (whose slippage is 0 or over the ceiling) by the same measure
+    Slippage,
+    /// Highest expected value first (expected profit when no competition model is set)
+    ExpectedValue,
+}
+
+/// Batch evaluation result tagged with its position in the input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RankedResult {
+    pub index: usize,
+    pub evaluation: OpportunityEvaluation,
+}
+
+/// Evaluate a batch of opportunities and rank them by `sort_key`
+/// Uses a stable sort, so ties keep their input order and the ranking is deterministic
+pub fn batch_evaluate_and_rank(
+    opportunities: &[(f64, f64, f64, f64)],
+    config: &ArbitrageConfig,
+    sort_key: SortKey,
+) -> Vec<RankedResult> {
+    let mut ranked: Vec<RankedResult> = batch_evaluate_opportunities(opportunities, config)
+        .into_iter()
+        .enumerate()
+        .map(|(index, evaluation)| RankedResult { index, evaluation })
+        .collect();
+
+    match sort_key {
+        SortKey::Profit | SortKey::ProfitPerGas => ranked.sort_by(|a, b| {
+            b.evaluation
+                .expected_profit
+                .total_cmp(&a.evaluation.expected_profit)
+        }),
+        SortKey::Slippage => ranked.sort_by(|a, b| {
+            b.evaluation
+                .should_execute
+                .cmp(&a.evaluation.should_execute)
+                .then(
+                    a.evaluation
+                        .max_leg_slippage_pct
+                        .total_cmp(&b.evaluation.max_leg_slippage_pct),
+                )
+        }),
+        SortKey::ExpectedValue => ranked.sort_by(|a, b| {
+            let value = |r: &RankedResult| {
+                r.evaluation
+                    .expected_value
+                    .unwrap_or(r.evaluation.expected_profit)
+            };
+            value(b).total_cmp(&value(a))
+        }),
+    }
+
+    ranked
+}
+
+/// Summary of predicted vs realized slippage for model calibration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationReport {
+    /// Mean of (predicted - realized); positive means predictions run high
+    pub bias: f64,
+    pub mae: f64,
+    pub rmse: f64,
+    /// Pearson correlation, 0 when either series has no variance
+    pub correlation: f64,
+}
+
+/// Leaf size of the fixed reduction tree behind `deterministic_sum`
+pub const REDUCTION_CHUNK: usize = 256;
+
+/// Reproducible sum for aggregate statistics over (possibly parallel) results
+/// Values are summed in order within fixed REDUCTION_CHUNK-sized leaves, and the leaf sums are
+/// combined by `combine_partial_sums`. The tree shape depends only on the number of values, so
+/// any thread count or schedule that reduces the same leaves gets a bit-identical result
+pub fn deterministic_sum(values: &[f64]) -> f64 {
+    let leaves: Vec<f64> = values
+        .chunks(REDUCTION_CHUNK)
+        .map(|chunk| chunk.iter().sum())
+        .collect();
+    combine_partial_sums(&leaves)
+}
+
+/// Combine per-leaf partial sums (in leaf order) with a balanced pairwise tree
+/// Parallel callers hand in one partial per REDUCTION_CHUNK leaf, never one per thread
+pub fn combine_partial_sums(partials: &[f64]) -> f64 {
+    match partials.len() {
+        0 => 0.0,
+        1 => partials[0],
+        len => {
+            let (left, right) = partials.split_at(len / 2);
+            combine_partial_sums(left) + combine_partial_sums(right)
+        }
+    }
+}
+
+/// Compare predicted and realized slippage from logged trades to track model drift
+/// Extra entries in the longer series are ignored
+pub fn calibration_report(predicted: &[f64], realized: &[f64]) -> CalibrationReport {
+    let n = predicted.len().min(realized.len());
+    if n == 0 {
+        return CalibrationReport {
+            bias: 0.0,
+            mae: 0.0,
+            rmse: 0.0,
+            correlation: 0.0,
+        };
+    }
+
+    let count = n as f64;
+    let (predicted, realized) = (&predicted[..n], &realized[..n]);
+    // Sum each statistic through the fixed reduction tree so reports are reproducible
+    let sum_of = |term: &dyn Fn(f64, f64) -> f64| {
+        let terms: Vec<f64> = predicted
+            .iter()
+            .zip(realized.iter())
+            .map(|(p, r)| term(*p, *r))
+            .collect();
+        deterministic_sum(&terms)
+    };
+
+    let error_sum = sum_of(&|p, r| p - r);
+    let abs_error_sum = sum_of(&|p, r| (p - r).abs());
+    let sq_error_sum = sum_of(&|p, r| (p - r) * (p - r));
+
+    let mean_predicted = deterministic_sum(predicted) / count;
+    let mean_realized = deterministic_sum(realized) / count;
+    let covariance = sum_of(&|p, r| (p - mean_predicted) * (r - mean_realized));
+    let var_predicted = sum_of(&|p, _| (p - mean_predicted) * (p - mean_predicted));
+    let var_realized = sum_of(&|_, r| (r - mean_realized) * (r - mean_realized));
+
+    let correlation = if var_predicted > 0.0 && var_realized > 0.0 {
+        covariance / (var_predicted.sqrt() * var_realized.sqrt())
+    } else {
+        0.0
+    };
+
+    CalibrationReport {
+        bias: error_sum / count,
+        mae: abs_error_sum / count,
+        rmse: (sq_error_sum / count).sqrt(),
+        correlation,
+    }
+}
+
+/// Backtest candidate `min_profit_threshold` values against (predicted, realized, gas_paid) logs
+/// Returns the most profitable threshold (higher on ties), INFINITY if all lose, 0 without history
+pub fn suggest_min_profit(historical: &[(f64, f64, f64)]) -> f64 {
+    let mut outcomes: Vec<(f64, f64)> = historical
+        .iter()
+        .filter(|(predicted, realized, gas)| {
+            predicted.is_finite() && realized.is_finite() && gas.is_finite()
+        })
+        .map(|(predicted, realized, gas)| (*predicted, realized - gas))
+        .collect();
+    if outcomes.is_empty() {
+        return 0.0;
+    }
+
+    // Sweep thresholds from the most selective down, admitting equal predictions together
+    outcomes.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut best_threshold = f64::INFINITY;
+    let mut best_net = 0.0;
+    let mut cumulative_net = 0.0;
+    let mut i = 0;
+    while i < outcomes.len() {
+        let threshold = outcomes[i].0;
+        while i < outcomes.len() && outcomes[i].0 == threshold {
+            cumulative_net += outcomes[i].1;
+            i += 1;
+        }
+        if cumulative_net > best_net {
+            best_net = cumulative_net;
+            best_threshold = threshold;
+        }
+    }
+
+    best_threshold
+}
+
+/// Run known-answer checks against the math engine
+/// Returns false if any result is off, e.g. when the native addon was mis-built for the platform
+pub fn self_test() -> bool {
+    let close = |actual: f64, expected: f64| (actual - expected).abs() <= expected.abs() * 1e-9;
+
+    if calculate_pool_price(1e6, 2e6) != 2.0 {
+        return false;
+    }
+    if !close(calculate_amount_out(1e6, 2e6, 1e4), 19743.160687941225) {
+        return false;
+    }
+    if solve_quadratic(1.0, -5.0, 6.0) != (3.0, 2.0) {
+        return false;
+    }
+    if !validate_with_twap(102.0, 100.0, 5.0) || validate_with_twap(110.0, 100.0, 5.0) {
+        return false;
+    }
+
+    // The 25% gap sizes to the top of the search grid under the 30%-of-reserve cap; the
+    // profit is the two 0.3%-fee swaps of that size less gas and the flashloan fee, worked
+    // out independently of the engine
+    let flow = canned_arbitrage_flow();
+    flow.should_execute
+        && close(flow.optimal_amount, 297000.0)
+        && close(flow.expected_profit, 485067.8828880374)
+}
+
+/// Canned end-to-end flow: pool 2 prices the token 25% higher than pool 1
+fn canned_arbitrage_flow() -> FlowDecision {
+    let samples = [(0.0, 2.0), (10.0, 2.05), (20.0, 2.1)];
+    let config = ArbitrageConfig {
+        gas_cost: 100.0,
+        flashloan_fee_pct: 0.0009,
+        min_price_diff_pct: 5.0,
+        max_twap_deviation_pct: 10.0,
+        min_profit_threshold: 50.0,
+        ..ArbitrageConfig::default()
+    };
+    execute_arbitrage_flow(1e6, 2e6, 1e6, 2.5e6, &samples, &samples, &config)
+}
+
+/// Throughput of the canned `execute_arbitrage_flow` workload
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchmarkResult {
+    pub ops_per_sec: f64,
+    pub avg_latency_ns: f64,
+}
+
+/// Time `iterations` runs of the canned flow used by `self_test`
+/// Meant for regression tracking and sanity-checking a build, not for precise profiling;
+/// zero iterations (or a run too fast for the clock) reports zeros
+pub fn benchmark(iterations: u32) -> BenchmarkResult {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(canned_arbitrage_flow());
+    }
+    let elapsed_ns = start.elapsed().as_nanos() as f64;
+
+    if iterations == 0 || elapsed_ns <= 0.0 {
+        return BenchmarkResult {
+            ops_per_sec: 0.0,
+            avg_latency_ns: 0.0,
+        };
+    }
+
+    BenchmarkResult {
+        ops_per_sec: iterations as f64 * 1e9 / elapsed_ns,
+        avg_latency_ns: elapsed_ns / iterations as f64,
+    }
+}
+
+/// Deterministic 16-hex-digit FNV-1a ID for the ordered pool ids, direction and block, which
+/// callers tag evaluation rows with; None for a direction other than 1 or 2
+pub fn opportunity_id(pool_ids: &[String], direction: u32, block_number: u64) -> Option<String> {
+    if direction != 1 && direction != 2 {
+        return None;
+    }
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    let mut hash = FNV_OFFSET_BASIS;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    };
+
+    for pool_id in pool_ids {
+        feed(pool_id.as_bytes());
+        // Separator so ["ab", "c"] and ["a", "bc"] hash differently
+        feed(&[0xff]);
+    }
+    feed(&[direction as u8]);
+    feed(&block_number.to_le_bytes());
+
+    Some(format!("{:016x}", hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_approx_eq() {
+        assert!(approx_eq(1.0, 1.0 + 1e-13, 1e-12, 0.0));
+        assert!(!approx_eq(1.0, 1.0 + 1e-11, 1e-12, 0.0));
+        // Near zero the absolute tolerance takes over
+        assert!(approx_eq(0.0, 1e-10, 1e-12, 1e-9));
+        assert!(approx_eq(f64::INFINITY, f64::INFINITY, 0.0, 0.0));
+        assert!(!approx_eq(f64::NAN, f64::NAN, 1.0, 1.0));
+    }
+
+    #[test]
+    fn test_uniswap_v2_slippage() {
+        let slippage = compute_uniswap_v2_slippage(1000000.0, 2000000.0, 10000.0);
+        assert!(slippage > 0.0);
+        assert!(slippage < 100.0);
+    }
+
+    #[test]
+    fn test_fee_free_pools() {
+        // With fee_bps = 0 output is the raw constant-product output
+        let raw = 2000000.0 * 10000.0 / (1000000.0 + 10000.0);
+        let amount_out = calculate_amount_out_with_fee(1000000.0, 2000000.0, 10000.0, 0);
+        assert!(approx_eq(amount_out, raw, 1e-12, 0.0));
+        assert!(amount_out > calculate_amount_out(1000000.0, 2000000.0, 10000.0));
+        assert_eq!(
+            calculate_amount_out_u128(1_000_000, 2_000_000, 10_000, 0),
+            Ok(19_801)
+        );
+
+        // Slippage is then pure price impact, amount_in / (reserve_in + amount_in)
+        let slippage = compute_uniswap_v2_slippage_with_fee(1000000.0, 2000000.0, 10000.0, 0);
+        assert!(approx_eq(slippage, 10000.0 / 1010000.0 * 100.0, 1e-12, 0.0));
+
+        let reserves = [(1000000.0, 2000000.0), (2000000.0, 1000000.0)];
+        let multihop = calculate_multihop_slippage_with_fee(&reserves, 10000.0, 0);
+        let second_hop = compute_uniswap_v2_slippage_with_fee(2000000.0, 1000000.0, raw, 0);
+        assert!(approx_eq(multihop, slippage + second_hop, 1e-12, 0.0));
+        assert_eq!(
+            calculate_multihop_slippage_with_fee(&reserves, 10000.0, DEFAULT_FEE_BPS),
+            calculate_multihop_slippage(&reserves, 10000.0)
+        );
+        assert_eq!(
+            calculate_multihop_amount_out_u128(
+                &[(1_000_000, 2_000_000), (2_000_000, 1_000_000)],
+                10_000,
+                0
+            ),
+            calculate_amount_out_u128(2_000_000, 1_000_000, 19_801, 0)
+        );
+    }
+
+    #[test]
+    fn test_zero_amount() {
+        let slippage = compute_uniswap_v2_slippage(1000000.0, 2000000.0, 0.0);
+        assert_eq!(slippage, 0.0);
+    }
+
+    #[test]
+    fn test_curve_slippage_vs_amp_monotonic() {
+        let amps = vec![1.0, 10.0, 50.0, 100.0, 500.0, 2000.0];
+        let slippages = curve_slippage_vs_amp(1000000.0, 1000000.0, 50000.0, &amps).unwrap();
+        assert_eq!(slippages.len(), amps.len());
+
+        // Balanced pools should see slippage fall as amplification rises
+        for pair in slippages.windows(2) {
+            assert!(pair[1] <= pair[0]);
+        }
+        assert!(slippages[0] > slippages[slippages.len() - 1]);
+    }
+
+    #[test]
+    fn test_solidly_stable_math() {
+        let (reserve_in, reserve_out, amount_in) = (1e6, 1.01e6, 1e4);
+
+        // The solved reserve keeps k, fee aside
+        let fee_free = solidly_get_amount_out(reserve_in, reserve_out, amount_in, 0).unwrap();
+        let k_before = solidly_k(reserve_in, reserve_out);
+        let k_after = solidly_k(reserve_in + amount_in, reserve_out - fee_free);
+        assert!(approx_eq(k_after, k_before, 1e-12, 0.0));
+
+        // The fee comes off the input
+        let with_fee = solidly_get_amount_out(reserve_in, reserve_out, amount_in, 5).unwrap();
+        let net_in = solidly_get_amount_out(reserve_in, reserve_out, amount_in * 0.9995, 0);
+        assert!(approx_eq(with_fee, net_in.unwrap(), 1e-12, 0.0));
+
+        // Near balance the stable curve barely moves: far less slippage than x·y = k
+        let stable = compute_solidly_stable_slippage(reserve_in, reserve_out, amount_in, 0);
+        let stable = stable.unwrap();
+        let constant_product =
+            compute_uniswap_v2_slippage_with_fee(reserve_in, reserve_out, amount_in, 0);
+        assert!(stable > 0.0);
+        assert!(stable < constant_product / 50.0);
+
+        // Slippage grows as the pool tips away from balance
+        let tipped = compute_solidly_stable_slippage(1e6, 3e6, amount_in, 0).unwrap();
+        assert!(tipped > stable);
+
+        assert_eq!(
+            solidly_get_amount_out(reserve_in, reserve_out, 0.0, 5),
+            Ok(0.0)
+        );
+        assert_eq!(
+            compute_solidly_stable_slippage(0.0, reserve_out, amount_in, 5),
+            Err(MathError::ZeroReserve {
+                name: "reserve_in",
+                value: 0.0
+            })
+        );
+        assert!(solidly_get_amount_out(reserve_in, reserve_out, -1.0, 5).is_err());
+        assert!(solidly_get_amount_out(reserve_in, reserve_out, amount_in, 10001).is_err());
+    }
+
+    #[test]
+    fn test_curve_get_dy() {
+        // 3pool-like balances (DAI, USDC, USDT) with its A and 4 bps fee
+        let balances = [120_000_000.0, 150_000_000.0, 90_000_000.0];
+        let dy = curve_get_dy(&balances, 0, 2, 100_000.0, 2000.0, 0.0004).unwrap();
+
+        // The fee-free output keeps D fixed
+        let d = stableswap_invariant(&balances, 2000.0).unwrap();
+        let moved = [
+            balances[0] + 100_000.0,
+            balances[1],
+            balances[2] - dy / (1.0 - 0.0004),
+        ];
+        assert!(approx_eq(
+            stableswap_invariant(&moved, 2000.0).unwrap(),
+            d,
+            1e-12,
+            0.0
+        ));
+
+        // Near peg at high A a trade loses little beyond the fee, and the cached quote agrees
+        let rate = dy / 100_000.0;
+        assert!(rate < 1.0 - 0.0004 && rate > 0.999);
+        let mut state = PoolState::new();
+        let cached = state
+            .stableswap_quote("3pool", &balances, 2000.0, (0, 2), 100_000.0, 0.0004)
+            .unwrap();
+        assert!(approx_eq(cached, dy, 1e-12, 0.0));
+
+        // Two-coin pools work the same way
+        let two_coin = curve_get_dy(&[1_000_000.0, 1_000_000.0], 1, 0, 1000.0, 100.0, 0.0).unwrap();
+        assert!(approx_eq(two_coin, 1000.0, 1e-5, 0.0));
+
+        assert!(curve_get_dy(&balances, 0, 0, 1000.0, 2000.0, 0.0004).is_err());
+        assert!(curve_get_dy(&balances, 0, 1, 1000.0, 2000.0, 1.0).is_err());
+        assert!(curve_get_dy(&balances, 0, 1, -1.0, 2000.0, 0.0004).is_err());
+    }
+
+    #[test]
+    fn test_stableswap_invariant_and_spot_price() {
+        // A balanced pool's invariant is the plain sum and it trades 1:1 at the margin
+        let balanced = [1000000.0, 1000000.0, 1000000.0];
+        let d = stableswap_invariant(&balanced, 100.0).unwrap();
+        assert!(approx_eq(d, 3000000.0, 1e-12, 0.0));
+        assert!(approx_eq(
+            stableswap_spot_price(&balanced, 100.0, 0, 1).unwrap(),
+            1.0,
+            1e-12,
+            0.0
+        ));
+
+        // get_y keeps the invariant: a small swap returns nearly 1:1 at high A
+        let y = stableswap_get_y(&balanced, 100.0, 0, 1, 1001000.0, d).unwrap();
+        let dy = 1000000.0 - y;
+        assert!(dy < 1000.0 && dy > 999.0);
+
+        assert_eq!(
+            stableswap_invariant(&balanced, 0.0),
+            Err(MathError::InvalidParameter {
+                name: "amplification",
+                value: 0.0
+            })
+        );
+    }
+
+    #[test]
+    fn test_stable_peg_arb() {
+        // Coin 0 is oversupplied, so the pool pays more than 1:1 for it
+        let balances = [1300000.0, 800000.0, 900000.0];
+        let arb = stable_peg_arb(&balances, 50.0, 1.0, 0.05, 4, 10.0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(arb.coin_out, 0);
+        assert_eq!(arb.coin_in, 1);
+        assert!(arb.deviation_pct > 0.05);
+        assert!(arb.amount_out > arb.amount_in);
+        assert!(arb.expected_profit > 0.0);
+
+        // The chosen size beats nearby sizes before gas
+        let d = stableswap_invariant(&balances, 50.0).unwrap();
+        let edge_at = |amount_in: f64| {
+            let y = stableswap_get_y(&balances, 50.0, 1, 0, 800000.0 + amount_in, d).unwrap();
+            (1300000.0 - y) * fee_multiplier(4) - amount_in
+        };
+        assert!(edge_at(arb.amount_in) >= edge_at(arb.amount_in * 0.9));
+        assert!(edge_at(arb.amount_in) >= edge_at(arb.amount_in * 1.1));
+
+        // A balanced pool or a higher threshold reports nothing
+        let balanced = [1000000.0, 1000000.0];
+        assert_eq!(stable_peg_arb(&balanced, 50.0, 1.0, 0.01, 4, 0.0), Ok(None));
+        assert_eq!(
+            stable_peg_arb(&balances, 50.0, 1.0, arb.deviation_pct + 1.0, 4, 0.0),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_non_convergent_reports_invariant_residual() {
+        // A balance at the bottom of the f64 range stalls the D iteration before it settles
+        let balances = [1e-300, 1.0];
+        let Err(MathError::NonConvergent { residual }) = stableswap_invariant(&balances, 1.0)
+        else {
+            panic!("expected the D solve to hit its iteration cap");
+        };
+        assert!(residual.is_finite() && residual > 0.0);
+        // The last step is ~1e-46, yet D is nowhere near satisfying the invariant
+        assert!(residual > 1e-20);
+
+        // A near-constant-sum pool leaves y oscillating in its last bits
+        let balances = [1.0, 1.0000001];
+        let d = stableswap_invariant(&balances, 1e12).unwrap();
+        match stableswap_get_y(&balances, 1e12, 0, 1, 2.0, d) {
+            Err(MathError::NonConvergent { residual }) => {
+                assert!(residual.is_finite());
+                assert!(MathError::NonConvergent { residual }
+                    .to_string()
+                    .contains("residual"));
+            }
+            other => panic!(
+                "expected the y solve to hit its iteration cap, got {:?}",
+                other
+            ),
+        }
+    }
+
+    #[test]
+    fn test_balancer_rejects_unnormalized_weights() {
+        // 80/20 style weights passed as raw numbers instead of fractions
+        assert_eq!(
+            compute_balancer_slippage(1000000.0, 1000000.0, 80.0, 20.0, 10000.0),
+            Err(MathError::InvalidParameter {
+                name: "weight_in",
+                value: 80.0
+            })
+        );
+        assert_eq!(
+            compute_balancer_slippage(1000000.0, 1000000.0, 0.5, 0.0, 10000.0),
+            Err(MathError::InvalidParameter {
+                name: "weight_out",
+                value: 0.0
+            })
+        );
+        assert_eq!(
+            compute_balancer_slippage(1000000.0, 1000000.0, 0.8, 0.8, 10000.0),
+            Err(MathError::InvalidParameter {
+                name: "weight_in + weight_out",
+                value: 1.6
+            })
+        );
+
+        let slippage = compute_balancer_slippage(1000000.0, 1000000.0, 0.25, 0.25, 10000.0);
+        assert!(slippage.unwrap() > 0.0);
+    }
+
+    #[test]
+    fn test_curve_rejects_nonpositive_amplification() {
+        for amp in [0.0, -10.0, f64::NAN] {
+            let result = compute_curve_slippage(1000000.0, 1000000.0, 10000.0, amp);
+            assert!(matches!(
+                result,
+                Err(MathError::InvalidParameter {
+                    name: "amplification",
+                    ..
+                })
+            ));
+        }
+        assert!(curve_slippage_vs_amp(1000000.0, 1000000.0, 10000.0, &[10.0, 0.0]).is_err());
+    }
+
+    #[test]
+    fn test_balancer_pool_quote() {
+        let pool = BalancerPool {
+            balances: vec![1000000.0, 2000000.0, 500000.0],
+            weights: vec![0.5, 0.3, 0.2],
+            swap_fee_bps: 30,
+        };
+        assert!(pool.is_valid());
+
+        let amount_out = pool.quote(0, 1, 1000.0);
+        // Spot price is (2_000_000 / 0.3) / (1_000_000 / 0.5) = 3.33 token1 per token0
+        assert!(amount_out > 3300.0 && amount_out < 3333.4);
+
+        assert_eq!(pool.quote(0, 0, 1000.0), 0.0);
+        assert_eq!(pool.quote(0, 3, 1000.0), 0.0);
+
+        let unnormalized = BalancerPool {
+            balances: vec![1000000.0, 2000000.0, 500000.0],
+            weights: vec![0.5, 0.5, 0.5],
+            swap_fee_bps: 30,
+        };
+        assert!(!unnormalized.is_valid());
+        assert_eq!(unnormalized.quote(0, 1, 1000.0), 0.0);
+    }
+
+    #[test]
+    fn test_balancer_spot_price_cross_protocol() {
+        // 80/20 pool: 800 A at weight 0.8 and 200 B at weight 0.2 is priced 1:1
+        assert!((balancer_spot_price(800.0, 200.0, 0.8, 0.2) - 1.0).abs() < 1e-12);
+
+        let weighted = PoolSpec::Balancer {
+            balance_in: 800000.0,
+            balance_out: 200000.0,
+            weight_in: 0.8,
+            weight_out: 0.2,
+        };
+        let v2 = PoolSpec::V2 {
+            reserve_in: 1000000.0,
+            reserve_out: 1100000.0,
+        };
+
+        // Reserve ratio alone would call the weighted pool 0.25 and flag a huge gap
+        let (has_opp, diff, direction) = identify_cross_protocol_opportunity(&weighted, &v2, 5.0);
+        assert!(has_opp);
+        assert!((diff - 10.0).abs() < 1e-9);
+        assert_eq!(direction, 1);
+    }
+
+    #[test]
+    fn test_aggregator_best() {
+        let pools = [
+            PoolSpec::V2 {
+                reserve_in: 1000000.0,
+                reserve_out: 1000000.0,
+            },
+            PoolSpec::Curve {
+                balance_in: 1000000.0,
+                balance_out: 1000000.0,
+                amplification: 100.0,
+            },
+            // Invalid weights would report 0 slippage; it must not be picked
+            PoolSpec::Balancer {
+                balance_in: 1000000.0,
+                balance_out: 1000000.0,
+                weight_in: 0.8,
+                weight_out: 0.8,
+            },
+        ];
+
+        let (best, slippage) = aggregator_best(&pools, 10000.0);
+        assert_eq!(best, 1);
+        assert_eq!(
+            slippage,
+            compute_curve_slippage(1000000.0, 1000000.0, 10000.0, 100.0).unwrap()
+        );
+        assert_eq!(aggregator_best(&[], 10000.0), (0, 0.0));
+    }
+
+    #[test]
+    fn test_marginal_price_starts_at_spot() {
+        let pools = [
+            PoolSpec::V2 {
+                reserve_in: 1000000.0,
+                reserve_out: 2000000.0,
+            },
+            PoolSpec::V3 {
+                liquidity: 1000000.0,
+                sqrt_price: 1.5,
+                zero_for_one: true,
+            },
+            PoolSpec::V3 {
+                liquidity: 1000000.0,
+                sqrt_price: 1.5,
+                zero_for_one: false,
+            },
+            PoolSpec::Curve {
+                balance_in: 1000000.0,
+                balance_out: 1100000.0,
+                amplification: 100.0,
+            },
+            PoolSpec::Balancer {
+                balance_in: 800000.0,
+                balance_out: 200000.0,
+                weight_in: 0.8,
+                weight_out: 0.2,
+            },
+        ];
+
+        for pool in &pools {
+            assert!(approx_eq(
+                pool.marginal_price(0.0),
+                pool.spot_price(),
+                1e-12,
+                0.0
+            ));
+            assert!(pool.marginal_price(50000.0) < pool.marginal_price(10000.0));
+
+            // Each is the derivative of the pool's fee-free output curve
+            let (lo, hi) = (9999.0, 10001.0);
+            let output = |amount: f64| match *pool {
+                PoolSpec::V2 {
+                    reserve_in,
+                    reserve_out,
+                } => reserve_out * amount / (reserve_in + amount),
+                PoolSpec::V3 {
+                    liquidity,
+                    sqrt_price,
+                    zero_for_one: true,
+                } => {
+                    liquidity
+                        * (sqrt_price - liquidity * sqrt_price / (liquidity + amount * sqrt_price))
+                }
+                PoolSpec::V3 {
+                    liquidity,
+                    sqrt_price,
+                    zero_for_one: false,
+                } => liquidity * (1.0 / sqrt_price - 1.0 / (sqrt_price + amount / liquidity)),
+                PoolSpec::Curve {
+                    balance_in,
+                    balance_out,
+                    amplification,
+                } => curve_get_dy(&[balance_in, balance_out], 0, 1, amount, amplification, 0.0)
+                    .unwrap(),
+                PoolSpec::Balancer {
+                    balance_in,
+                    balance_out,
+                    weight_in,
+                    weight_out,
+                } => {
+                    balance_out
+                        * (1.0 - (balance_in / (balance_in + amount)).powf(weight_in / weight_out))
+                }
+            };
+            let slope = (output(hi) - output(lo)) / (hi - lo);
+            assert!(approx_eq(pool.marginal_price(10000.0), slope, 1e-6, 0.0));
+        }
+
+        let empty = PoolSpec::V2 {
+            reserve_in: 0.0,
+            reserve_out: 1.0,
+        };
+        assert_eq!(empty.marginal_price(0.0), 0.0);
+    }
+
+    #[test]
+    fn test_best_aggregator_route_improvement() {
+        assert_eq!(routing_improvement(1.25, 0.5), 75.0);
+
+        let pools = [
+            PoolSpec::V2 {
+                reserve_in: 1000000.0,
+                reserve_out: 1000000.0,
+            },
+            PoolSpec::Curve {
+                balance_in: 1000000.0,
+                balance_out: 1000000.0,
+                amplification: 100.0,
+            },
+        ];
+        let route = best_aggregator_route(&pools, 10000.0, Some(0), 0);
+        assert_eq!(route.pool_index, 1);
+        let direct = pools[0].slippage(10000.0).unwrap();
+        assert_eq!(
+            route.improvement_bps,
+            Some(routing_improvement(direct, route.slippage_pct))
+        );
+        assert!(route.improvement_bps.unwrap() > 0.0);
+
+        assert_eq!(
+            best_aggregator_route(&pools, 10000.0, None, 0).improvement_bps,
+            None
+        );
+        assert_eq!(
+            best_aggregator_route(&pools, 10000.0, Some(5), 0).improvement_bps,
+            None
+        );
+
+        // A router cut larger than the edge makes the direct pool the better execution
+        let edge_bps = route.improvement_bps.unwrap();
+        let routed = best_aggregator_route(&pools, 10000.0, Some(0), 100);
+        assert_eq!(routed.pool_index, 1);
+        let net = 100.0 - (100.0 - route.slippage_pct) * 0.99;
+        assert!((routed.slippage_pct - net).abs() < 1e-12);
+        assert!(routed.improvement_bps.unwrap() < edge_bps);
+        assert!(routed.improvement_bps.unwrap() < 0.0);
+    }
+
+    #[test]
+    fn test_optimal_trade_size() {
+        let size = optimal_trade_size(1000000.0, 2000000.0, 100.0, 50.0);
+        assert!(size > 0.0);
+    }
+
+    #[test]
+    fn test_sizing_utilization_respects_caps() {
+        let (size, utilization) =
+            optimal_trade_size_with_utilization(1000000.0, 2000000.0, 100.0, 50.0);
+        assert_eq!(utilization, size / 1000000.0 * 100.0);
+        assert!(utilization > 0.0 && utilization <= 10.0);
+
+        let (amount, utilization) = calculate_flashloan_amount_with_utilization(
+            1000000.0, 2200000.0, 2000000.0, 1000000.0, 0.0009, 10.0,
+        );
+        assert_eq!(utilization, utilization_pct(amount, 1000000.0));
+        assert!(utilization > 0.0 && utilization <= 30.0);
+
+        let (size, utilization) = optimize_trade_size_quadratic_with_utilization(
+            1000000.0, 2200000.0, 2000000.0, 1000000.0, 10.0, 0.0009,
+        );
+        assert_eq!(utilization, utilization_pct(size, 1000000.0));
+        assert!(utilization > 0.0 && utilization <= 30.0);
+
+        assert_eq!(utilization_pct(100.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_v3_liquidity_in_range() {
+        let ticks = vec![
+            TickRange {
+                tick_lower: -600,
+                tick_upper: -60,
+                liquidity: 500000.0,
+            },
+            TickRange {
+                tick_lower: 60,
+                tick_upper: 600,
+                liquidity: 750000.0,
+            },
+        ];
+
+        // Price 1.0 is tick 0, which falls in the gap between the two ranges
+        assert!(!v3_has_liquidity_at(1.0, &ticks));
+        assert_eq!(
+            compute_uniswap_v3_slippage_in_range(1.0, &ticks, 1000.0, true),
+            Err(MathError::InsufficientLiquidity {
+                requested: 1000.0,
+                available: 0.0
+            })
+        );
+
+        let in_range = tick_to_sqrt_price(120);
+        assert_eq!(sqrt_price_to_tick(in_range), 120);
+        assert!(v3_has_liquidity_at(in_range, &ticks));
+        let slippage = compute_uniswap_v3_slippage_in_range(in_range, &ticks, 1000.0, true);
+        assert!(slippage.unwrap() > 0.0);
+
+        // Token1 in prices against the same active liquidity in the other direction
+        let token1_in = compute_uniswap_v3_slippage_in_range(in_range, &ticks, 1000.0, false);
+        assert_eq!(
+            token1_in,
+            Ok(compute_uniswap_v3_slippage_with_direction(
+                750000.0, in_range, 1000.0, false
+            ))
+        );
+        assert!(token1_in.unwrap() > 0.0);
+    }
+
+    #[test]
+    fn test_uniswap_v3_slippage_with_direction() {
+        let (liquidity, sqrt_price) = (1000000.0, 1.2);
+
+        // Token0 in matches the fee-free within-tick swap
+        let (amount_out, _) = v3_swap_within_tick(sqrt_price, liquidity, 1000.0, 0);
+        let expected = 1000.0 * sqrt_price * sqrt_price;
+        let slippage =
+            compute_uniswap_v3_slippage_with_direction(liquidity, sqrt_price, 1000.0, true);
+        assert!(approx_eq(
+            slippage,
+            (expected - amount_out) / expected * 100.0,
+            1e-9,
+            0.0
+        ));
+
+        // Token1 in is the same swap seen from the reciprocal price
+        let mirrored =
+            compute_uniswap_v3_slippage_with_direction(liquidity, 1.0 / sqrt_price, 1000.0, true);
+        let token1_in =
+            compute_uniswap_v3_slippage_with_direction(liquidity, sqrt_price, 1000.0, false);
+        assert!(approx_eq(token1_in, mirrored, 1e-9, 0.0));
+
+        // Slippage grows with size, and the deprecated shim is the token0-in direction
+        assert!(
+            compute_uniswap_v3_slippage_with_direction(liquidity, sqrt_price, 10000.0, true)
+                > slippage
+        );
+        #[allow(deprecated)]
+        let shim = compute_uniswap_v3_slippage(liquidity, sqrt_price, 1000.0);
+        assert_eq!(shim, slippage);
+        assert_eq!(
+            compute_uniswap_v3_slippage_with_direction(0.0, sqrt_price, 1000.0, false),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_v3_swap_within_tick() {
+        let (liquidity, sqrt_price) = (1000000.0, 1.2);
+        let (amount_out, sqrt_price_after) = v3_swap_within_tick(sqrt_price, liquidity, 1000.0, 30);
+
+        // Same as the constant-product curve on the range's virtual reserves
+        let hop = HopSpec::V3 {
+            liquidity,
+            sqrt_price,
+            zero_for_one: true,
+            fee_bps: 30,
+        };
+        assert!(approx_eq(amount_out, hop.amount_out(1000.0), 1e-12, 0.0));
+        assert!(sqrt_price_after < sqrt_price);
+
+        // A small trade from mid-tick in a deep range stays inside the current tick
+        let tick = sqrt_price_to_tick(sqrt_price);
+        let mid_tick = (tick_to_sqrt_price(tick) * tick_to_sqrt_price(tick + 1)).sqrt();
+        let (_, after_small) = v3_swap_within_tick(mid_tick, liquidity, 1.0, 30);
+        assert_eq!(sqrt_price_to_tick(after_small), tick);
+        // Token0 delta matches L·(1/√P_after - 1/√P) for the fee-adjusted input
+        let token0_in = liquidity * (1.0 / sqrt_price_after - 1.0 / sqrt_price);
+        assert!(approx_eq(token0_in, 1000.0 * fee_multiplier(30), 1e-9, 0.0));
+
+        assert_eq!(
+            v3_swap_within_tick(sqrt_price, 0.0, 1000.0, 30),
+            (0.0, sqrt_price)
+        );
+    }
+
+    #[test]
+    fn test_v3_swap_across_ticks() {
+        let range = |tick_lower, tick_upper, liquidity| TickRange {
+            tick_lower,
+            tick_upper,
+            liquidity,
+        };
+        let ranges = [range(-200, 200, 2e6), range(-2000, -200, 5e5)];
+        let gamma = fee_multiplier(30);
+
+        // Inside the first range it is the within-tick swap
+        let small = v3_swap_across_ticks(1.0, &ranges, 100.0, 30, true);
+        let (amount_out, sqrt_price_after) = v3_swap_within_tick(1.0, 2e6, 100.0, 30);
+        assert_eq!(small.amount_out, amount_out);
+        assert_eq!(small.sqrt_price_after, sqrt_price_after);
+        assert_eq!((small.amount_in_used, small.ticks_crossed), (100.0, 0));
+
+        // Past tick -200 the rest of the input swaps against the thinner range
+        let boundary = tick_to_sqrt_price(-200);
+        let to_boundary = 2e6 * (1.0 / boundary - 1.0) / gamma;
+        let crossing = v3_swap_across_ticks(1.0, &ranges, 40000.0, 30, true);
+        let rest = v3_swap_within_tick(boundary, 5e5, 40000.0 - to_boundary, 30);
+        assert_eq!(crossing.ticks_crossed, 1);
+        assert!(approx_eq(
+            crossing.amount_out,
+            2e6 * (1.0 - boundary) + rest.0,
+            1e-12,
+            0.0
+        ));
+        assert!(approx_eq(crossing.sqrt_price_after, rest.1, 1e-12, 0.0));
+        assert!(crossing.amount_out < v3_swap_within_tick(1.0, 2e6, 40000.0, 30).0);
+
+        // Token1 in pushes the price up: √P' = √P + x·γ / L
+        let up = v3_swap_across_ticks(1.0, &ranges, 100.0, 30, false);
+        let price_up = 1.0 + 100.0 * gamma / 2e6;
+        assert!(approx_eq(up.sqrt_price_after, price_up, 1e-12, 0.0));
+        assert!(approx_eq(
+            up.amount_out,
+            2e6 * (1.0 - 1.0 / price_up),
+            1e-12,
+            0.0
+        ));
+
+        // Past the last boundary there is nothing left to swap against
+        let lowest = tick_to_sqrt_price(-2000);
+        let capacity =
+            (2e6 * (1.0 / boundary - 1.0) + 5e5 * (1.0 / lowest - 1.0 / boundary)) / gamma;
+        let drained = v3_swap_across_ticks(1.0, &ranges, f64::INFINITY, 30, true);
+        assert!(approx_eq(drained.amount_in_used, capacity, 1e-12, 0.0));
+        assert_eq!(
+            (drained.sqrt_price_after, drained.ticks_crossed),
+            (lowest, 2)
+        );
+        let oversized = v3_swap_across_ticks(1.0, &ranges, 2.0 * capacity, 30, true);
+        assert!(approx_eq(oversized.amount_in_used, capacity, 1e-12, 0.0));
+
+        // A gap between ranges is crossed without output
+        let gapped = [range(-200, 200, 2e6), range(-2000, -400, 2e6)];
+        let across_gap = v3_swap_across_ticks(1.0, &gapped, 40000.0, 30, true);
+        let after_gap =
+            v3_swap_within_tick(tick_to_sqrt_price(-400), 2e6, 40000.0 - to_boundary, 30);
+        assert_eq!(across_gap.ticks_crossed, 2);
+        assert!(approx_eq(
+            across_gap.amount_out,
+            2e6 * (1.0 - boundary) + after_gap.0,
+            1e-12,
+            0.0
+        ));
+
+        assert_eq!(
+            v3_swap_across_ticks(1.0, &ranges, 0.0, 30, true).amount_out,
+            0.0
+        );
+        assert_eq!(
+            v3_swap_across_ticks(1.0, &[], 100.0, 30, true).amount_in_used,
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_calculate_flashloan_amount_v3_ticks() {
+        let pool = |sqrt_price, ranges: &[(i32, i32, f64)]| V3Pool {
+            sqrt_price,
+            ranges: ranges
+                .iter()
+                .map(|&(tick_lower, tick_upper, liquidity)| TickRange {
+                    tick_lower,
+                    tick_upper,
+                    liquidity,
+                })
+                .collect(),
+            fee_bps: 30,
+        };
+        let profit = |buy: &V3Pool, sell: &V3Pool, amount: f64| {
+            let bought = v3_swap_across_ticks(buy.sqrt_price, &buy.ranges, amount, 30, false);
+            let sold =
+                v3_swap_across_ticks(sell.sqrt_price, &sell.ranges, bought.amount_out, 30, true);
+            sold.amount_out + (amount - bought.amount_in_used) - amount * 1.0009 - 10.0
+        };
+
+        // token0 is 2% dearer on the sell pool
+        let buy = pool(1.0, &[(-6000, 6000, 1e7)]);
+        let sell = pool(1.01, &[(-6000, 6000, 1e7)]);
+        let size = calculate_flashloan_amount_v3_ticks(&buy, &sell, 0.0009, 10.0);
+        assert!(size > 0.0);
+        for nearby in [size * 0.99, size * 1.01] {
+            assert!(profit(&buy, &sell, nearby) <= profit(&buy, &sell, size));
+        }
+
+        // Thin liquidity beyond the next tick caps the size the single-range model would take
+        let thin = pool(1.0, &[(-6000, 50, 1e7), (50, 6000, 1e5)]);
+        let thin_size = calculate_flashloan_amount_v3_ticks(&thin, &sell, 0.0009, 10.0);
+        assert!(thin_size > 0.0 && thin_size < size);
+        let crossed = v3_swap_across_ticks(1.0, &thin.ranges, thin_size, 30, false);
+        assert!(crossed.ticks_crossed >= 1);
+        for nearby in [thin_size * 0.99, thin_size * 1.01] {
+            assert!(profit(&thin, &sell, nearby) <= profit(&thin, &sell, thin_size));
+        }
+
+        // No edge, or gas above the best profit, sizes to zero
+        assert_eq!(
+            calculate_flashloan_amount_v3_ticks(&sell, &buy, 0.0009, 10.0),
+            0.0
+        );
+        assert_eq!(
+            calculate_flashloan_amount_v3_ticks(&buy, &sell, 0.0009, 1e9),
+            0.0
+        );
+
+        // The single-liquidity entry is a full-range pool, so size scales with liquidity
+        let legacy = calculate_flashloan_amount_v3(5e6, 1.414, 1.732, 0.0009, 75.0);
+        assert!(legacy > 0.0);
+        assert!(approx_eq(
+            calculate_flashloan_amount_v3(1e7, 1.414, 1.732, 0.0009, 75.0),
+            2.0 * legacy,
+            1e-9,
+            0.0
+        ));
+        assert!(
+            calculate_flashloan_amount_v3(5e6, 1.0, 2.0, 0.0009, 75.0)
+                > calculate_flashloan_amount_v3(5e6, 1.0, 1.2, 0.0009, 75.0)
+        );
+        assert_eq!(
+            calculate_flashloan_amount_v3(0.0, 1.414, 1.732, 0.0009, 75.0),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_adjust_v3_liquidity() {
+        let sqrt_price = tick_to_sqrt_price(120);
+        assert!(v3_position_in_range(sqrt_price, 60, 600));
+        assert!(!v3_position_in_range(sqrt_price, -600, 120));
+
+        // A large in-range add deepens the pool and lowers slippage
+        let before = compute_uniswap_v3_slippage_with_direction(750000.0, sqrt_price, 1000.0, true);
+        let deeper = adjust_v3_liquidity(750000.0, 750000.0, true);
+        assert_eq!(deeper, 1500000.0);
+        assert!(
+            compute_uniswap_v3_slippage_with_direction(deeper, sqrt_price, 1000.0, true) < before
+        );
+
+        // Out-of-range positions leave active liquidity alone; removals floor at zero
+        assert_eq!(adjust_v3_liquidity(750000.0, 750000.0, false), 750000.0);
+        assert_eq!(adjust_v3_liquidity(750000.0, -1000000.0, true), 0.0);
+    }
+
+    #[test]
+    fn test_sqrt_price_to_tick_on_boundaries() {
+        // A price exactly on a tick is in that tick, just below it is in the one before
+        for tick in (-887_200..=887_200)
+            .step_by(997)
+            .chain([-1, 0, 1, 60, 120, 887_271])
+        {
+            let sqrt_price = tick_to_sqrt_price(tick);
+            assert_eq!(sqrt_price_to_tick(sqrt_price), tick);
+            assert_eq!(sqrt_price_to_tick(sqrt_price.next_down()), tick - 1);
+            let mid_tick = (sqrt_price * tick_to_sqrt_price(tick + 1)).sqrt();
+            assert_eq!(sqrt_price_to_tick(mid_tick), tick);
+        }
+    }
+
+    #[test]
+    fn test_round_to_tick_spacing() {
+        assert_eq!(round_to_tick_spacing(125, 60), 120);
+        assert_eq!(round_to_tick_spacing(151, 60), 180);
+        assert_eq!(round_to_tick_spacing(150, 60), 120);
+        assert_eq!(round_to_tick_spacing(-125, 60), -120);
+        assert_eq!(round_to_tick_spacing(-151, 60), -180);
+        assert_eq!(round_to_tick_spacing(MAX_TICK, 60), 887220);
+        assert_eq!(round_to_tick_spacing(MIN_TICK, 60), -887220);
+
+        // Target just above tick 120 in the 0.3% tier snaps back to tick 120
+        let target = tick_to_sqrt_price(125);
+        let achievable = nearest_achievable_sqrt_price(target, 30);
+        assert!((achievable - tick_to_sqrt_price(120)).abs() < 1e-12);
+
+        // Unknown fee tiers leave the target alone
+        assert_eq!(nearest_achievable_sqrt_price(target, 42), target);
+    }
+
+    #[test]
+    fn test_flashloan_amount_calculation() {
+        let amount = calculate_flashloan_amount(
+            1000000.0, 2000000.0, // Buy pool - price is 2:1
+            1800000.0, 1000000.0, // Sell pool - price is 0.556:1 (significant difference)
+            0.0009,    // 0.09% flashloan fee (Aave)
+            100.0,     // Gas cost
+        );
+        // With a significant price difference, should find profitable flashloan amount
+        assert!(amount >= 0.0); // At minimum should not be negative
+    }
+
+    #[test]
+    fn test_flashloan_amount_with_asymmetric_leg_fees() {
+        let (buy, sell) = ((1e8, 2e8), (2e8, 1.02e8));
+        let size_for = |fees: (u32, u32)| {
+            calculate_flashloan_amount_with_fees(buy.0, buy.1, sell.0, sell.1, 0.0009, 100.0, fees)
+        };
+
+        assert_eq!(
+            size_for((30, 30)),
+            calculate_flashloan_amount(buy.0, buy.1, sell.0, sell.1, 0.0009, 100.0)
+        );
+
+        // A 1% sell venue shrinks the loan even with a cheap buy leg
+        let asymmetric = size_for((5, 100));
+        assert!(asymmetric > 0.0 && asymmetric < size_for((30, 30)));
+
+        // The chosen loan is still repaid with profit under those fees
+        let bought = calculate_amount_out_with_fee(buy.0, buy.1, asymmetric, 5);
+        let sold = calculate_amount_out_with_fee(sell.0, sell.1, bought, 100);
+        assert!(sold - asymmetric * 1.0009 - 100.0 > 0.0);
+    }
+
+    #[test]
+    fn test_simulate_bundle() {
+        // Buy token1 where it is cheap, sell it back where it is dear
+        let pools = [(1e6, 2e6), (1e6, 2.5e6)];
+        let buy = BundleTx {
+            pool_index: 0,
+            zero_for_one: true,
+            amount_in: Some(10000.0),
+            min_amount_out: 0.0,
+            fee_bps: 30,
+            gas_cost: 50.0,
+        };
+        let sell = BundleTx {
+            pool_index: 1,
+            zero_for_one: false,
+            amount_in: None,
+            ..buy
+        };
+
+        let result = simulate_bundle(&[buy, sell], &pools);
+        assert_eq!(result.reverted_index, None);
+        let bought = calculate_amount_out(1e6, 2e6, 10000.0);
+        let sold = calculate_amount_out(2.5e6, 1e6, bought);
+        assert_eq!(result.per_tx_effects[1].amount_in, bought);
+        assert_eq!(result.net_profit, sold - 10000.0 - 100.0);
+        assert_eq!(result.final_pool_states[0], (1e6 + 10000.0, 2e6 - bought));
+        assert_eq!(result.final_pool_states[1], (1e6 - sold, 2.5e6 + bought));
+
+        // Each swap sees the reserves the previous one left behind
+        let twice = simulate_bundle(&[buy, buy], &pools);
+        assert!(twice.per_tx_effects[1].amount_out < twice.per_tx_effects[0].amount_out);
+
+        // A failed slippage check reverts everything
+        let strict_sell = BundleTx {
+            min_amount_out: 1e9,
+            ..sell
+        };
+        let result = simulate_bundle(&[buy, strict_sell], &pools);
+        assert_eq!(result.reverted_index, Some(1));
+        assert_eq!(result.net_profit, 0.0);
+        assert_eq!(result.per_tx_effects.len(), 1);
+        assert_eq!(result.final_pool_states, pools.to_vec());
+
+        let unknown_pool = BundleTx {
+            pool_index: 7,
+            ..buy
+        };
+        assert_eq!(
+            simulate_bundle(&[unknown_pool], &pools).reverted_index,
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_simulate_parallel_flashloan_paths_lengths() {
+        let paths = vec![
+            vec![(1e6, 2e6), (2e6, 1.2e6)],
+            vec![(1e6, 2e6), (2e6, 0.9e6)],
+        ];
+        let results =
+            simulate_parallel_flashloan_paths(&paths, &[1000.0, 1000.0], 0.0009, &[1.0, 1.0])
+                .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].2, 1);
+        assert!(results[0].0 > 0.0 && results[1].0 < 0.0);
+
+        // Misaligned inputs are reported instead of silently dropping paths
+        assert_eq!(
+            simulate_parallel_flashloan_paths(&paths, &[1000.0], 0.0009, &[1.0, 1.0]),
+            Err(MathError::LengthMismatch {
+                name: "flashloan amounts",
+                expected: 2,
+                actual: 1,
+            })
+        );
+        assert_eq!(
+            simulate_parallel_flashloan_paths(&paths, &[1000.0, 1000.0], 0.0009, &[1.0; 3]),
+            Err(MathError::LengthMismatch {
+                name: "gas costs",
+                expected: 2,
+                actual: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn test_profit_from_fills() {
+        assert_eq!(profit_from_fills(10000.0, 10500.0, 100.0, 9.0), 391.0);
+
+        // Fills that match the engine's quote reproduce its expected profit
+        let amount_in = 20000.0;
+        let bought = calculate_amount_out(1e6, 2e6, amount_in);
+        let sold = calculate_amount_out(2e6, 1.1e6, bought);
+        assert_eq!(
+            profit_from_fills(amount_in, sold, 100.0, amount_in * 0.0009),
+            estimate_arbitrage_profit(1e6, 2e6, 2e6, 1.1e6, amount_in, 100.0, 0.0009)
+        );
+    }
+
+    #[test]
+    fn test_profit_vs_gas() {
+        let (buy, sell) = ((1e6, 2e6), (2e6, 1.1e6));
+        let gas_costs = [0.0, 25.0, 100.0, 1e6];
+        let profits = profit_vs_gas(buy, sell, 20000.0, 0.0009, &gas_costs);
+        assert_eq!(profits.len(), gas_costs.len());
+
+        for (profit, gas_cost) in profits.iter().zip(gas_costs.iter()) {
+            let single =
+                estimate_arbitrage_profit(buy.0, buy.1, sell.0, sell.1, 20000.0, *gas_cost, 0.0009);
+            assert!((profit - single).abs() < 1e-9);
+        }
+        assert!(profits[3] < 0.0);
+        assert!(profit_vs_gas(buy, sell, 20000.0, 0.0009, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_expected_profit_with_revert_protection() {
+        let (buy, sell) = ((1e6, 2e6), (2e6, 1.1e6));
+        let amount_in = 20000.0;
+        let unprotected =
+            estimate_arbitrage_profit(buy.0, buy.1, sell.0, sell.1, amount_in, 100.0, 0.0009);
+
+        // A minimum at or below the real output changes nothing
+        assert_eq!(
+            expected_profit_with_revert_protection(buy, sell, amount_in, 100.0, 0.0009, amount_in),
+            unprotected
+        );
+
+        // After the sell pool moves against us, the unprotected loss exceeds gas but the
+        // protected trade reverts and only pays gas
+        let moved = (2.2e6, 1.0e6);
+        let loss =
+            estimate_arbitrage_profit(buy.0, buy.1, moved.0, moved.1, amount_in, 100.0, 0.0009);
+        assert!(loss < -100.0);
+        assert_eq!(
+            expected_profit_with_revert_protection(buy, moved, amount_in, 100.0, 0.0009, amount_in),
+            -100.0
+        );
+    }
+
+    #[test]
+    fn test_profit_after_competitor() {
+        let (buy, sell) = ((1e6, 2e6), (2e6, 1.1e6));
+        let uncontested = profit_after_competitor(buy, sell, 20000.0, 0.0, true, 30);
+        assert_eq!(
+            uncontested,
+            estimate_arbitrage_profit(buy.0, buy.1, sell.0, sell.1, 20000.0, 0.0, 0.0)
+        );
+
+        // A rival on either leg eats into the same gap, more so the larger their trade
+        for competitor_on_buy in [true, false] {
+            let small = profit_after_competitor(buy, sell, 20000.0, 5000.0, competitor_on_buy, 30);
+            let large = profit_after_competitor(buy, sell, 20000.0, 50000.0, competitor_on_buy, 30);
+            assert!(uncontested > small && small > large);
+        }
+
+        // A big enough rival closes the gap and leaves us losing money
+        assert!(profit_after_competitor(buy, sell, 20000.0, 200000.0, true, 30) < 0.0);
+    }
+
+    #[test]
+    fn test_apply_swap() {
+        let (new_in, new_out, amount_out) = apply_swap(1000000.0, 2000000.0, 10000.0, 30);
+        assert_eq!(
+            amount_out,
+            calculate_amount_out(1000000.0, 2000000.0, 10000.0)
+        );
+        assert_eq!(new_in, 1010000.0);
+        assert_eq!(new_out, 2000000.0 - amount_out);
+        // Fees accrue to the pool, so k grows
+        assert!(new_in * new_out > 1000000.0 * 2000000.0);
+
+        // A second swap sees the reserves the first one left behind
+        let (next_in, next_out, second) = apply_swap(new_in, new_out, 10000.0, 30);
+        assert!(second < amount_out);
+        assert!(next_out / next_in < new_out / new_in);
+
+        assert_eq!(
+            apply_swap(1000000.0, 2000000.0, 0.0, 30),
+            (1000000.0, 2000000.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn test_market_impact() {
+        let impact = calculate_market_impact(1000000.0, 2000000.0, 50000.0);
+        assert!(impact > 0.0);
+        assert!(impact < 100.0);
+    }
+
+    #[test]
+    fn test_combined_leg_impact() {
+        let (buy, sell) = ((1000000.0, 2000000.0), (2000000.0, 1100000.0));
+        let independent = combined_leg_impact(buy, sell, 20000.0, LegTokens::Independent);
+        let bought = calculate_amount_out(buy.0, buy.1, 20000.0);
+        assert_eq!(
+            independent,
+            calculate_market_impact(buy.0, buy.1, 20000.0)
+                + calculate_market_impact(sell.0, sell.1, bought)
+        );
+
+        // On one shared market the opposite legs offset, leaving far less net impact
+        let shared = combined_leg_impact(buy, sell, 20000.0, LegTokens::Shared);
+        assert!(shared < independent * 0.5);
+
+        // Equal-depth pools cancel up to the fee taken on the first leg
+        let pool = (1000000.0, 1000000.0);
+        let mirrored = combined_leg_impact(pool, pool, 10.0, LegTokens::Shared);
+        let unnetted = combined_leg_impact(pool, pool, 10.0, LegTokens::Independent);
+        assert!(mirrored < unnetted * 0.01);
+        assert_eq!(combined_leg_impact(buy, sell, 0.0, LegTokens::Shared), 0.0);
+    }
+
+    #[test]
+    fn test_estimate_arbitrage_profit_shared_vs_independent_legs() {
+        let (buy, sell) = ((1000000.0, 2000000.0), (2000000.0, 1100000.0));
+        let profit = |amount: f64, legs| {
+            estimate_arbitrage_profit_with_legs(buy, sell, amount, 5.0, 0.0009, legs)
+        };
+
+        // Independent legs are the exact two-fill estimate
+        assert_eq!(
+            profit(20000.0, LegTokens::Independent),
+            estimate_arbitrage_profit(buy.0, buy.1, sell.0, sell.1, 20000.0, 5.0, 0.0009)
+        );
+
+        // Netting the opposite moves on one market keeps more of the spread as size grows
+        let independent = profit(20000.0, LegTokens::Independent);
+        let shared = profit(20000.0, LegTokens::Shared);
+        assert!(shared > independent);
+        let spot_out = 20000.0 * 0.997 * 0.997 * 2.0 * 0.55;
+        assert!(shared < spot_out - 20000.0 - 5.0 - 20000.0 * 0.0009);
+
+        // A small trade barely moves either market, so both modes agree
+        assert!(approx_eq(
+            profit(10.0, LegTokens::Shared),
+            profit(10.0, LegTokens::Independent),
+            1e-3,
+            0.0
+        ));
+    }
+
+    #[test]
+    fn test_multihop_slippage() {
+        let reserves = vec![(1000000.0, 2000000.0), (2000000.0, 1000000.0)];
+        let slippage = calculate_multihop_slippage(&reserves, 10000.0);
+        assert!(slippage >= 0.0);
+    }
+
+    #[test]
+    fn test_simulate_mixed_path_liquidity_checks() {
+        let hops = vec![
+            HopSpec::V2 {
+                reserve_in: 1000000.0,
+                reserve_out: 2000000.0,
+                fee_bps: 30,
+            },
+            HopSpec::V3 {
+                liquidity: 1000000.0,
+                sqrt_price: 2.0_f64.sqrt().recip(),
+                zero_for_one: true,
+                fee_bps: 5,
+            },
+            // Thin final pool relative to what arrives from the previous hop
+            HopSpec::V2 {
+                reserve_in: 50000.0,
+                reserve_out: 50000.0,
+                fee_bps: 30,
+            },
+        ];
+
+        let small = simulate_mixed_path(&hops, 1000.0, 0.3);
+        assert_eq!(small.infeasible_hop, None);
+        assert!(small.amount_out > 0.0);
+
+        let large = simulate_mixed_path(&hops, 100000.0, 0.3);
+        assert_eq!(large.infeasible_hop, Some(2));
+        assert_eq!(large.amount_out, 0.0);
+
+        // The first hop is checked too
+        let too_large = simulate_mixed_path(&hops, 500000.0, 0.3);
+        assert_eq!(too_large.infeasible_hop, Some(0));
+    }
+
+    #[test]
+    fn test_net_profit_full() {
+        let legs = vec![
+            HopSpec::V2 {
+                reserve_in: 1000000.0,
+                reserve_out: 2000000.0,
+                fee_bps: 30,
+            },
+            HopSpec::V2 {
+                reserve_in: 2000000.0,
+                reserve_out: 1100000.0,
+                fee_bps: 30,
+            },
+        ];
+
+        let breakdown = net_profit_full(&legs, 10000.0, 50.0, 0.0009, 20.0, 5.0);
+        let two_leg = estimate_arbitrage_profit(
+            1000000.0, 2000000.0, 2000000.0, 1100000.0, 10000.0, 50.0, 0.0009,
+        );
+
+        // Matches the two-leg estimate once the extra costs are added back
+        assert!((breakdown.net_profit + 20.0 + 5.0 - two_leg).abs() < 1e-6);
+        assert!(breakdown.lp_fee_cost > 0.0);
+        assert!((breakdown.flashloan_fee - 9.0).abs() < 1e-9);
+
+        let total_cost = breakdown.flashloan_fee
+            + breakdown.gas_cost
+            + breakdown.mev_tip
+            + breakdown.bridge_cost;
+        assert!((breakdown.amount_out - 10000.0 - total_cost - breakdown.net_profit).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_optimal_split_heterogeneous() {
+        let v2 = HopSpec::V2 {
+            reserve_in: 1000000.0,
+            reserve_out: 2000000.0,
+            fee_bps: 30,
+        };
+        // V3 range with the same virtual reserves as the V2 pool
+        let v3 = HopSpec::V3 {
+            liquidity: (1000000.0_f64 * 2000000.0).sqrt(),
+            sqrt_price: 2.0_f64.sqrt(),
+            zero_for_one: true,
+            fee_bps: 30,
+        };
+        let deep_v2 = HopSpec::V2 {
+            reserve_in: 4000000.0,
+            reserve_out: 8000000.0,
+            fee_bps: 30,
+        };
+
+        let split = optimal_split(&[v2, v3], 100000.0);
+        assert!((split[0] - 50000.0).abs() < 1e-3);
+        assert!((split[1] - 50000.0).abs() < 1e-3);
+
+        let pools = [v2, v3, deep_v2];
+        let split = optimal_split(&pools, 300000.0);
+        assert!((split.iter().sum::<f64>() - 300000.0).abs() < 1e-6);
+        assert!(split[2] > split[0]);
+
+        // Splitting beats routing everything through the deepest pool
+        let split_output: f64 = pools
+            .iter()
+            .zip(split.iter())
+            .map(|(pool, amount)| pool.amount_out(*amount))
+            .sum();
+        assert!(split_output > deep_v2.amount_out(300000.0));
+
+        // Pools with a worse starting price receive nothing for small orders
+        let expensive = HopSpec::V2 {
+            reserve_in: 1000000.0,
+            reserve_out: 1000000.0,
+            fee_bps: 30,
+        };
+        let split = optimal_split(&[v2, expensive], 1000.0);
+        assert_eq!(split[1], 0.0);
+    }
+
+    #[test]
+    fn test_batch_pool_prices() {
+        let prices = batch_pool_prices(&[(1e6, 2e6), (f64::NAN, f64::NAN), (0.0, 1e6), (2e6, 1e6)]);
+        assert_eq!(prices.len(), 4);
+        assert_eq!(prices[0], 2.0);
+        assert!(prices[1].is_nan());
+        assert_eq!(prices[2], 0.0);
+        assert_eq!(prices[3], 0.5);
+    }
+
+    #[test]
+    fn test_pool_rejects_same_token() {
+        let pool = Pool::new("0xA0b8".to_string(), "0xC02a".to_string(), 1e6, 2e6).unwrap();
+        assert_eq!(pool.price(), 2.0);
+
+        let err = Pool::new("0xC02a".to_string(), "0xc02A".to_string(), 1e6, 1e6).unwrap_err();
+        assert_eq!(
+            err,
+            MathError::SameToken {
+                token: "0xC02a".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_calculate_pool_price() {
+        let price = calculate_pool_price(1000000.0, 2000000.0);
+        assert_eq!(price, 2.0);
+    }
+
+    #[test]
+    fn test_signed_price_divergence() {
+        let below = signed_price_divergence((1000000.0, 2000000.0), (1000000.0, 2200000.0));
+        let above = signed_price_divergence((1000000.0, 2200000.0), (1000000.0, 2000000.0));
+        assert!((below - (2.0 - 2.2) / 2.2).abs() < 1e-12);
+        assert!((above - 0.1).abs() < 1e-12);
+        assert_eq!(signed_price_divergence((0.0, 1.0), (1.0, 1.0)), 0.0);
+    }
+
+    #[test]
+    fn test_net_spread_bps() {
+        // A 10% gap less two 30 bps fees
+        let spread = net_spread_bps((1000000.0, 2000000.0), (1000000.0, 2200000.0), 30, 30);
+        assert!((spread - (0.997 * 0.997 * 1.1 - 1.0) * 10000.0).abs() < 1e-9);
+        assert_eq!(
+            spread,
+            net_spread_bps((1000000.0, 2200000.0), (1000000.0, 2000000.0), 30, 30)
+        );
+
+        // Fee-free pools keep the raw gap; fees wider than the gap go negative
+        let raw = net_spread_bps((1000000.0, 2000000.0), (1000000.0, 2200000.0), 0, 0);
+        assert!((raw - 1000.0).abs() < 1e-9);
+        assert!(net_spread_bps((1000000.0, 2000000.0), (1000000.0, 2004000.0), 30, 30) < 0.0);
+        assert_eq!(net_spread_bps((0.0, 1.0), (1.0, 1.0), 30, 30), 0.0);
+    }
+
+    #[test]
+    fn test_quick_gap_exists() {
+        // Prices 2.0 vs 2.2: a 10% gap in either orientation
+        assert!(quick_gap_exists(1e6, 2e6, 1e6, 2.2e6, 1.05));
+        assert!(quick_gap_exists(1e6, 2.2e6, 1e6, 2e6, 1.05));
+        assert!(!quick_gap_exists(1e6, 2e6, 1e6, 2.2e6, 1.15));
+        assert!(!quick_gap_exists(0.0, 2e6, 1e6, 2.2e6, 1.05));
+    }
+
+    #[test]
+    fn test_identify_arbitrage_opportunity() {
+        // Pool 1: price = 2.0, Pool 2: price = 2.2 (10% difference)
+        let (has_opp, diff, direction) = identify_arbitrage_opportunity(
+            1000000.0, 2000000.0, // Pool 1
+            1000000.0, 2200000.0, // Pool 2
+            5.0,       // Min 5% difference
+        );
+        assert!(has_opp);
+        assert!(diff >= 5.0);
+        assert_eq!(direction, 1); // Buy pool 1, sell pool 2
+    }
+
+    #[test]
+    fn test_identify_stable_opportunity() {
+        // USDC/USDT pools 3 bps apart: 1.0001 vs 1.0004
+        let (has_opp, gap_bps, direction) = identify_stable_opportunity(
+            50_000_000.0,
+            50_005_000.0,
+            50_000_000.0,
+            50_020_000.0,
+            1.0,
+            2.0,
+        );
+        assert!(has_opp);
+        assert!((gap_bps - 3.0).abs() < 1e-6);
+        assert_eq!(direction, 1);
+
+        let (has_opp, gap_bps, direction) = identify_stable_opportunity(
+            50_000_000.0,
+            50_020_000.0,
+            50_000_000.0,
+            50_005_000.0,
+            1.0,
+            5.0,
+        );
+        assert!(!has_opp);
+        assert!((gap_bps - 3.0).abs() < 1e-6);
+        assert_eq!(direction, 0);
+
+        assert_eq!(
+            identify_stable_opportunity(1e6, 1e6, 1e6, 1e6, 1.0, 0.0),
+            (false, 0.0, 0)
+        );
+        assert_eq!(
+            identify_stable_opportunity(1e6, 1e6, 1e6, 1e6, 0.0, 0.0),
+            (false, 0.0, 0)
+        );
+    }
+
+    #[test]
+    fn test_calculate_amount_in() {
+        let amount_in = calculate_amount_in(1000000.0, 2000000.0, 10000.0);
+        assert!(amount_in > 0.0);
+        assert!(amount_in < 1000000.0);
+    }
+
+    #[test]
+    fn test_size_sell_leg_for_repayment() {
+        let borrowed = 10000.0;
+        let repayment = borrowed * (1.0 + 0.0009);
+
+        let sell_input = size_sell_leg_for_repayment(2000000.0, 1000000.0, repayment, 30);
+        assert!(sell_input > 0.0);
+
+        // Selling that much covers the repayment without materially overshooting
+        let produced = calculate_amount_out(2000000.0, 1000000.0, sell_input);
+        assert!(produced >= repayment);
+        assert!(produced - repayment < 1.0);
+
+        // The pool cannot produce more than its reserve
+        assert_eq!(
+            size_sell_leg_for_repayment(2000000.0, 1000000.0, 1000000.0, 30),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_calculate_amount_out() {
+        let amount_out = calculate_amount_out(1000000.0, 2000000.0, 10000.0);
+        assert!(amount_out > 0.0);
+        assert!(amount_out < 20000.0);
+    }
+
+    #[test]
+    fn test_amount_out_u128_matches_uniswap_v2() {
+        // (amount_in, reserve_in, reserve_out, output) in whole 18-decimal tokens, from the
+        // swap cases in Uniswap v2-core's UniswapV2Pair spec. Each output is the most the
+        // pair's K check accepts, which is exactly what the router's getAmountOut returns
+        let e18: u128 = 10u128.pow(18);
+        let cases: [(u128, u128, u128, u128); 7] = [
+            (1, 5, 10, 1_662_497_915_624_478_906),
+            (1, 10, 5, 453_305_446_940_074_565),
+            (2, 5, 10, 2_851_015_155_847_869_602),
+            (2, 10, 5, 831_248_957_812_239_453),
+            (1, 10, 10, 906_610_893_880_149_131),
+            (1, 100, 100, 987_158_034_397_061_298),
+            (1, 1000, 1000, 996_006_981_039_903_216),
+        ];
+        for (amount_in, reserve_in, reserve_out, expected) in cases {
+            assert_eq!(
+                calculate_amount_out_u128(
+                    reserve_in * e18,
+                    reserve_out * e18,
+                    amount_in * e18,
+                    DEFAULT_FEE_BPS
+                ),
+                Ok(expected)
+            );
+        }
+
+        // One wei more than the floor would break K, so the floor is the on-chain maximum
+        // (small raw amounts keep the K products inside u128)
+        let (reserve_in, reserve_out, amount_in): (u128, u128, u128) = (5000, 10000, 1000);
+        let out =
+            calculate_amount_out_u128(reserve_in, reserve_out, amount_in, DEFAULT_FEE_BPS).unwrap();
+        let k_holds = |out: u128| {
+            let balance_in = (reserve_in + amount_in) * 1000 - amount_in * 3;
+            balance_in * (reserve_out - out) >= reserve_in * reserve_out * 1000
+        };
+        assert!(k_holds(out));
+        assert!(!k_holds(out + 1));
+
+        // Overflowing intermediates and invalid fees are errors rather than wrapping or 0
+        assert!(matches!(
+            calculate_amount_out_u128(u128::MAX, 1, 1, 30),
+            Err(MathError::InvalidParameter {
+                name: "reserve_in",
+                ..
+            })
+        ));
+        assert!(matches!(
+            calculate_amount_out_u128(e18, e18, u128::MAX, 30),
+            Err(MathError::InvalidParameter {
+                name: "amount_in",
+                ..
+            })
+        ));
+        assert_eq!(
+            calculate_amount_out_u128(e18, e18, e18, 10001),
+            Err(MathError::InvalidParameter {
+                name: "fee_bps",
+                value: 10001.0
+            })
+        );
+        assert!(matches!(
+            calculate_amount_out_u128(0, e18, e18, 10000),
+            Err(MathError::ZeroReserve { .. })
+        ));
+        assert_eq!(calculate_amount_out_u128(e18, e18, 0, 30), Ok(0));
+
+        // A failing hop fails the whole path instead of feeding 0 into the next one
+        assert!(calculate_multihop_amount_out_u128(
+            &[(e18, e18), (u128::MAX, e18)],
+            e18,
+            DEFAULT_FEE_BPS
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_multihop_exact_bps_vs_float_drift() {
+        let hops: [(u128, u128); 5] = [
+            (
+                3_000_000_000_000_000_000_000_000,
+                5_000_000_000_000_000_000_000_000,
+            ),
+            (
+                700_000_000_000_000_000_000_000,
+                1_100_000_000_000_000_000_000_000,
+            ),
+            (
+                1_300_000_000_000_000_000_000_000,
+                900_000_000_000_000_000_000_000,
+            ),
+            (
+                2_000_000_000_000_000_000_000_000,
+                1_700_000_000_000_000_000_000_000,
+            ),
+            (
+                1_900_000_000_000_000_000_000_000,
+                2_300_000_000_000_000_000_000_000,
+            ),
+        ];
+        let amount_in: u128 = 12_345_678_901_234_567_890_123;
+
+        let exact = calculate_multihop_amount_out_u128(&hops, amount_in, DEFAULT_FEE_BPS).unwrap();
+        assert_eq!(exact, 21_030_405_157_440_295_267_757);
+
+        let float = hops
+            .iter()
+            .fold(amount_in as f64, |amount, &(r_in, r_out)| {
+                calculate_amount_out_with_fee(r_in as f64, r_out as f64, amount, DEFAULT_FEE_BPS)
+            });
+        let drift_wei = (float - exact as f64).abs();
+        // f64 lands millions of wei away from the router after five hops, but stays
+        // within a relative error that is invisible to anything but exact comparisons
+        assert!(drift_wei > 1.0);
+        assert!(drift_wei / (exact as f64) < 1e-12);
+    }
+
+    #[test]
+    fn test_infer_fee_bps() {
+        for fee_bps in [0, 5, 25, 30, 100] {
+            let amount_out = calculate_amount_out_with_fee(1000000.0, 2000000.0, 10000.0, fee_bps);
+            assert_eq!(
+                infer_fee_bps(1000000.0, 2000000.0, 10000.0, amount_out),
+                fee_bps
+            );
+        }
+
+        // Floor-rounded on-chain output still resolves to the right tier
+        let floored =
+            calculate_amount_out_u128(10u128.pow(24), 2 * 10u128.pow(24), 10u128.pow(21), 25)
+                .unwrap();
+        assert_eq!(infer_fee_bps(1e24, 2e24, 1e21, floored as f64), 25);
+
+        // An output above the fee-free quote cannot come from any fee
+        assert_eq!(
+            infer_fee_bps(1000000.0, 2000000.0, 10000.0, 19900.0),
+            DEFAULT_FEE_BPS
+        );
+    }
+
+    #[test]
+    fn test_amount_out_with_protocol_fee() {
+        let lp_only = calculate_amount_out_with_fee(1000000.0, 2000000.0, 10000.0, 25);
+        let with_protocol =
+            calculate_amount_out_with_protocol_fee(1000000.0, 2000000.0, 10000.0, 25, 5);
+        assert!(with_protocol < lp_only);
+        assert_eq!(
+            calculate_amount_out_with_protocol_fee(1000000.0, 2000000.0, 10000.0, 25, 0),
+            lp_only
+        );
+
+        // Default 30 bps fee matches the 997/1000 formula
+        let default_fee = calculate_amount_out(1000000.0, 2000000.0, 10000.0);
+        let expected = 2000000.0 * 10000.0 * 997.0 / (1000000.0 * 1000.0 + 10000.0 * 997.0);
+        assert!((default_fee - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_per_pool_fee_overloads() {
+        let (reserve_in, reserve_out, amount) = (1000000.0, 2000000.0, 10000.0);
+
+        // The defaulted functions are the 30 bps overloads
+        assert_eq!(
+            optimal_trade_size(reserve_in, reserve_out, 0.0, 0.0),
+            optimal_trade_size_with_fee(reserve_in, reserve_out, 0.0, 0.0, DEFAULT_FEE_BPS)
+        );
+        assert_eq!(
+            calculate_market_impact(reserve_in, reserve_out, amount),
+            calculate_market_impact_with_fee(reserve_in, reserve_out, amount, DEFAULT_FEE_BPS)
+        );
+
+        // A cheaper pool (PancakeSwap's 25 bps) fills more; a 1% pool fills less
+        let pancake = calculate_amount_out_with_fee(reserve_in, reserve_out, amount, 25);
+        let one_pct = calculate_amount_out_with_fee(reserve_in, reserve_out, amount, 100);
+        let default = calculate_amount_out(reserve_in, reserve_out, amount);
+        assert!(pancake > default && default > one_pct);
+        let expected = reserve_out * amount * 9975.0 / (reserve_in * 10000.0 + amount * 9975.0);
+        assert!(approx_eq(pancake, expected, 1e-12, 0.0));
+        let back = calculate_amount_in_with_fee(reserve_in, reserve_out, pancake, 25);
+        assert!((back - 1.0 - amount).abs() < 1e-6);
+
+        // The fee is what moves the pool, so it changes the impact too
+        assert!(
+            calculate_market_impact_with_fee(reserve_in, reserve_out, amount, 100)
+                < calculate_market_impact(reserve_in, reserve_out, amount)
+        );
+
+        let paths = vec![vec![(1000000.0, 2000000.0), (2000000.0, 1100000.0)]];
+        let default_run =
+            simulate_parallel_flashloan_paths(&paths, &[1000.0], 0.0009, &[0.0]).unwrap();
+        let cheap_run =
+            simulate_parallel_flashloan_paths_with_fee(&paths, &[1000.0], 0.0009, &[0.0], 1)
+                .unwrap();
+        assert_eq!(
+            default_run,
+            simulate_parallel_flashloan_paths_with_fee(
+                &paths,
+                &[1000.0],
+                0.0009,
+                &[0.0],
+                DEFAULT_FEE_BPS
+            )
+            .unwrap()
+        );
+        assert!(cheap_run[0].0 > default_run[0].0);
+        assert!(cheap_run[0].1 < default_run[0].1);
+    }
+
+    #[test]
+    fn test_default_fee_consistent_across_functions() {
+        assert_eq!(fee_multiplier(DEFAULT_FEE_BPS), 0.997);
+        assert_eq!(fee_multiplier(0), 1.0);
+        assert_eq!(fee_multiplier(20000), 0.0);
+
+        // Slippage is derived from the same output the amount-out function returns
+        let (reserve_in, reserve_out, amount_in) = (1000000.0, 2000000.0, 10000.0);
+        let amount_out = calculate_amount_out(reserve_in, reserve_out, amount_in);
+        let expected_out = amount_in * reserve_out / reserve_in;
+        let implied_slippage = (expected_out - amount_out) / expected_out * 100.0;
+        let slippage = compute_uniswap_v2_slippage(reserve_in, reserve_out, amount_in);
+        assert!((slippage - implied_slippage).abs() < 1e-9);
+
+        // Inverting the output recovers the input (plus the one-unit rounding guard)
+        let amount_back = calculate_amount_in(reserve_in, reserve_out, amount_out);
+        assert!((amount_back - 1.0 - amount_in).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_estimate_arbitrage_profit() {
+        let profit = estimate_arbitrage_profit(
+            1000000.0, 2000000.0, // Buy pool
+            2000000.0, 1000000.0, // Sell pool
+            10000.0,   // Amount in
+            100.0,     // Gas cost
+            0.0009,    // Flashloan fee
+        );
+        // Should be negative or very low due to price similarity
+        assert!(profit < 1000.0);
+    }
+
+    #[test]
+    fn test_profit_vs_reference() {
+        // Pool gives 2.2 tokens per input, the CEX values them at 2.0 per input
+        let profit = profit_vs_reference(1000000.0, 2200000.0, 1000.0, 2.0, 30, 10.0);
+        assert!(profit > 0.0);
+
+        // Settling at the pool's own price loses the fee, slippage, and gas
+        let at_parity = profit_vs_reference(1000000.0, 2200000.0, 1000.0, 2.2, 30, 10.0);
+        assert!(at_parity < 0.0);
+
+        assert_eq!(
+            profit_vs_reference(1000000.0, 2200000.0, 1000.0, 0.0, 30, 10.0),
+            -10.0
+        );
+    }
+
+    #[test]
+    fn test_limit_order_plus_amm_profit() {
+        // The order sells 2.1 per input while the pool buys them back at 0.5 input each
+        let amm = (2000000.0, 1000000.0);
+        let profit = limit_order_plus_amm_profit(2.1, 1000.0, amm, 1000.0, 30, 10.0);
+        let expected = calculate_amount_out_with_fee(2000000.0, 1000000.0, 2100.0, 30) - 1000.0;
+        assert!((profit - (expected - 10.0)).abs() < 1e-9);
+        assert!(profit > 0.0);
+
+        // Size beyond the order is not deployed
+        assert_eq!(
+            limit_order_plus_amm_profit(2.1, 1000.0, amm, 5000.0, 30, 10.0),
+            profit
+        );
+        assert_eq!(
+            limit_order_plus_amm_profit(0.0, 1000.0, amm, 1000.0, 30, 10.0),
+            -10.0
+        );
+    }
+
+    #[test]
+    fn test_cross_token_profit() {
+        // With the output priced like the input this reduces to the cyclic estimate
+        let cyclic = estimate_arbitrage_profit(
+            1000000.0, 2000000.0, 2000000.0, 1100000.0, 1000.0, 10.0, 0.0009,
+        );
+        let same_price = cross_token_profit(
+            (1000000.0, 2000000.0),
+            (2000000.0, 1100000.0),
+            1000.0,
+            3000.0,
+            3000.0,
+            10.0,
+            0.0009,
+        );
+        assert!((same_price - cyclic).abs() < 1e-9);
+
+        // Ending in a token worth half as much halves the output's value
+        let half = cross_token_profit(
+            (1000000.0, 2000000.0),
+            (2000000.0, 1100000.0),
+            1000.0,
+            1500.0,
+            3000.0,
+            10.0,
+            0.0009,
+        );
+        let amount_out = calculate_amount_out(
+            2000000.0,
+            1100000.0,
+            calculate_amount_out(1000000.0, 2000000.0, 1000.0),
+        );
+        assert!((half - (amount_out * 0.5 - 1000.0 * 1.0009 - 10.0)).abs() < 1e-9);
+        assert_eq!(
+            cross_token_profit((1.0, 1.0), (1.0, 1.0), 1.0, 1.0, 0.0, 10.0, 0.0),
+            -10.0
+        );
+    }
+
+    #[test]
+    fn test_cross_asset_flashloan_profit() {
+        // Borrow X, convert to Y at 2 per X, arb Y -> Z -> Y, convert back
+        let borrow = (10000000.0, 20000000.0);
+        let repay = (20000000.0, 10000000.0);
+        let leg1 = (1000000.0, 2000000.0);
+        let leg2 = (2000000.0, 1100000.0);
+        let profit = cross_asset_flashloan_profit(borrow, leg1, leg2, repay, 1000.0, 0.0009, 1.0);
+
+        let y_in = calculate_amount_out(borrow.0, borrow.1, 1000.0);
+        let y_out =
+            calculate_amount_out(leg2.0, leg2.1, calculate_amount_out(leg1.0, leg1.1, y_in));
+        let x_out = calculate_amount_out(repay.0, repay.1, y_out);
+        assert!((profit - (x_out - 1000.0 * 1.0009 - 1.0)).abs() < 1e-9);
+        assert!(profit > 0.0);
+
+        // The conversion legs cost fees: the same arb funded in Y directly earns more
+        let direct = estimate_arbitrage_profit(leg1.0, leg1.1, leg2.0, leg2.1, y_in, 0.0, 0.0);
+        assert!(profit < direct / 2.0);
+    }
+
+    #[test]
+    fn test_best_flashloan_provider() {
+        let providers = [
+            ProviderSpec {
+                fee_pct: 0.0009,
+                max_borrow: 1e9,
+            },
+            ProviderSpec {
+                fee_pct: 0.0,
+                max_borrow: 5e5,
+            },
+            ProviderSpec {
+                fee_pct: 0.0005,
+                max_borrow: 2e6,
+            },
+        ];
+
+        // The free provider wins while it has the liquidity
+        assert_eq!(best_flashloan_provider(1e5, &providers), Some((1, 0.0)));
+        let (idx, fee) = best_flashloan_provider(1e6, &providers).unwrap();
+        assert_eq!(idx, 2);
+        assert!((fee - 500.0).abs() < 1e-9);
+        assert_eq!(
+            best_flashloan_provider(1e8, &providers).map(|(idx, _)| idx),
+            Some(0)
+        );
+        assert_eq!(best_flashloan_provider(1e10, &providers), None);
+    }
+
+    #[test]
+    fn test_max_flashloan_fee_for_profit() {
+        let buy = (1000000.0, 2000000.0);
+        let sell = (2000000.0, 1100000.0);
+        let amount_in = 10000.0;
+
+        // Borrowing at exactly the breakeven fee nets zero
+        let max_fee = max_flashloan_fee_for_profit(buy, sell, amount_in, 50.0);
+        assert!(max_fee > 0.0);
+        let profit_at_max =
+            estimate_arbitrage_profit(buy.0, buy.1, sell.0, sell.1, amount_in, 50.0, max_fee);
+        assert!(profit_at_max.abs() < 1e-6);
+
+        // Only providers under the breakeven fee are worth choosing
+        let providers = [
+            ProviderSpec {
+                fee_pct: max_fee * 2.0,
+                max_borrow: 1e9,
+            },
+            ProviderSpec {
+                fee_pct: max_fee / 2.0,
+                max_borrow: 1e9,
+            },
+        ];
+        let (idx, _) = best_flashloan_provider(amount_in, &providers).unwrap();
+        assert!(providers[idx].fee_pct < max_fee);
+
+        // No gap means no fee is low enough
+        let round_trip = (buy.1, buy.0);
+        assert!(max_flashloan_fee_for_profit(buy, round_trip, amount_in, 50.0) < 0.0);
+        assert_eq!(max_flashloan_fee_for_profit(buy, sell, 0.0, 50.0), 0.0);
+    }
+
+    #[test]
+    fn test_repayment_and_surplus() {
+        let same = repayment_and_surplus(10000.0, 0.0009, 10100.0, true, 0.0).unwrap();
+        assert!((same.repayment_due - 10009.0).abs() < 1e-9);
+        assert!((same.surplus - 91.0).abs() < 1e-9);
+        assert_eq!(same.surplus_token, SurplusToken::Borrowed);
+
+        // Output worth 2 borrowed tokens each: 5004.5 of it repays the loan
+        let other = repayment_and_surplus(10000.0, 0.0009, 5100.0, false, 2.0).unwrap();
+        assert!((other.repayment_due - 10009.0).abs() < 1e-9);
+        assert!((other.surplus - 95.5).abs() < 1e-9);
+        assert_eq!(other.surplus_token, SurplusToken::Output);
+
+        assert!(repayment_and_surplus(10000.0, 0.0009, 5100.0, false, 0.0).is_err());
+    }
+
+    #[test]
+    fn test_gas_rebate_flips_marginal_trade() {
+        let gross = estimate_arbitrage_profit(
+            1000000.0, 2000000.0, // Buy pool
+            2000000.0, 1050000.0, // Sell pool
+            1000.0,    // Amount in
+            0.0,       // No gas
+            0.0009,    // Flashloan fee
+        );
+        assert!(gross > 0.0);
+
+        // Raw gas eats the whole edge
+        let raw_gas = gross * 1.5;
+        let without_rebate = estimate_arbitrage_profit(
+            1000000.0, 2000000.0, 2000000.0, 1050000.0, 1000.0, raw_gas, 0.0009,
+        );
+        assert!(without_rebate < 0.0);
+
+        // Half the gas refunded by the builder makes it profitable
+        let rebated_gas = effective_gas_cost(raw_gas, 0.5);
+        let with_rebate = estimate_arbitrage_profit(
+            1000000.0,
+            2000000.0,
+            2000000.0,
+            1050000.0,
+            1000.0,
+            rebated_gas,
+            0.0009,
+        );
+        assert!(with_rebate > 0.0);
+
+        // A refund larger than gas is a negative cost and adds to profit
+        let negative_gas = effective_gas_cost(raw_gas, 1.2);
+        assert!(negative_gas < 0.0);
+        let with_refund = estimate_arbitrage_profit(
+            1000000.0,
+            2000000.0,
+            2000000.0,
+            1050000.0,
+            1000.0,
+            negative_gas,
+            0.0009,
+        );
+        assert!(with_refund > gross);
+    }
+
+    #[test]
+    fn test_optimal_entry() {
+        // Uncontested opportunities keep their full profit
+        assert_eq!(optimal_entry(1000.0, 0, 0.0), 1000.0);
+
+        // Four equal competitors plus us: one in five
+        assert!((optimal_entry(1000.0, 4, 0.0) - 200.0).abs() < 1e-9);
+
+        // An edge raises our share, more competitors lower it
+        assert!(optimal_entry(1000.0, 4, 50.0) > optimal_entry(1000.0, 4, 0.0));
+        assert!(optimal_entry(1000.0, 9, 0.0) < optimal_entry(1000.0, 4, 0.0));
+    }
+
+    #[test]
+    fn test_session_size_cap() {
+        // No drawdown: full size
+        assert_eq!(session_size_cap(1000.0, 500.0, 10000.0, 0.0), 1000.0);
+
+        // Half the limit used: half size
+        assert!((session_size_cap(1000.0, 0.0, 10000.0, 5000.0) - 500.0).abs() < 1e-9);
+
+        // Realized losses count even if drawdown was not reported
+        assert!((session_size_cap(1000.0, -7500.0, 10000.0, 0.0) - 250.0).abs() < 1e-9);
+
+        // At or beyond the limit: no trading
+        assert_eq!(session_size_cap(1000.0, 0.0, 10000.0, 12000.0), 0.0);
+    }
+
+    #[test]
+    fn test_optimal_two_leg_input() {
+        let optimum = optimal_two_leg_input(1000000.0, 2200000.0, 2000000.0, 1000000.0, 30);
+        assert!(optimum > 0.0);
+
+        // The closed form beats nearby sizes
+        let profit_at = |amount: f64| {
+            let mid = calculate_amount_out(1000000.0, 2200000.0, amount);
+            calculate_amount_out(2000000.0, 1000000.0, mid) - amount
+        };
+        assert!(profit_at(optimum) > profit_at(optimum * 0.95));
+        assert!(profit_at(optimum) > profit_at(optimum * 1.05));
+
+        // Same price on both legs: nothing to do
+        assert_eq!(
+            optimal_two_leg_input(1000000.0, 2000000.0, 2000000.0, 1000000.0, 30),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_optimize_path_size() {
+        let path = [
+            HopSpec::V2 {
+                reserve_in: 1000000.0,
+                reserve_out: 2200000.0,
+                fee_bps: 30,
+            },
+            HopSpec::V2 {
+                reserve_in: 2000000.0,
+                reserve_out: 1000000.0,
+                fee_bps: 30,
+            },
+        ];
+
+        // Without fees or gas the search lands on the two-leg closed form
+        let sizing = optimize_path_size(&path, 0.0, 0.0);
+        let closed_form = optimal_two_leg_input(1000000.0, 2200000.0, 2000000.0, 1000000.0, 30);
+        assert!((sizing.amount_in - closed_form).abs() / closed_form < 1e-6);
+        assert!(sizing.expected_profit > 0.0);
+        assert!(sizing.converged);
+
+        // A flashloan fee shrinks the optimum; gas larger than the edge rejects the path
+        let with_fee = optimize_path_size(&path, 0.0009, 10.0);
+        assert!(with_fee.amount_in < sizing.amount_in);
+        assert_eq!(optimize_path_size(&path, 0.0, 1e9).amount_in, 0.0);
+        assert_eq!(optimize_path_size(&[], 0.0, 0.0).amount_in, 0.0);
+    }
+
+    #[test]
+    fn test_golden_section_flat_curve_falls_back() {
+        // A flat profit curve has no peak: report the smallest best sample, unconverged
+        let (amount, profit, converged) = golden_section_max(|_| 5.0, 0.0, 1000.0);
+        assert!(!converged);
+        assert_eq!(profit, 5.0);
+        let inv_phi = (5.0_f64.sqrt() - 1.0) / 2.0;
+        assert!(amount <= 1000.0 * (1.0 - inv_phi));
+        assert_eq!(
+            golden_section_max(|_| 5.0, 0.0, 1000.0),
+            (amount, profit, converged)
+        );
+
+        // A peaked curve converges on its maximum
+        let (amount, profit, converged) =
+            golden_section_max(|x| -(x - 300.0) * (x - 300.0), 0.0, 1000.0);
+        assert!(converged);
+        assert!((amount - 300.0).abs() < 1e-6);
+        assert!(profit <= 0.0 && profit > -1e-9);
+    }
+
+    #[test]
+    fn test_equilibrium_price_band() {
+        let (low, high) = equilibrium_price((1000000.0, 2000000.0), (1000000.0, 2400000.0), 30);
+        assert!(low > 2.0 && high < 2.4);
+
+        // The remaining gap is inside the round-trip fee band but not far from its edge
+        let gamma = fee_multiplier(30);
+        let fee_band = 1.0 / (gamma * gamma);
+        assert!(high / low < fee_band);
+        assert!(high / low > 1.0 + (fee_band - 1.0) * 0.9);
+
+        // Pools already inside the band stay where they are
+        let (low, high) = equilibrium_price((1000000.0, 2000000.0), (1000000.0, 2005000.0), 30);
+        assert_eq!((low, high), (2.0, 2.005));
+    }
+
+    #[test]
+    fn test_no_arb_band_widens_with_gas() {
+        let pool1 = (1000000.0, 2000000.0);
+        let pool2 = (1000000.0, 2000000.0);
+        let gamma = fee_multiplier(30);
+
+        let (low, high) = no_arb_band(pool1, pool2, 0.0, 30);
+        assert!(approx_eq(low, gamma * gamma, 1e-6, 0.0));
+        assert!(approx_eq(high, 1.0 / (gamma * gamma), 1e-6, 0.0));
+
+        let (gas_low, gas_high) = no_arb_band(pool1, pool2, 100.0, 30);
+        assert!(gas_low < low && gas_high > high);
+
+        // At the upper edge the optimal cycle nets exactly the gas
+        let price2 = gas_high * 2.0;
+        let k2 = 1000000.0 * 2000000.0;
+        let (x2, y2) = ((k2 / price2).sqrt(), (k2 * price2).sqrt());
+        let amount_in = optimal_two_leg_input(x2, y2, pool1.1, pool1.0, 30);
+        let y_bought = calculate_amount_out_with_fee(x2, y2, amount_in, 30);
+        let gross = calculate_amount_out_with_fee(pool1.1, pool1.0, y_bought, 30) - amount_in;
+        assert!((gross - 100.0).abs() < 1e-3);
+
+        assert_eq!(
+            no_arb_band((0.0, 1.0), pool2, 0.0, 30),
+            (0.0, f64::INFINITY)
+        );
+    }
+
+    #[test]
+    fn test_multi_venue_arb_three_pools() {
+        // Prices 1.9 and 2.0 are cheap relative to 2.2
+        let pools = [
+            (1000000.0, 1900000.0),
+            (1000000.0, 2000000.0),
+            (1000000.0, 2200000.0),
+        ];
+        let result = multi_venue_arb(&pools, 30, 0.0, 0.0009);
+        let sides: Vec<VenueSide> = result.actions.iter().map(|a| a.side).collect();
+        assert_eq!(sides, vec![VenueSide::Buy, VenueSide::Buy, VenueSide::Sell]);
+
+        // Y from the sell funds the buys exactly
+        let y_spent: f64 = result.actions[..2].iter().map(|a| a.amount_in).sum();
+        assert!((y_spent - result.actions[2].amount_out).abs() < 1e-6);
+
+        // Touching all three venues beats the best single buy/sell pair
+        let pair = multi_venue_arb(&[pools[0], pools[2]], 30, 0.0, 0.0009);
+        assert!(result.net_profit > pair.net_profit);
+        assert!(pair.net_profit > 0.0);
+
+        // Gas larger than the edge leaves every pool untouched
+        let unprofitable = multi_venue_arb(&pools, 30, 1e9, 0.0009);
+        assert_eq!(unprofitable.net_profit, 0.0);
+        assert!(unprofitable
+            .actions
+            .iter()
+            .all(|a| a.side == VenueSide::Skip));
+    }
+
+    #[test]
+    fn test_multi_venue_arb_order_independent() {
+        let pools = [
+            (1000000.0, 1900000.0),
+            (3000000.0, 6600000.0),
+            (1000000.0, 2000000.0),
+            (500000.0, 1000000.0),
+            (1000000.0, 2200000.0),
+        ];
+        assert_eq!(canonical_pool_order(&pools), vec![0, 3, 2, 4, 1]);
+
+        // Every rotation and the reversal give bit-identical results per pool
+        let baseline = multi_venue_arb(&pools, 30, 10.0, 0.0009);
+        assert!(baseline.net_profit > 0.0);
+        for shift in 0..pools.len() {
+            let mut order: Vec<usize> = (0..pools.len()).collect();
+            order.rotate_left(shift);
+            if shift % 2 == 1 {
+                order.reverse();
+            }
+            let permuted: Vec<(f64, f64)> = order.iter().map(|&idx| pools[idx]).collect();
+            let result = multi_venue_arb(&permuted, 30, 10.0, 0.0009);
+
+            assert_eq!(result.net_profit.to_bits(), baseline.net_profit.to_bits());
+            for (action, &idx) in result.actions.iter().zip(order.iter()) {
+                assert_eq!(*action, baseline.actions[idx]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_solve_quadratic() {
+        // x² - 5x + 6 = 0, roots: 2 and 3
+        let (r1, r2) = solve_quadratic(1.0, -5.0, 6.0);
+        assert!((r1 - 3.0).abs() < 0.01 || (r1 - 2.0).abs() < 0.01);
+        assert!((r2 - 3.0).abs() < 0.01 || (r2 - 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_calculate_twap() {
+        let samples = vec![(0.0, 100.0), (10.0, 110.0), (20.0, 105.0)];
+        let twap = calculate_twap(&samples);
+        assert!(twap > 0.0);
+        assert!((100.0..=110.0).contains(&twap));
+    }
+
+    #[test]
+    fn test_calculate_weighted_twap() {
+        // Equal weights reduce to the plain TWAP
+        let uniform = [(0.0, 100.0, 1.0), (10.0, 110.0, 1.0), (20.0, 105.0, 1.0)];
+        let plain = calculate_twap(&[(0.0, 100.0), (10.0, 110.0), (20.0, 105.0)]);
+        assert_eq!(calculate_weighted_twap(&uniform), Ok(plain));
+
+        // A poisoned print from a flaky source carries a quarter of the weight
+        let poisoned = [
+            (0.0, 100.0, 1.0),
+            (10.0, 500.0, 0.25),
+            (20.0, 100.0, 1.0),
+            (30.0, 100.0, 1.0),
+        ];
+        let twap = calculate_weighted_twap(&poisoned).unwrap();
+        assert!((twap - (2000.0 + 1250.0) / 22.5).abs() < 1e-9);
+
+        assert!(calculate_weighted_twap(&[(0.0, 100.0, -1.0), (10.0, 100.0, 1.0)]).is_err());
+        assert_eq!(calculate_weighted_twap(&[]), Ok(0.0));
+    }
+
+    #[test]
+    fn test_realized_volatility() {
+        // Two ±1% moves: σ = √(ln(1.01)² + ln(1/1.01)²)
+        let samples = [(0.0, 100.0), (10.0, 101.0), (20.0, 100.0)];
+        let expected = (2.0 * 1.01_f64.ln().powi(2)).sqrt();
+        assert!((realized_volatility(&samples, 60.0) - expected).abs() < 1e-12);
+
+        // Only returns inside the trailing window count
+        let with_history = [(-100.0, 50.0), (0.0, 100.0), (10.0, 101.0), (20.0, 100.0)];
+        assert!((realized_volatility(&with_history, 20.0) - expected).abs() < 1e-12);
+        assert!(realized_volatility(&with_history, 200.0) > expected);
+
+        assert_eq!(
+            realized_volatility(&[(0.0, 100.0), (10.0, 100.0)], 60.0),
+            0.0
+        );
+        assert_eq!(realized_volatility(&samples, 0.0), 0.0);
+        assert_eq!(realized_volatility(&[], 60.0), 0.0);
+    }
+
+    #[test]
+    fn test_calculate_ema() {
+        // One half-life after the jump the EMA is halfway to the new price
+        let ema = calculate_ema(&[(0.0, 100.0), (60.0, 200.0)], 60.0);
+        assert!((ema - 150.0).abs() < 1e-9);
+
+        // Two 30s steps decay exactly like one 60s step
+        let stepped = calculate_ema(&[(0.0, 100.0), (30.0, 200.0), (60.0, 200.0)], 60.0);
+        assert!((stepped - 150.0).abs() < 1e-9);
+
+        assert_eq!(calculate_ema(&[(0.0, 100.0), (10.0, 120.0)], 0.0), 120.0);
+        assert_eq!(calculate_ema(&[], 60.0), 0.0);
+    }
+
+    #[test]
+    fn test_validate_with_twap() {
+        let is_valid = validate_with_twap(102.0, 100.0, 5.0); // 2% deviation, max 5%
+        assert!(is_valid);
+
+        let is_invalid = validate_with_twap(110.0, 100.0, 5.0); // 10% deviation, max 5%
+        assert!(!is_invalid);
+    }
+
+    #[test]
+    fn test_manipulation_risk() {
+        // At TWAP nothing looks manipulated, even on a thin, quiet pool
+        assert_eq!(manipulation_risk(100.0, 100.0, 1000.0, 0.0), 0.0);
+
+        // The same 10% move is riskier the shallower and quieter the pool
+        let deep_busy = manipulation_risk(110.0, 100.0, 1e9, 1e9);
+        let shallow_busy = manipulation_risk(110.0, 100.0, 1e4, 1e4);
+        let shallow_quiet = manipulation_risk(110.0, 100.0, 1e4, 0.0);
+        assert!(deep_busy < shallow_busy && shallow_busy < shallow_quiet);
+        assert!(shallow_quiet > 0.8 && shallow_quiet < 1.0);
+
+        // Larger deviations raise the score on the same pool
+        assert!(
+            manipulation_risk(102.0, 100.0, 1e6, 1e4) < manipulation_risk(120.0, 100.0, 1e6, 1e4)
+        );
+        assert_eq!(manipulation_risk(100.0, 0.0, 1e6, 1e4), 1.0);
+    }
+
+    #[test]
+    fn test_oracle_reversion_arb() {
+        // Pool pushed 10% above its TWAP of 2.0: sell token_in back into it
+        let arb = oracle_reversion_arb(1e6, 2.2e6, 2.0, 30, 10.0).unwrap();
+        assert!(arb.sells_token_in);
+        assert!(arb.price_after < 2.2 && arb.price_after > 2.0);
+        assert_eq!(
+            arb.expected_profit,
+            arb.amount_out - arb.amount_in * 2.0 - 10.0
+        );
+
+        // The size maximizes profit at the TWAP valuation
+        let profit_at = |amount: f64| calculate_amount_out(1e6, 2.2e6, amount) - amount * 2.0;
+        let best = profit_at(arb.amount_in);
+        assert!(best >= profit_at(arb.amount_in * 0.99));
+        assert!(best >= profit_at(arb.amount_in * 1.01));
+
+        // Below TWAP: buy token_in with token_out
+        let arb = oracle_reversion_arb(1e6, 1.8e6, 2.0, 30, 10.0).unwrap();
+        assert!(!arb.sells_token_in);
+        assert!(arb.price_after > 1.8 && arb.price_after < 2.0);
+        assert!(arb.expected_profit > 0.0);
+
+        // Inside the fee band, or with gas larger than the edge, there is nothing to do
+        assert_eq!(oracle_reversion_arb(1e6, 2.002e6, 2.0, 30, 0.0), None);
+        assert_eq!(oracle_reversion_arb(1e6, 2.2e6, 2.0, 30, 1e9), None);
+        assert_eq!(oracle_reversion_arb(1e6, 2.2e6, 0.0, 30, 0.0), None);
+    }
+
+    #[test]
+    fn test_batch_evaluate_parallel_matches_sequential() {
+        let opportunities: Vec<(f64, f64, f64, f64)> = (0..500)
+            .map(|i| {
+                let gap = 1.0 + (i % 50) as f64 / 100.0;
+                (1e6, 2e6, 1e6, 2e6 * gap)
+            })
+            .collect();
+        let config = ArbitrageConfig {
+            gas_cost: 100.0,
+            flashloan_fee_pct: 0.0009,
+            min_price_diff_pct: 1.0,
+            competition: Some(CompetitionModel {
+                competitor_count: 2,
+                our_edge_pct: 10.0,
+            }),
+            ..ArbitrageConfig::default()
+        };
+
+        let parallel = batch_evaluate_opportunities_parallel(&opportunities, &config);
+        assert_eq!(
+            parallel,
+            batch_evaluate_opportunities(&opportunities, &config)
+        );
+        assert!(parallel.iter().any(|result| result.should_execute));
+        assert!(batch_evaluate_opportunities_parallel(&[], &config).is_empty());
+    }
+
+    #[test]
+    fn test_configure_threads() {
+        let opportunities: Vec<(f64, f64, f64, f64)> =
+            (0..64).map(|i| (1e6, 2e6, 1e6, 2.1e6 + i as f64)).collect();
+        let config = ArbitrageConfig::default();
+        let sequential = batch_evaluate_opportunities(&opportunities, &config);
+
+        configure_threads(3).unwrap();
+        assert_eq!(parallel_threads(), 3);
+        // Batch work is scheduled on the dedicated pool's workers, not the caller
+        let workers = run_parallel(|| {
+            opportunities
+                .par_iter()
+                .map(|_| rayon::current_thread_index())
+                .collect::<Vec<_>>()
+        });
+        assert!(workers.iter().all(|index| matches!(index, Some(0..=2))));
+        assert_eq!(
+            batch_evaluate_opportunities_parallel(&opportunities, &config),
+            sequential
+        );
+
+        configure_threads(0).unwrap();
+        assert_eq!(parallel_threads(), rayon::current_num_threads());
+        assert_eq!(
+            batch_evaluate_opportunities_parallel(&opportunities, &config),
+            sequential
+        );
+    }
+
+    #[test]
+    fn test_batch_slippage_ceiling() {
+        let opportunities = vec![
+            (1000000.0, 2000000.0, 1000000.0, 2500000.0), // Large gap
+            (1000000.0, 2000000.0, 1000000.0, 2000000.0), // No gap
+        ];
+        let mut config = ArbitrageConfig {
+            gas_cost: 100.0,
+            flashloan_fee_pct: 0.0009,
+            min_price_diff_pct: 5.0,
+            max_twap_deviation_pct: 10.0,
+            min_profit_threshold: 0.0,
+            ..ArbitrageConfig::default()
+        };
+
+        let results = batch_evaluate_opportunities(&opportunities, &config);
+        assert_eq!(results[1].reject_reason, RejectReason::NoOpportunity);
+        assert!(results[0].should_execute);
+        assert!(results[0].max_leg_slippage_pct > 0.0);
+
+        // Tighten the ceiling below the realized slippage
+        config.max_slippage_pct = results[0].max_leg_slippage_pct / 2.0;
+        let results = batch_evaluate_opportunities(&opportunities, &config);
+        assert!(!results[0].should_execute);
+        assert_eq!(results[0].reject_reason, RejectReason::SlippageExceeded);
+        assert!(results[0].optimal_amount > 0.0);
+    }
+
+    #[test]
+    fn test_soft_cap_returns_true_optimum() {
+        // A wide gap between deep pools pushes the optimum past 30% of reserves
+        let opportunities = vec![(1000000.0, 2000000.0, 1000000.0, 2500000.0)];
+        let mut config = ArbitrageConfig {
+            flashloan_fee_pct: 0.0009,
+            min_price_diff_pct: 5.0,
+            ..ArbitrageConfig::default()
+        };
+
+        let hard = batch_evaluate_opportunities(&opportunities, &config)[0];
+        assert!(!hard.exceeds_soft_cap);
+        assert!(hard.optimal_amount <= 300000.0);
+
+        config.cap_mode = CapMode::Soft;
+        let soft = batch_evaluate_opportunities(&opportunities, &config)[0];
+        assert!(soft.exceeds_soft_cap);
+        assert!(soft.optimal_amount > 300000.0);
+        assert!(soft.expected_profit > hard.expected_profit);
+
+        // The full flow carries the same flag, and traces it after the size
+        let (p1_in, p1_out, p2_in, p2_out) = opportunities[0];
+        let samples = [(0.0, 2.0), (10.0, 2.0)];
+        let (flow, steps) = execute_arbitrage_flow_traced(
+            (p1_in, p1_out),
+            (p2_in, p2_out),
+            &samples,
+            &samples,
+            &config,
+        );
+        assert!(flow.exceeds_soft_cap);
+        assert_eq!(flow.optimal_amount, soft.optimal_amount);
+        let flag = steps.iter().find(|step| step.name == "exceeds_soft_cap");
+        assert_eq!(flag.map(|step| step.value), Some(1.0));
+        config.cap_mode = CapMode::Hard;
+        let hard_flow =
+            execute_arbitrage_flow(p1_in, p1_out, p2_in, p2_out, &samples, &samples, &config);
+        assert!(!hard_flow.exceeds_soft_cap);
+        assert_eq!(hard_flow.optimal_amount, hard.optimal_amount);
+
+        // When the cap does not bind, soft mode matches hard mode
+        let (size, flagged) = optimize_trade_size_with_cap_mode(
+            1000000.0,
+            2000000.0,
+            2000000.0,
+            1100000.0,
+            0.0,
+            0.0009,
+            CapMode::Soft,
+        );
+        assert!(!flagged);
+        assert!(size > 0.0);
+        assert_eq!(
+            size,
+            optimize_trade_size_quadratic(1000000.0, 2000000.0, 2000000.0, 1100000.0, 0.0, 0.0009)
+        );
+    }
+
+    #[test]
+    fn test_slippage_penalty_shrinks_size() {
+        let pools = (1000000.0, 2000000.0, 2000000.0, 1100000.0);
+        let raw = optimize_trade_size_quadratic(pools.0, pools.1, pools.2, pools.3, 0.0, 0.0009);
+        let sized = |weight: f64| {
+            optimize_trade_size_quadratic_with_penalty(
+                pools.0, pools.1, pools.2, pools.3, 0.0, 0.0009, weight,
+            )
+        };
+        assert_eq!(sized(0.0), raw);
+
+        let penalized = sized(100.0);
+        assert!(penalized > 0.0 && penalized < raw);
+        assert!(
+            two_leg_slippage_pct(pools.0, pools.1, pools.2, pools.3, penalized)
+                < two_leg_slippage_pct(pools.0, pools.1, pools.2, pools.3, raw)
+        );
+
+        // The config weight reaches the batch sizing
+        let opportunities = vec![(pools.0, pools.1, pools.2, pools.3)];
+        let mut config = ArbitrageConfig {
+            flashloan_fee_pct: 0.0009,
+            ..ArbitrageConfig::default()
+        };
+        let unpenalized = batch_evaluate_opportunities(&opportunities, &config)[0];
+        config.slippage_penalty_weight = 100.0;
+        let evaluation = batch_evaluate_opportunities(&opportunities, &config)[0];
+        assert!(evaluation.optimal_amount < unpenalized.optimal_amount);
+        assert!(evaluation.max_leg_slippage_pct < unpenalized.max_leg_slippage_pct);
+    }
+
+    #[test]
+    fn test_diff_reserves() {
+        let old = vec![
+            vec![1000000.0, 2000000.0],
+            vec![500000.0, 500000.0],
+            vec![750000.0, 250000.0],
+        ];
+        let new = vec![
+            vec![1000000.0, 2000000.0],
+            vec![500100.0, 499900.0],    // Moved 0.02%
+            vec![750000.0001, 250000.0], // Float noise
+            vec![100.0, 100.0],          // New pool
+        ];
+
+        assert_eq!(diff_reserves(&old, &new, 1e-6), vec![1, 3]);
+        assert_eq!(diff_reserves(&old, &new, 1e-2), vec![3]);
+        assert_eq!(diff_reserves(&new, &old, 1e-6), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_batch_expected_value() {
+        let opportunities = vec![
+            (1000000.0, 2000000.0, 1000000.0, 2500000.0),
+            (1000000.0, 2000000.0, 1000000.0, 2000000.0),
+        ];
+        let mut config = ArbitrageConfig {
+            gas_cost: 100.0,
+            flashloan_fee_pct: 0.0009,
+            min_price_diff_pct: 5.0,
+            ..ArbitrageConfig::default()
+        };
+
+        let results = batch_evaluate_opportunities(&opportunities, &config);
+        assert_eq!(results[0].expected_value, None);
+
+        config.competition = Some(CompetitionModel {
+            competitor_count: 3,
+            our_edge_pct: 0.0,
+        });
+        let results = batch_evaluate_opportunities(&opportunities, &config);
+        let expected_value = results[0].expected_value.unwrap();
+        assert!((expected_value - results[0].expected_profit / 4.0).abs() < 1e-9);
+        assert_eq!(results[1].expected_value, Some(0.0));
+
+        let ranked = batch_evaluate_and_rank(&opportunities, &config, SortKey::ExpectedValue);
+        assert_eq!(ranked[0].index, 0);
+    }
+
+    #[test]
+    fn test_prefilter_opportunities() {
+        let opportunities = vec![
+            (1000000.0, 2000000.0, 1000000.0, 2000000.0), // No gap
+            (1000000.0, 2000000.0, 1000000.0, 2500000.0), // 25% gap
+            (1000000.0, 2000000.0, 1000000.0, 2020000.0), // 1% gap
+            (0.0, 2000000.0, 1000000.0, 2500000.0),       // Invalid pool
+        ];
+
+        assert_eq!(prefilter_opportunities(&opportunities, 5.0), vec![1]);
+        assert_eq!(prefilter_opportunities(&opportunities, 0.5), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_evaluate_forecasted() {
+        let config = ArbitrageConfig {
+            gas_cost: 10.0,
+            flashloan_fee_pct: 0.0009,
+            min_price_diff_pct: 1.0,
+            ..ArbitrageConfig::default()
+        };
+        let forecasted = (1000000.0, 2000000.0, 1000000.0, 2500000.0);
+        let forecast_only = batch_evaluate_opportunities(&[forecasted], &config)[0];
+
+        // Deep current pools: the forecast size stands, but today's smaller gap earns less
+        let current = (1000000.0, 2000000.0, 1000000.0, 2200000.0);
+        let result = evaluate_forecasted(current, forecasted, &config);
+        assert!(result.should_execute);
+        assert_eq!(result.amount, forecast_only.optimal_amount);
+        assert_eq!(result.forecasted_profit, forecast_only.expected_profit);
+        assert!(result.current_profit < result.forecasted_profit);
+
+        // Thin current pools cap the size at 30% of what is there now
+        let thin = (100000.0, 200000.0, 100000.0, 220000.0);
+        let capped = evaluate_forecasted(thin, forecasted, &config);
+        assert_eq!(capped.amount, 30000.0);
+        assert!(capped.forecasted_profit < forecast_only.expected_profit);
+
+        let flat = (1000000.0, 2000000.0, 1000000.0, 2000000.0);
+        assert!(!evaluate_forecasted(current, flat, &config).should_execute);
+    }
+
+    #[test]
+    fn test_evaluate_with_reasons() {
+        // small_gap buys in pool 2 (price 0.55), so that is the history that gets checked
+        let candidate = |reserves: (f64, f64, f64, f64), samples: Vec<(f64, f64)>| Candidate {
+            reserves,
+            price_samples_pool1: vec![],
+            price_samples_pool2: samples,
+        };
+        let small_gap = (1000000.0, 2000000.0, 2000000.0, 1100000.0);
+        let wide_gap = (1000000.0, 2000000.0, 1000000.0, 2500000.0);
+        let candidates = vec![
+            candidate(small_gap, vec![]),
+            candidate((1000000.0, 2000000.0, 1000000.0, 2000000.0), vec![]),
+            candidate(small_gap, vec![(0.0, 3.0), (10.0, 3.0)]),
+            candidate(small_gap, vec![(0.0, 0.55), (10.0, 0.55)]),
+        ];
+        let config = ArbitrageConfig {
+            gas_cost: 100.0,
+            flashloan_fee_pct: 0.0009,
+            min_price_diff_pct: 1.0,
+            max_twap_deviation_pct: 5.0,
+            ..ArbitrageConfig::default()
+        };
+        let reasons: Vec<RejectReason> = evaluate_with_reasons(&candidates, &config)
+            .iter()
+            .map(|result| result.reject_reason)
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                RejectReason::Accepted,
+                RejectReason::NoOpportunity,
+                RejectReason::TwapRejected,
+                RejectReason::Accepted,
+            ]
+        );
+
+        // A profitable trade short of the threshold, with the cap slack or binding
+        let demanding = ArbitrageConfig {
+            min_profit_threshold: 1e9,
+            ..config
+        };
+        let results = evaluate_with_reasons(
+            &[candidate(small_gap, vec![]), candidate(wide_gap, vec![])],
+            &demanding,
+        );
+        assert_eq!(results[0].reject_reason, RejectReason::BelowMinProfit);
+        assert!(results[0].profit > 0.0);
+        assert_eq!(results[1].reject_reason, RejectReason::LiquidityConstrained);
+        assert!(!results[1].should_execute);
+
+        // Gas larger than the whole edge
+        let expensive = ArbitrageConfig {
+            gas_cost: 1e4,
+            ..config
+        };
+        let result = evaluate_with_reasons(&[candidate(small_gap, vec![])], &expensive)[0];
+        assert_eq!(result.reject_reason, RejectReason::NegativeAfterGas);
+        assert_eq!(result.profit, -1e4);
+    }
 
-    // Step 6: Validate with TWAP
-    let current_price = calculate_pool_price(buy_res_in, buy_res_out);
-    let twap = calculate_twap(price_samples);
+    #[test]
+    fn test_v2_virtual_liquidity() {
+        assert_eq!(v2_virtual_liquidity(1e6, 4e6), 2e6);
+        assert_eq!(v2_virtual_liquidity(0.0, 4e6), 0.0);
+
+        // The equivalent full-range V3 position quotes exactly like the V2 pool
+        let (reserve_in, reserve_out): (f64, f64) = (1e6, 2.5e6);
+        let (amount_out, _) = v3_swap_within_tick(
+            (reserve_out / reserve_in).sqrt(),
+            v2_virtual_liquidity(reserve_in, reserve_out),
+            5000.0,
+            DEFAULT_FEE_BPS,
+        );
+        assert!(approx_eq(
+            amount_out,
+            calculate_amount_out(reserve_in, reserve_out, 5000.0),
+            1e-9,
+            0.0
+        ));
+    }
 
-    if !validate_with_twap(current_price, twap, config.max_twap_deviation_pct) {
-        return (false, 0.0, 0.0);
+    #[test]
+    fn test_v3_quote_cache() {
+        let ticks = [
+            TickRange {
+                tick_lower: -600,
+                tick_upper: 600,
+                liquidity: 1_000_000.0,
+            },
+            TickRange {
+                tick_lower: 0,
+                tick_upper: 1200,
+                liquidity: 500_000.0,
+            },
+        ];
+        let mut cache = V3QuoteCache::new();
+
+        let price = tick_to_sqrt_price(100);
+        let quote = cache.quote("eth-usdc", 30, price, &ticks, 1000.0).unwrap();
+        assert_eq!(quote, v3_swap_within_tick(price, 1_500_000.0, 1000.0, 30).0);
+
+        // Moving inside [0, 600) reuses the range, even with the ticks no longer passed
+        let moved = tick_to_sqrt_price(450);
+        let warm = cache.quote("eth-usdc", 30, moved, &[], 1000.0).unwrap();
+        assert_eq!(warm, v3_swap_within_tick(moved, 1_500_000.0, 1000.0, 30).0);
+        assert_eq!(
+            cache.stats(),
+            V3QuoteCacheStats {
+                hits: 1,
+                recomputes: 1,
+                entries: 1
+            }
+        );
+
+        // Crossing tick 600 picks up the narrower liquidity
+        let crossed = tick_to_sqrt_price(700);
+        let quote = cache
+            .quote("eth-usdc", 30, crossed, &ticks, 1000.0)
+            .unwrap();
+        assert_eq!(quote, v3_swap_within_tick(crossed, 500_000.0, 1000.0, 30).0);
+        assert_eq!(cache.stats().recomputes, 2);
+
+        // Fee tiers are cached separately; unknown tiers and empty ranges quote nothing
+        assert!(cache.quote("eth-usdc", 5, crossed, &ticks, 1000.0).is_ok());
+        assert_eq!(cache.stats().entries, 2);
+        assert_eq!(
+            cache.quote("eth-usdc", 7, crossed, &ticks, 1000.0),
+            Err(MathError::InvalidParameter {
+                name: "fee_bps",
+                value: 7.0
+            })
+        );
+        assert!(matches!(
+            cache.quote("eth-usdc", 30, tick_to_sqrt_price(1500), &ticks, 1000.0),
+            Err(MathError::InsufficientLiquidity { .. })
+        ));
+
+        cache.invalidate("eth-usdc");
+        assert_eq!(cache.stats().entries, 0);
     }
 
-    // Step 5: Optimize trade size
-    let optimal_amount = optimize_trade_size_quadratic(
-        buy_res_in,
-        buy_res_out,
-        sell_res_in,
-        sell_res_out,
-        config.gas_cost,
-        config.flashloan_fee_pct,
-    );
+    #[test]
+    fn test_pool_state_cache() {
+        let mut state = PoolState::new();
+        let balances = [1_000_000.0, 1_200_000.0, 900_000.0];
+
+        let d = state
+            .stableswap_invariant("3pool", &balances, 100.0)
+            .unwrap();
+        assert_eq!(d, stableswap_invariant(&balances, 100.0).unwrap());
+        assert_eq!(
+            state
+                .stableswap_invariant("3pool", &balances, 100.0)
+                .unwrap(),
+            d
+        );
+        assert_eq!(state.len(), 1);
+
+        // Warm quotes agree with solving from scratch
+        let quote = state
+            .stableswap_quote("3pool", &balances, 100.0, (0, 1), 10_000.0, 0.0004)
+            .unwrap();
+        let new_y = stableswap_get_y(&balances, 100.0, 0, 1, 1_010_000.0, d).unwrap();
+        assert_eq!(quote, (1_200_000.0 - new_y) * (1.0 - 0.0004));
+        assert_eq!(
+            Ok(quote),
+            curve_get_dy(&balances, 0, 1, 10_000.0, 100.0, 0.0004)
+        );
+        assert!(state
+            .stableswap_quote("3pool", &balances, 100.0, (0, 1), 10_000.0, 1.0)
+            .is_err());
+
+        // New balances replace the entry
+        let moved = [1_010_000.0, 1_190_000.0, 900_000.0];
+        let d_moved = state.stableswap_invariant("3pool", &moved, 100.0).unwrap();
+        assert_eq!(d_moved, stableswap_invariant(&moved, 100.0).unwrap());
+        assert_eq!(state.len(), 1);
+        assert_eq!(
+            state.stableswap_invariant("3pool", &moved, 200.0).unwrap(),
+            stableswap_invariant(&moved, 200.0).unwrap()
+        );
 
-    if optimal_amount <= 0.0 {
-        return (false, 0.0, 0.0);
+        let weights = [0.8, 0.2];
+        let v = state
+            .balancer_invariant("80-20", &[800.0, 200.0], &weights)
+            .unwrap();
+        assert!(approx_eq(
+            v,
+            800.0_f64.powf(0.8) * 200.0_f64.powf(0.2),
+            1e-12,
+            0.0
+        ));
+        assert_eq!(state.len(), 2);
+        assert!(state.balancer_invariant("bad", &[800.0], &weights).is_err());
+        assert_eq!(state.len(), 2);
+
+        state.invalidate("3pool");
+        assert_eq!(state.len(), 1);
+        state.clear();
+        assert_eq!(state.len(), 0);
     }
 
-    // Step 4: Estimate profitability
-    let expected_profit = estimate_arbitrage_profit(
-        buy_res_in,
-        buy_res_out,
-        sell_res_in,
-        sell_res_out,
-        optimal_amount,
-        config.gas_cost,
-        config.flashloan_fee_pct,
-    );
+    #[test]
+    fn test_price_table_to_usd() {
+        let mut table = PriceTable::new();
+        table.set_price("WETH", 3000.0);
+        table.set_price("usdc", 1.0);
 
-    // Step 7: Execute if profitable
-    let should_execute = expected_profit >= config.min_profit_threshold;
+        assert_eq!(to_usd(0.5, "weth", &table), Some(1500.0));
+        assert_eq!(to_usd(-20.0, "USDC", &table), Some(-20.0));
+        assert_eq!(to_usd(1.0, "DAI", &table), None);
 
-    (should_execute, optimal_amount, expected_profit)
-}
+        let config = ArbitrageConfig {
+            gas_cost: 100.0,
+            flashloan_fee_pct: 0.0009,
+            ..ArbitrageConfig::default()
+        };
+        let evaluations = batch_evaluate_opportunities(
+            &[
+                (1000000.0, 2000000.0, 1000000.0, 2500000.0),
+                (1000000.0, 2000000.0, 1000000.0, 2000000.0),
+            ],
+            &config,
+        );
+        let tokens = vec!["WETH".to_string(), "DAI".to_string()];
+        let usd = batch_to_usd(&evaluations, &tokens, &table).unwrap();
+        assert_eq!(usd[0], Some(evaluations[0].expected_profit * 3000.0));
+        assert_eq!(usd[1], None);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        assert!(batch_to_usd(&evaluations, &tokens[..1], &table).is_err());
+    }
 
     #[test]
-    fn test_uniswap_v2_slippage() {
-        let slippage = compute_uniswap_v2_slippage(1000000.0, 2000000.0, 10000.0);
-        assert!(slippage > 0.0);
-        assert!(slippage < 100.0);
+    fn test_next_initialized_tick() {
+        // Tick 18000 is compressed tick 300, one 256-tick word above the others
+        let bitmap = tick_bitmap_from_ticks(&[-120, 0, 600, 18000, 45], 60);
+
+        // zero_for_one searches at or below the current tick
+        assert_eq!(next_initialized_tick(&bitmap, 30, 60, true), Some(0));
+        assert_eq!(next_initialized_tick(&bitmap, 0, 60, true), Some(0));
+        assert_eq!(next_initialized_tick(&bitmap, -1, 60, true), Some(-120));
+        assert_eq!(next_initialized_tick(&bitmap, 20000, 60, true), Some(18000));
+        assert_eq!(next_initialized_tick(&bitmap, -121, 60, true), None);
+
+        // one_for_zero searches strictly above, crossing into the next word
+        assert_eq!(next_initialized_tick(&bitmap, 0, 60, false), Some(600));
+        assert_eq!(next_initialized_tick(&bitmap, -120, 60, false), Some(0));
+        assert_eq!(next_initialized_tick(&bitmap, 600, 60, false), Some(18000));
+        assert_eq!(next_initialized_tick(&bitmap, 18000, 60, false), None);
+
+        // Within one word the contract stops at the word boundary when nothing is set
+        assert_eq!(
+            next_initialized_tick_within_one_word(&bitmap, 600, 60, false),
+            (255 * 60, false)
+        );
+        assert_eq!(
+            next_initialized_tick_within_one_word(&bitmap, 600, 60, true),
+            (600, true)
+        );
+        assert_eq!(
+            next_initialized_tick_within_one_word(&bitmap, -121, 60, true),
+            (-256 * 60, false)
+        );
+
+        // Set bits in every limb of a word are found across limb boundaries
+        let bitmap = tick_bitmap_from_ticks(&[63, 64, 191, 192], 1);
+        assert_eq!(next_initialized_tick(&bitmap, 63, 1, false), Some(64));
+        assert_eq!(next_initialized_tick(&bitmap, 64, 1, false), Some(191));
+        assert_eq!(next_initialized_tick(&bitmap, 190, 1, true), Some(64));
+        assert_eq!(next_initialized_tick(&bitmap, 255, 1, true), Some(192));
+        assert_eq!(next_initialized_tick(&bitmap, 0, 0, true), None);
     }
 
     #[test]
-    fn test_zero_amount() {
-        let slippage = compute_uniswap_v2_slippage(1000000.0, 2000000.0, 0.0);
-        assert_eq!(slippage, 0.0);
+    fn test_try_variants_report_bad_input() {
+        // Valid input matches the infallible functions
+        assert_eq!(
+            try_calculate_amount_out(1e6, 2e6, 1000.0),
+            Ok(calculate_amount_out(1e6, 2e6, 1000.0))
+        );
+        assert_eq!(
+            try_calculate_amount_in(1e6, 2e6, 1000.0),
+            Ok(calculate_amount_in(1e6, 2e6, 1000.0))
+        );
+        assert_eq!(try_calculate_pool_price(1e6, 2e6), Ok(2.0));
+        assert_eq!(
+            try_estimate_arbitrage_profit(1e6, 2e6, 2e6, 1.1e6, 20000.0, 100.0, 0.0009),
+            Ok(estimate_arbitrage_profit(
+                1e6, 2e6, 2e6, 1.1e6, 20000.0, 100.0, 0.0009
+            ))
+        );
+        assert_eq!(try_calculate_amount_out(1e6, 2e6, 0.0), Ok(0.0));
+        // A rebate above the raw gas cost nets out negative, and adds to profit
+        let rebated_gas = effective_gas_cost(100.0, 1.5);
+        assert!(rebated_gas < 0.0);
+        assert_eq!(
+            try_estimate_arbitrage_profit(1e6, 2e6, 2e6, 1.1e6, 20000.0, rebated_gas, 0.0009),
+            Ok(estimate_arbitrage_profit(
+                1e6,
+                2e6,
+                2e6,
+                1.1e6,
+                20000.0,
+                rebated_gas,
+                0.0009
+            ))
+        );
+        assert!(matches!(
+            try_estimate_arbitrage_profit(1e6, 2e6, 2e6, 1.1e6, 20000.0, f64::NAN, 0.0009),
+            Err(MathError::InvalidParameter {
+                name: "gas_cost",
+                ..
+            })
+        ));
+
+        // Where the infallible versions return 0, these say why
+        assert_eq!(calculate_pool_price(0.0, 2e6), 0.0);
+        assert_eq!(
+            try_calculate_pool_price(0.0, 2e6),
+            Err(MathError::ZeroReserve {
+                name: "reserve_in",
+                value: 0.0
+            })
+        );
+        assert_eq!(
+            try_calculate_amount_out(1e6, 2e6, -5.0),
+            Err(MathError::NegativeAmount {
+                name: "amount_in",
+                value: -5.0
+            })
+        );
+        assert_eq!(
+            try_calculate_amount_in(1e6, 2e6, 2e6),
+            Err(MathError::InsufficientLiquidity {
+                requested: 2e6,
+                available: 2e6
+            })
+        );
+        assert!(matches!(
+            try_estimate_arbitrage_profit(1e6, 2e6, 2e6, 0.0, 20000.0, 100.0, 0.0009),
+            Err(MathError::ZeroReserve {
+                name: "reserve_out",
+                ..
+            })
+        ));
+        assert!(try_calculate_amount_out_with_fee(1e6, 2e6, 1000.0, 10001).is_err());
+        assert_eq!(
+            MathError::InsufficientLiquidity {
+                requested: 5.0,
+                available: 4.0
+            }
+            .to_string(),
+            "requested 5 out of a reserve of only 4"
+        );
     }
 
     #[test]
-    fn test_optimal_trade_size() {
-        let size = optimal_trade_size(1000000.0, 2000000.0, 100.0, 50.0);
-        assert!(size > 0.0);
+    fn test_safe_mode() {
+        let invalid = || stableswap_invariant(&[1000.0], 100.0);
+
+        // Off by default: errors pass through and are not counted
+        reset_error_counts();
+        assert!(or_safe(invalid(), 0.0).is_err());
+        assert_eq!(last_error_counts(), ErrorCounts::default());
+
+        set_safe_mode(true);
+        assert_eq!(or_safe(invalid(), 0.0), Ok(0.0));
+        assert_eq!(
+            or_safe(
+                simulate_parallel_flashloan_paths(&[vec![]], &[], 0.0, &[]),
+                vec![]
+            ),
+            Ok(vec![])
+        );
+        assert_eq!(or_safe(Ok(2.5), 0.0), Ok(2.5));
+        set_safe_mode(false);
+
+        assert_eq!(
+            last_error_counts(),
+            ErrorCounts {
+                invalid_parameter: 1,
+                length_mismatch: 1,
+                ..ErrorCounts::default()
+            }
+        );
+        reset_error_counts();
+        assert_eq!(last_error_counts(), ErrorCounts::default());
     }
 
     #[test]
-    fn test_flashloan_amount_calculation() {
-        let amount = calculate_flashloan_amount(
-            1000000.0, 2000000.0, // Buy pool - price is 2:1
-            1800000.0, 1000000.0, // Sell pool - price is 0.556:1 (significant difference)
-            0.0009,    // 0.09% flashloan fee (Aave)
-            100.0,     // Gas cost
+    fn test_validate_path_tokens() {
+        let hop = |token_in: &str, token_out: &str| (token_in.to_string(), token_out.to_string());
+        let cycle = [hop("WETH", "USDC"), hop("usdc", "DAI"), hop("DAI", "WETH")];
+        assert_eq!(validate_path_tokens(&cycle, 3), Ok(()));
+        assert_eq!(validate_path_tokens(&[], 0), Ok(()));
+
+        // The second hop is oriented backwards, so it takes USDC's counterpart instead
+        let reversed = [hop("WETH", "USDC"), hop("DAI", "USDC"), hop("DAI", "WETH")];
+        let err = validate_path_tokens(&reversed, 3).unwrap_err();
+        assert_eq!(
+            err,
+            MathError::BrokenPath {
+                hop: 1,
+                expected: "USDC".to_string(),
+                actual: "DAI".to_string(),
+            }
         );
-        // With a significant price difference, should find profitable flashloan amount
-        assert!(amount >= 0.0); // At minimum should not be negative
+        assert_eq!(
+            err.to_string(),
+            "hop 1 takes DAI but the previous hop outputs USDC"
+        );
+
+        assert_eq!(
+            validate_path_tokens(&cycle, 2),
+            Err(MathError::LengthMismatch {
+                name: "hop token pairs",
+                expected: 2,
+                actual: 3,
+            })
+        );
+
+        let paths = vec![vec![(1e6, 2e6); 3]; 2];
+        assert_eq!(
+            validate_paths_tokens(&[cycle.to_vec(), cycle.to_vec()], &paths),
+            Ok(())
+        );
+        assert!(matches!(
+            validate_paths_tokens(&[cycle.to_vec(), reversed.to_vec()], &paths),
+            Err(MathError::BrokenPath { hop: 1, .. })
+        ));
+        assert!(validate_paths_tokens(&[cycle.to_vec()], &paths).is_err());
     }
 
     #[test]
-    fn test_market_impact() {
-        let impact = calculate_market_impact(1000000.0, 2000000.0, 50000.0);
-        assert!(impact > 0.0);
-        assert!(impact < 100.0);
+    fn test_viable_count() {
+        let small_gap = (1000000.0, 2000000.0, 1000000.0, 2050000.0);
+        let opportunities = vec![
+            (1000000.0, 2000000.0, 1000000.0, 2500000.0),
+            (1000000.0, 2000000.0, 1000000.0, 2000000.0),
+            small_gap,
+        ];
+        let config = ArbitrageConfig {
+            gas_cost: 100.0,
+            flashloan_fee_pct: 0.0009,
+            min_price_diff_pct: 1.0,
+            ..ArbitrageConfig::default()
+        };
+
+        // Never fewer than the full batch would execute
+        let executed = batch_evaluate_opportunities(&opportunities, &config)
+            .iter()
+            .filter(|evaluation| evaluation.should_execute)
+            .count();
+        assert_eq!(viable_count(&opportunities, &config), 2);
+        assert!(viable_count(&opportunities, &config) >= executed);
+
+        // Gas beyond the small gap's best profit leaves only the wide gap
+        let best_small = estimate_arbitrage_profit(
+            small_gap.0,
+            small_gap.1,
+            small_gap.2,
+            small_gap.3,
+            two_leg_optimum_with_cost(
+                small_gap.0,
+                small_gap.1,
+                small_gap.2,
+                small_gap.3,
+                30,
+                1.0009,
+            ),
+            0.0,
+            0.0009,
+        );
+        let expensive = ArbitrageConfig {
+            gas_cost: best_small + 1.0,
+            ..config
+        };
+        assert_eq!(viable_count(&opportunities, &expensive), 1);
+
+        let demanding = ArbitrageConfig {
+            min_profit_threshold: 1e12,
+            ..config
+        };
+        assert_eq!(viable_count(&opportunities, &demanding), 0);
     }
 
     #[test]
-    fn test_multihop_slippage() {
-        let reserves = vec![(1000000.0, 2000000.0), (2000000.0, 1000000.0)];
-        let slippage = calculate_multihop_slippage(&reserves, 10000.0);
-        assert!(slippage >= 0.0);
+    fn test_batch_evaluate_with_budget() {
+        let opportunities = vec![
+            (1000000.0, 2000000.0, 1000000.0, 2500000.0),
+            (1000000.0, 2000000.0, 2000000.0, 1100000.0),
+            (1000000.0, 2000000.0, 1000000.0, 2000000.0),
+        ];
+        let config = ArbitrageConfig {
+            gas_cost: 100.0,
+            flashloan_fee_pct: 0.0009,
+            ..ArbitrageConfig::default()
+        };
+
+        // A generous budget matches the unbudgeted batch exactly
+        let relaxed = batch_evaluate_with_budget(&opportunities, &config, 60.0);
+        assert_eq!(
+            relaxed.evaluations,
+            batch_evaluate_opportunities(&opportunities, &config)
+        );
+        assert_eq!(relaxed.avg_iterations, 100.0);
+
+        // An exhausted budget still evaluates everything, on the coarsest grid
+        let rushed = batch_evaluate_with_budget(&opportunities, &config, 0.0);
+        assert_eq!(rushed.evaluations.len(), 3);
+        assert_eq!(rushed.avg_iterations, 10.0);
+        assert!(rushed.evaluations[0].should_execute);
+        assert!(rushed.evaluations[0].expected_profit <= relaxed.evaluations[0].expected_profit);
+
+        assert_eq!(
+            batch_evaluate_with_budget(&[], &config, 1.0).avg_iterations,
+            0.0
+        );
     }
 
     #[test]
-    fn test_calculate_pool_price() {
-        let price = calculate_pool_price(1000000.0, 2000000.0);
-        assert_eq!(price, 2.0);
+    fn test_below_min_trade_size() {
+        // Near parity on shallow pools: the optimum is a few thousandths of a token
+        let near_parity = (1.0, 2.0, 2.0, 1.01);
+        let config = ArbitrageConfig {
+            min_trade_size: 0.01,
+            ..ArbitrageConfig::default()
+        };
+
+        let evaluation = evaluate_opportunity(&near_parity, &ArbitrageConfig::default());
+        assert!(evaluation.should_execute);
+        assert!(evaluation.optimal_amount > 0.0 && evaluation.optimal_amount < 0.01);
+
+        let evaluation = evaluate_opportunity(&near_parity, &config);
+        assert!(!evaluation.should_execute);
+        assert_eq!(evaluation.reject_reason, RejectReason::BelowMinSize);
+        assert!(evaluation.expected_profit > 0.0);
+
+        let (p1_in, p1_out, p2_in, p2_out) = near_parity;
+        let samples = [(0.0, 0.505), (10.0, 0.505)];
+        let flow =
+            execute_arbitrage_flow(p1_in, p1_out, p2_in, p2_out, &samples, &samples, &config);
+        assert!(!flow.should_execute);
+        assert!(flow.optimal_amount > 0.0 && flow.optimal_amount < 0.01);
+
+        let candidate = Candidate {
+            reserves: near_parity,
+            price_samples_pool1: vec![],
+            price_samples_pool2: vec![],
+        };
+        let result = evaluate_with_reasons(&[candidate], &config)[0];
+        assert_eq!(result.reject_reason, RejectReason::BelowMinSize);
     }
 
     #[test]
-    fn test_identify_arbitrage_opportunity() {
-        // Pool 1: price = 2.0, Pool 2: price = 2.2 (10% difference)
-        let (has_opp, diff, direction) = identify_arbitrage_opportunity(
-            1000000.0, 2000000.0, // Pool 1
-            1000000.0, 2200000.0, // Pool 2
-            5.0,       // Min 5% difference
-        );
-        assert!(has_opp);
-        assert!(diff >= 5.0);
-        assert_eq!(direction, 1); // Buy pool 1, sell pool 2
+    fn test_batch_evaluate_and_rank() {
+        let opportunities = vec![
+            (1000000.0, 2000000.0, 1000000.0, 2000000.0), // No gap
+            (1000000.0, 2000000.0, 1000000.0, 2200000.0), // Medium gap
+            (1000000.0, 2000000.0, 1000000.0, 2500000.0), // Large gap
+            (1000000.0, 2000000.0, 1000000.0, 2000000.0), // No gap, ties with index 0
+        ];
+        let config = ArbitrageConfig {
+            gas_cost: 100.0,
+            flashloan_fee_pct: 0.0009,
+            min_price_diff_pct: 1.0,
+            max_twap_deviation_pct: 10.0,
+            min_profit_threshold: 0.0,
+            ..ArbitrageConfig::default()
+        };
+
+        let by_profit = batch_evaluate_and_rank(&opportunities, &config, SortKey::Profit);
+        let order: Vec<usize> = by_profit.iter().map(|r| r.index).collect();
+        assert_eq!(order, vec![2, 1, 0, 3]);
+
+        // With one gas cost for the whole batch, profit per gas orders as profit does
+        let by_gas = batch_evaluate_and_rank(&opportunities, &config, SortKey::ProfitPerGas);
+        assert_eq!(by_gas, by_profit);
+
+        // Rejected opportunities have zero slippage but rank after every executable one,
+        // in input order
+        let by_slippage = batch_evaluate_and_rank(&opportunities, &config, SortKey::Slippage);
+        let order: Vec<usize> = by_slippage.iter().map(|r| r.index).collect();
+        assert!(by_slippage[..2].iter().all(|r| r.evaluation.should_execute));
+        assert_eq!(order[2..], [0, 3]);
+        assert!(by_slippage[0].evaluation.max_leg_slippage_pct > 0.0);
     }
 
     #[test]
-    fn test_calculate_amount_in() {
-        let amount_in = calculate_amount_in(1000000.0, 2000000.0, 10000.0);
-        assert!(amount_in > 0.0);
-        assert!(amount_in < 1000000.0);
+    fn test_rank_by_slippage_differs_from_profit() {
+        // A deep pool with a small gap trades at low slippage for less than a shallow pool
+        // with a wide gap makes at high slippage
+        let opportunities = vec![
+            (1000000.0, 2000000.0, 2000000.0, 1100000.0), // 10% round trip, shallow
+            (10000000.0, 20000000.0, 20000000.0, 10200000.0), // 2% round trip, deep
+        ];
+        let config = ArbitrageConfig {
+            gas_cost: 100.0,
+            flashloan_fee_pct: 0.0009,
+            min_price_diff_pct: 1.0,
+            max_twap_deviation_pct: 10.0,
+            ..ArbitrageConfig::default()
+        };
+        let evaluations = batch_evaluate_opportunities(&opportunities, &config);
+        assert!(evaluations.iter().all(|e| e.should_execute));
+        assert!(evaluations[0].expected_profit > evaluations[1].expected_profit);
+        assert!(evaluations[0].max_leg_slippage_pct > evaluations[1].max_leg_slippage_pct);
+
+        let order = |sort_key| {
+            batch_evaluate_and_rank(&opportunities, &config, sort_key)
+                .iter()
+                .map(|r| r.index)
+                .collect::<Vec<usize>>()
+        };
+        assert_eq!(order(SortKey::Profit), vec![0, 1]);
+        assert_eq!(order(SortKey::Slippage), vec![1, 0]);
     }
 
     #[test]
-    fn test_calculate_amount_out() {
-        let amount_out = calculate_amount_out(1000000.0, 2000000.0, 10000.0);
-        assert!(amount_out > 0.0);
-        assert!(amount_out < 20000.0);
+    fn test_deterministic_sum_independent_of_split() {
+        let values: Vec<f64> = (0..1000)
+            .map(|i| ((i * 7919) % 1000) as f64 * 1e-3 + 1e6 * (i % 3) as f64)
+            .collect();
+        let total = deterministic_sum(&values);
+
+        // Two workers each reduce half the leaves; stitching their partials back in leaf
+        // order reproduces the single-threaded result bit for bit
+        let leaves: Vec<&[f64]> = values.chunks(REDUCTION_CHUNK).collect();
+        let (first, second) = leaves.split_at(leaves.len() / 2);
+        let second_worker: Vec<f64> = second.iter().map(|leaf| leaf.iter().sum()).collect();
+        let first_worker: Vec<f64> = first.iter().map(|leaf| leaf.iter().sum()).collect();
+        let partials = [first_worker, second_worker].concat();
+        assert_eq!(combine_partial_sums(&partials).to_bits(), total.to_bits());
+
+        assert_eq!(deterministic_sum(&[]), 0.0);
+        assert_eq!(deterministic_sum(&[1.5, 2.5]), 4.0);
     }
 
     #[test]
-    fn test_estimate_arbitrage_profit() {
-        let profit = estimate_arbitrage_profit(
-            1000000.0, 2000000.0, // Buy pool
-            2000000.0, 1000000.0, // Sell pool
-            10000.0,   // Amount in
-            100.0,     // Gas cost
-            0.0009,    // Flashloan fee
-        );
-        // Should be negative or very low due to price similarity
-        assert!(profit < 1000.0);
+    fn test_calibration_report() {
+        let predicted = vec![1.0, 2.0, 3.0, 4.0];
+        let realized = vec![0.5, 1.5, 2.5, 3.5];
+        let report = calibration_report(&predicted, &realized);
+        assert!((report.bias - 0.5).abs() < 1e-12);
+        assert!((report.mae - 0.5).abs() < 1e-12);
+        assert!((report.rmse - 0.5).abs() < 1e-12);
+        assert!((report.correlation - 1.0).abs() < 1e-12);
+
+        let inverted = vec![4.0, 3.0, 2.0, 1.0];
+        let report = calibration_report(&predicted, &inverted);
+        assert!((report.bias).abs() < 1e-12);
+        assert!((report.correlation + 1.0).abs() < 1e-12);
+
+        let empty = calibration_report(&[], &[]);
+        assert_eq!(empty.rmse, 0.0);
     }
 
     #[test]
-    fn test_solve_quadratic() {
-        // x² - 5x + 6 = 0, roots: 2 and 3
-        let (r1, r2) = solve_quadratic(1.0, -5.0, 6.0);
-        assert!((r1 - 3.0).abs() < 0.01 || (r1 - 2.0).abs() < 0.01);
-        assert!((r2 - 3.0).abs() < 0.01 || (r2 - 2.0).abs() < 0.01);
+    fn test_suggest_min_profit() {
+        // Net outcomes by prediction: 100 -> +80, 50 -> +20, 20 -> -30, 10 -> +5
+        let historical = [
+            (20.0, 10.0, 40.0),
+            (100.0, 90.0, 10.0),
+            (10.0, 15.0, 10.0),
+            (50.0, 30.0, 10.0),
+        ];
+        assert_eq!(suggest_min_profit(&historical), 50.0);
+
+        // Trades sharing a prediction are admitted together: the pair at 50 nets -10 overall
+        let tied = [(100.0, 90.0, 10.0), (50.0, 40.0, 10.0), (50.0, 0.0, 40.0)];
+        assert_eq!(suggest_min_profit(&tied), 100.0);
+
+        let all_losing = [(100.0, 5.0, 10.0), (50.0, 0.0, 10.0)];
+        assert_eq!(suggest_min_profit(&all_losing), f64::INFINITY);
+        assert_eq!(suggest_min_profit(&[]), 0.0);
     }
 
     #[test]
-    fn test_calculate_twap() {
-        let samples = vec![(0.0, 100.0), (10.0, 110.0), (20.0, 105.0)];
-        let twap = calculate_twap(&samples);
-        assert!(twap > 0.0);
-        assert!(twap >= 100.0 && twap <= 110.0);
+    fn test_self_test() {
+        assert!(self_test());
     }
 
     #[test]
-    fn test_validate_with_twap() {
-        let is_valid = validate_with_twap(102.0, 100.0, 5.0); // 2% deviation, max 5%
-        assert!(is_valid);
+    fn test_benchmark() {
+        let result = benchmark(100);
+        assert!(result.ops_per_sec > 0.0);
+        assert!((result.ops_per_sec * result.avg_latency_ns - 1e9).abs() < 1e-3);
+        assert_eq!(benchmark(0).ops_per_sec, 0.0);
+    }
 
-        let is_invalid = validate_with_twap(110.0, 100.0, 5.0); // 10% deviation, max 5%
-        assert!(!is_invalid);
+    #[test]
+    fn test_opportunity_id() {
+        let pools = vec!["0xpool_a".to_string(), "0xpool_b".to_string()];
+        let id = opportunity_id(&pools, 1, 18_000_000).unwrap();
+        assert_eq!(id.len(), 16);
+        assert_eq!(Some(id.clone()), opportunity_id(&pools, 1, 18_000_000));
+
+        // Changing any component changes the ID
+        assert_ne!(Some(id.clone()), opportunity_id(&pools, 2, 18_000_000));
+        assert_ne!(Some(id.clone()), opportunity_id(&pools, 1, 18_000_001));
+        let reversed = vec!["0xpool_b".to_string(), "0xpool_a".to_string()];
+        assert_ne!(Some(id), opportunity_id(&reversed, 1, 18_000_000));
+
+        // Only the two real directions hash; 257 would otherwise alias 1 as a byte
+        for direction in [0, 3, 257] {
+            assert_eq!(opportunity_id(&pools, direction, 18_000_000), None);
+        }
+
+        let split_a = vec!["ab".to_string(), "c".to_string()];
+        let split_b = vec!["a".to_string(), "bc".to_string()];
+        assert_ne!(
+            opportunity_id(&split_a, 1, 1),
+            opportunity_id(&split_b, 1, 1)
+        );
+    }
+
+    #[test]
+    fn test_order_flow_imbalance() {
+        let trades = vec![(300.0, true), (100.0, false), (0.0, false)];
+        assert!((order_flow_imbalance(&trades) - 0.5).abs() < 1e-12);
+
+        assert_eq!(order_flow_imbalance(&[(50.0, false)]), -1.0);
+        assert_eq!(order_flow_imbalance(&[]), 0.0);
     }
 
     #[test]
@@ -796,9 +8798,15 @@ mod tests {
             min_price_diff_pct: 5.0,
             max_twap_deviation_pct: 10.0,
             min_profit_threshold: 50.0,
+            ..ArbitrageConfig::default()
         };
 
-        let (should_execute, optimal_amount, profit) = execute_arbitrage_flow(
+        let FlowDecision {
+            should_execute,
+            optimal_amount,
+            expected_profit: profit,
+            ..
+        } = execute_arbitrage_flow(
             1000000.0,
             2000000.0, // Pool 1
             1000000.0,
@@ -814,57 +8822,190 @@ mod tests {
             assert!(profit >= 50.0);
         }
     }
-}
-
-/// Batch process multiple arbitrage opportunities for optimized performance
-/// Returns Vec<(should_execute, optimal_amount, expected_profit)>
-pub fn batch_evaluate_opportunities(
-    opportunities: &[(f64, f64, f64, f64)], // (pool1_res_in, pool1_res_out, pool2_res_in, pool2_res_out)
-    config: &ArbitrageConfig,
-) -> Vec<(bool, f64, f64)> {
-    opportunities
-        .iter()
-        .map(|(p1_in, p1_out, p2_in, p2_out)| {
-            let (has_opp, _, direction) = identify_arbitrage_opportunity(
-                *p1_in,
-                *p1_out,
-                *p2_in,
-                *p2_out,
-                config.min_price_diff_pct,
-            );
-
-            if !has_opp {
-                return (false, 0.0, 0.0);
-            }
-
-            let (buy_res_in, buy_res_out, sell_res_in, sell_res_out) = if direction == 1 {
-                (*p1_in, *p1_out, *p2_in, *p2_out)
-            } else {
-                (*p2_in, *p2_out, *p1_in, *p1_out)
-            };
 
-            let optimal_amount = optimize_trade_size_quadratic(
-                buy_res_in,
-                buy_res_out,
-                sell_res_in,
-                sell_res_out,
-                config.gas_cost,
-                config.flashloan_fee_pct,
-            );
+    #[test]
+    fn test_execute_arbitrage_flow_traced() {
+        let price_samples = vec![(0.0, 2.0), (10.0, 2.0)];
+        let config = ArbitrageConfig {
+            gas_cost: 50.0,
+            flashloan_fee_pct: 0.0009,
+            min_price_diff_pct: 5.0,
+            max_twap_deviation_pct: 10.0,
+            ..ArbitrageConfig::default()
+        };
 
-            let profit = estimate_arbitrage_profit(
-                buy_res_in,
-                buy_res_out,
-                sell_res_in,
-                sell_res_out,
-                optimal_amount,
-                config.gas_cost,
-                config.flashloan_fee_pct,
-            );
+        let (result, steps) = execute_arbitrage_flow_traced(
+            (1000000.0, 2000000.0),
+            (1000000.0, 2500000.0),
+            &price_samples,
+            &price_samples,
+            &config,
+        );
+        assert_eq!(
+            result,
+            execute_arbitrage_flow(
+                1000000.0,
+                2000000.0,
+                1000000.0,
+                2500000.0,
+                &price_samples,
+                &price_samples,
+                &config,
+            )
+        );
+        let names: Vec<&str> = steps.iter().map(|step| step.name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "price_diff_pct",
+                "direction",
+                "current_price",
+                "twap",
+                "optimal_amount",
+                "exceeds_soft_cap",
+                "expected_profit",
+                "should_execute"
+            ]
+        );
+        assert_eq!(steps[1].value, 1.0);
+        assert_eq!(steps[3].value, 2.0);
+        assert_eq!(steps[4].value, result.optimal_amount);
+        assert_eq!(steps[5].value, 0.0);
+        assert_eq!(steps[6].value, result.expected_profit);
+
+        // A TWAP rejection stops the trace after the prices
+        let far_samples = vec![(0.0, 3.0), (10.0, 3.0)];
+        let (result, steps) = execute_arbitrage_flow_traced(
+            (1000000.0, 2000000.0),
+            (1000000.0, 2500000.0),
+            &far_samples,
+            &far_samples,
+            &config,
+        );
+        assert!(!result.should_execute);
+        assert_eq!(steps.last().map(|step| step.name), Some("twap"));
+    }
 
-            let should_execute = profit >= config.min_profit_threshold;
+    #[test]
+    fn test_execute_arbitrage_flow_gas_pricing() {
+        let price_samples = vec![(0.0, 2.0), (10.0, 2.0)];
+        let flat = ArbitrageConfig {
+            gas_cost: 50.0,
+            flashloan_fee_pct: 0.0009,
+            min_price_diff_pct: 5.0,
+            max_twap_deviation_pct: 10.0,
+            ..ArbitrageConfig::default()
+        };
+        // 200k gas at 100 gwei is 0.02 native, i.e. 50 at a native price of 2500
+        let pricing = GasPricing {
+            gas_units: 200_000.0,
+            gas_price_gwei: 100.0,
+            native_price_quote_token: 2500.0,
+        };
+        let priced = ArbitrageConfig {
+            gas_cost: 0.0,
+            gas_pricing: Some(pricing),
+            ..flat
+        };
+        assert!((priced.resolved_gas_cost() - 50.0).abs() < 1e-9);
+
+        let flow = |config: &ArbitrageConfig| {
+            execute_arbitrage_flow(
+                1000000.0,
+                2000000.0,
+                1000000.0,
+                2500000.0,
+                &price_samples,
+                &price_samples,
+                config,
+            )
+        };
+        let flat_flow = flow(&flat);
+        let priced_flow = flow(&priced);
+        assert!(priced_flow.should_execute);
+        assert_eq!(priced_flow.optimal_amount, flat_flow.optimal_amount);
+        assert!((priced_flow.expected_profit - flat_flow.expected_profit).abs() < 1e-6);
+
+        // A higher native price raises gas without touching gas_cost
+        let pricier = ArbitrageConfig {
+            gas_pricing: Some(GasPricing {
+                native_price_quote_token: 5000.0,
+                ..pricing
+            }),
+            ..priced
+        };
+        let pricier_profit = flow(&pricier).expected_profit;
+        assert!((flat_flow.expected_profit - pricier_profit - 50.0).abs() < 1e-6);
+    }
 
-            (should_execute, optimal_amount, profit)
-        })
-        .collect()
+    #[test]
+    fn test_execute_arbitrage_flow_both_directions() {
+        let config = ArbitrageConfig {
+            gas_cost: 100.0,
+            flashloan_fee_pct: 0.0009,
+            min_price_diff_pct: 5.0,
+            max_twap_deviation_pct: 10.0,
+            min_profit_threshold: 50.0,
+            ..ArbitrageConfig::default()
+        };
+        // Cheap pool quotes 2.0 with a history to match; the rich pool's history is far off,
+        // so the flow only passes if the TWAP is taken from the buy (cheap) pool
+        let cheap = (1000000.0, 2000000.0);
+        let rich = (800000.0, 2000000.0);
+        let cheap_samples = [(0.0, 2.0), (10.0, 2.02), (20.0, 2.01)];
+        let rich_samples = [(0.0, 9.0), (10.0, 9.0), (20.0, 9.0)];
+
+        let (has_opp, _, direction) =
+            identify_arbitrage_opportunity(cheap.0, cheap.1, rich.0, rich.1, 5.0);
+        assert!(has_opp);
+        assert_eq!(direction, 1);
+        let forward = execute_arbitrage_flow(
+            cheap.0,
+            cheap.1,
+            rich.0,
+            rich.1,
+            &cheap_samples,
+            &rich_samples,
+            &config,
+        );
+        assert!(forward.should_execute);
+        assert!(forward.optimal_amount > 0.0 && forward.expected_profit >= 50.0);
+
+        // Direction 2: the same market with the pools swapped gives the same trade
+        let (_, _, direction) =
+            identify_arbitrage_opportunity(rich.0, rich.1, cheap.0, cheap.1, 5.0);
+        assert_eq!(direction, 2);
+        let backward = execute_arbitrage_flow(
+            rich.0,
+            rich.1,
+            cheap.0,
+            cheap.1,
+            &rich_samples,
+            &cheap_samples,
+            &config,
+        );
+        assert_eq!(backward, forward);
+
+        // Handing the buy pool's history to the sell pool slot fails validation either way
+        let misrouted = execute_arbitrage_flow(
+            cheap.0,
+            cheap.1,
+            rich.0,
+            rich.1,
+            &rich_samples,
+            &cheap_samples,
+            &config,
+        );
+        assert_eq!(misrouted, FlowDecision::default());
+        let misrouted = execute_arbitrage_flow(
+            rich.0,
+            rich.1,
+            cheap.0,
+            cheap.1,
+            &cheap_samples,
+            &rich_samples,
+            &config,
+        );
+        assert_eq!(misrouted, FlowDecision::default());
+    }
 }